@@ -255,7 +255,7 @@ impl state_machine::StateMachine for HashmapStateMachine {
     /// `apply()` is called on when a client's `.propose()` is commited and reaches the state
     /// machine. At this point it is durable and is going to be applied on at least half the nodes
     /// within the next couple round trips.
-    fn apply(&mut self, new_value: &[u8]) -> Vec<u8> {
+    fn apply(&mut self, new_value: &[u8]) -> Result<Vec<u8>, state_machine::ApplyError> {
         scoped_info!("Applying {:?}", String::from_utf8_lossy(new_value));
         // Deserialize
         let string = String::from_utf8_lossy(new_value);
@@ -282,7 +282,7 @@ impl state_machine::StateMachine for HashmapStateMachine {
         };
 
         // Respond.
-        response.unwrap().into_bytes()
+        Ok(response.unwrap().into_bytes())
     }
 
     /// `query()` is called on when a client's `.query()` is recieved. It does not go through the
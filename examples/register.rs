@@ -223,11 +223,11 @@ impl RegisterStateMachine {
 /// `query`.
 impl state_machine::StateMachine for RegisterStateMachine {
 
-    fn apply(&mut self, proposal: &[u8]) -> Vec<u8> {
+    fn apply(&mut self, proposal: &[u8]) -> Result<Vec<u8>, state_machine::ApplyError> {
 
         let message = match bincode::serde::deserialize::<Proposal>(&proposal) {
             Ok(proposal) => proposal,
-            Err(err) => return format!("{}", err).into_bytes(),
+            Err(err) => return Ok(format!("{}", err).into_bytes()),
         };
 
         // Encoding the current value should never fail.
@@ -242,7 +242,7 @@ impl state_machine::StateMachine for RegisterStateMachine {
             },
         }
 
-        response
+        Ok(response)
     }
 
     fn query(&self, query: &[u8]) -> Vec<u8> {
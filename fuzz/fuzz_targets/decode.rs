@@ -0,0 +1,10 @@
+//! Feeds arbitrary bytes through a connection, decoding them the way the server decodes messages
+//! received from the network. Errors are expected; panics are bugs.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate raft;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = raft::fuzzing::connection(data);
+});
@@ -0,0 +1,102 @@
+//! Application of committed entries to the `StateMachine`, either inline in the event loop or on a
+//! dedicated worker thread.
+//!
+//! A worker thread keeps an expensive `StateMachine::apply` from blocking network processing.
+//! Committed entries and confirmed queries are handed to the worker over a channel in log order,
+//! and the worker processes them strictly in that order. Its outputs are returned to the event
+//! loop through `ServerCommand::Applied`.
+
+use std::result;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use mio::{NotifyError, Sender};
+
+use {ClientId, LogIndex, Result, ServerId, Term};
+use server::ServerCommand;
+use state_machine::{ApplyError, StateMachine};
+
+/// Applies commands to the state machine on behalf of `Consensus`.
+pub enum Applier<M> {
+    /// The state machine is applied to inline, in the event loop.
+    Inline(M),
+    /// The state machine is owned by a worker thread, to which tasks are sent.
+    Worker(mpsc::Sender<ApplyTask>),
+}
+
+/// Work for the apply worker thread.
+pub enum ApplyTask {
+    /// Apply the committed entry at the index. Empty entries are no-ops which are not applied,
+    /// but are passed through to preserve ordering.
+    Apply(LogIndex, Vec<u8>),
+    /// Query the state machine on behalf of the client, after all previously sent entries are
+    /// applied.
+    Query(ClientId, Vec<u8>),
+    /// Notify the state machine that the server gained (`true`) or lost leadership in the term.
+    LeadershipChange(bool, Term),
+}
+
+/// The output of the apply worker thread.
+#[derive(Debug)]
+pub enum ApplyOutput {
+    /// The entry at the index was applied, with the state machine's result, or `None` if the
+    /// entry was a no-op.
+    Applied(LogIndex, Option<result::Result<Vec<u8>, ApplyError>>),
+    /// The client's query was answered.
+    Queried(ClientId, Vec<u8>),
+}
+
+/// Spawns a worker thread which owns the state machine, and returns the channel on which to send
+/// it tasks. Outputs are sent to the event loop through `notify`. The worker exits once the task
+/// channel is closed, the event loop is gone, or the state machine fails fatally.
+pub fn spawn_worker<M>(id: ServerId,
+                       mut state_machine: M,
+                       notify: Sender<ServerCommand>)
+                       -> Result<mpsc::Sender<ApplyTask>>
+where M: StateMachine {
+    let (tx, rx) = mpsc::channel();
+    try!(thread::Builder::new().name(format!("raft::Apply({})", id)).spawn(move || {
+        for task in rx.iter() {
+            let output = match task {
+                ApplyTask::Apply(index, ref command) if command.is_empty() => {
+                    ApplyOutput::Applied(index, None)
+                },
+                ApplyTask::Apply(index, command) => {
+                    ApplyOutput::Applied(index, Some(state_machine.apply(&command)))
+                },
+                ApplyTask::Query(client, query) => {
+                    ApplyOutput::Queried(client, state_machine.query(&query))
+                },
+                ApplyTask::LeadershipChange(is_leader, term) => {
+                    state_machine.on_leadership_change(is_leader, term);
+                    continue;
+                },
+            };
+            let fatal = match output {
+                ApplyOutput::Applied(_, Some(Err(ApplyError::Fatal(..)))) => true,
+                _ => false,
+            };
+            if !send_output(&notify, output) || fatal {
+                break;
+            }
+        }
+    }));
+    Ok(tx)
+}
+
+/// Sends an output to the event loop, waiting for room if its channel is full. Returns `false` if
+/// the event loop is gone.
+fn send_output(notify: &Sender<ServerCommand>, output: ApplyOutput) -> bool {
+    let mut command = ServerCommand::Applied(output);
+    loop {
+        match notify.send(command) {
+            Ok(()) => return true,
+            Err(NotifyError::Full(unsent)) => {
+                command = unsent;
+                thread::sleep(Duration::from_millis(1));
+            },
+            Err(..) => return false,
+        }
+    }
+}
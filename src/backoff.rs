@@ -41,6 +41,11 @@ impl Backoff {
         self.retries = 0;
     }
 
+    /// Returns the number of retries since the last reset.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
     /// Retrieves the next backoff duration in milliseconds.
     pub fn next_backoff_ms(&mut self) -> u64 {
         // Prevent overflow by testing if the backoff will be greater than the
@@ -0,0 +1,171 @@
+use std::rc::Rc;
+
+use capnp::MallocMessageBuilder;
+use capnp::serialize;
+
+/// A pool of byte buffers into which outgoing messages are serialized. Buffers are returned to the
+/// pool once their message is written, so that a busy server does not allocate a buffer for each
+/// message it sends.
+pub struct BufferPool {
+
+    /// Buffers available for reuse.
+    buffers: Vec<Vec<u8>>,
+
+    /// The maximum number of buffers retained.
+    max_buffers: usize,
+
+    /// The maximum capacity, in bytes, of a retained buffer. Larger buffers are freed, so that an
+    /// occasional large message does not pin its memory.
+    max_buffer_bytes: usize,
+
+    /// Number of buffers handed out which were reused rather than allocated.
+    reused: u64,
+
+    /// The most recently serialized message, and its serialization. A message broadcast to several
+    /// connections is queued on each in turn, and is serialized only for the first.
+    last_serialized: Option<(Rc<MallocMessageBuilder>, Rc<Vec<u8>>)>,
+}
+
+impl BufferPool {
+
+    /// Creates a pool retaining up to `max_buffers` buffers of up to `max_buffer_bytes` each.
+    pub fn new(max_buffers: usize, max_buffer_bytes: usize) -> BufferPool {
+        BufferPool {
+            buffers: Vec::new(),
+            max_buffers: max_buffers,
+            max_buffer_bytes: max_buffer_bytes,
+            reused: 0,
+            last_serialized: None,
+        }
+    }
+
+    /// Takes an empty buffer from the pool, allocating one if none is available.
+    pub fn take(&mut self) -> Vec<u8> {
+        match self.buffers.pop() {
+            Some(buffer) => {
+                self.reused += 1;
+                buffer
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns a buffer to the pool.
+    pub fn give(&mut self, mut buffer: Vec<u8>) {
+        if self.buffers.len() < self.max_buffers && buffer.capacity() <= self.max_buffer_bytes {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+
+    /// Returns a shared buffer to the pool, if nothing else holds it.
+    pub fn give_shared(&mut self, buffer: Rc<Vec<u8>>) {
+        if let Ok(buffer) = Rc::try_unwrap(buffer) {
+            self.give(buffer);
+        }
+    }
+
+    /// Serializes the message into a buffer taken from the pool. The buffer is shared by every
+    /// connection the message is queued on; a message queued on several connections in a row is
+    /// serialized only once.
+    pub fn serialize(&mut self, message: &Rc<MallocMessageBuilder>) -> Rc<Vec<u8>> {
+        if let Some((ref last, ref buffer)) = self.last_serialized {
+            // The retained reference keeps the last message alive, so its address is not reused.
+            if &**last as *const MallocMessageBuilder == &**message as *const MallocMessageBuilder {
+                return buffer.clone();
+            }
+        }
+        self.forget_serialized();
+        let mut buffer = self.take();
+        serialize::write_message(&mut buffer, &**message)
+            .expect("serializing to a buffer can not fail");
+        let buffer = Rc::new(buffer);
+        self.last_serialized = Some((message.clone(), buffer.clone()));
+        buffer
+    }
+
+    /// Releases the pool's references to the last serialized message and its buffer, so that a
+    /// connection holding the only other reference to the buffer may modify it. The buffer returns
+    /// to the pool if no connection holds it.
+    pub fn forget_serialized(&mut self) {
+        if let Some((_, buffer)) = self.last_serialized.take() {
+            self.give_shared(buffer);
+        }
+    }
+
+    /// Returns the number of buffers handed out which were reused rather than allocated.
+    #[cfg(test)]
+    pub fn reused(&self) -> u64 {
+        self.reused
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::rc::Rc;
+
+    use capnp::serialize;
+
+    use messages;
+    use super::*;
+
+    #[test]
+    fn test_buffer_reuse() {
+        let mut pool = BufferPool::new(2, 16);
+
+        let mut a = pool.take();
+        a.extend_from_slice(b"foo");
+        let b = pool.take();
+        let c = pool.take();
+        assert_eq!(0, pool.reused());
+
+        pool.give(a);
+        pool.give(b);
+        // The pool is full.
+        pool.give(c);
+
+        let a = pool.take();
+        assert!(a.is_empty());
+        pool.take();
+        assert_eq!(2, pool.reused());
+        pool.take();
+        assert_eq!(2, pool.reused());
+
+        // Oversized buffers are not retained.
+        pool.give(Vec::with_capacity(17));
+        pool.take();
+        assert_eq!(2, pool.reused());
+    }
+
+    #[test]
+    fn test_serialize_shared() {
+        let mut pool = BufferPool::new(2, 1024);
+        let message = Rc::new(messages::ping_request());
+
+        // A message queued several times in a row is serialized once.
+        let a = pool.serialize(&message);
+        let b = pool.serialize(&message.clone());
+        assert_eq!(&*a as *const Vec<u8>, &*b as *const Vec<u8>);
+
+        let mut expected = Vec::new();
+        serialize::write_message(&mut expected, &*message).unwrap();
+        assert_eq!(expected, *a);
+
+        let c = pool.serialize(&Rc::new(messages::ping_request()));
+        assert!(&*a as *const Vec<u8> != &*c as *const Vec<u8>);
+        assert_eq!(*a, *c);
+
+        // A shared buffer returns to the pool once the last holder gives it back.
+        pool.give_shared(a);
+        assert_eq!(0, pool.buffers.len());
+        pool.give_shared(b);
+        assert_eq!(1, pool.buffers.len());
+
+        // The last serialized buffer returns once the pool forgets it.
+        pool.give_shared(c);
+        assert_eq!(1, pool.buffers.len());
+        pool.forget_serialized();
+        assert_eq!(2, pool.buffers.len());
+    }
+}
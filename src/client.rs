@@ -13,13 +13,18 @@ use bufstream::BufStream;
 use capnp::{serialize, MessageReader, ReaderOptions, MallocMessageBuilder};
 
 use messages_capnp::{client_response, command_response};
-use messages;
+use messages::{self, Preamble};
 use ClientId;
+use Error;
 use Result;
 use RaftError;
 
 const CLIENT_TIMEOUT: u64 = 1500;
 
+/// The default number of consecutive failures communicating with the known leader after which
+/// the client searches the cluster for the leader again.
+const DEFAULT_MAX_LEADER_FAILURES: u32 = 3;
+
 /// The representation of a Client connection to the cluster.
 pub struct Client {
     /// The `Uuid` of the client, should be unique in the cluster.
@@ -30,22 +35,53 @@ pub struct Client {
     leader_connection: Option<BufStream<TcpStream>>,
     /// A lookup for the cluster's nodes.
     cluster: HashSet<SocketAddr>,
+    /// The cluster's nodes, in the order in which they are tried when searching for the leader.
+    members: Vec<SocketAddr>,
+    /// The position in `members` of the next node to try.
+    next_member: usize,
+    /// The address of the last known leader, if any.
+    leader: Option<SocketAddr>,
+    /// The number of consecutive failures communicating with `leader`.
+    leader_failures: u32,
+    /// The number of consecutive failures communicating with `leader` after which the leader is
+    /// searched for again.
+    max_leader_failures: u32,
 }
 
 impl Client {
 
     /// Creates a new client.
     pub fn new(cluster: HashSet<SocketAddr>) -> Client {
+        let members = cluster.iter().cloned().collect();
         Client {
             id: ClientId::new(),
             leader_connection: None,
             cluster: cluster,
+            members: members,
+            next_member: 0,
+            leader: None,
+            leader_failures: 0,
+            max_leader_failures: DEFAULT_MAX_LEADER_FAILURES,
         }
     }
 
+    /// Sets the number of consecutive failures communicating with the known leader, whether
+    /// connection errors or timeouts, after which the client searches the cluster for the leader
+    /// again. Defaults to 3.
+    pub fn set_max_leader_failures(&mut self, failures: u32) {
+        self.max_leader_failures = failures;
+    }
+
     /// Proposes an entry to be appended to the replicated log. This will only
     /// return once the entry has been durably committed.
     /// Returns `Error` when the entire cluster has an unknown leader. Try proposing again later.
+    ///
+    /// Proposing an empty entry is a barrier: the entry is committed but not applied, and an empty
+    /// result is returned once every entry proposed before it has committed.
+    ///
+    /// Servers do not detect duplicate proposals. If the connection to the leader fails before the
+    /// response arrives, the proposal is sent again, so if the first was committed the entry is
+    /// applied twice.
     pub fn propose(&mut self, entry: &[u8]) -> Result<Vec<u8>> {
         scoped_trace!("{:?}: propose", self);
         let mut message = messages::proposal_request(entry);
@@ -60,44 +96,106 @@ impl Client {
         self.send_message(&mut message)
     }
 
+    /// Asks the cluster for its members, and replaces the servers the client knows of with them,
+    /// for instance after their addresses changed. The leader is asked if the client is connected
+    /// to it, and otherwise each known server in turn until one answers.
+    pub fn refresh_members(&mut self) -> Result<()> {
+        scoped_trace!("{:?}: refresh_members", self);
+        if let Some(mut connection) = self.leader_connection.take() {
+            if let Ok(members) = self.request_members(&mut connection) {
+                self.leader_connection = Some(connection);
+                self.set_members(members);
+                return Ok(());
+            }
+            self.leader_failed();
+        }
+        for addr in self.members.clone() {
+            let members = self.connect(addr)
+                              .and_then(|mut connection| self.request_members(&mut connection));
+            if let Ok(members) = members {
+                self.set_members(members);
+                return Ok(());
+            }
+        }
+        Err(RaftError::ServerUnavailable.into())
+    }
+
+    /// Asks the server at the other end of the connection for the members of the cluster.
+    fn request_members(&self, connection: &mut BufStream<TcpStream>) -> Result<HashSet<SocketAddr>> {
+        try!(serialize::write_message(connection, &messages::get_configuration_request()));
+        try!(connection.flush());
+        let response = try!(serialize::read_message(connection, ReaderOptions::new()));
+        let servers = try!(messages::decode_configuration_response(&response));
+        Ok(servers.into_iter().map(|(_, addr)| addr).collect())
+    }
+
+    /// Replaces the servers the client knows of. The known leader is forgotten unless it remains
+    /// a member.
+    fn set_members(&mut self, cluster: HashSet<SocketAddr>) {
+        scoped_debug!("{:?}: cluster members: {:?}", self, cluster);
+        self.members = cluster.iter().cloned().collect();
+        self.next_member = 0;
+        if self.leader.map_or(false, |leader| !cluster.contains(&leader)) {
+            self.leader = None;
+            self.leader_failures = 0;
+            self.leader_connection = None;
+        }
+        self.cluster = cluster;
+    }
+
+    /// Sends the message to the leader and returns its response, searching for the leader if
+    /// necessary. Nodes are tried in round-robin order, and each node which fails to answer or
+    /// does not know the leader is tried at most once per message.
     fn send_message(&mut self, message: &mut MallocMessageBuilder) -> Result<Vec<u8>> {
-        let mut members = self.cluster.iter().cloned();
+        let mut remaining_members = self.members.len();
 
         loop {
             // We presume in this loop that most errors are temporary and it may take a redirect
             // (or more!) to find a leader in bad network conditions.
-            // TODO: Have timouts.
-            let mut connection = match self.leader_connection.take() {
+            let (addr, mut connection) = match self.leader_connection.take() {
                 Some(cxn) => {
                     scoped_debug!("had existing connection {:?}", cxn.get_ref().peer_addr());
-                    cxn
+                    (self.leader, cxn)
                 },
                 None => {
-                    let leader = try!(members.next().ok_or(RaftError::LeaderSearchExhausted));
-                    scoped_debug!("connecting to potential leader {}", leader);
-                    // Send the preamble.
-                    let preamble = messages::client_connection_preamble(self.id);
-                    let mut stream = match TcpStream::connect(leader) {
-                        Ok(stream) => {
-                            let timeout = Some(Duration::from_millis(CLIENT_TIMEOUT));
-                            if let Err(_) = stream.set_read_timeout(timeout) {
-                                continue
+                    let addr = match self.leader {
+                        Some(leader) if self.leader_failures < self.max_leader_failures => leader,
+                        _ => {
+                            self.leader = None;
+                            if remaining_members == 0 {
+                                return Err(RaftError::LeaderSearchExhausted.into());
                             }
-                            BufStream::new(stream)
+                            remaining_members -= 1;
+                            let member = self.members[self.next_member % self.members.len()];
+                            self.next_member = self.next_member.wrapping_add(1);
+                            member
                         },
-                        Err(_) => continue,
                     };
-                    scoped_debug!("connected");
-                    if let Err(_) = serialize::write_message(&mut stream, &*preamble) { continue };
-                    stream
+                    scoped_debug!("connecting to potential leader {}", addr);
+                    match self.connect(addr) {
+                        Ok(stream) => (Some(addr), stream),
+                        Err(_) => {
+                            self.leader_failed();
+                            continue
+                        },
+                    }
                 }
             };
-            if let Err(_) = serialize::write_message(&mut connection, message) { continue };
-            if let Err(_) = connection.flush() { continue };
+            if let Err(_) = serialize::write_message(&mut connection, message) {
+                self.leader_failed();
+                continue
+            };
+            if let Err(_) = connection.flush() {
+                self.leader_failed();
+                continue
+            };
             scoped_debug!("awaiting response from connection");
             let response = match serialize::read_message(&mut connection, ReaderOptions::new()) {
                 Ok(res) => res,
-                Err(_) => continue,
+                Err(_) => {
+                    self.leader_failed();
+                    continue
+                },
             };
             let reader = match response.get_root::<client_response::Reader>() {
                 Ok(reader) => reader,
@@ -108,28 +206,51 @@ impl Client {
                     match status.which() {
                         Ok(command_response::Which::Success(data)) => {
                             scoped_debug!("received response Success");
-                            self.leader_connection = Some(connection);
+                            self.leader_answered(addr, connection);
                             return data
                                 .map(|v| Vec::from(v))
                                 .map_err(|e| e.into()) // Exit the function.
                         },
+                        Ok(command_response::Which::Committed(())) => {
+                            scoped_debug!("received response Committed");
+                            self.leader_answered(addr, connection);
+                            return Ok(Vec::new()) // Exit the function.
+                        },
+                        Ok(command_response::Which::Rejected(reason)) => {
+                            scoped_debug!("received response Rejected");
+                            self.leader_answered(addr, connection);
+                            let reason = Vec::from(try!(reason));
+                            return Err(RaftError::ProposalRejected(reason).into()) // Exit the function.
+                        },
+                        Ok(command_response::Which::NotCommitted(())) => {
+                            scoped_debug!("received response NotCommitted");
+                            self.leader_answered(addr, connection);
+                            return Err(RaftError::NotCommitted.into()) // Exit the function.
+                        },
+                        Ok(command_response::Which::CommandTooLarge(())) => {
+                            scoped_debug!("received response CommandTooLarge");
+                            self.leader_answered(addr, connection);
+                            return Err(RaftError::CommandTooLarge.into()) // Exit the function.
+                        },
+                        Ok(command_response::Which::Overloaded(())) => {
+                            scoped_debug!("received response Overloaded");
+                            self.leader_answered(addr, connection);
+                            return Err(RaftError::Overloaded.into()) // Exit the function.
+                        },
                         Ok(command_response::Which::UnknownLeader(())) => {
                             scoped_debug!("received response UnknownLeader");
+                            self.leader = None;
                             () // Keep looping.
                         },
                         Ok(command_response::Which::NotLeader(leader)) => {
                             scoped_debug!("received response NotLeader");
-                            let leader_str = try!(leader);
-                            if !self.cluster.contains(&try!(SocketAddr::from_str(leader_str))) {
+                            let leader = try!(SocketAddr::from_str(try!(leader)));
+                            if !self.cluster.contains(&leader) {
                                 scoped_debug!("cluster violation detected");
                                 return Err(RaftError::ClusterViolation.into()) // Exit the function.
                             }
-                            let mut connection: TcpStream = try!(TcpStream::connect(leader_str));
-                            let preamble = messages::client_connection_preamble(self.id);
-                            if let Err(_) = serialize::write_message(&mut connection, &*preamble) {
-                                continue
-                            };
-                            self.leader_connection = Some(BufStream::new(connection));
+                            self.leader = Some(leader);
+                            self.leader_failures = 0;
                         },
                         Err(_) => continue,
                     }
@@ -138,6 +259,47 @@ impl Client {
             };
         }
     }
+
+    /// Opens a connection to the server, sends the preamble, and checks the protocol version of
+    /// the preamble the server replies with.
+    fn connect(&self, addr: SocketAddr) -> Result<BufStream<TcpStream>> {
+        let stream = try!(TcpStream::connect(addr));
+        try!(stream.set_read_timeout(Some(Duration::from_millis(CLIENT_TIMEOUT))));
+        let mut stream = BufStream::new(stream);
+        let preamble = messages::client_connection_preamble(self.id);
+        try!(serialize::write_message(&mut stream, &*preamble));
+        try!(stream.flush());
+        let reply = try!(serialize::read_message(&mut stream, ReaderOptions::new()));
+        match messages::decode_connection_preamble(&reply) {
+            Ok(Preamble::Server(..)) => (),
+            Ok(Preamble::Client(..)) => return Err(RaftError::UnknownConnectionType.into()),
+            Err(error) => {
+                if let Error::Raft(RaftError::IncompatibleProtocolVersion(version)) = error {
+                    scoped_error!("server {} uses protocol version {}; this client uses version {}",
+                                  addr, version, messages::PROTOCOL_VERSION);
+                }
+                return Err(error);
+            },
+        }
+        scoped_debug!("connected");
+        Ok(stream)
+    }
+
+    /// Records that the server at `addr` answered as the leader, and keeps its connection.
+    fn leader_answered(&mut self, addr: Option<SocketAddr>, connection: BufStream<TcpStream>) {
+        if addr.is_some() {
+            self.leader = addr;
+        }
+        self.leader_failures = 0;
+        self.leader_connection = Some(connection);
+    }
+
+    /// Records a failure to communicate with the known leader, if any.
+    fn leader_failed(&mut self) {
+        if self.leader.is_some() {
+            self.leader_failures += 1;
+        }
+    }
 }
 
 impl fmt::Debug for Client {
@@ -152,16 +314,16 @@ mod tests {
     extern crate env_logger;
 
     use std::collections::HashSet;
-    use std::io::Write;
+    use std::io::{Read, Write};
     use std::net::{TcpStream, TcpListener};
     use std::thread;
 
     use uuid::Uuid;
-    use capnp::{serialize, ReaderOptions};
+    use capnp::{serialize, MallocMessageBuilder, MessageBuilder, ReaderOptions};
     use capnp::message::MessageReader;
     use bufstream::BufStream;
 
-    use {Client, messages, Result};
+    use {Client, messages, Result, ServerId};
     use messages_capnp::{connection_preamble, client_request};
 
     fn expect_preamble(connection: &mut TcpStream, client_id: Uuid) -> Result<bool> {
@@ -175,6 +337,20 @@ mod tests {
         }
     }
 
+    /// Replies to the client's preamble with a server preamble of the provided protocol version.
+    fn send_preamble(connection: &mut TcpStream, protocol_version: u32) {
+        let mut preamble = MallocMessageBuilder::new_default();
+        {
+            let mut builder = preamble.init_root::<connection_preamble::Builder>();
+            builder.set_protocol_version(protocol_version);
+            let mut server = builder.init_id().init_server();
+            server.set_addr(&format!("{}", connection.local_addr().unwrap()));
+            server.set_id(0);
+        }
+        serialize::write_message(connection, &preamble).unwrap();
+        connection.flush().unwrap();
+    }
+
     fn expect_proposal(connection: &mut TcpStream, value: &[u8]) -> Result<bool> {
         let message = try!(serialize::read_message(connection, ReaderOptions::new()));
         let request = try!(message.get_root::<client_request::Reader>());
@@ -207,6 +383,7 @@ mod tests {
             // Proposal should be fine, no errors.
             scoped_debug!("Should get preamble and proposal. Responds Success");
             expect_preamble(&mut connection, client_id).unwrap();
+            send_preamble(&mut connection, messages::PROTOCOL_VERSION);
             expect_proposal(&mut connection, to_propose).unwrap();
             // Send response! (success!)
             let response = messages::command_response_success(b"Foxes");
@@ -232,6 +409,7 @@ mod tests {
         cluster.insert(test_addr);
 
         let mut client = Client::new(cluster);
+        let client_id = client.id.0.clone();
         let to_propose = b"Bears";
 
         // The client connects on the proposal.
@@ -240,7 +418,9 @@ mod tests {
             let (mut connection, _)  = test_server.accept().unwrap();
 
             // Proposal should report unknown leader, and have the client return error.
-            scoped_debug!("Should get proposal. Responds UnknownLeader");
+            scoped_debug!("Should get preamble and proposal. Responds UnknownLeader");
+            expect_preamble(&mut connection, client_id).unwrap();
+            send_preamble(&mut connection, messages::PROTOCOL_VERSION);
             expect_proposal(&mut connection, to_propose).unwrap();
             // Send response! (unknown leader!) Client should drop connection.
             let response = messages::command_response_unknown_leader();
@@ -288,6 +468,7 @@ mod tests {
             scoped_debug!("Second server should get preamble and proposal. Responds Success.");
             let (mut connection, _)  = second_server.accept().unwrap();
             expect_preamble(&mut connection, client_id).unwrap();
+            send_preamble(&mut connection, messages::PROTOCOL_VERSION);
             expect_proposal(&mut connection, to_propose).unwrap();
 
             // Send final response! (Success!)
@@ -310,6 +491,103 @@ mod tests {
         child.join().unwrap();
     }
 
+    /// Tests that the client finds the new leader when the leader it has been talking to fails.
+    #[test]
+    fn test_proposal_leader_failover() {
+        setup_test!("test_proposal_leader_failover");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+        cluster.insert(test_addr);
+
+        let second_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let second_addr = second_server.local_addr().unwrap();
+        cluster.insert(second_addr);
+
+        let mut client = Client::new(cluster);
+        let client_id = client.id.0.clone();
+
+        let child = thread::spawn(move || {
+            scoped_debug!("Should get preamble and proposal. Responds Success, then fails.");
+            let (mut connection, _)  = test_server.accept().unwrap();
+            expect_preamble(&mut connection, client_id).unwrap();
+            expect_proposal(&mut connection, b"Bears").unwrap();
+            let response = messages::command_response_success(b"Foxes");
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush().unwrap();
+            drop(connection);
+            drop(test_server);
+
+            scoped_debug!("Second server should get preamble and proposal. Responds Success.");
+            let (mut connection, _)  = second_server.accept().unwrap();
+            expect_preamble(&mut connection, client_id).unwrap();
+            send_preamble(&mut connection, messages::PROTOCOL_VERSION);
+            expect_proposal(&mut connection, b"Wolves").unwrap();
+            let response = messages::command_response_success(b"Owls");
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush().unwrap();
+        });
+
+        // Workaround to set up rigged selection of servers.
+        client.leader = Some(test_addr);
+        client.leader_connection = {
+            let preamble = messages::client_connection_preamble(client.id);
+            let mut stream = BufStream::new(TcpStream::connect(test_addr).unwrap());
+            serialize::write_message(&mut stream, &*preamble).unwrap();
+            Some(stream)
+        };
+
+        assert_eq!(client.propose(b"Bears").unwrap(), b"Foxes");
+        assert_eq!(Some(test_addr), client.leader);
+
+        // The leader is gone; the client moves on to the other server.
+        assert_eq!(client.propose(b"Wolves").unwrap(), b"Owls");
+        assert_eq!(Some(second_addr), client.leader);
+
+        child.join().unwrap();
+    }
+
+    /// Tests that the client replaces the servers it knows of with the members reported by the
+    /// cluster.
+    #[test]
+    fn test_refresh_members() {
+        setup_test!("test_refresh_members");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        cluster.insert(test_server.local_addr().unwrap());
+
+        // The other servers have moved to addresses the client does not know of.
+        let second_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let third_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let test_addr = test_server.local_addr().unwrap();
+
+        let mut client = Client::new(cluster);
+        let client_id = client.id.0.clone();
+
+        let child = thread::spawn(move || {
+            let (mut connection, _)  = test_server.accept().unwrap();
+            expect_preamble(&mut connection, client_id).unwrap();
+            send_preamble(&mut connection, messages::PROTOCOL_VERSION);
+            let message = serialize::read_message(&mut connection, ReaderOptions::new()).unwrap();
+            match message.get_root::<client_request::Reader>().unwrap().which().unwrap() {
+                client_request::Which::GetConfiguration(..) => (),
+                _ => panic!("expected a GetConfiguration request"),
+            }
+            let response = messages::configuration_response(&[(ServerId::from(0), test_addr),
+                                                               (ServerId::from(1), second_addr),
+                                                               (ServerId::from(2), third_addr)]);
+            serialize::write_message(&mut connection, &*response).unwrap();
+            connection.flush().unwrap();
+        });
+
+        client.refresh_members().unwrap();
+        let expected: HashSet<_> = vec![test_addr, second_addr, third_addr].into_iter().collect();
+        assert_eq!(expected, client.cluster);
+        assert_eq!(3, client.members.len());
+
+        child.join().unwrap();
+    }
+
     /// This test makes sure that the client cannot be redirected to a leader which exists outside
     /// the cluster. This is a necessary test since it would introduce error into the cluster.
     #[test]
@@ -359,4 +637,32 @@ mod tests {
 
         child.join().unwrap();
     }
+
+    /// Tests that the client refuses a server which replies to its preamble with an incompatible
+    /// protocol version, without sending it the proposal.
+    #[test]
+    fn test_incompatible_protocol_version() {
+        setup_test!("test_incompatible_protocol_version");
+        let mut cluster = HashSet::new();
+        let test_server = TcpListener::bind("127.0.0.1:0").unwrap();
+        cluster.insert(test_server.local_addr().unwrap());
+
+        let mut client = Client::new(cluster);
+        let client_id = client.id.0.clone();
+
+        let child = thread::spawn(move || {
+            let (mut connection, _)  = test_server.accept().unwrap();
+            expect_preamble(&mut connection, client_id).unwrap();
+            send_preamble(&mut connection, messages::PROTOCOL_VERSION + 1);
+            // The client closes the connection.
+            let mut buf = Vec::new();
+            connection.read_to_end(&mut buf).unwrap();
+            assert!(buf.is_empty());
+        });
+
+        assert!(client.propose(b"Bears").is_err());
+        assert!(client.leader_connection.is_none());
+
+        child.join().unwrap();
+    }
 }
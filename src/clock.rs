@@ -0,0 +1,85 @@
+//! Sources of time for the `Consensus` module.
+//!
+//! The `Consensus` module never reads wall-clock time directly. Instead, it asks a `Clock` for the
+//! current time in milliseconds when it schedules a timeout, and fires the timeout once the clock
+//! has advanced past its deadline. In production the `Server` drives timeouts through the event
+//! loop and the `SystemClock` is used. Tests can substitute a `ManualClock`, which only moves
+//! forward when told to, making election and heartbeat behavior deterministic.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// A monotonic source of time.
+pub trait Clock {
+
+    /// Returns the number of milliseconds elapsed since an arbitrary, fixed origin.
+    fn now_ms(&self) -> u64;
+}
+
+/// A `Clock` backed by the operating system's monotonic clock.
+pub struct SystemClock {
+    origin: Instant,
+}
+
+impl SystemClock {
+
+    /// Creates a new `SystemClock` with its origin at the current instant.
+    pub fn new() -> SystemClock {
+        SystemClock { origin: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let elapsed = self.origin.elapsed();
+        elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64
+    }
+}
+
+/// A `Clock` which only advances when explicitly told to.
+///
+/// Clones of a `ManualClock` share the same time, so a test can hand one clone to a `Consensus`
+/// and keep another to advance it.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl ManualClock {
+
+    /// Creates a new `ManualClock` starting at time 0.
+    pub fn new() -> ManualClock {
+        ManualClock { now: Rc::new(Cell::new(0)) }
+    }
+
+    /// Advances the clock by the provided number of milliseconds.
+    pub fn advance(&self, ms: u64) {
+        self.now.set(self.now.get().checked_add(ms).expect("overflow while advancing ManualClock"));
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let shared = clock.clone();
+        assert_eq!(0, clock.now_ms());
+
+        shared.advance(10);
+        assert_eq!(10, clock.now_ms());
+
+        clock.advance(5);
+        assert_eq!(15, shared.now_ms());
+    }
+}
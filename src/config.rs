@@ -0,0 +1,153 @@
+//! Tunable parameters of a Raft `Server`.
+
+use std::net::SocketAddr;
+
+/// The configuration of a `Server`. `Config::default()` provides values suitable for a cluster on
+/// a local network.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The maximum time, in milliseconds, a peer connection may go without receiving any data
+    /// while a message sent on it remains unanswered. Once exceeded the peer is presumed dead, and
+    /// the connection is reset and reconnected. Should be several times the heartbeat interval.
+    pub peer_idle_timeout_ms: u64,
+    /// The number of consecutive failed connection attempts after which a peer is presumed dead.
+    /// A warning is logged once, and the peer is reported by `ServerHandle::dead_peers` until it
+    /// is heard from again. Reconnection continues at the maximum backoff either way; the count
+    /// only distinguishes a brief outage from a lasting one. `None` never presumes a peer dead.
+    pub peer_dead_after_reconnects: Option<u32>,
+    /// The maximum number of open peer and client connections. When a new connection arrives and
+    /// the limit is reached, the least recently active client connection is closed to make room.
+    /// Must be greater than the number of peers.
+    pub max_connections: usize,
+    /// The maximum number of connections accepted from a listener each time it becomes readable.
+    /// A burst of incoming connections is drained in batches of this size, so that it is
+    /// established quickly without starving events on open connections.
+    pub accept_batch_size: usize,
+    /// The maximum number of bytes of log entries a leader sends to a follower before waiting for
+    /// the follower to acknowledge them. Bounds the data buffered for a slow follower. A single
+    /// entry is always sent when nothing is outstanding, even if it is larger.
+    pub max_in_flight_bytes: u64,
+    /// The maximum number of bytes of entry data in a single AppendEntries request. Entries sent
+    /// together are split into several requests at this limit, and client proposals larger than
+    /// it are rejected with `RaftError::CommandTooLarge`.
+    pub max_message_bytes: u64,
+    /// The maximum number of entries a leader holds in its log beyond the commit index. Once
+    /// reached, for instance because the leader has lost contact with a majority, further
+    /// proposals are rejected with `RaftError::Overloaded` until the commit index catches up.
+    /// Bounds the entries which must be re-replicated or discarded after a loss of quorum. `None`
+    /// disables the limit.
+    pub max_uncommitted_entries: Option<u64>,
+    /// The maximum rate, in bytes per second, at which a leader sends entries to a follower which
+    /// is far behind, so that catching it up does not starve heartbeats to healthy followers.
+    /// Followers near the end of the leader's log are not limited. `None` disables the limit.
+    pub catch_up_bytes_per_sec: Option<u64>,
+    /// When set, the election timeout adapts to the network: the lower bound of the range from
+    /// which election timeouts are drawn becomes this multiple of the 99th percentile round trip
+    /// time of requests to peers, clamped to between `election_timeout_min_ms` and
+    /// `election_timeout_max_ms`. Followers, which send no requests while a leader is active,
+    /// instead use the round trip time the leader reports in its AppendEntries requests. `None`
+    /// uses a fixed range.
+    pub election_rtt_multiple: Option<u64>,
+    /// The smallest lower bound of an adaptive election timeout. Must comfortably exceed the
+    /// heartbeat interval of one second.
+    pub election_timeout_min_ms: u64,
+    /// The largest lower bound of an adaptive election timeout.
+    pub election_timeout_max_ms: u64,
+    /// The interval, in milliseconds, after hearing from a leader during which a server refuses
+    /// to vote for another candidate or to adopt its term: a follower after an AppendEntries
+    /// request from its leader, or a leader after responses from a majority. A follower which
+    /// briefly misses heartbeats then can not depose a healthy leader. When set, a follower also
+    /// asks its peers whether they would vote for it before starting an election (Pre-Vote), and
+    /// only adopts a new term once a majority agrees, so that a refused follower accepts the
+    /// leader's next heartbeat. Should be at least the minimum election timeout. `None` always
+    /// considers vote requests.
+    pub leader_stickiness_ms: Option<u64>,
+    /// Whether the server is a read replica: it replicates and applies the log like any follower,
+    /// but never starts an election or grants a vote, so it never leads. It answers queries from
+    /// its own state machine at once, so its reads may be stale. Unlike a Raft learner, a read
+    /// replica is not excluded from the quorum: the other servers are not configured with which
+    /// peers are replicas, and count it toward the majority needed to commit entries and to win
+    /// elections. Its acknowledgements of persisted entries keep commits safe, but a majority of
+    /// the cluster must be voting servers for a leader to be elected.
+    pub read_replica: bool,
+    /// Whether committed entries are applied to the state machine on a dedicated worker thread,
+    /// rather than in the event loop. Enable for state machines whose `apply` is slow, so that
+    /// network events continue to be processed while entries are applied. Entries are applied in
+    /// log order either way.
+    pub apply_on_worker: bool,
+    /// Whether a follower forwards client proposals to the leader over its peer connection, and
+    /// relays the leader's response, rather than redirecting the client to the leader. Adds a hop
+    /// to each proposal, but serves clients which can not reconnect to the leader themselves.
+    pub forward_proposals: bool,
+    /// Whether `TCP_NODELAY` is set on peer and client connections, disabling Nagle's algorithm.
+    /// Raft messages are small and latency sensitive, so it is set by default.
+    pub tcp_nodelay: bool,
+    /// The size, in bytes, of the kernel send buffer (`SO_SNDBUF`) of peer and client connections.
+    /// `None` leaves the operating system's default, which may limit the throughput of
+    /// replication over links with a large bandwidth-delay product.
+    pub tcp_send_buffer_bytes: Option<usize>,
+    /// The size, in bytes, of the kernel receive buffer (`SO_RCVBUF`) of peer and client
+    /// connections. `None` leaves the operating system's default.
+    pub tcp_recv_buffer_bytes: Option<usize>,
+    /// The maximum number of bytes of responses to a client which are combined into a single
+    /// write. Responses queued while earlier ones are waiting to be written are appended to the
+    /// same buffer, which reduces system calls when many proposals commit at once. Zero disables
+    /// combining.
+    pub client_write_coalesce_bytes: usize,
+    /// The maximum number of bytes queued for writing to a peer or client connection. A connection
+    /// whose backlog exceeds it has stopped reading, and is reset to reclaim the memory: a peer
+    /// connection is reconnected, and a client connection is closed. Must exceed
+    /// `max_in_flight_bytes`, so that replication to a slow but live follower is not cut off; a
+    /// server is not created otherwise.
+    pub max_write_buffer_bytes: usize,
+    /// The maximum number of bytes of a single message received from a peer or client. The size
+    /// a message declares in its header is checked before the message is buffered, and the bytes
+    /// received are checked as they arrive. A connection which declares or sends a larger message
+    /// is reset. Must comfortably exceed `max_message_bytes`, so that AppendEntries requests and
+    /// proposals at that limit are read along with their framing; a server is not created unless
+    /// it is larger.
+    pub max_read_buffer_bytes: usize,
+    /// The maximum time, in milliseconds, between receiving the first and last bytes of a message.
+    /// A connection which stalls partway through a message is reset, so that a remote process can
+    /// not hold the buffer of a partial message indefinitely.
+    pub partial_read_timeout_ms: u64,
+    /// Whether the server accepts client connections. When unset the server accepts only peer
+    /// connections, and closes any connection which identifies itself as a client, for
+    /// deployments in which clients reach the cluster through a separate proxy.
+    pub accept_clients: bool,
+    /// Further addresses on which the server accepts peer and client connections, in addition to
+    /// the address it is created with, for instance to listen on several interfaces. Peers are
+    /// told only the address the server is created with.
+    pub additional_addrs: Vec<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            peer_idle_timeout_ms: 5000,
+            peer_dead_after_reconnects: None,
+            max_connections: 129,
+            accept_batch_size: 32,
+            max_in_flight_bytes: 1024 * 1024,
+            max_message_bytes: 256 * 1024,
+            max_uncommitted_entries: None,
+            catch_up_bytes_per_sec: None,
+            election_rtt_multiple: None,
+            election_timeout_min_ms: 1500,
+            election_timeout_max_ms: 10000,
+            leader_stickiness_ms: None,
+            read_replica: false,
+            apply_on_worker: false,
+            forward_proposals: false,
+            tcp_nodelay: true,
+            tcp_send_buffer_bytes: None,
+            tcp_recv_buffer_bytes: None,
+            client_write_coalesce_bytes: 64 * 1024,
+            max_write_buffer_bytes: 16 * 1024 * 1024,
+            max_read_buffer_bytes: 4 * 1024 * 1024,
+            partial_read_timeout_ms: 10000,
+            accept_clients: true,
+            additional_addrs: Vec::new(),
+        }
+    }
+}
@@ -1,9 +1,14 @@
-use std::fmt;
+use std::{cmp, fmt};
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::time::Instant;
 
-use mio::tcp::TcpStream;
 use mio::Timeout as TimeoutHandle;
 use mio::{
     EventLoop,
@@ -17,26 +22,176 @@ use capnp::{
     ReaderOptions,
 };
 use capnp::serialize::{
+    self,
     read_message_async,
-    write_message_async,
     AsyncValue,
     ReadContinuation,
-    WriteContinuation,
 };
 
 use ClientId;
+use Error;
+use RaftError;
 use Result;
 use ServerId;
-use backoff::Backoff;
+use buffer_pool::BufferPool;
 use messages;
 use server::{Server, ServerTimeout};
 use state_machine::StateMachine;
 use persistent_log::Log;
+use transport::{BufferOption, Stream, Transport};
+#[cfg(all(test, unix))]
+use transport;
 
 fn poll_opt() -> PollOpt {
     PollOpt::edge() | PollOpt::oneshot()
 }
 
+/// Returns the number of milliseconds elapsed since the instant.
+fn elapsed_ms(since: Instant) -> u64 {
+    let elapsed = since.elapsed();
+    elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1000000) as u64
+}
+
+/// A reader which counts the bytes read through it, and passes them to the segment table of the
+/// message being read. The read fails once the table declares a message larger than `max_bytes`.
+struct CountingReader<'a, R: 'a> {
+    inner: &'a mut R,
+    count: usize,
+    segment_table: &'a mut SegmentTable,
+    max_bytes: usize,
+}
+
+impl<'a, R> Read for CountingReader<'a, R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.count += n;
+        try!(self.segment_table.observe(&buf[..n], self.max_bytes));
+        Ok(n)
+    }
+}
+
+/// Returns the socket of a connection, or a `NotConnected` error if the connection has never been
+/// established.
+fn connected(stream: &mut Option<Stream>) -> io::Result<&mut Stream> {
+    stream.as_mut().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotConnected, "connection has never been established")
+    })
+}
+
+/// Decodes a little-endian `u32`.
+fn le_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// The segment table at the start of a serialized message, collected as the message is read, so
+/// that the size it declares is checked before space for the segments is allocated. The table
+/// holds the segment count less one, followed by the size of each segment in words, as
+/// little-endian `u32`s, and is padded to a word boundary.
+struct SegmentTable {
+    /// The bytes of the table received so far.
+    bytes: Vec<u8>,
+    /// Whether the whole table has been received, and declares a message within the limit.
+    checked: bool,
+    /// Whether the table declares a message larger than the limit.
+    oversized: bool,
+}
+
+impl SegmentTable {
+
+    fn new() -> SegmentTable {
+        SegmentTable { bytes: Vec::new(), checked: false, oversized: false }
+    }
+
+    /// Returns the length of the table without padding, as far as it is known.
+    fn len(&self) -> usize {
+        if self.bytes.len() < 4 {
+            4
+        } else {
+            4 + 4 * (le_u32(&self.bytes[..4]) as usize + 1)
+        }
+    }
+
+    /// Records bytes read from the stream. Returns an error once the table is known to declare a
+    /// message larger than `max_bytes`.
+    fn observe(&mut self, mut data: &[u8], max_bytes: usize) -> io::Result<()> {
+        while !self.checked && !data.is_empty() {
+            let take = cmp::min(self.len() - self.bytes.len(), data.len());
+            self.bytes.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            let len = self.len();
+            let mut size = len as u64;
+            if self.bytes.len() == len && len > 4 {
+                let words = self.bytes[4..]
+                                .chunks(4)
+                                .fold(0, |words, segment| words + le_u32(segment) as u64);
+                size = ((len as u64 + 7) & !7) + words * 8;
+                self.checked = true;
+            }
+            if size > max_bytes as u64 {
+                self.checked = false;
+                self.oversized = true;
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "message exceeds the size limit"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepares for the next message.
+    fn reset(&mut self) {
+        self.bytes.clear();
+        self.checked = false;
+        self.oversized = false;
+    }
+}
+
+/// An operation which tests may cause to fail.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Fault {
+    /// Registering or reregistering a connection with the event loop.
+    Registration,
+    /// Accepting a connection from a listener.
+    Accept,
+}
+
+#[cfg(test)]
+thread_local!(static FAULTS: RefCell<HashMap<Fault, (io::ErrorKind, u32)>> =
+                  RefCell::new(HashMap::new()));
+
+/// Causes the next `count` attempts at the operation on the current thread to fail with an error
+/// of the given kind.
+#[cfg(test)]
+pub fn inject_faults(fault: Fault, kind: io::ErrorKind, count: u32) {
+    FAULTS.with(|faults| faults.borrow_mut().insert(fault, (kind, count)));
+}
+
+/// Returns an error if a failure of the operation has been injected.
+#[cfg(test)]
+pub fn injected_fault(fault: Fault) -> io::Result<()> {
+    FAULTS.with(|faults| {
+        if let Some(&mut (kind, ref mut count)) = faults.borrow_mut().get_mut(&fault) {
+            if *count > 0 {
+                *count -= 1;
+                return Err(io::Error::new(kind, format!("injected {:?} failure", fault)));
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+pub fn injected_fault(_fault: Fault) -> io::Result<()> {
+    Ok(())
+}
+
+/// Registers or reregisters a connection with the event loop through `register`, failing
+/// instead if a registration failure has been injected.
+fn registration<F>(register: F) -> Result<()> where F: FnOnce() -> Result<()> {
+    injected_fault(Fault::Registration).map_err(From::from).and_then(|_| register())
+}
+
 /// The type of a connection.
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum ConnectionKind {
@@ -63,13 +218,37 @@ pub struct Connection {
     /// The address to reconnect to - for a connection initiated by the remote,
     /// this is not the remote address.
     addr: SocketAddr,
-    stream: TcpStream,
-    backoff: Backoff,
+    /// The socket, or `None` for a peer connection which has not yet been established.
+    stream: Option<Stream>,
     events: EventSet,
     read_continuation: Option<ReadContinuation>,
-    write_continuation: Option<WriteContinuation>,
-    write_queue: VecDeque<Rc<MallocMessageBuilder>>,
+    /// The number of bytes of the partially read message at `read_continuation`.
+    partial_read_bytes: usize,
+    /// The time at which the first bytes of the partially read message were received, if any.
+    partial_read_since: Option<Instant>,
+    /// The segment table of the message being read.
+    segment_table: SegmentTable,
+    /// Serialized messages waiting to be written, and whether each is a control message which may
+    /// be replaced by a later one. A buffer may be shared with the queues of other connections the
+    /// same message was sent to.
+    write_queue: VecDeque<(Rc<Vec<u8>>, bool)>,
+    /// The number of bytes of the message at the front of the write queue already written.
+    write_offset: usize,
+    /// The total size of the buffers in the write queue.
+    queued_bytes: usize,
+    /// Whether the message at the front of the write queue is the connection preamble, and has
+    /// not been completely written. The preamble survives `clear_messages`.
+    preamble_pending: bool,
+    /// Whether the connection was dialed, and the remote's preamble sent in reply to ours has not
+    /// been received.
+    awaiting_preamble: bool,
     is_connected: bool,
+    /// The time at which the oldest message sent since data was last received on the connection
+    /// was queued, if any.
+    unanswered_since: Option<Instant>,
+    /// The time at which data was last received on the connection, or at which the connection was
+    /// created if no data has been received.
+    last_active: Instant,
 }
 
 impl Connection {
@@ -80,35 +259,59 @@ impl Connection {
     ///
     /// Note: the caller must manually set the token field after inserting the
     /// connection into a slab.
-    pub fn unknown(socket: TcpStream) -> Result<Connection> {
+    pub fn unknown(socket: Stream) -> Result<Connection> {
         let addr = try!(socket.peer_addr());
         Ok(Connection {
             kind: ConnectionKind::Unknown,
             addr: addr,
-            stream: socket,
-            backoff: Backoff::with_duration_range(50, 10000),
+            stream: Some(socket),
             events: EventSet::hup() | EventSet::readable(),
             read_continuation: None,
-            write_continuation: None,
+            partial_read_bytes: 0,
+            partial_read_since: None,
+            segment_table: SegmentTable::new(),
             write_queue: VecDeque::new(),
+            write_offset: 0,
+            queued_bytes: 0,
+            preamble_pending: false,
+            awaiting_preamble: false,
             is_connected: true,
+            unanswered_since: None,
+            last_active: Instant::now(),
         })
     }
 
-    /// Creates a new peer connection.
-    pub fn peer(id: ServerId, addr: SocketAddr) -> Result<Connection> {
-        let stream = try!(TcpStream::connect(&addr));
-        Ok(Connection {
+    /// Creates a new peer connection, connected through the transport.
+    pub fn peer(id: ServerId, addr: SocketAddr, transport: &Transport) -> Result<Connection> {
+        let stream = try!(transport.connect(&addr));
+        let mut connection = Connection::unconnected_peer(id, addr);
+        connection.stream = Some(stream);
+        connection.is_connected = true;
+        connection.awaiting_preamble = true;
+        Ok(connection)
+    }
+
+    /// Creates a peer connection which is not connected. The connection must be reset to
+    /// schedule an attempt to connect.
+    pub fn unconnected_peer(id: ServerId, addr: SocketAddr) -> Connection {
+        Connection {
             kind: ConnectionKind::Peer(id),
             addr: addr,
-            stream: stream,
-            backoff: Backoff::with_duration_range(50, 10000),
+            stream: None,
             events: EventSet::hup() | EventSet::readable(),
             read_continuation: None,
-            write_continuation: None,
+            partial_read_bytes: 0,
+            partial_read_since: None,
+            segment_table: SegmentTable::new(),
             write_queue: VecDeque::new(),
-            is_connected: true,
-        })
+            write_offset: 0,
+            queued_bytes: 0,
+            preamble_pending: false,
+            awaiting_preamble: false,
+            is_connected: false,
+            unanswered_since: None,
+            last_active: Instant::now(),
+        }
     }
 
     pub fn kind(&self) -> &ConnectionKind {
@@ -127,25 +330,102 @@ impl Connection {
         self.addr = addr;
     }
 
-    /// Writes queued messages to the socket.
-    pub fn writable(&mut self) -> Result<()> {
+    /// Returns true if the connection is established, and has not been reset.
+    pub fn is_connected(&self) -> bool {
+        self.is_connected
+    }
+
+    /// Returns the time at which data was last received on the connection.
+    pub fn last_active(&self) -> Instant {
+        self.last_active
+    }
+
+    /// Sets the `TCP_NODELAY` option of the connection's socket, if it is established.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        match self.stream {
+            Some(ref stream) => stream.set_nodelay(nodelay).map_err(From::from),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets the sizes of the kernel send and receive buffers of the connection's socket, if it is
+    /// established. A size of `None` leaves the buffer as it is.
+    pub fn set_buffer_sizes(&mut self,
+                            send_bytes: Option<usize>,
+                            recv_bytes: Option<usize>)
+                            -> Result<()> {
+        if let Some(ref stream) = self.stream {
+            if let Some(bytes) = send_bytes {
+                try!(stream.set_buffer_size(BufferOption::Send, bytes));
+            }
+            if let Some(bytes) = recv_bytes {
+                try!(stream.set_buffer_size(BufferOption::Recv, bytes));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the kernel send or receive buffer of the connection's socket.
+    #[cfg(all(test, unix))]
+    pub fn buffer_size(&mut self, option: BufferOption) -> usize {
+        use std::os::unix::io::AsRawFd;
+        transport::buffer_size(self.stream().unwrap().as_raw_fd(), option).unwrap()
+    }
+
+    /// Returns whether the `TCP_NODELAY` option is set on the connection's socket.
+    #[cfg(all(test, unix))]
+    pub fn nodelay(&mut self) -> bool {
+        use std::net;
+        use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+        // The socket is borrowed as a standard library stream, which is released without closing
+        // it.
+        let stream = unsafe { net::TcpStream::from_raw_fd(self.stream().unwrap().as_raw_fd()) };
+        let nodelay = stream.nodelay().unwrap();
+        stream.into_raw_fd();
+        nodelay
+    }
+
+    /// Returns the connection's socket. Only connections which have been established have a
+    /// socket, and only established connections are registered with the event loop; otherwise a
+    /// `NotConnected` error is returned.
+    fn stream(&mut self) -> io::Result<&mut Stream> {
+        connected(&mut self.stream)
+    }
+
+    /// Writes queued messages to the socket. The buffers of fully written messages are returned to
+    /// the pool.
+    pub fn writable(&mut self, pool: &mut BufferPool) -> Result<()> {
         scoped_trace!("{:?}: writable; queued message count: {}", self, self.write_queue.len());
         scoped_assert!(self.is_connected, "{:?}: writable event while not connected", self);
 
-        while let Some(message) = self.write_queue.pop_front() {
-            let continuation = self.write_continuation.take();
-            match write_message_async(&mut self.stream, &*message, continuation) {
-                Ok(AsyncValue::Complete(())) => (),
-                Ok(AsyncValue::Continue(continuation)) =>  {
-                    // The write only partially completed. Save the continuation and add the
+        while let Some((buffer, replaceable)) = self.write_queue.pop_front() {
+            let offset = self.write_offset;
+            let written = self.stream().and_then(|stream| stream.write(&buffer[offset..]));
+            match written {
+                Ok(0) => {
+                    self.write_queue.push_front((buffer, replaceable));
+                    return Err(From::from(io::Error::new(io::ErrorKind::WriteZero,
+                                                         "failed to write message")));
+                },
+                Ok(n) if offset + n < buffer.len() => {
+                    // The write only partially completed. Record the progress and add the
                     // message back to the front of the queue.
-                    self.write_continuation = Some(continuation);
-                    self.write_queue.push_front(message);
+                    self.write_offset = offset + n;
+                    self.write_queue.push_front((buffer, replaceable));
+                },
+                Ok(..) => {
+                    self.write_offset = 0;
+                    self.queued_bytes -= buffer.len();
+                    self.preamble_pending = false;
+                    pool.give_shared(buffer);
+                },
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    self.write_queue.push_front((buffer, replaceable));
                     break;
-                }
+                },
                 Err(error) => {
                     // The write failed; reinsert the message back to the write queue.
-                    self.write_queue.push_front(message);
+                    self.write_queue.push_front((buffer, replaceable));
                     return Err(From::from(error));
                 }
             }
@@ -155,7 +435,6 @@ impl Connection {
             self.events.remove(EventSet::writable());
         }
 
-        self.backoff.reset();
         Ok(())
     }
 
@@ -164,102 +443,300 @@ impl Connection {
     ///
     /// Connections are edge-triggered, so the handler must continue calling
     /// until no more messages are returned.
-    pub fn readable(&mut self) -> Result<Option<OwnedSpaceMessageReader>> {
+    ///
+    /// Returns `RaftError::MessageTooLarge` once the segment table of a message declares more than
+    /// `max_bytes`, before space for the message is allocated, or once more than `max_bytes` of a
+    /// single message have been received.
+    pub fn readable(&mut self, max_bytes: usize) -> Result<Option<OwnedSpaceMessageReader>> {
         scoped_trace!("{:?}: readable", self);
         scoped_assert!(self.is_connected, "{:?}: readable event while not connected", self);
 
-        let read = try!(read_message_async(&mut self.stream,
-                                           ReaderOptions::new(),
-                                           self.read_continuation.take()));
-        self.backoff.reset();
+        let continuation = self.read_continuation.take();
+        let (read, count) = {
+            let mut reader = CountingReader {
+                inner: try!(connected(&mut self.stream)),
+                count: 0,
+                segment_table: &mut self.segment_table,
+                max_bytes: max_bytes,
+            };
+            let read = read_message_async(&mut reader, ReaderOptions::new(), continuation);
+            (read, reader.count)
+        };
+        if self.segment_table.oversized {
+            return Err(Error::Raft(RaftError::MessageTooLarge));
+        }
+        let read = try!(read);
+        self.unanswered_since = None;
+        self.last_active = Instant::now();
         match read {
             AsyncValue::Complete(message) => {
+                let message_bytes = self.partial_read_bytes + count;
+                self.partial_read_bytes = 0;
+                self.partial_read_since = None;
+                self.segment_table.reset();
+                if message_bytes > max_bytes {
+                    return Err(Error::Raft(RaftError::MessageTooLarge));
+                }
                 Ok(Some(message))
             },
             AsyncValue::Continue(continuation) => {
                 // the read only partially completed. Save the continuation and return.
                 self.read_continuation = Some(continuation);
+                self.partial_read_bytes += count;
+                if self.partial_read_bytes > 0 && self.partial_read_since.is_none() {
+                    self.partial_read_since = Some(Instant::now());
+                }
+                if self.partial_read_bytes > max_bytes {
+                    return Err(Error::Raft(RaftError::MessageTooLarge));
+                }
                 Ok(None)
             },
         }
     }
 
-    /// Queues a message to send to the connection. Returns `true` if the connection should be
-    /// reregistered with the event loop.
-    pub fn send_message(&mut self, message: Rc<MallocMessageBuilder>) -> bool {
+    /// Queues a message to send to the connection, serialized into a buffer taken from the pool.
+    /// Returns `true` if the connection should be reregistered with the event loop.
+    pub fn send_message(&mut self, message: Rc<MallocMessageBuilder>, pool: &mut BufferPool) -> bool {
         scoped_trace!("{:?}: send_message", self);
+        self.queue_message(message, pool, false)
+    }
+
+    /// Queues the connection preamble to send to the connection. It must be the first message
+    /// sent, and is not dropped by `clear_messages`. Returns whether the connection should be
+    /// reregistered with the event loop.
+    pub fn send_preamble(&mut self, message: Rc<MallocMessageBuilder>, pool: &mut BufferPool) -> bool {
+        scoped_trace!("{:?}: send_preamble", self);
+        scoped_assert!(self.write_queue.is_empty(), "{:?}: preamble queued behind messages", self);
+        self.preamble_pending = true;
+        self.queue_message(message, pool, false)
+    }
+
+    /// Returns whether the connection was dialed, and the remote has not yet answered with its
+    /// preamble.
+    pub fn awaiting_preamble(&self) -> bool {
+        self.awaiting_preamble
+    }
+
+    /// Records that the remote answered the connection with its preamble.
+    pub fn preamble_received(&mut self) {
+        self.awaiting_preamble = false;
+    }
+
+    /// Queues a control message to send to the connection like `send_message`, first dropping any
+    /// queued control message which has not begun to be written, so that at most one waits behind
+    /// a backlog. Messages carrying log entries must not be sent this way.
+    pub fn send_replaceable_message(&mut self,
+                                    message: Rc<MallocMessageBuilder>,
+                                    pool: &mut BufferPool)
+                                    -> bool {
+        scoped_trace!("{:?}: send_replaceable_message", self);
+        // The message at the front of the queue is being written if any of it has been.
+        let skip = if self.write_offset > 0 { 1 } else { 0 };
+        let stale = self.write_queue
+                        .iter()
+                        .skip(skip)
+                        .position(|&(_, replaceable)| replaceable)
+                        .map(|position| position + skip);
+        if let Some(position) = stale {
+            scoped_trace!("{:?}: dropping unsent control message", self);
+            let (buffer, _) = self.write_queue.remove(position).unwrap();
+            self.queued_bytes -= buffer.len();
+            pool.give_shared(buffer);
+        }
+        self.queue_message(message, pool, true)
+    }
+
+    /// Serializes the message into a buffer taken from the pool, unless it was just serialized for
+    /// another connection, and queues it. Returns `true` if the connection should be reregistered
+    /// with the event loop.
+    fn queue_message(&mut self,
+                     message: Rc<MallocMessageBuilder>,
+                     pool: &mut BufferPool,
+                     replaceable: bool)
+                     -> bool {
         let mut reregister = false;
         if self.is_connected {
             if self.write_queue.is_empty() {
                 self.events.insert(EventSet::writable());
                 reregister = true;
             }
-            self.write_queue.push_back(message);
+            let buffer = pool.serialize(&message);
+            self.queued_bytes += buffer.len();
+            self.write_queue.push_back((buffer, replaceable));
         }
         reregister
     }
 
-    /// Registers the connection with the event loop.
+    /// Queues a message to send to the connection like `send_message`, but appends it to the last
+    /// queued buffer if writing of that buffer has not begun, it is not shared with another
+    /// connection, and it holds fewer than `max_bytes`, so that a burst of messages is written with
+    /// fewer system calls. Messages are framed individually, so the receiver reads them as usual.
+    /// Not for peer connections, whose queued messages may be cleared individually.
+    pub fn send_message_coalesced(&mut self,
+                                  message: Rc<MallocMessageBuilder>,
+                                  pool: &mut BufferPool,
+                                  max_bytes: usize)
+                                  -> bool {
+        // The pool's reference to the last serialized message's buffer, which may be the last
+        // queued buffer, is released so that the buffer may be appended to.
+        pool.forget_serialized();
+        let writing_back = self.write_queue.len() == 1 && self.write_offset > 0;
+        let coalesce = self.is_connected && !writing_back &&
+                       self.write_queue.back_mut().map_or(false, |back| {
+                           !back.1 && back.0.len() < max_bytes && Rc::get_mut(&mut back.0).is_some()
+                       });
+        if !coalesce {
+            return self.send_message(message, pool);
+        }
+        scoped_trace!("{:?}: send_message_coalesced", self);
+        let added = {
+            let buffer = Rc::get_mut(&mut self.write_queue.back_mut().unwrap().0).unwrap();
+            let len = buffer.len();
+            serialize::write_message(buffer, &*message)
+                .expect("serializing to a buffer can not fail");
+            buffer.len() - len
+        };
+        self.queued_bytes += added;
+        false
+    }
+
+    /// Returns the number of buffers queued for writing.
+    pub fn queued_buffers(&self) -> usize {
+        self.write_queue.len()
+    }
+
+    /// Returns the number of bytes queued for writing, including any already written part of a
+    /// partially written message.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Records that a request was sent on the connection. The peer is expected to answer it, so
+    /// the connection is considered idle if nothing is received within the idle timeout.
+    pub fn await_response(&mut self) {
+        if self.unanswered_since.is_none() {
+            self.unanswered_since = Some(Instant::now());
+        }
+    }
+
+    /// Returns true if the connection is established, and no data has been received on it within
+    /// `timeout_ms` milliseconds of sending a message.
+    pub fn is_idle(&self, timeout_ms: u64) -> bool {
+        match self.unanswered_since {
+            Some(since) if self.is_connected => elapsed_ms(since) >= timeout_ms,
+            _ => false,
+        }
+    }
+
+    /// Returns true if a message has been partially received on the connection, and has not been
+    /// completed within `timeout_ms` milliseconds of its first bytes.
+    pub fn is_stalled_reading(&self, timeout_ms: u64) -> bool {
+        self.partial_read_since.map_or(false, |since| elapsed_ms(since) >= timeout_ms)
+    }
+
+    /// Registers the connection with the event loop. A failure, which may be transient, for
+    /// instance under momentary fd exhaustion, is not retried here, since the event loop must not
+    /// block: the server resets the connection, so a peer connection is reconnected with backoff,
+    /// and a client connection is closed. Clients are expected to reconnect.
     pub fn register<L, M>(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token) -> Result<()>
     where L: Log, M: StateMachine {
         scoped_trace!("{:?}: register", self);
-        event_loop.register_opt(&self.stream, token, self.events, poll_opt())
-                  .map_err(|error| {
-                      scoped_warn!("{:?}: reregister failed: {}", self, error);
-                      From::from(error)
-                  })
+        let events = self.events;
+        let result = registration(|| {
+            self.stream()
+                .and_then(|stream| event_loop.register_opt(&*stream, token, events, poll_opt()))
+                .map_err(From::from)
+        });
+        result.map_err(|error| {
+            scoped_warn!("{:?}: register failed: {}", self, error);
+            error
+        })
     }
 
-    /// Reregisters the connection with the event loop.
+    /// Reregisters the connection with the event loop. As with `register`, a failure is not
+    /// retried; the server resets the connection.
     pub fn reregister<L, M>(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token) -> Result<()>
     where L: Log, M: StateMachine {
         scoped_trace!("{:?}: reregister", self);
-        event_loop.reregister(&self.stream, token, self.events, poll_opt())
-                  .map_err(|error| {
-                      scoped_warn!("{:?}: register failed: {}", self, error);
-                      From::from(error)
-                  })
+        let events = self.events;
+        let result = registration(|| {
+            self.stream()
+                .and_then(|stream| event_loop.reregister(&*stream, token, events, poll_opt()))
+                .map_err(From::from)
+        });
+        result.map_err(|error| {
+            scoped_warn!("{:?}: reregister failed: {}", self, error);
+            error
+        })
     }
 
-    /// Reconnects to the given peer ID and sends the preamble, advertising the
-    /// given local address to the peer.
-    pub fn reconnect_peer(&mut self, id: ServerId, local_addr: &SocketAddr) -> Result<()> {
+    /// Reconnects to the given peer ID through the transport and sends the preamble, advertising
+    /// the given local address to the peer. The preamble is queued ahead of any other message,
+    /// since messages queued before the reset are discarded.
+    pub fn reconnect_peer(&mut self,
+                          id: ServerId,
+                          local_addr: &SocketAddr,
+                          transport: &Transport,
+                          pool: &mut BufferPool)
+                          -> Result<()> {
         scoped_trace!("{:?}: reconnect", self);
-        self.stream = try!(TcpStream::connect(&self.addr));
+        self.stream = Some(try!(transport.connect(&self.addr)));
         self.is_connected = true;
+        self.unanswered_since = None;
         self.read_continuation = None;
-        self.write_continuation = None;
+        self.partial_read_bytes = 0;
+        self.partial_read_since = None;
+        self.segment_table.reset();
         self.write_queue.clear();
-        self.send_message(messages::server_connection_preamble(id, local_addr));
+        self.write_offset = 0;
+        self.queued_bytes = 0;
+        self.awaiting_preamble = true;
+        self.send_preamble(messages::server_connection_preamble(id, local_addr), pool);
         Ok(())
     }
 
-    /// Resets a peer connection.
+    /// Resets a peer connection, and schedules a reconnection attempt in `duration` milliseconds.
     pub fn reset_peer<L, M>(&mut self,
                             event_loop: &mut EventLoop<Server<L, M>>,
-                            token: Token)
+                            token: Token,
+                            duration: u64)
                             -> Result<(ServerTimeout, TimeoutHandle)>
     where L: Log, M: StateMachine {
         scoped_assert!(self.kind.is_peer());
-        let duration = self.backoff.next_backoff_ms();
+        // The connection may still be registered if it is reset while healthy, for instance when
+        // it is idle. Stop listening for its events; it will be replaced upon reconnection.
+        if let Some(ref stream) = self.stream {
+            let _ = event_loop.deregister(stream);
+        }
         self.read_continuation = None;
-        self.write_continuation = None;
+        self.partial_read_bytes = 0;
+        self.partial_read_since = None;
+        self.segment_table.reset();
         self.write_queue.clear();
+        self.write_offset = 0;
+        self.queued_bytes = 0;
+        self.preamble_pending = false;
         self.is_connected = false;
+        self.unanswered_since = None;
         let timeout = ServerTimeout::Reconnect(token);
-        let handle = event_loop.timeout_ms(timeout, duration).unwrap();
+        let handle = try!(event_loop.timeout_ms(timeout, duration)
+                                    .map_err(|_| Error::Raft(RaftError::TimeoutRegisterFailed)));
 
         scoped_info!("{:?}: reset, will attempt to reconnect in {}ms", self, duration);
         Ok((timeout, handle))
     }
 
     pub fn clear_messages(&mut self) {
-        if self.write_continuation.is_some() {
+        // A partially written message must be completed, or the stream is corrupted. The remote
+        // expects the preamble before any other message.
+        if self.write_offset > 0 || self.preamble_pending {
             let message = self.write_queue.pop_front().unwrap();
             self.write_queue.clear();
+            self.queued_bytes = message.0.len();
             self.write_queue.push_front(message);
         } else {
             self.write_queue.clear();
+            self.queued_bytes = 0;
         }
     }
 }
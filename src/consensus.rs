@@ -12,36 +12,50 @@
 //! In response to an event, the `Consensus` may mutate its own state, apply a command to the local
 //! `StateMachine`, or return an event to be sent to one or more remote peers or clients.
 
-use std::{cmp, fmt};
-use std::collections::HashMap;
+use std::{cmp, fmt, result};
+#[cfg(test)]
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::sync::mpsc;
 
 use capnp::{
     MallocMessageBuilder,
-    MessageBuilder,
     MessageReader,
 };
 use rand::{self, Rng};
 
-use {LogIndex, Term, ServerId, ClientId, messages};
+use {Config, Error, LogIndex, Result, RaftError, Term, ServerId, ClientId, messages};
+use apply::{Applier, ApplyOutput, ApplyTask};
+use clock::{Clock, SystemClock};
 use messages_capnp::{
     append_entries_request,
     append_entries_response,
     client_request,
+    forwarded_proposal_request,
+    forwarded_proposal_response,
     proposal_request,
     query_request,
     message,
     request_vote_request,
     request_vote_response,
+    subscribe_request,
 };
-use state::{ConsensusState, LeaderState, CandidateState, FollowerState};
-use state_machine::StateMachine;
-use persistent_log::Log;
+use state::{ConsensusState, LeaderState, CandidateState, FollowerState, RoundTrips};
+use state_machine::{ApplyError, StateMachine};
+use persistent_log::{Log, LogMetrics};
 
 const ELECTION_MIN: u64 = 1500;
 const ELECTION_MAX: u64 = 3000;
 const HEARTBEAT_DURATION: u64 = 1000;
+const CATCH_UP_INTERVAL: u64 = 100;
+/// The most entries read from the log in the first range read made when sending entries to a
+/// peer. Each further read of the same send may be twice as long.
+const SEND_ENTRIES_READ: u64 = 64;
+/// The number of bytes of entry data after which a batch of entries streamed to a subscribed
+/// client is closed. A batch holds at least one entry.
+const SUBSCRIPTION_BATCH_BYTES: u64 = 64 * 1024;
 
 /// Consensus timeout types.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -50,6 +64,8 @@ pub enum ConsensusTimeout {
     Election,
     // A heartbeat timeout. Stable value.
     Heartbeat(ServerId),
+    // The pacing interval between batches of entries sent to a lagging peer. Stable value.
+    CatchUp(ServerId),
 }
 
 impl ConsensusTimeout {
@@ -58,15 +74,86 @@ impl ConsensusTimeout {
         match *self {
             ConsensusTimeout::Election => rand::thread_rng().gen_range::<u64>(ELECTION_MIN, ELECTION_MAX),
             ConsensusTimeout::Heartbeat(..) => HEARTBEAT_DURATION,
+            ConsensusTimeout::CatchUp(..) => CATCH_UP_INTERVAL,
         }
     }
 }
 
+/// Returns true if moving the named index from `from` to `to` would decrease it, in which case the
+/// move must not be made. The commit index only ever increases; a decrease means entries would be
+/// applied twice or out of order, so it panics in debug builds, and is logged otherwise.
+fn index_regresses(name: &str, from: LogIndex, to: LogIndex) -> bool {
+    if to >= from {
+        return false;
+    }
+    debug_assert!(false, "{} moved backward from {} to {}", name, from, to);
+    scoped_warn!("{} moved backward from {} to {}; ignoring", name, from, to);
+    true
+}
+
+/// Records that reading the log beginning at `index` failed, though the log holds the entries. The
+/// entries can be neither replicated nor applied, and nothing may take their place, so like a
+/// failed write this halts the consensus module; see `Consensus::persist`.
+fn read_failed<E>(fatal_error: &mut Option<RaftError>, index: LogIndex, error: E)
+where E: fmt::Display {
+    scoped_error!("unable to read log entry {}: {}", index, error);
+    if fatal_error.is_none() {
+        *fatal_error = Some(RaftError::LogReadFailed(format!("entry {}: {}", index, error)));
+    }
+}
+
+/// Records that the apply worker has exited, so that no more entries can be applied nor queries
+/// answered. The server halts, as it would had the state machine failed to apply an entry.
+fn worker_exited(fatal_error: &mut Option<RaftError>) {
+    scoped_error!("apply worker has exited");
+    if fatal_error.is_none() {
+        *fatal_error = Some(RaftError::ApplyFailed("apply worker has exited".to_string()));
+    }
+}
+
+/// Overrides the normal vote-granting rules in tests. Given the candidate and its term, returns
+/// `Some(true)` to grant the vote, `Some(false)` to refuse it, or `None` to apply the normal rules.
+#[cfg(test)]
+type VotePolicy = Option<Box<Fn(ServerId, Term) -> Option<bool>>>;
+/// Vote policies are not available outside of tests.
+#[cfg(not(test))]
+type VotePolicy = ();
+
+/// The highest term observed by `Consensus::check_invariants`, against which the current term is
+/// checked for regression.
+#[cfg(test)]
+type CheckedTerm = Cell<Option<Term>>;
+/// Invariants are only checked in tests.
+#[cfg(not(test))]
+type CheckedTerm = ();
+
+/// Counters of the elections a `Consensus` has taken part in, for diagnosing unstable leadership:
+/// a cluster which keeps changing leaders shows a steadily rising election count.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ElectionMetrics {
+    /// The number of elections this server started as a candidate.
+    pub elections_started: u64,
+    /// The number of elections this server won, becoming leader.
+    pub elections_won: u64,
+    /// The number of elections this server started and did not win, because it learned of a
+    /// leader or newer term, or because its election timed out.
+    pub elections_lost: u64,
+    /// The number of votes this server granted to candidates, including itself.
+    pub votes_granted: u64,
+    /// The number of votes this server received from peers as a candidate.
+    pub votes_received: u64,
+}
+
 /// A set of actions for the `Server` to carry out asyncronously in response to applying an event
 /// to a `Consensus` state machine.
 pub struct Actions {
-    /// Messages to be sent to peers.
+    /// Messages to be sent to peers. A message broadcast to several peers is built once and
+    /// shared, so a broadcast holds a single copy of its entries however large the cluster.
     pub peer_messages: Vec<(ServerId, Rc<MallocMessageBuilder>)>,
+    /// The indexes into `peer_messages` of control messages, such as heartbeats and vote requests,
+    /// which carry no log entries and are superseded by the next control message to the same
+    /// peer. An unsent control message may be dropped in favor of a newer one.
+    pub replaceable_peer_messages: HashSet<usize>,
     /// Messages to be send to clients.
     pub client_messages: Vec<(ClientId, Rc<MallocMessageBuilder>)>,
     /// Whether to clear existing consensus timeouts.
@@ -97,12 +184,39 @@ impl Actions {
     pub fn new() -> Actions {
         Actions {
             peer_messages: vec![],
+            replaceable_peer_messages: HashSet::new(),
             client_messages: vec![],
             clear_timeouts: false,
             timeouts: vec![],
             clear_peer_messages: false,
         }
     }
+
+    /// Returns whether there is nothing for the `Server` to carry out.
+    pub fn is_empty(&self) -> bool {
+        self.peer_messages.is_empty() &&
+        self.client_messages.is_empty() &&
+        !self.clear_timeouts &&
+        self.timeouts.is_empty() &&
+        !self.clear_peer_messages
+    }
+
+    /// Adds a control message to be sent to the peer, which may be dropped in favor of a later
+    /// control message if it has not been sent by then.
+    pub fn push_replaceable_peer_message(&mut self,
+                                         peer: ServerId,
+                                         message: Rc<MallocMessageBuilder>) {
+        self.replaceable_peer_messages.insert(self.peer_messages.len());
+        self.peer_messages.push((peer, message));
+    }
+}
+
+/// A client's subscription to committed entries.
+struct Subscription {
+    /// The index of the next committed entry to stream to the client.
+    next_index: LogIndex,
+    /// Whether a batch of entries streamed to the client is still waiting to be written to it.
+    streaming: bool,
 }
 
 /// An instance of a Raft state machine. The Consensus controls a client state machine, to which it
@@ -110,18 +224,24 @@ impl Actions {
 pub struct Consensus<L, M> {
     /// The ID of this consensus instance.
     id: ServerId,
+    /// The address on which this server accepts connections, if known.
+    addr: Option<SocketAddr>,
     /// The IDs of peers in the consensus group.
     peers: HashMap<ServerId, SocketAddr>,
 
     /// The persistent log.
     log: L,
-    /// The client state machine to which client commands are applied.
-    state_machine: M,
+    /// The client state machine to which client commands are applied, or the worker thread
+    /// which applies them.
+    state_machine: Applier<M>,
 
     /// Index of the latest entry known to be committed.
     commit_index: LogIndex,
     /// Index of the latest entry applied to the state machine.
     last_applied: LogIndex,
+    /// Index of the latest entry handed to the state machine. Ahead of `last_applied` while
+    /// entries are being applied by a worker thread; equal to it otherwise.
+    apply_dispatched: LogIndex,
 
     /// The current state of the `Consensus` (`Leader`, `Candidate`, or `Follower`).
     state: ConsensusState,
@@ -129,8 +249,81 @@ pub struct Consensus<L, M> {
     leader_state: LeaderState,
     /// State necessary while a `Candidate`. Should not be used otherwise.
     candidate_state: CandidateState,
+    /// The peers which granted a pre-vote, while this follower canvasses for one before starting
+    /// an election. `None` otherwise.
+    pre_vote_state: Option<CandidateState>,
     /// State necessary while a `Follower`. Should not be used otherwise.
     follower_state: FollowerState,
+
+    /// The clients which proposed uncommitted or unapplied entries, by log index. Not persisted;
+    /// whichever server applies an entry while leader responds to its client. Origins only route
+    /// responses, and are not used to detect duplicate proposals.
+    client_origins: BTreeMap<LogIndex, ClientId>,
+
+    /// Whether a follower forwards client proposals to the leader, rather than redirecting the
+    /// client.
+    forward_proposals: bool,
+    /// The clients whose proposals this follower forwarded to the leader, and which await the
+    /// leader's response, by the follower's ID for the proposal.
+    forwarded_clients: HashMap<u64, ClientId>,
+    /// The ID which this follower assigns to the next proposal it forwards.
+    next_forwarded_proposal: u64,
+    /// The followers which forwarded the proposals appended by this leader, with each follower's
+    /// ID for the proposal, by log index.
+    forwarded_origins: HashMap<LogIndex, (ServerId, u64)>,
+    /// The clients subscribed to committed entries.
+    subscribers: HashMap<ClientId, Subscription>,
+
+    /// The maximum number of bytes of entries sent to a follower and not yet acknowledged.
+    max_in_flight_bytes: u64,
+    /// The maximum number of bytes of entries in a single AppendEntries request.
+    max_message_bytes: u64,
+    /// The maximum number of entries beyond the commit index a leader accepts proposals for.
+    max_uncommitted_entries: Option<u64>,
+    /// The maximum rate, in bytes per second, at which entries are sent to a lagging follower.
+    catch_up_bytes_per_sec: Option<u64>,
+
+    /// Round trip times of the AppendEntries and RequestVote requests sent to each peer.
+    round_trips: RoundTrips,
+    /// The 99th percentile round trip time reported in the latest AppendEntries request from the
+    /// leader, if the leader has measured one.
+    leader_round_trip_ms: Option<u64>,
+    /// When set, the lower bound of the election timeout is this multiple of the 99th percentile
+    /// round trip time, clamped to `election_timeout_clamp_ms`.
+    election_rtt_multiple: Option<u64>,
+    /// The minimum and maximum lower bound of an adaptive election timeout, in milliseconds.
+    election_timeout_clamp_ms: (u64, u64),
+
+    /// The `clock` time of the latest AppendEntries exchange with each peer: the latest response
+    /// received from a follower while leader, or the latest request received from the leader
+    /// while follower.
+    last_contact: HashMap<ServerId, u64>,
+    /// The `clock` time at which a follower last received an AppendEntries request from the
+    /// leader of its current term.
+    leader_heard_ms: Option<u64>,
+    /// The interval after hearing from a leader during which vote requests are refused.
+    leader_stickiness_ms: Option<u64>,
+    /// Whether this server only replicates the log, never starting an election or granting a vote.
+    read_replica: bool,
+
+    /// Counters of the elections this server has taken part in.
+    election_metrics: ElectionMetrics,
+
+    /// The error with which reading or persisting state in the log, or applying an entry, first
+    /// failed. Once set, the consensus module is halted.
+    fatal_error: Option<RaftError>,
+
+    /// The source of time used to schedule timeouts.
+    clock: Box<Clock>,
+    /// The deadlines of currently scheduled timeouts, in `clock` milliseconds.
+    deadlines: HashMap<ConsensusTimeout, u64>,
+
+    /// Test-only override of the vote-granting rules.
+    #[cfg_attr(not(test), allow(dead_code))]
+    vote_policy: VotePolicy,
+    /// Test-only record of the highest term seen by `check_invariants`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    checked_term: CheckedTerm,
 }
 
 impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
@@ -140,26 +333,155 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
                log: L,
                state_machine: M)
                -> Consensus<L, M> {
-        let leader_state = LeaderState::new(log.latest_log_index().unwrap(),
+        Consensus::with_clock(id, peers, log, state_machine, Box::new(SystemClock::new()))
+    }
+
+    /// Creates a `Consensus` which schedules timeouts according to the provided `Clock`.
+    pub fn with_clock(id: ServerId,
+                      peers: HashMap<ServerId, SocketAddr>,
+                      log: L,
+                      state_machine: M,
+                      clock: Box<Clock>)
+                      -> Consensus<L, M> {
+        Consensus::with_applier(id, peers, log, Applier::Inline(state_machine), clock)
+    }
+
+    /// Creates a `Consensus` which hands committed entries and queries to an apply worker thread
+    /// through `tasks`. The worker's outputs must be passed to `apply_output`.
+    pub fn with_apply_worker(id: ServerId,
+                             peers: HashMap<ServerId, SocketAddr>,
+                             log: L,
+                             tasks: mpsc::Sender<ApplyTask>)
+                             -> Consensus<L, M> {
+        Consensus::with_applier(id, peers, log, Applier::Worker(tasks), Box::new(SystemClock::new()))
+    }
+
+    /// Creates a `Consensus` which applies commands through the provided `Applier`.
+    fn with_applier(id: ServerId,
+                    peers: HashMap<ServerId, SocketAddr>,
+                    log: L,
+                    state_machine: Applier<M>,
+                    clock: Box<Clock>)
+                    -> Consensus<L, M> {
+        let hard_state = log.hard_state().unwrap();
+        let leader_state = LeaderState::new(hard_state.latest_log_index,
                                             &peers.keys().cloned().collect());
         Consensus {
             id: id,
+            addr: None,
             peers: peers,
             log: log,
             state_machine: state_machine,
             commit_index: LogIndex(0),
             last_applied: LogIndex(0),
+            apply_dispatched: LogIndex(0),
             state: ConsensusState::Follower,
             leader_state: leader_state,
             candidate_state: CandidateState::new(),
+            pre_vote_state: None,
             follower_state: FollowerState::new(),
+            client_origins: BTreeMap::new(),
+            forward_proposals: Config::default().forward_proposals,
+            forwarded_clients: HashMap::new(),
+            next_forwarded_proposal: 0,
+            forwarded_origins: HashMap::new(),
+            subscribers: HashMap::new(),
+            max_in_flight_bytes: Config::default().max_in_flight_bytes,
+            max_message_bytes: Config::default().max_message_bytes,
+            max_uncommitted_entries: Config::default().max_uncommitted_entries,
+            catch_up_bytes_per_sec: Config::default().catch_up_bytes_per_sec,
+            round_trips: RoundTrips::new(),
+            leader_round_trip_ms: None,
+            election_rtt_multiple: Config::default().election_rtt_multiple,
+            election_timeout_clamp_ms: (Config::default().election_timeout_min_ms,
+                                        Config::default().election_timeout_max_ms),
+            last_contact: HashMap::new(),
+            leader_heard_ms: None,
+            leader_stickiness_ms: Config::default().leader_stickiness_ms,
+            read_replica: Config::default().read_replica,
+            election_metrics: ElectionMetrics::default(),
+            fatal_error: None,
+            clock: clock,
+            deadlines: HashMap::new(),
+            vote_policy: Default::default(),
+            checked_term: Default::default(),
         }
     }
 
-    /// Returns the set of initial action which should be executed upon startup.
-    pub fn init(&self) -> Actions {
+    /// Sets the address on which this server accepts connections, which is reported to clients
+    /// along with the addresses of its peers.
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        self.addr = Some(addr);
+    }
+
+    /// Sets whether a follower forwards client proposals to the leader, rather than redirecting
+    /// the client.
+    pub fn set_forward_proposals(&mut self, forward_proposals: bool) {
+        self.forward_proposals = forward_proposals;
+    }
+
+    /// Sets the maximum number of bytes of entries sent to a follower before waiting for it to
+    /// acknowledge them.
+    pub fn set_max_in_flight_bytes(&mut self, bytes: u64) {
+        self.max_in_flight_bytes = bytes;
+    }
+
+    /// Sets the maximum number of bytes of entries in a single AppendEntries request. Larger
+    /// proposals are rejected.
+    pub fn set_max_message_bytes(&mut self, bytes: u64) {
+        self.max_message_bytes = bytes;
+    }
+
+    /// Sets the maximum number of entries beyond the commit index for which a leader accepts
+    /// proposals. Further proposals are rejected until the commit index catches up. `None`
+    /// disables the limit.
+    pub fn set_max_uncommitted_entries(&mut self, entries: Option<u64>) {
+        self.max_uncommitted_entries = entries;
+    }
+
+    /// Sets whether the election timeout adapts to the round trip times observed to peers. When
+    /// `rtt_multiple` is set, the lower bound of the election timeout range is that multiple of
+    /// the 99th percentile round trip time, clamped to between `min_ms` and `max_ms`. Followers
+    /// use the round trip time reported by the leader when it exceeds their own. The range keeps
+    /// its usual width. `None` uses the fixed range.
+    pub fn set_adaptive_election_timeout(&mut self, rtt_multiple: Option<u64>, min_ms: u64, max_ms: u64) {
+        self.election_rtt_multiple = rtt_multiple;
+        self.election_timeout_clamp_ms = (min_ms, max_ms);
+    }
+
+    /// Sets the maximum rate, in bytes per second, at which entries are sent to a follower which
+    /// is far behind the leader, or `None` for no limit.
+    pub fn set_catch_up_rate(&mut self, bytes_per_sec: Option<u64>) {
+        self.catch_up_bytes_per_sec = bytes_per_sec;
+    }
+
+    /// Sets the interval after hearing from a leader during which vote requests from other
+    /// candidates are refused without adopting their term, or `None` to always consider them.
+    /// When set, a follower also requests pre-votes before starting an election; see
+    /// `start_pre_vote`.
+    pub fn set_leader_stickiness(&mut self, interval_ms: Option<u64>) {
+        self.leader_stickiness_ms = interval_ms;
+    }
+
+    /// Sets whether this server is a read replica, which replicates the log from the leader but
+    /// never starts an election or grants a vote. A read replica answers queries from its own
+    /// state machine without confirming the leader, so its reads may be stale. The other servers
+    /// do not know which of their peers are read replicas, and count them toward the majority; see
+    /// `Config::read_replica`. Must be set before `init`.
+    pub fn set_read_replica(&mut self, read_replica: bool) {
+        self.read_replica = read_replica;
+    }
+
+    /// Returns the set of initial action which should be executed upon startup. A solitary
+    /// consensus module, which is trivially elected, becomes leader immediately.
+    pub fn init(&mut self) -> Actions {
         let mut actions = Actions::new();
-        actions.timeouts.push(ConsensusTimeout::Election);
+        if self.peers.is_empty() && !self.read_replica {
+            self.election_timeout(&mut actions);
+        } else {
+            self.set_timeout(ConsensusTimeout::Election, &mut actions);
+        }
+        self.apply_committed(&mut actions);
         actions
     }
 
@@ -168,47 +490,115 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         &self.peers
     }
 
+    /// Sets a policy consulted before the normal rules when deciding whether to grant a vote.
+    #[cfg(test)]
+    pub fn set_vote_policy<F>(&mut self, policy: F) where F: Fn(ServerId, Term) -> Option<bool> + 'static {
+        self.vote_policy = Some(Box::new(policy));
+    }
+
+    /// Returns the decision of the vote policy, if any, for the candidate.
+    #[cfg(test)]
+    fn vote_override(&self, candidate: ServerId, term: Term) -> Option<bool> {
+        self.vote_policy.as_ref().and_then(|policy| policy(candidate, term))
+    }
+
+    #[cfg(not(test))]
+    #[inline(always)]
+    fn vote_override(&self, _candidate: ServerId, _term: Term) -> Option<bool> {
+        None
+    }
+
+    /// Sets the address of a peer, which is used to redirect clients to the peer when it leads.
+    pub fn set_peer_addr(&mut self, peer: ServerId, addr: SocketAddr) {
+        scoped_assert!(self.peers.insert(peer, addr).is_some(), "unknown peer {}", peer);
+    }
+
     /// Applies a peer message to the consensus state machine.
-    pub fn apply_peer_message<R>(&mut self, from: ServerId, message: &R, actions: &mut Actions)
+    ///
+    /// Returns an error if the message is malformed. Any actions added before the error was
+    /// discovered should still be executed.
+    pub fn apply_peer_message<R>(&mut self,
+                                 from: ServerId,
+                                 message: &R,
+                                 actions: &mut Actions)
+                                 -> Result<()>
     where R: MessageReader {
         push_log_scope!("{:?}", self);
-        let reader = message.get_root::<message::Reader>().unwrap().which().unwrap();
-        match reader {
-            message::Which::AppendEntriesRequest(Ok(request)) =>
-                self.append_entries_request(from, request, actions),
-            message::Which::AppendEntriesResponse(Ok(response)) =>
-                self.append_entries_response(from, response, actions),
-            message::Which::RequestVoteRequest(Ok(request)) =>
-                self.request_vote_request(from, request, actions),
-            message::Which::RequestVoteResponse(Ok(response)) =>
-                self.request_vote_response(from, response, actions),
-            _ => panic!("cannot handle message"),
+        let reader = try!(try!(message.get_root::<message::Reader>()).which());
+        let result = match reader {
+            message::Which::AppendEntriesRequest(request) =>
+                self.append_entries_request(from, try!(request), actions),
+            message::Which::AppendEntriesResponse(response) =>
+                Ok(self.append_entries_response(from, try!(response), actions)),
+            message::Which::RequestVoteRequest(request) =>
+                Ok(self.request_vote_request(from, try!(request), actions)),
+            message::Which::RequestVoteResponse(response) =>
+                Ok(self.request_vote_response(from, try!(response), actions)),
+            message::Which::ForwardedProposalRequest(request) =>
+                self.forwarded_proposal_request(from, try!(request), actions),
+            message::Which::ForwardedProposalResponse(response) =>
+                self.forwarded_proposal_response(try!(response), actions),
         };
+        self.apply_committed(actions);
+        result
     }
 
     /// Applies a client message to the consensus state machine.
+    ///
+    /// Returns an error if the message is malformed or unsupported. Any actions added before the
+    /// error was discovered should still be executed.
     pub fn apply_client_message<R>(&mut self,
                                    from: ClientId,
                                    message: &R,
                                    actions: &mut Actions)
+                                   -> Result<()>
     where R: MessageReader {
         push_log_scope!("{:?}", self);
-        let reader = message.get_root::<client_request::Reader>().unwrap().which().unwrap();
-        match reader {
-            client_request::Which::Proposal(Ok(request)) =>
-                self.proposal_request(from, request, actions),
-            client_request::Which::Query(Ok(query)) =>
-                self.query_request(from, query, actions),
-            _ => panic!("cannot handle message"),
-        }
+        let reader = try!(try!(message.get_root::<client_request::Reader>()).which());
+        let result = match reader {
+            client_request::Which::Proposal(request) =>
+                self.proposal_request(from, try!(request), actions),
+            client_request::Which::Query(query) =>
+                self.query_request(from, try!(query), actions),
+            client_request::Which::Subscribe(request) =>
+                self.subscribe_request(from, try!(request), actions),
+            client_request::Which::GetConfiguration(..) =>
+                self.get_configuration_request(from, actions),
+            client_request::Which::Ping(..) =>
+                Err(Error::Raft(RaftError::UnsupportedRequest)),
+        };
+        self.apply_committed(actions);
+        result
     }
 
     /// Applies a timeout's actions to the `Consensus`.
     pub fn apply_timeout(&mut self, timeout: ConsensusTimeout, actions: &mut Actions) {
         push_log_scope!("{:?}", self);
+        self.deadlines.remove(&timeout);
         match timeout {
             ConsensusTimeout::Election => self.election_timeout(actions),
             ConsensusTimeout::Heartbeat(peer) => self.heartbeat_timeout(peer, actions),
+            ConsensusTimeout::CatchUp(peer) => self.catch_up_timeout(peer, actions),
+        }
+        self.apply_committed(actions);
+    }
+
+    /// Applies every scheduled timeout whose deadline has been reached according to the `Clock`.
+    ///
+    /// The `Server` drives timeouts through the event loop, so this is only necessary when the
+    /// `Consensus` is driven by a `ManualClock`.
+    pub fn tick(&mut self, actions: &mut Actions) {
+        let now = self.clock.now_ms();
+        let expired: Vec<ConsensusTimeout> = self.deadlines
+                                                 .iter()
+                                                 .filter(|&(_, &deadline)| deadline <= now)
+                                                 .map(|(&timeout, _)| timeout)
+                                                 .collect();
+        for timeout in expired {
+            // A previously applied timeout may have cleared this one.
+            if self.deadlines.contains_key(&timeout) {
+                self.apply_timeout(timeout, actions);
+            }
         }
     }
 
@@ -223,40 +613,32 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         match self.state {
             ConsensusState::Leader => {
                 // Send any outstanding entries to the peer, or an empty heartbeat if there are no
-                // outstanding entries.
+                // outstanding entries. Entries sent on the old connection may have been lost, so
+                // they are sent again rather than assuming the peer received them, which would
+                // leave a gap for the peer to reject.
+                if let Some(first_lost) = self.leader_state.clear_in_flight(peer) {
+                    if first_lost < self.leader_state.next_index(&peer) {
+                        scoped_debug!("peer {} connection reset; resending entries from {}",
+                                      peer, first_lost);
+                        self.leader_state.set_next_index(peer, first_lost);
+                    }
+                }
                 let from_index = self.leader_state.next_index(&peer);
-                let until_index = self.latest_log_index() + 1;
-
-                let prev_log_index = from_index - 1;
-                let prev_log_term =
-                    if prev_log_index == LogIndex::from(0) {
-                        Term::from(0)
-                    } else {
-                        self.log.entry(prev_log_index).unwrap().0
-                    };
-
-                let entries = self.log.entries(from_index, until_index).unwrap();
-                let message = messages::append_entries_request(
-                    self.current_term(),
-                    prev_log_index,
-                    prev_log_term,
-                    &entries,
-                    self.commit_index);
-
-                self.leader_state.set_next_index(peer, until_index);
-                actions.peer_messages.push((peer, message));
+                self.send_entries(peer, from_index, actions);
             },
             ConsensusState::Candidate => {
                 // Resend the request vote request if a response has not yet been receieved.
                 if self.candidate_state.peer_voted(peer) { return; }
                 let current_term = self.current_term();
                 let latest_index = self.latest_log_index();
-                let latest_term = self.log.latest_log_term().unwrap();
+                let latest_term = self.latest_log_term();
 
                 let message = messages::request_vote_request(current_term,
                                                              latest_index,
-                                                             latest_term);
-                actions.peer_messages.push((peer, message));
+                                                             latest_term,
+                                                             false);
+                actions.push_replaceable_peer_message(peer, message);
+                self.request_sent(peer);
             },
             ConsensusState::Follower => {
                 // No message is necessary; if the peer is a leader or candidate they will send a
@@ -269,25 +651,41 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
     fn append_entries_request(&mut self,
                               from: ServerId,
                               request: append_entries_request::Reader,
-                              actions: &mut Actions) {
+                              actions: &mut Actions)
+                              -> Result<()> {
         scoped_trace!("AppendEntriesRequest from peer {}", &from);
 
         let leader_term = Term(request.get_term());
         let current_term = self.current_term();
+        let entries = try!(request.get_entries());
 
         if leader_term < current_term {
             let message = messages::append_entries_response_stale_term(current_term);
             actions.peer_messages.push((from, message));
-            return;
+            return Ok(());
         }
 
         match self.state {
             ConsensusState::Follower => {
+                self.last_contact.insert(from, self.clock.now_ms());
                 let message = {
                     if current_term < leader_term {
-                        self.log.set_current_term(leader_term).unwrap();
-                        self.follower_state.set_leader(from);
+                        self.set_term(leader_term);
+                    }
+                    // The leader hint may name a peer which only voted in this term.
+                    if self.follower_state.leader != Some(from) {
+                        self.abort_forwarded_proposals(actions);
                     }
+                    self.follower_state.set_leader(from);
+                    // The leader is alive; a pre-vote in progress would be refused anyway.
+                    self.pre_vote_state = None;
+                    self.leader_heard_ms = Some(self.clock.now_ms());
+                    // A follower sends no requests to time while the leader is active, so it
+                    // adopts the round trip time measured by the leader.
+                    self.leader_round_trip_ms = match request.get_round_trip_ms() {
+                        0 => None,
+                        round_trip_ms => Some(round_trip_ms),
+                    };
 
                     let leader_prev_log_index = LogIndex(request.get_prev_log_index());
                     let leader_prev_log_term = Term(request.get_prev_log_term());
@@ -299,12 +697,11 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
                                       leader: {}, local: {}",
                                       leader_prev_log_index, latest_log_index);
                         messages::append_entries_response_inconsistent_prev_entry(
-                            self.current_term(), leader_prev_log_index)
+                            self.current_term(), leader_prev_log_index, request.get_read_round())
                     } else {
-                        let existing_term = if leader_prev_log_index == LogIndex::from(0) {
-                            Term::from(0)
-                        } else {
-                            self.log.entry(leader_prev_log_index).unwrap().0
+                        let existing_term = match self.log_term(leader_prev_log_index) {
+                            Some(term) => term,
+                            None => return Ok(()),
                         };
 
                         if existing_term != leader_prev_log_term {
@@ -314,32 +711,78 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
                             // If an existing entry conflicts with a new one (same index but different terms),
                             // delete the existing entry and all that follow it
                             messages::append_entries_response_inconsistent_prev_entry(self.current_term(),
-                                leader_prev_log_index)
+                                leader_prev_log_index, request.get_read_round())
                         } else {
-                            if let Ok(entries) = request.get_entries() {
-                                let num_entries: u32 = entries.len();
-                                scoped_debug!("AppendEntriesRequest: {} entries from leader: {}",
-                                              num_entries, from);
-
-                                let entries_vec: Vec<(Term, &[u8])> = entries.iter().map(
-                                    |entry| (Term::from(entry.get_term()), entry.get_data().unwrap_or(b""))
-                                ).collect();
-
-                                self.log.append_entries(leader_prev_log_index + 1, &entries_vec).unwrap();
-                                let latest_log_index = leader_prev_log_index + num_entries as u64;
-                                // We are matching the leader's log up to and including `latest_log_index`.
-                                self.commit_index = cmp::min(LogIndex::from(request.get_leader_commit()), latest_log_index);
-                                self.apply_commits();
-                            } else {
-                                panic!("AppendEntriesRequest: no entry list")
+                            let num_entries: u32 = entries.len();
+                            scoped_debug!("AppendEntriesRequest: {} entries from leader: {}",
+                                          num_entries, from);
+
+                            let entries_vec: Vec<(Term, &[u8])> = entries.iter().map(
+                                |entry| (Term::from(entry.get_term()), entry.get_data().unwrap_or(b""))
+                            ).collect();
+                            let mut origins = Vec::new();
+                            for (n, entry) in entries.iter().enumerate() {
+                                if entry.has_client() {
+                                    let client = try!(ClientId::from_bytes(try!(entry.get_client())));
+                                    origins.push((leader_prev_log_index + 1 + n as u64, client));
+                                }
+                            }
+                            // The first local entry which conflicts with the leader's. It and every
+                            // following local entry are absent from the leader's log (Raft §5.3),
+                            // and will never be committed.
+                            let conflict_index = entries_vec.iter()
+                                .enumerate()
+                                .map(|(n, &(term, _))| (leader_prev_log_index + 1 + n as u64, term))
+                                .find(|&(index, term)| {
+                                    index <= latest_log_index && self.log_term(index) != Some(term)
+                                })
+                                .map(|(index, _)| index);
+
+                            // The log is truncated after the leader's entries, so local entries
+                            // beyond them are lost as well, even if none conflicts.
+                            let latest_log_index = leader_prev_log_index + num_entries as u64;
+                            let first_lost = cmp::min(conflict_index.unwrap_or(latest_log_index + 1),
+                                                      latest_log_index + 1);
+
+                            let from = leader_prev_log_index + 1;
+                            if !self.persist(|log| log.append_entries(from, &entries_vec)) {
+                                return Ok(());
+                            }
+                            // Origins of any overwritten entries no longer apply; the clients of
+                            // lost entries are told that their proposals were not committed.
+                            let overwritten = self.client_origins.split_off(&(leader_prev_log_index + 1));
+                            for (index, client) in overwritten {
+                                if index >= first_lost {
+                                    scoped_debug!("entry {} from client {} was overwritten", index, client);
+                                    let forwarded = self.forwarded_origins.remove(&index);
+                                    self.respond_to_proposal(client,
+                                                             forwarded,
+                                                             messages::command_response_not_committed(),
+                                                             actions);
+                                }
+                            }
+                            self.client_origins.extend(origins);
+                            // The entries must be durable before they are acknowledged.
+                            if !self.persist(|log| log.flush()) {
+                                return Ok(());
+                            }
+                            // We are matching the leader's log up to and including `latest_log_index`.
+                            // A new leader may not yet know how far the log is committed; the commit
+                            // index only moves forward.
+                            let leader_commit = cmp::min(LogIndex::from(request.get_leader_commit()),
+                                                         latest_log_index);
+                            if leader_commit > self.commit_index {
+                                self.set_commit_index(leader_commit);
                             }
                             messages::append_entries_response_success(
-                                self.current_term(), self.log.latest_log_index().unwrap())
+                                self.current_term(), self.log.latest_log_index().unwrap(),
+                                request.get_read_round())
                         }
                     }
                 };
                 actions.peer_messages.push((from, message));
-                actions.timeouts.push(ConsensusTimeout::Election);
+                self.set_timeout(ConsensusTimeout::Election, actions);
+                Ok(())
             },
             ConsensusState::Candidate => {
                 // recognize the new leader, return to follower state, and apply the entries
@@ -395,6 +838,15 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
             // the response.
             return;
         }
+        self.last_contact.insert(from, self.clock.now_ms());
+        self.round_trips.received(from, self.clock.now_ms());
+
+        // Any response in the current term confirms this server's leadership for the heartbeat's
+        // read round.
+        if self.is_leader() {
+            self.leader_state.confirm_reads(from, response.get_read_round());
+            self.serve_reads(actions);
+        }
 
         match response.which() {
             Ok(append_entries_response::Which::Success(follower_latest_log_index)) => {
@@ -403,13 +855,18 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
                 let follower_latest_log_index = LogIndex::from(follower_latest_log_index);
                 scoped_assert!(follower_latest_log_index <= local_latest_log_index);
                 self.leader_state.set_match_index(from, follower_latest_log_index);
+                self.leader_state.ack_in_flight(from, follower_latest_log_index);
                 self.advance_commit_index(actions);
             }
             Ok(append_entries_response::Which::InconsistentPrevEntry(next_index)) => {
                 scoped_assert!(self.is_leader());
                 scoped_debug!("AppendEntriesResponse from peer {}: \
                               inconsistent previous entry index: {}", from, next_index);
-                self.leader_state.set_next_index(from, LogIndex::from(next_index));
+                // The peer's log may be empty, but it can not need entries the leader lacks.
+                let next_index = cmp::min(cmp::max(LogIndex::from(next_index), LogIndex(1)),
+                                          local_latest_log_index + 1);
+                self.leader_state.set_next_index(from, next_index);
+                self.leader_state.clear_in_flight(from);
             }
             Ok(append_entries_response::Which::StaleTerm(..)) => {
                 // The peer is reporting a stale term, but the term number matches the local term.
@@ -433,34 +890,12 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         if next_index <= local_latest_log_index {
             // If the peer is behind, send it entries to catch up.
             scoped_debug!("AppendEntriesResponse: peer {} is missing at least {} entries; \
-                          sending missing entries", from, (local_latest_log_index + 1 - next_index.0).0);
-            let prev_log_index = next_index - 1;
-            let prev_log_term =
-                if prev_log_index == LogIndex(0) {
-                    Term(0)
-                } else {
-                    self.log.entry(prev_log_index).unwrap().0
-                };
-
-            let from_index = next_index;
-            let until_index = local_latest_log_index + 1;
-
-            let entries = self.log.entries(LogIndex::from(from_index), LogIndex::from(until_index)).unwrap();
-
-            let message = messages::append_entries_request(
-                local_term,
-                prev_log_index,
-                prev_log_term,
-                &entries,
-                self.commit_index);
-
-            self.leader_state.set_next_index(from, local_latest_log_index + 1);
-            actions.peer_messages.push((from, message));
+                          sending missing entries", from, local_latest_log_index + 1 - next_index);
+            self.send_entries(from, next_index, actions);
         } else {
             // If the peer is caught up, set a heartbeat timeout.
             scoped_trace!("AppendEntriesResponse: scheduling heartbeat for peer {}", from);
-            let timeout = ConsensusTimeout::Heartbeat(from);
-            actions.timeouts.push(timeout);
+            self.set_timeout(ConsensusTimeout::Heartbeat(from), actions);
         }
     }
 
@@ -473,10 +908,39 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         let candidate_log_term = Term(request.get_last_log_term());
         let candidate_log_index = LogIndex(request.get_last_log_index());
         scoped_debug!("RequestVoteRequest from Consensus {{ id: {}, term: {}, latest_log_term: {}, \
-                      latest_log_index: {} }}",
-                      &candidate, candidate_term, candidate_log_term, candidate_log_index);
+                      latest_log_index: {}, pre_vote: {} }}",
+                      &candidate, candidate_term, candidate_log_term, candidate_log_index,
+                      request.get_pre_vote());
         let local_term = self.current_term();
 
+        if request.get_pre_vote() {
+            // The candidate's log is checked as for a vote, but nothing is persisted: neither the
+            // term nor the vote are recorded, as the candidate has adopted neither.
+            let message = if candidate_term <= local_term {
+                messages::request_vote_response_stale_term(local_term, true)
+            } else if self.read_replica || self.leader_is_sticky() {
+                messages::request_vote_response_already_voted(local_term, true)
+            } else if candidate_log_term < self.latest_log_term()
+                   || candidate_log_index < self.latest_log_index() {
+                messages::request_vote_response_inconsistent_log(local_term, true)
+            } else {
+                messages::request_vote_response_granted(candidate_term, true)
+            };
+            actions.peer_messages.push((candidate, message));
+            return;
+        }
+
+        if candidate_term > local_term && self.leader_is_sticky() {
+            // A healthy leader is not deposed by a server which has merely missed its heartbeats.
+            // Neither adopt the candidate's term nor grant the vote; the refusal carries the local
+            // term, which the candidate disregards.
+            scoped_info!("refusing RequestVoteRequest from Consensus {{ id: {}, term: {} }} while \
+                         a leader is active", candidate, candidate_term);
+            let message = messages::request_vote_response_already_voted(local_term, false);
+            actions.peer_messages.push((candidate, message));
+            return;
+        }
+
         let new_local_term = if candidate_term > local_term {
             scoped_info!("received RequestVoteRequest from Consensus {{ id: {}, term: {} }} \
                          with newer term; transitioning to Follower", candidate, candidate_term);
@@ -487,27 +951,70 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         };
 
         let message = if candidate_term < local_term {
-            messages::request_vote_response_stale_term(new_local_term)
+            messages::request_vote_response_stale_term(new_local_term, false)
+        } else if self.read_replica {
+            scoped_debug!("read replica refusing vote for {}", candidate);
+            messages::request_vote_response_already_voted(new_local_term, false)
+        } else if let Some(granted) = self.vote_override(candidate, candidate_term) {
+            scoped_debug!("vote policy {} vote for {}", if granted { "granted" } else { "refused" }, candidate);
+            if granted {
+                if !self.persist(|log| log.set_voted_for(candidate)) {
+                    return;
+                }
+                self.election_metrics.votes_granted += 1;
+                messages::request_vote_response_granted(new_local_term, false)
+            } else {
+                messages::request_vote_response_already_voted(new_local_term, false)
+            }
         } else if candidate_log_term < self.latest_log_term()
                || candidate_log_index < self.latest_log_index() {
-            messages::request_vote_response_inconsistent_log(new_local_term)
+            messages::request_vote_response_inconsistent_log(new_local_term, false)
         } else {
             match self.log.voted_for().unwrap() {
                 None => {
-                    self.log.set_voted_for(candidate).unwrap();
-                    messages::request_vote_response_granted(new_local_term)
+                    if !self.persist(|log| log.set_voted_for(candidate)) {
+                        return;
+                    }
+                    self.election_metrics.votes_granted += 1;
+                    messages::request_vote_response_granted(new_local_term, false)
                 },
                 Some(voted_for) if voted_for == candidate => {
-                    messages::request_vote_response_granted(new_local_term)
+                    messages::request_vote_response_granted(new_local_term, false)
                 },
                 _ => {
-                    messages::request_vote_response_already_voted(new_local_term)
+                    messages::request_vote_response_already_voted(new_local_term, false)
                 },
             }
         };
         actions.peer_messages.push((candidate, message));
     }
 
+    /// Returns true if a leader of the current term has been heard from within the leader
+    /// stickiness interval: by a follower, from its leader; by a leader, from a majority of the
+    /// cluster.
+    fn leader_is_sticky(&self) -> bool {
+        let interval = match self.leader_stickiness_ms {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let now = self.clock.now_ms();
+        let recent = |heard: u64| now.saturating_sub(heard) < interval;
+        match self.state {
+            ConsensusState::Follower => self.leader_heard_ms.map_or(false, &recent),
+            ConsensusState::Leader => {
+                let last_contact = &self.last_contact;
+                let heard = self.peers
+                                .keys()
+                                .filter(|peer| {
+                                    last_contact.get(peer).map_or(false, |&heard| recent(heard))
+                                })
+                                .count();
+                heard + 1 >= self.majority()
+            },
+            ConsensusState::Candidate => false,
+        }
+    }
+
     /// Applies a request vote response to the consensus state machine.
     fn request_vote_response(&mut self,
                              from: ServerId,
@@ -519,7 +1026,9 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         let voter_term = Term::from(response.get_term());
 
         let majority = self.majority();
-        if local_term < voter_term {
+        if response.get_pre_vote() {
+            self.pre_vote_response(from, voter_term, response, actions);
+        } else if local_term < voter_term {
             // Responder has a higher term number. The election is compromised; abandon it and
             // revert to follower state with the updated term number. Any further responses we
             // receive from this election term will be ignored because the term will be outdated.
@@ -532,35 +1041,236 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         } else if local_term > voter_term {
             // Ignore this message; it came from a previous election cycle.
         } else if self.is_candidate() {
-            // A vote was received!
-            if let Ok(request_vote_response::Granted(_)) = response.which() {
-                self.candidate_state.record_vote(from.clone());
-                if self.candidate_state.count_votes() >= majority {
-                    scoped_info!("election for term {} won; transitioning to Leader", local_term);
-                    self.transition_to_leader(actions);
-                }
+            self.round_trips.received(from, self.clock.now_ms());
+            match response.which() {
+                Ok(request_vote_response::Granted(_)) => {
+                    // A vote was received!
+                    if self.candidate_state.record_vote(from) {
+                        self.election_metrics.votes_received += 1;
+                    }
+                    if self.candidate_state.count_votes() >= majority {
+                        scoped_info!("election for term {} won; transitioning to Leader", local_term);
+                        self.transition_to_leader(actions);
+                    }
+                },
+                Ok(request_vote_response::StaleTerm(_)) => {
+                    scoped_info!("vote refused by peer {}: stale term", from);
+                },
+                Ok(request_vote_response::AlreadyVoted(_)) => {
+                    scoped_info!("vote refused by peer {}: already voted in term {}", from, local_term);
+                },
+                Ok(request_vote_response::InconsistentLog(_)) => {
+                    scoped_info!("vote refused by peer {}: local log is out of date", from);
+                },
+                Ok(request_vote_response::InternalError(error)) => {
+                    scoped_warn!("vote refused by peer {}: internal error: {}",
+                                 from, error.unwrap_or("[unable to decode internal error]"));
+                },
+                Err(error) => {
+                    scoped_warn!("RequestVoteResponse from peer {}: unable to deserialize response: {}",
+                                 from, error);
+                },
             }
         };
     }
 
+    /// Applies a response to a pre-vote request. A granted pre-vote carries the term which it was
+    /// requested for; any other response carries the voter's term, which is adopted if newer.
+    fn pre_vote_response(&mut self,
+                         from: ServerId,
+                         voter_term: Term,
+                         response: request_vote_response::Reader,
+                         actions: &mut Actions) {
+        let local_term = self.current_term();
+        match response.which() {
+            Ok(request_vote_response::Granted(_)) => {
+                // Grants from a previous term's pre-vote are ignored.
+                if voter_term != local_term + 1 {
+                    return;
+                }
+                let majority = self.majority();
+                let won = match self.pre_vote_state {
+                    Some(ref mut pre_vote_state) => {
+                        pre_vote_state.record_vote(from);
+                        pre_vote_state.count_votes() >= majority
+                    },
+                    None => return,
+                };
+                if won {
+                    scoped_info!("pre-vote for term {} won; transitioning to Candidate", voter_term);
+                    self.transition_to_candidate(actions);
+                }
+            },
+            Ok(_) if voter_term > local_term => {
+                scoped_info!("received pre-vote response from Consensus {{ id: {}, term: {} }} \
+                             with newer term; transitioning to Follower", from, voter_term);
+                self.transition_to_follower(voter_term, from, actions);
+            },
+            Ok(_) => {
+                scoped_info!("pre-vote refused by peer {}", from);
+            },
+            Err(error) => {
+                scoped_warn!("RequestVoteResponse from peer {}: unable to deserialize response: {}",
+                             from, error);
+            },
+        }
+    }
+
     /// Applies a client proposal to the consensus state machine.
     fn proposal_request(&mut self,
                         from: ClientId,
                         request: proposal_request::Reader,
-                        actions: &mut Actions) {
+                        actions: &mut Actions)
+                        -> Result<()> {
+        let entry = try!(request.get_entry());
+        self.append_proposal(from, None, entry, actions);
+        Ok(())
+    }
+
+    /// Answers a client's request for the members of the cluster with the id and address of every
+    /// server. The membership is fixed, so any server may answer.
+    fn get_configuration_request(&mut self, from: ClientId, actions: &mut Actions) -> Result<()> {
+        scoped_debug!("GetConfigurationRequest from client {}", from);
+        let mut servers: Vec<(ServerId, SocketAddr)> = self.peers
+                                                           .iter()
+                                                           .map(|(&id, &addr)| (id, addr))
+                                                           .collect();
+        if let Some(addr) = self.addr {
+            servers.push((self.id, addr));
+        }
+        servers.sort_by_key(|&(id, _)| id.as_u64());
+        actions.client_messages.push((from, messages::configuration_response(&servers)));
+        Ok(())
+    }
+
+    /// Applies a client proposal forwarded by a follower. The response is returned to the
+    /// follower, which relays it to the client.
+    fn forwarded_proposal_request(&mut self,
+                                  from: ServerId,
+                                  request: forwarded_proposal_request::Reader,
+                                  actions: &mut Actions)
+                                  -> Result<()> {
+        let client = try!(ClientId::from_bytes(try!(request.get_client())));
+        let entry = try!(request.get_entry());
+        let proposal = request.get_proposal();
+        scoped_debug!("ForwardedProposalRequest from peer {} for client {}", from, client);
+        self.append_proposal(client, Some((from, proposal)), entry, actions);
+        Ok(())
+    }
+
+    /// Relays the leader's response to a forwarded proposal to the client which made it.
+    fn forwarded_proposal_response(&mut self,
+                                   response: forwarded_proposal_response::Reader,
+                                   actions: &mut Actions)
+                                   -> Result<()> {
+        let (proposal, message) = try!(messages::decode_forwarded_proposal_response(response));
+        match self.forwarded_clients.remove(&proposal) {
+            Some(client) => actions.client_messages.push((client, message)),
+            None => scoped_debug!("ignoring response to unknown forwarded proposal {}", proposal),
+        }
+        Ok(())
+    }
+
+    /// Answers the clients of all proposals forwarded to the leader which await a response. The
+    /// leader may have appended the proposals, so their clients must retry once a new leader is
+    /// known; see `propose`.
+    fn abort_forwarded_proposals(&mut self, actions: &mut Actions) {
+        for (_, client) in self.forwarded_clients.drain() {
+            scoped_debug!("leader changed; aborting forwarded proposal from client {}", client);
+            actions.client_messages.push((client, messages::command_response_unknown_leader()));
+        }
+    }
+
+    /// Sends the response to a proposal to the client, by way of the follower which forwarded
+    /// the proposal, if any.
+    fn respond_to_proposal(&mut self,
+                           client: ClientId,
+                           forwarded: Option<(ServerId, u64)>,
+                           message: Rc<MallocMessageBuilder>,
+                           actions: &mut Actions) {
+        match forwarded {
+            Some((peer, proposal)) => {
+                match messages::forwarded_proposal_response(client, proposal, &message) {
+                    Ok(message) => actions.peer_messages.push((peer, message)),
+                    Err(error) => {
+                        scoped_warn!("unable to forward response to client {} via peer {}: {}",
+                                     client, peer, error)
+                    },
+                }
+            },
+            None => actions.client_messages.push((client, message)),
+        }
+    }
+
+    /// Proposes a new entry on behalf of the client. If this server is the leader the entry is
+    /// appended to the log and replicated, and the client is answered once it is applied. If this
+    /// server is a follower which forwards proposals, the entry is forwarded to the leader;
+    /// otherwise the client is answered immediately with the known leader, if any. Forwarded
+    /// proposals are not forwarded again. A leader rejects entries larger than the maximum
+    /// message size, and rejects all entries while its uncommitted entries are at the limit.
+    ///
+    /// An empty entry is a no-op: it is replicated and committed like any other, but is not
+    /// applied to the state machine, and the client is answered with a `committed` response once
+    /// it commits. Clients use it as a barrier, to learn that their earlier proposals have
+    /// committed.
+    pub fn propose(&mut self, from: ClientId, entry: &[u8], actions: &mut Actions) {
+        self.append_proposal(from, None, entry, actions);
+        self.apply_committed(actions);
+    }
+
+    /// Handles a proposal as described by `propose`, without applying any entries it commits.
+    /// `forwarded` identifies the follower which forwarded the proposal, and the follower's ID
+    /// for it, if any.
+    fn append_proposal(&mut self,
+                       from: ClientId,
+                       forwarded: Option<(ServerId, u64)>,
+                       entry: &[u8],
+                       actions: &mut Actions) {
         if self.is_candidate() || (self.is_follower() && self.follower_state.leader.is_none()) {
-            actions.client_messages.push((from, messages::command_response_unknown_leader()));
+            self.respond_to_proposal(from,
+                                     forwarded,
+                                     messages::command_response_unknown_leader(),
+                                     actions);
         } else if self.is_follower() {
-            let message =
-                messages::command_response_not_leader(&self.peers[&self.follower_state.leader.unwrap()]);
-            actions.client_messages.push((from, message));
-        } else if let Ok(entry) = request.get_entry() {
+            let leader = self.follower_state.leader.unwrap();
+            if self.forward_proposals && forwarded.is_none() {
+                let proposal = self.next_forwarded_proposal;
+                self.next_forwarded_proposal += 1;
+                scoped_debug!("ProposalRequest from client {}: forwarding proposal {} to leader {}",
+                              from, proposal, leader);
+                self.forwarded_clients.insert(proposal, from);
+                let message = messages::forwarded_proposal_request(from, proposal, entry);
+                actions.peer_messages.push((leader, message));
+            } else {
+                let message = messages::command_response_not_leader(&self.peers[&leader]);
+                self.respond_to_proposal(from, forwarded, message, actions);
+            }
+        } else if entry.len() as u64 > self.max_message_bytes {
+            scoped_debug!("ProposalRequest from client {}: {} byte entry exceeds the message limit",
+                          from, entry.len());
+            self.respond_to_proposal(from,
+                                     forwarded,
+                                     messages::command_response_command_too_large(),
+                                     actions);
+        } else if self.max_uncommitted_entries.map_or(false, |max| self.uncommitted_entries() >= max) {
+            scoped_debug!("ProposalRequest from client {}: {} entries are uncommitted",
+                          from, self.uncommitted_entries());
+            self.respond_to_proposal(from,
+                                     forwarded,
+                                     messages::command_response_overloaded(),
+                                     actions);
+        } else {
             let prev_log_index = self.latest_log_index();
             let prev_log_term = self.latest_log_term();
             let term = self.current_term();
             let log_index = prev_log_index + 1;
-            self.log.append_entries(log_index, &[(term, entry)]).unwrap();
-            self.leader_state.proposals.push_back((from, log_index));
+            if !self.persist(|log| log.append_entries(log_index, &[(term, entry)])) {
+                return;
+            }
+            self.client_origins.insert(log_index, from);
+            if let Some(forwarded) = forwarded {
+                self.forwarded_origins.insert(log_index, forwarded);
+            }
             if self.peers.len() == 0 {
                 scoped_debug!("ProposalRequest from client {}: entry {}", from, log_index);
                 self.advance_commit_index(actions);
@@ -571,16 +1281,261 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
                                                                prev_log_index,
                                                                prev_log_term,
                                                                &[(term, entry)],
-                                                               self.commit_index);
+                                                               &self.client_origins,
+                                                               self.commit_index,
+                                                               self.leader_state.read_round(),
+                                                               self.round_trip_ms());
+                let bytes = entry.len() as u64;
                 for &peer in self.peers.keys() {
-                    if self.leader_state.next_index(&peer) == log_index {
-                        actions.peer_messages.push((peer, message.clone()));
-                        self.leader_state.set_next_index(peer, log_index + 1);
+                    if self.leader_state.next_index(&peer) != log_index {
+                        // The peer is catching up, and will receive the entry when it acknowledges
+                        // the entries it was sent.
+                        continue;
+                    }
+                    let in_flight = self.leader_state.in_flight_bytes(&peer);
+                    if in_flight > 0 && in_flight + bytes > self.max_in_flight_bytes {
+                        scoped_debug!("ProposalRequest: replication window to peer {} is full", peer);
+                        continue;
                     }
+                    actions.peer_messages.push((peer, message.clone()));
+                    self.leader_state.record_in_flight(peer, log_index, log_index, bytes);
+                    self.leader_state.set_next_index(peer, log_index + 1);
+                    self.round_trips.sent(peer, self.clock.now_ms());
                 }
             }
-        } else {
-            panic!("ProposalRequest: no entry given")
+        }
+    }
+
+    /// Sends the peer the entries beginning at `from_index`, as many as fit in its replication
+    /// window. Nothing is sent if the window is full; otherwise at least one entry is sent when
+    /// no entries are in flight, so that the peer always makes progress. If the peer has all of
+    /// the entries, an empty AppendEntries request is sent. The entries are split into several
+    /// requests if they exceed the maximum message size.
+    ///
+    /// If a catch up rate is configured and the peer is missing more entries than may be sent
+    /// in one `CatchUp` interval, at most one interval's worth of entries is sent, and no more
+    /// entries are sent to the peer until the interval ends.
+    fn send_entries(&mut self, peer: ServerId, from_index: LogIndex, actions: &mut Actions) {
+        let term = self.current_term();
+        let until_index = self.latest_log_index() + 1;
+        let catch_up_pending = self.deadlines.contains_key(&ConsensusTimeout::CatchUp(peer));
+        if catch_up_pending && from_index < until_index {
+            scoped_trace!("peer {} is catching up; waiting for the next interval", peer);
+            return;
+        }
+        let prev_log_index = from_index.saturating_sub(1);
+        let prev_log_term = match self.log_term(prev_log_index) {
+            Some(term) => term,
+            None => return,
+        };
+        let in_flight = self.leader_state.in_flight_bytes(&peer);
+        let mut budget = self.max_in_flight_bytes.saturating_sub(in_flight);
+        let catch_up_budget = self.catch_up_bytes_per_sec
+                                  .map(|rate| cmp::max(1, rate * CATCH_UP_INTERVAL / 1000));
+        let exceeds_catch_up = |bytes: u64| {
+            catch_up_budget.map_or(false, |catch_up_budget| bytes > catch_up_budget)
+        };
+
+        let (requests, count, paced) = {
+            // The entries which fit in the window, split into batches of the entry count and bytes
+            // of each request. Entries are read only until one does not fit, and, if a catch up
+            // rate is configured, until it is known whether the peer is missing more than one
+            // interval's worth, so that a lagging peer does not cost a read of the whole tail.
+            // They are read in contiguous ranges, usually one: every entry but a no-op holds at
+            // least a byte, so a range is bounded by the bytes which remain to be examined.
+            let read_bytes = catch_up_budget.map_or(budget, |catch_up_budget| {
+                cmp::max(budget, catch_up_budget)
+            });
+            let max_message_bytes = self.max_message_bytes;
+            let mut entries: Vec<(Term, &[u8])> = Vec::new();
+            let mut batches: Vec<(usize, u64)> = Vec::new();
+            let mut bytes = 0;
+            let mut count = 0;
+            let mut missing = 0;
+            let mut window_full = false;
+            let mut index = from_index;
+            let mut read_len = SEND_ENTRIES_READ;
+            'read: while index < until_index {
+                let len = cmp::min(read_len, read_bytes.saturating_sub(missing) + 1);
+                let bound = cmp::min(index + len, until_index);
+                let range = match self.log.entries(index, bound) {
+                    Ok(range) => range,
+                    Err(error) => {
+                        read_failed(&mut self.fatal_error, index, error);
+                        return;
+                    },
+                };
+                index = bound;
+                read_len *= 2;
+                for (entry_term, data) in range {
+                    if window_full && (catch_up_budget.is_none() || exceeds_catch_up(missing)) {
+                        break 'read;
+                    }
+                    let len = data.len() as u64;
+                    missing += len;
+                    if window_full {
+                        continue;
+                    }
+                    if exceeds_catch_up(missing) {
+                        budget = cmp::min(budget, catch_up_budget.unwrap());
+                    }
+                    if bytes + len > budget && (count > 0 || in_flight > 0) {
+                        window_full = true;
+                        continue;
+                    }
+                    let fits_batch = batches.last().map_or(false, |&(_, batch_bytes)| {
+                        batch_bytes + len <= max_message_bytes
+                    });
+                    if fits_batch {
+                        let batch = batches.last_mut().unwrap();
+                        batch.0 += 1;
+                        batch.1 += len;
+                    } else {
+                        batches.push((1, len));
+                    }
+                    entries.push((entry_term, data));
+                    bytes += len;
+                    count += 1;
+                }
+                if window_full && (catch_up_budget.is_none() || exceeds_catch_up(missing)) {
+                    break;
+                }
+            }
+            let paced = exceeds_catch_up(missing);
+            if count == 0 && from_index < until_index {
+                scoped_debug!("replication window to peer {} is full; {} bytes in flight",
+                              peer, in_flight);
+                return;
+            }
+            if batches.is_empty() {
+                batches.push((0, 0));
+            }
+
+            let mut requests = Vec::with_capacity(batches.len());
+            let mut offset = 0;
+            for (batch_count, batch_bytes) in batches {
+                let (batch_prev_index, batch_prev_term) = if offset == 0 {
+                    (prev_log_index, prev_log_term)
+                } else {
+                    (from_index + (offset - 1) as u64, entries[offset - 1].0)
+                };
+                let message = messages::append_entries_request(term,
+                                                               batch_prev_index,
+                                                               batch_prev_term,
+                                                               &entries[offset..offset + batch_count],
+                                                               &self.client_origins,
+                                                               self.commit_index,
+                                                               self.leader_state.read_round(),
+                                                               self.round_trip_ms());
+                offset += batch_count;
+                requests.push((message, from_index + offset as u64, batch_count, batch_bytes));
+            }
+            (requests, count, paced)
+        };
+
+        for (message, next_index, batch_count, batch_bytes) in requests {
+            if batch_count > 0 {
+                self.leader_state.record_in_flight(peer,
+                                                  next_index - batch_count as u64,
+                                                  next_index - 1,
+                                                  batch_bytes);
+            }
+            actions.peer_messages.push((peer, message));
+        }
+        self.leader_state.set_next_index(peer, from_index + count as u64);
+        self.request_sent(peer);
+        if paced {
+            self.set_timeout(ConsensusTimeout::CatchUp(peer), actions);
+        }
+    }
+
+    /// Subscribes the client to the committed entries beginning at the requested index, replacing
+    /// any existing subscription of the client. Entries which are already committed are streamed
+    /// immediately.
+    fn subscribe_request(&mut self,
+                         from: ClientId,
+                         request: subscribe_request::Reader,
+                         actions: &mut Actions)
+                         -> Result<()> {
+        let from_index = cmp::max(LogIndex(request.get_from_index()), LogIndex(1));
+        scoped_debug!("SubscribeRequest from client {}: from index {}", from, from_index);
+        self.subscribers.insert(from, Subscription { next_index: from_index, streaming: false });
+        self.stream_to_subscriber(from, actions);
+        Ok(())
+    }
+
+    /// Streams the next batch of committed entries to each subscribed client which is not still
+    /// being sent a batch.
+    fn stream_to_subscribers(&mut self, actions: &mut Actions) {
+        let clients: Vec<ClientId> = self.subscribers.keys().cloned().collect();
+        for client in clients {
+            self.stream_to_subscriber(client, actions);
+        }
+    }
+
+    /// Streams the next batch of committed entries to the subscribed client, unless a batch sent
+    /// to it is still waiting to be written. Only one batch is outstanding per subscriber, so a
+    /// subscriber which reads slowly holds at most one batch in this server's buffers; the rest
+    /// remain in the log until `client_drained` reports that the batch was written. A failure to
+    /// read the entries halts the consensus module; see `read_failed`.
+    fn stream_to_subscriber(&mut self, client: ClientId, actions: &mut Actions) {
+        let next_index = match self.subscribers.get(&client) {
+            Some(subscription) if !subscription.streaming => subscription.next_index,
+            _ => return,
+        };
+        if next_index > self.commit_index {
+            return;
+        }
+        let mut index = next_index;
+        let message = {
+            // Entries are read one at a time, so that only those in the batch are read.
+            let mut entries = Vec::new();
+            let mut bytes = 0;
+            while index <= self.commit_index && bytes < SUBSCRIPTION_BATCH_BYTES {
+                let data = match self.log.entry(index) {
+                    Ok((_, data)) => data,
+                    Err(error) => {
+                        read_failed(&mut self.fatal_error, index, error);
+                        return;
+                    },
+                };
+                // No-op entries are not streamed.
+                if !data.is_empty() {
+                    bytes += data.len() as u64;
+                    entries.push((index, data));
+                }
+                index = index + 1;
+            }
+            if entries.is_empty() {
+                None
+            } else {
+                scoped_trace!("streaming {} entries to subscribed client {}", entries.len(), client);
+                Some(messages::subscription_entries(&entries))
+            }
+        };
+        let subscription = self.subscribers.get_mut(&client).unwrap();
+        subscription.next_index = index;
+        if let Some(message) = message {
+            subscription.streaming = true;
+            actions.client_messages.push((client, message));
+        }
+    }
+
+    /// Notifies the `Consensus` that all messages sent to the client have been written to it. A
+    /// subscribed client is streamed its next batch of committed entries, if any.
+    pub fn client_drained(&mut self, client: ClientId, actions: &mut Actions) {
+        match self.subscribers.get_mut(&client) {
+            Some(subscription) => subscription.streaming = false,
+            None => return,
+        }
+        self.stream_to_subscriber(client, actions);
+    }
+
+    /// Notifies the `Consensus` that the client has disconnected, ending its subscription, if
+    /// any.
+    pub fn client_disconnected(&mut self, client: ClientId) {
+        if self.subscribers.remove(&client).is_some() {
+            scoped_debug!("subscribed client {} disconnected", client);
         }
     }
 
@@ -588,171 +1543,596 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
     fn query_request(&mut self,
                     from: ClientId,
                     request: query_request::Reader,
-                    actions: &mut Actions) {
+                    actions: &mut Actions)
+                    -> Result<()> {
         scoped_trace!("query from Client({})", from);
+        let query = try!(request.get_query());
 
-        if self.is_candidate() || (self.is_follower() && self.follower_state.leader.is_none()) {
+        if self.read_replica {
+            // A read replica answers from its own state machine, which may lag the leader's, so
+            // its reads are stale but never wait on the rest of the cluster.
+            self.serve_query(from, query.to_vec(), actions);
+        } else if self.is_candidate() || (self.is_follower() && self.follower_state.leader.is_none()) {
             actions.client_messages.push((from, messages::command_response_unknown_leader()));
         } else if self.is_follower() {
             let message =
                 messages::command_response_not_leader(&self.peers[&self.follower_state.leader.unwrap()]);
             actions.client_messages.push((from, message));
         } else {
-            // TODO: This is probably not exactly safe.
-            let query = request.get_query().unwrap();
-            let result = self.state_machine.query(query);
-            let message = messages::command_response_success(&result);
-            actions.client_messages.push((from, message));
+            // A deposed leader which has not yet heard of the newer term must not serve stale
+            // data, so the read waits until a majority acknowledges a heartbeat sent after it
+            // arrived. Waiting for the whole log as of now to be applied covers every entry which
+            // may have been committed before the query arrived.
+            let read_index = self.latest_log_index();
+            self.leader_state.register_read(from, query.to_vec(), read_index);
+            let peers: Vec<ServerId> = self.peers.keys().cloned().collect();
+            for peer in peers {
+                self.send_heartbeat(peer, actions);
+            }
+            self.serve_reads(actions);
         }
+        Ok(())
     }
 
-    /// Triggers a heartbeat timeout for the peer.
-    fn heartbeat_timeout(&mut self, peer: ServerId, actions: &mut Actions) {
-        scoped_assert!(self.is_leader());
-        scoped_debug!("HeartbeatTimeout for peer: {}", peer);
-        let mut message = MallocMessageBuilder::new_default();
-        {
-            let mut request = message.init_root::<message::Builder>()
-                                     .init_append_entries_request();
-            request.set_term(self.current_term().as_u64());
-            request.set_prev_log_index(self.latest_log_index().as_u64());
-            request.set_prev_log_term(self.log.latest_log_term().unwrap().as_u64());
-            request.set_leader_commit(self.commit_index.as_u64());
-            request.init_entries(0);
+    /// Answers the pending queries which are ready to be served.
+    fn serve_reads(&mut self, actions: &mut Actions) {
+        let majority = self.majority();
+        // A worker applies tasks in order, so a query sent after the entries it must observe is
+        // answered after they are applied.
+        for (client, query) in self.leader_state.take_ready_reads(majority, self.apply_dispatched) {
+            self.serve_query(client, query, actions);
         }
-        actions.peer_messages.push((peer, Rc::new(message)));
     }
 
-    /// Triggers an election timeout.
-    fn election_timeout(&mut self, actions: &mut Actions) {
-        scoped_assert!(!self.is_leader());
-        if self.peers.is_empty() {
-            // Solitary replica special case; jump straight to Leader state.
-            scoped_info!("ElectionTimeout: transitioning to Leader");
-            scoped_assert!(self.is_follower());
-            scoped_assert!(self.log.voted_for().unwrap().is_none());
-            self.log.inc_current_term().unwrap();
-            self.log.set_voted_for(self.id).unwrap();
-            let latest_log_index = self.latest_log_index();
-            self.state = ConsensusState::Leader;
-            self.leader_state.reinitialize(latest_log_index);
-        } else {
-            scoped_info!("ElectionTimeout: transitioning to Candidate");
-            self.transition_to_candidate(actions);
+    /// Answers a query from the state machine, as of the entries dispatched to it so far.
+    fn serve_query(&mut self, client: ClientId, query: Vec<u8>, actions: &mut Actions) {
+        scoped_trace!("serving query from client {}", client);
+        match self.state_machine {
+            Applier::Inline(ref state_machine) => {
+                let result = state_machine.query(&query);
+                actions.client_messages.push((client, messages::command_response_success(&result)));
+            },
+            Applier::Worker(ref tasks) => {
+                if tasks.send(ApplyTask::Query(client, query)).is_err() {
+                    worker_exited(&mut self.fatal_error);
+                }
+            },
         }
     }
 
+    /// Notifies the state machine that this server gained or lost leadership of the current term.
+    /// A worker is notified in order with the entries it applies.
+    fn notify_leadership_change(&mut self, is_leader: bool) {
+        let term = self.current_term();
+        match self.state_machine {
+            Applier::Inline(ref mut state_machine) => state_machine.on_leadership_change(is_leader, term),
+            Applier::Worker(ref tasks) => {
+                if tasks.send(ApplyTask::LeadershipChange(is_leader, term)).is_err() {
+                    worker_exited(&mut self.fatal_error);
+                }
+            },
+        }
+    }
+
+    /// Redirects the clients of pending queries, which can not be served after leadership is
+    /// lost.
+    fn abort_reads(&mut self, actions: &mut Actions) {
+        let message = match self.follower_state.leader {
+            Some(leader) => messages::command_response_not_leader(&self.peers[&leader]),
+            None => messages::command_response_unknown_leader(),
+        };
+        for client in self.leader_state.take_pending_reads() {
+            scoped_debug!("redirecting query from client {}", client);
+            actions.client_messages.push((client, message.clone()));
+        }
+    }
+
+    /// Triggers a heartbeat timeout for the peer.
+    fn heartbeat_timeout(&mut self, peer: ServerId, actions: &mut Actions) {
+        scoped_debug!("HeartbeatTimeout for peer: {}", peer);
+        self.send_heartbeat(peer, actions);
+    }
+
+    /// Sends an empty AppendEntries request to the peer, carrying the latest read round.
+    fn send_heartbeat(&mut self, peer: ServerId, actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        let message = messages::append_entries_request(self.current_term(),
+                                                       self.latest_log_index(),
+                                                       self.latest_log_term(),
+                                                       &[],
+                                                       &self.client_origins,
+                                                       self.commit_index,
+                                                       self.leader_state.read_round(),
+                                                       self.round_trip_ms());
+        actions.push_replaceable_peer_message(peer, message);
+        self.request_sent(peer);
+    }
+
+    /// Triggers a catch up timeout for the peer, sending it the next batch of missing entries.
+    fn catch_up_timeout(&mut self, peer: ServerId, actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        let next_index = self.leader_state.next_index(&peer);
+        if next_index <= self.latest_log_index() {
+            scoped_debug!("CatchUpTimeout for peer: {}", peer);
+            self.send_entries(peer, next_index, actions);
+        }
+    }
+
+    /// Triggers an election timeout.
+    fn election_timeout(&mut self, actions: &mut Actions) {
+        scoped_assert!(!self.is_leader());
+        if self.peers.is_empty() {
+            // Solitary replica special case; jump straight to Leader state.
+            scoped_info!("ElectionTimeout: transitioning to Leader");
+            scoped_assert!(self.is_follower());
+            // A vote may remain from the previous term if the replica restarted; it is cleared
+            // along with the term.
+            let term = self.current_term() + 1;
+            self.set_term(term);
+            let id = self.id;
+            if !self.persist(|log| log.set_voted_for(id)) {
+                return;
+            }
+            self.election_metrics.elections_started += 1;
+            self.election_metrics.votes_granted += 1;
+            self.transition_to_leader(actions);
+        } else if self.leader_stickiness_ms.is_some() && self.is_follower() {
+            scoped_info!("ElectionTimeout: requesting pre-votes");
+            self.start_pre_vote(actions);
+        } else {
+            scoped_info!("ElectionTimeout: transitioning to Candidate");
+            self.transition_to_candidate(actions);
+        }
+    }
+
+    /// Asks the peers whether they would vote for this follower in the next term, without
+    /// adopting it. The election starts once a majority agrees; until then, a refused follower
+    /// keeps its term, and so is not deposed the next time it hears from the leader (Raft thesis
+    /// §9.6). Peers agree unless they have heard from a leader within the stickiness interval, or
+    /// the follower's log is behind theirs.
+    fn start_pre_vote(&mut self, actions: &mut Actions) {
+        let mut pre_vote_state = CandidateState::new();
+        pre_vote_state.record_vote(self.id);
+        self.pre_vote_state = Some(pre_vote_state);
+
+        let message = messages::request_vote_request(self.current_term() + 1,
+                                                     self.latest_log_index(),
+                                                     self.latest_log_term(),
+                                                     true);
+        for &peer in self.peers.keys() {
+            actions.push_replaceable_peer_message(peer, message.clone());
+        }
+        self.set_timeout(ConsensusTimeout::Election, actions);
+    }
+
     /// Transitions this consensus state machine to Leader state.
     fn transition_to_leader(&mut self, actions: &mut Actions) {
         scoped_trace!("transitioning to Leader");
+        self.election_metrics.elections_won += 1;
         let current_term = self.current_term();
         let latest_log_index = self.latest_log_index();
         let latest_log_term = self.log.latest_log_term().unwrap();
         self.state = ConsensusState::Leader;
         self.leader_state.reinitialize(latest_log_index);
+        // Forwarded proposals from a previous term are answered by whichever leader applies them,
+        // if it knows their clients.
+        self.forwarded_origins.clear();
+
+        // Append a no-op entry in the new term. Entries from previous terms can only be committed
+        // indirectly, by committing an entry from the leader's current term (Raft §5.4.2).
+        let noop: &[u8] = &[];
+        let noop_index = latest_log_index + 1;
+        if !self.persist(|log| log.append_entries(noop_index, &[(current_term, noop)])) {
+            return;
+        }
 
         let message = messages::append_entries_request(current_term,
                                                        latest_log_index,
                                                        latest_log_term,
-                                                       &[],
-                                                       self.commit_index);
-        for &peer in self.peers().keys() {
+                                                       &[(current_term, noop)],
+                                                       &self.client_origins,
+                                                       self.commit_index,
+                                                       self.leader_state.read_round(),
+                                                       self.round_trip_ms());
+        self.round_trips.clear_pending();
+        let now = self.clock.now_ms();
+        for &peer in self.peers.keys() {
             actions.peer_messages.push((peer, message.clone()));
+            self.leader_state.set_next_index(peer, noop_index + 1);
+            self.round_trips.sent(peer, now);
         }
 
-        actions.clear_timeouts = true;
+        self.clear_timeouts(actions);
         actions.clear_peer_messages = true;
+        self.notify_leadership_change(true);
+
+        // A solitary leader commits the no-op immediately.
+        self.advance_commit_index(actions);
     }
 
     /// Transitions the consensus state machine to Candidate state.
     fn transition_to_candidate(&mut self, actions: &mut Actions) {
         scoped_trace!("transitioning to Candidate");
-        self.log.inc_current_term().unwrap();
-        self.log.set_voted_for(self.id).unwrap();
+        if self.is_candidate() {
+            // The previous election timed out, for instance because the vote was split.
+            self.election_metrics.elections_lost += 1;
+        }
+        self.election_metrics.elections_started += 1;
+        self.election_metrics.votes_granted += 1;
+        let term = self.current_term() + 1;
+        self.set_term(term);
+        let id = self.id;
+        if !self.persist(|log| log.set_voted_for(id)) {
+            return;
+        }
         self.state = ConsensusState::Candidate;
         self.candidate_state.clear();
+        self.pre_vote_state = None;
+        // The leader to which proposals were forwarded is no longer followed.
+        self.abort_forwarded_proposals(actions);
         self.candidate_state.record_vote(self.id);
 
         let message = messages::request_vote_request(self.current_term(),
                                                      self.latest_log_index(),
-                                                     self.log.latest_log_term().unwrap());
+                                                     self.latest_log_term(),
+                                                     false);
 
-        for &peer in self.peers().keys() {
-            actions.peer_messages.push((peer, message.clone()));
+        self.round_trips.clear_pending();
+        let now = self.clock.now_ms();
+        for &peer in self.peers.keys() {
+            actions.push_replaceable_peer_message(peer, message.clone());
+            self.round_trips.sent(peer, now);
         }
-        actions.timeouts.push(ConsensusTimeout::Election);
+        self.set_timeout(ConsensusTimeout::Election, actions);
         actions.clear_peer_messages = true;
     }
 
-    /// Advances the commit index and applies committed entries to the state machine.
+    /// Advances the commit index. The newly committed entries are applied by `apply_committed`.
     fn advance_commit_index(&mut self, actions: &mut Actions) {
         scoped_assert!(self.is_leader());
         let majority = self.majority();
-        // TODO: Figure out failure condition here.
-        while self.commit_index < self.log.latest_log_index().unwrap() {
-            if self.leader_state.count_match_indexes(self.commit_index + 1) >= majority {
-                self.commit_index = self.commit_index + 1;
-                scoped_debug!("commit index advanced to {}", self.commit_index);
+        let current_term = self.current_term();
+        let latest_log_index = self.latest_log_index();
+        // The leader counts its own log toward the majority, so its entries must be durable first.
+        if !self.persist(|log| log.flush()) {
+            return;
+        }
+        let mut index = self.commit_index;
+        while index < latest_log_index {
+            if self.leader_state.count_match_indexes(index + 1) >= majority {
+                index = index + 1;
+                // Only entries from the current term are committed by counting replicas. Earlier
+                // entries are committed along with them (Raft §5.4.2).
+                let term = match self.log_term(index) {
+                    Some(term) => term,
+                    None => return,
+                };
+                if term == current_term {
+                    self.set_commit_index(index);
+                    scoped_debug!("commit index advanced to {}", self.commit_index);
+                }
             } else {
                 break; // If there isn't a majority now, there won't be one later.
             }
         }
+    }
 
-        let results = self.apply_commits();
-
-        while let Some(&(client, index)) = self.leader_state.proposals.get(0) {
-            if index <= self.commit_index {
-                scoped_trace!("responding to client {} for entry {}", client, index);
-                // We know that there will be an index here since it was commited
-                // and the index is less than that which has been commited.
-                let result = results.get(&index).unwrap();
-                let message = messages::command_response_success(result);
-                actions.client_messages.push((client, message));
-                self.leader_state.proposals.pop_front();
-            } else {
-                break;
+    /// Applies all committed but unapplied log entries to the state machine, or hands them to the
+    /// apply worker, which returns their results through `apply_output`. While leader, the result
+    /// of each applied entry is sent to the client which proposed it, if known, and reads waiting
+    /// on the applied entries are served.
+    ///
+    /// Message and timeout handling only advance the commit index; this step runs once at the end
+    /// of each, so entries committed by a single message are applied together.
+    pub fn apply_committed(&mut self, actions: &mut Actions) {
+        // The commit index of a halted module may cover entries which were never persisted.
+        if self.fatal_error.is_some() {
+            return;
+        }
+        while self.apply_dispatched < self.commit_index {
+            let index = self.apply_dispatched + 1;
+            // The entry is known to exist, so an error means the log is damaged. Applying anything
+            // else in its place would silently diverge from the other replicas.
+            let result = match self.log.entry(index) {
+                Ok((_, entry)) => match self.state_machine {
+                    // Empty entries are the no-ops appended by new leaders; they are not applied.
+                    Applier::Inline(_) if entry.is_empty() => Some(None),
+                    Applier::Inline(ref mut state_machine) => Some(Some(state_machine.apply(entry))),
+                    Applier::Worker(ref tasks) => {
+                        if tasks.send(ApplyTask::Apply(index, entry.to_vec())).is_err() {
+                            worker_exited(&mut self.fatal_error);
+                            return;
+                        }
+                        None
+                    },
+                },
+                Err(error) => {
+                    read_failed(&mut self.fatal_error, index, error);
+                    return;
+                },
+            };
+            self.apply_dispatched = index;
+            if let Some(result) = result {
+                self.finish_apply(index, result, actions);
+                if self.fatal_error.is_some() {
+                    return;
+                }
             }
         }
+        self.stream_to_subscribers(actions);
+        if self.is_leader() {
+            self.serve_reads(actions);
+        }
     }
 
-    /// Applies all committed but unapplied log entries to the state machine.  Returns the set of
-    /// return values from the commits applied.
-    fn apply_commits(&mut self) -> HashMap<LogIndex, Vec<u8>> {
-        let mut results = HashMap::new();
-        while self.last_applied < self.commit_index {
-            // Unwrap justified here since we know there is an entry here.
-            let (_, entry) = self.log.entry(self.last_applied + 1).unwrap();
+    /// Handles an output of the apply worker thread.
+    pub fn apply_output(&mut self, output: ApplyOutput, actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        match output {
+            ApplyOutput::Applied(index, result) => self.finish_apply(index, result, actions),
+            ApplyOutput::Queried(client, result) => {
+                actions.client_messages.push((client, messages::command_response_success(&result)));
+            },
+        }
+    }
 
-            if !entry.is_empty() {
-                let result = self.state_machine.apply(entry);
-                results.insert(self.last_applied + 1, result);
-            }
-            self.last_applied = self.last_applied + 1;
+    /// Records that the entry at the index has been applied, with the state machine's result or
+    /// `None` for a no-op entry, and responds to the client which proposed it. Entries must be
+    /// applied strictly in order.
+    fn finish_apply(&mut self,
+                    index: LogIndex,
+                    result: Option<result::Result<Vec<u8>, ApplyError>>,
+                    actions: &mut Actions) {
+        scoped_assert!(index == self.last_applied + 1,
+                       "entry {} applied out of order; last applied: {}", index, self.last_applied);
+        let origin = self.client_origins.remove(&index);
+        let forwarded = self.forwarded_origins.remove(&index);
+        let message = match result {
+            Some(Ok(result)) => messages::command_response_success(&result),
+            Some(Err(ApplyError::Rejected(reason))) => {
+                scoped_debug!("state machine rejected entry {}", index);
+                messages::command_response_rejected(&reason)
+            },
+            Some(Err(ApplyError::Fatal(error))) => {
+                scoped_error!("{:?}: state machine failed to apply entry {}; halting: {}",
+                              self, index, error);
+                if self.fatal_error.is_none() {
+                    self.fatal_error = Some(RaftError::ApplyFailed(error));
+                }
+                return;
+            },
+            // An empty command proposed by a client is a no-op which is not applied; committing
+            // it is all the client asked for.
+            None => messages::command_response_committed(),
+        };
+        if let (true, Some(client)) = (self.is_leader(), origin) {
+            scoped_trace!("responding to client {} for entry {}", client, index);
+            self.respond_to_proposal(client, forwarded, message, actions);
+        }
+        self.last_applied = index;
+    }
+
+    /// Sets the index of the latest committed entry. The commit index never decreases; see
+    /// `index_regresses`.
+    fn set_commit_index(&mut self, index: LogIndex) {
+        if index_regresses("commit index", self.commit_index, index) {
+            return;
         }
-        results
+        self.commit_index = index;
     }
 
-    /// Transitions the consensus state machine to Follower state with the provided term. The
-    /// `voted_for` field will be reset. The provided leader hint will replace the last known
+    /// Transitions the consensus state machine to Follower state with the provided term. If the
+    /// term is newer than the current term the `voted_for` field will be reset; a vote already
+    /// cast in the current term stands. The provided leader hint will replace the last known
     /// leader.
     fn transition_to_follower(&mut self,
                               term: Term,
                               leader: ServerId,
                               actions: &mut Actions) {
         scoped_trace!("transitioning to Follower");
-        self.log.set_current_term(term).unwrap();
+        if self.is_leader() {
+            self.notify_leadership_change(false);
+        } else if self.is_candidate() {
+            self.election_metrics.elections_lost += 1;
+        }
+        if term > self.current_term() {
+            self.set_term(term);
+        }
+        if !self.is_follower() || self.follower_state.leader != Some(leader) {
+            self.abort_forwarded_proposals(actions);
+        }
         self.state = ConsensusState::Follower;
         self.follower_state.set_leader(leader);
+        self.round_trips.clear_pending();
+        self.abort_reads(actions);
+        self.clear_timeouts(actions);
+        actions.clear_peer_messages = true;
+        self.set_timeout(ConsensusTimeout::Election, actions);
+    }
+
+    /// Returns the term of the entry at the provided index, or term 0 for index 0, which precedes
+    /// the first entry of every log. Returns `None` if the entry can not be read, in which case
+    /// the consensus module is halted; see `read_failed`.
+    fn log_term(&mut self, index: LogIndex) -> Option<Term> {
+        if index == LogIndex(0) {
+            return Some(Term(0));
+        }
+        match self.log.entry(index) {
+            Ok((term, _)) => Some(term),
+            Err(error) => {
+                read_failed(&mut self.fatal_error, index, error);
+                None
+            },
+        }
+    }
+
+    /// Sets the current term, which must be greater than the existing term, and clears
+    /// `voted_for` so that a vote may be cast in the new term. The `Log` persists the new term and
+    /// the cleared vote together. All term changes must go through this method.
+    fn set_term(&mut self, term: Term) {
+        scoped_assert!(term > self.current_term(),
+                       "term must increase; current: {}, new: {}", self.current_term(), term);
+        if !self.persist(|log| log.set_current_term(term)) {
+            return;
+        }
+        scoped_assert!(self.log.voted_for().unwrap().is_none(),
+                       "log did not reset voted_for along with the term");
+        self.leader_heard_ms = None;
+        self.pre_vote_state = None;
+    }
+
+    /// Persists state to the log through `write`, returning whether it succeeded. The first
+    /// failure halts the consensus module: no further writes are attempted and no entries are
+    /// applied, and the server shuts down rather than act on state which is not durable. Callers
+    /// return as soon as a write fails, so that nothing depends on the state which was lost.
+    fn persist<F>(&mut self, write: F) -> bool
+    where F: FnOnce(&mut L) -> result::Result<(), L::Error> {
+        if self.fatal_error.is_some() {
+            return false;
+        }
+        match write(&mut self.log) {
+            Ok(()) => true,
+            Err(error) => {
+                scoped_error!("{:?}: unable to persist state: {}", self, error);
+                self.fatal_error = Some(RaftError::LogWriteFailed(error.to_string()));
+                false
+            },
+        }
+    }
+
+    /// Returns the error with which reading or persisting state in the log, or applying an entry,
+    /// failed, if one has. A consensus module which has failed must not be used further, nor its
+    /// actions executed.
+    pub fn fatal_error(&self) -> Option<&RaftError> {
+        self.fatal_error.as_ref()
+    }
+
+    /// Schedules a timeout, replacing any existing timeout of the same kind.
+    fn set_timeout(&mut self, timeout: ConsensusTimeout, actions: &mut Actions) {
+        if timeout == ConsensusTimeout::Election && self.read_replica {
+            // A read replica never campaigns, however long the leader is silent.
+            return;
+        }
+        let duration = match timeout {
+            ConsensusTimeout::Election => {
+                let (min, max) = self.election_timeout_range();
+                rand::thread_rng().gen_range::<u64>(min, max)
+            },
+            _ => timeout.duration_ms(),
+        };
+        let deadline = self.clock.now_ms() + duration;
+        self.deadlines.insert(timeout, deadline);
+        actions.timeouts.push(timeout);
+    }
+
+    /// Returns the number of milliseconds until the scheduled timeout fires, or its usual
+    /// duration if it is not scheduled.
+    pub fn timeout_ms(&self, timeout: ConsensusTimeout) -> u64 {
+        match self.deadlines.get(&timeout) {
+            Some(&deadline) => deadline.saturating_sub(self.clock.now_ms()),
+            None => timeout.duration_ms(),
+        }
+    }
+
+    /// Returns the range from which election timeouts are drawn, in milliseconds.
+    fn election_timeout_range(&self) -> (u64, u64) {
+        match self.election_rtt_multiple {
+            None => (ELECTION_MIN, ELECTION_MAX),
+            Some(multiple) => {
+                let (min_ms, max_ms) = self.election_timeout_clamp_ms;
+                // `None` orders before any measured round trip time.
+                let min = cmp::max(self.round_trips.p99(), self.leader_round_trip_ms)
+                              .map_or(min_ms, |p99| cmp::min(cmp::max(p99 * multiple, min_ms), max_ms));
+                (min, min + ELECTION_MAX - ELECTION_MIN)
+            },
+        }
+    }
+
+    /// Returns the 99th percentile round trip time of requests to peers, as reported to followers
+    /// in AppendEntries requests, or 0 if none has been measured.
+    fn round_trip_ms(&self) -> u64 {
+        self.round_trips.p99().unwrap_or(0)
+    }
+
+    /// Records that a request was sent to the peer, for timing its round trip.
+    fn request_sent(&mut self, peer: ServerId) {
+        self.round_trips.sent(peer, self.clock.now_ms());
+    }
+
+    /// Clears all scheduled timeouts.
+    fn clear_timeouts(&mut self, actions: &mut Actions) {
+        self.deadlines.clear();
         actions.clear_timeouts = true;
+    }
+
+    /// Relinquishes leadership without designating a successor. The leader becomes a follower in
+    /// the same term and stops sending heartbeats, so that the election timeout of another server
+    /// elects a new leader. Has no effect if not currently the leader.
+    pub fn step_down(&mut self, actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        if !self.is_leader() {
+            scoped_debug!("not leader; ignoring request to step down");
+            return;
+        }
+        scoped_info!("stepping down as leader of term {}", self.current_term());
+        self.notify_leadership_change(false);
+        self.state = ConsensusState::Follower;
+        self.follower_state.leader = None;
+        self.abort_reads(actions);
+        self.clear_timeouts(actions);
         actions.clear_peer_messages = true;
-        actions.timeouts.push(ConsensusTimeout::Election);
+        self.set_timeout(ConsensusTimeout::Election, actions);
     }
 
     /// Returns whether the consensus state machine is currently a Leader.
-    fn is_leader(&self) -> bool {
+    ///
+    /// This is the local view of the consensus module; a leader which has been deposed but has not
+    /// yet heard of the newer term will still report itself as leader.
+    pub fn is_leader(&self) -> bool {
         self.state == ConsensusState::Leader
     }
 
+    /// Returns the leader of the current term: this server while leader, and while follower the
+    /// peer from which an AppendEntries request was received in the current term. `None` during an
+    /// election, or before the leader has been heard from.
+    pub fn current_leader(&self) -> Option<ServerId> {
+        match self.state {
+            ConsensusState::Leader => Some(self.id),
+            ConsensusState::Candidate => None,
+            ConsensusState::Follower => self.leader_heard_ms.and(self.follower_state.leader),
+        }
+    }
+
+    /// Returns the number of milliseconds since the latest AppendEntries exchange with each peer,
+    /// or `None` for peers which have not been heard from. While leader, every follower's
+    /// responses are tracked; while follower, only the leader's requests are.
+    pub fn last_contact(&self) -> HashMap<ServerId, Option<u64>> {
+        let now = self.clock.now_ms();
+        self.peers.keys().map(|&peer| {
+            (peer, self.last_contact.get(&peer).map(|&contact| now.saturating_sub(contact)))
+        }).collect()
+    }
+
+    /// Returns the index of the latest entry applied to the state machine.
+    pub fn last_applied(&self) -> LogIndex {
+        self.last_applied
+    }
+
+    /// Returns the number of entries in the log beyond the commit index.
+    pub fn uncommitted_entries(&self) -> u64 {
+        self.latest_log_index().as_u64().saturating_sub(self.commit_index.as_u64())
+    }
+
+    /// Returns the log's entry read counters.
+    pub fn log_metrics(&self) -> LogMetrics {
+        self.log.metrics()
+    }
+
+    /// Returns counters of the elections this server has taken part in.
+    pub fn election_metrics(&self) -> ElectionMetrics {
+        self.election_metrics
+    }
+
     /// Returns whether the consensus state machine is currently a Follower.
     fn is_follower(&self) -> bool {
         self.state == ConsensusState::Follower
@@ -784,6 +2164,49 @@ impl <L, M> Consensus<L, M> where L: Log, M: StateMachine {
         let cluster_members = peers.checked_add(1).expect(&format!("unable to support {} cluster members", peers));
         (cluster_members >> 1) + 1
     }
+
+    /// Panics if a safety invariant of the consensus state does not hold. Test harnesses call this
+    /// after every step, so that a violation fails the test at the step which caused it, rather
+    /// than as a divergence noticed much later.
+    #[cfg(test)]
+    pub fn check_invariants(&self) {
+        let term = self.current_term();
+        let latest_log_index = self.latest_log_index();
+        let voted_for = self.log.voted_for().unwrap();
+
+        if let Some(checked_term) = self.checked_term.get() {
+            assert!(term >= checked_term, "{:?}: term regressed from {}", self, checked_term);
+        }
+        self.checked_term.set(Some(term));
+
+        assert!(self.commit_index <= latest_log_index,
+                "{:?}: commit index {} beyond latest log index {}",
+                self, self.commit_index, latest_log_index);
+        assert!(self.apply_dispatched <= self.commit_index,
+                "{:?}: entry {} dispatched for application beyond commit index {}",
+                self, self.apply_dispatched, self.commit_index);
+        assert!(self.last_applied <= self.apply_dispatched,
+                "{:?}: last applied {} beyond dispatched index {}",
+                self, self.last_applied, self.apply_dispatched);
+        assert!(self.latest_log_term() <= term,
+                "{:?}: latest log term {} beyond current term", self, self.latest_log_term());
+
+        match self.state {
+            ConsensusState::Leader | ConsensusState::Candidate => {
+                // Leaders and candidates voted for themselves when they started their term.
+                assert_eq!(Some(self.id), voted_for, "{:?}: did not vote for itself", self);
+            },
+            ConsensusState::Follower => (),
+        }
+        if self.is_leader() {
+            for peer in self.peers.keys() {
+                let match_index = self.leader_state.match_index(peer);
+                assert!(match_index <= latest_log_index,
+                        "{:?}: match index {} of peer {} beyond latest log index {}",
+                        self, match_index, peer, latest_log_index);
+            }
+        }
+    }
 }
 
 impl <L, M> fmt::Debug for Consensus<L, M> where L: Log, M: StateMachine {
@@ -810,23 +2233,30 @@ mod tests {
 
     extern crate env_logger;
 
-    use std::collections::{HashMap, VecDeque};
+    use std::collections::{BTreeMap, HashMap, VecDeque};
     use std::io::Cursor;
     use std::net::SocketAddr;
     use std::rc::Rc;
+    use std::result;
     use std::str::FromStr;
+    use std::sync::{mpsc, Arc, Mutex};
 
-    use capnp::{MallocMessageBuilder, MessageBuilder, ReaderOptions};
+    use capnp::{MallocMessageBuilder, MessageBuilder, MessageReader, ReaderOptions};
     use capnp::serialize::{self, OwnedSpaceMessageReader};
 
     use ClientId;
+    use Error;
     use LogIndex;
+    use RaftError;
     use ServerId;
     use Term;
     use messages;
-    use consensus::{Actions, Consensus, ConsensusTimeout};
-    use state_machine::NullStateMachine;
-    use persistent_log::{MemLog, Log};
+    use clock::ManualClock;
+    use consensus::{Actions, Consensus, ConsensusTimeout, ElectionMetrics, ELECTION_MAX, ELECTION_MIN,
+                    HEARTBEAT_DURATION};
+    use messages_capnp::{client_response, command_response, message, request_vote_response};
+    use state_machine::{ApplyError, ChannelStateMachine, NullStateMachine, StateMachine};
+    use persistent_log::{MemLog, Log, TestLog};
 
     type TestPeer = Consensus<MemLog, NullStateMachine>;
 
@@ -852,7 +2282,8 @@ mod tests {
     }
 
     /// Applies the actions to the consensus peers (and recursively applies any resulting
-    /// actions), and returns any client messages.
+    /// actions), and returns any client messages. The invariants of each peer are checked after
+    /// it applies a message.
     fn apply_actions(from: ServerId,
                      mut actions: Actions,
                      peers: &mut HashMap<ServerId, TestPeer>)
@@ -866,7 +2297,8 @@ mod tests {
 
         while let Some((from, to, message)) = queue.pop_front() {
             let reader = into_reader(&*message);
-            peers.get_mut(&to).unwrap().apply_peer_message(from, &reader, &mut actions);
+            peers.get_mut(&to).unwrap().apply_peer_message(from, &reader, &mut actions).unwrap();
+            peers[&to].check_invariants();
             let inner_from = to;
             for (inner_to, message) in actions.peer_messages.iter().cloned() {
                 queue.push_back((inner_from, inner_to, message));
@@ -883,11 +2315,252 @@ mod tests {
     fn elect_leader(leader: ServerId, peers: &mut HashMap<ServerId, TestPeer>) {
         let mut actions = Actions::new();
         peers.get_mut(&leader).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        peers[&leader].check_invariants();
         let client_messages = apply_actions(leader, actions, peers);
         assert!(client_messages.is_empty());
         assert!(peers[&leader].is_leader());
     }
 
+    /// Delivers the pending messages between a leader and a follower, and the messages each sends
+    /// in response, until none remain. Each message is passed to `observe`, along with its
+    /// recipient, before it is delivered. Returns the number of messages delivered.
+    fn deliver_until_quiescent<L, F>(leader: &mut Consensus<L, NullStateMachine>,
+                                     follower: &mut TestPeer,
+                                     mut pending: Vec<(ServerId, Rc<MallocMessageBuilder>)>,
+                                     mut observe: F)
+                                     -> usize
+    where L: Log, F: FnMut(ServerId, &MallocMessageBuilder) {
+        let mut delivered = 0;
+        while !pending.is_empty() {
+            let mut next = Vec::new();
+            for (to, message) in pending {
+                observe(to, &message);
+                let mut actions = Actions::new();
+                if to == follower.id {
+                    follower.apply_peer_message(leader.id, &into_reader(&*message), &mut actions)
+                            .unwrap();
+                } else {
+                    leader.apply_peer_message(follower.id, &into_reader(&*message), &mut actions)
+                          .unwrap();
+                }
+                next.extend(actions.peer_messages);
+                delivered += 1;
+            }
+            pending = next;
+        }
+        delivered
+    }
+
+    /// Wins an election in term 1 for `leader` with the votes of `voters` only, delivered as
+    /// crafted responses, and returns the messages the new leader sends.
+    fn win_election(leader: &mut TestPeer, voters: &[ServerId]) -> Actions {
+        let mut actions = Actions::new();
+        leader.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        leader.check_invariants();
+        let mut actions = Actions::new();
+        for &voter in voters {
+            let vote = into_reader(&*messages::request_vote_response_granted(Term(1), false));
+            leader.apply_peer_message(voter, &vote, &mut actions).unwrap();
+            leader.check_invariants();
+        }
+        assert!(leader.is_leader());
+        actions
+    }
+
+    /// Tests that the commit index of a newly elected leader does not cover its no-op entry
+    /// until a majority acknowledges it.
+    #[test]
+    fn test_election_win_noop_uncommitted() {
+        setup_test!("test_election_win_noop_uncommitted");
+        let mut peers = new_cluster(3);
+        let mut ids: Vec<ServerId> = peers.keys().cloned().collect();
+        ids.sort();
+        let leader = peers.get_mut(&ids[0]).unwrap();
+        win_election(leader, &ids[1..2]);
+
+        assert_eq!(Term(1), leader.current_term());
+        assert_eq!(LogIndex(1), leader.latest_log_index());
+        assert_eq!(LogIndex(0), leader.commit_index);
+        assert_eq!(LogIndex(0), leader.leader_state.match_index(&ids[1]));
+        assert_eq!(LogIndex(0), leader.leader_state.match_index(&ids[2]));
+    }
+
+    /// Tests that a newly elected leader commits its no-op entry once a single follower of a
+    /// three server cluster acknowledges it, and that acknowledgements of older entries do not
+    /// advance the commit index.
+    #[test]
+    fn test_election_win_noop_committed_by_majority() {
+        setup_test!("test_election_win_noop_committed_by_majority");
+        let mut peers = new_cluster(3);
+        let mut ids: Vec<ServerId> = peers.keys().cloned().collect();
+        ids.sort();
+        let leader = peers.get_mut(&ids[0]).unwrap();
+        win_election(leader, &ids[1..2]);
+
+        let mut actions = Actions::new();
+        let ack = into_reader(&*messages::append_entries_response_success(Term(1), LogIndex(0), 0));
+        leader.apply_peer_message(ids[1], &ack, &mut actions).unwrap();
+        assert_eq!(LogIndex(0), leader.commit_index);
+
+        let ack = into_reader(&*messages::append_entries_response_success(Term(1), LogIndex(1), 0));
+        leader.apply_peer_message(ids[2], &ack, &mut actions).unwrap();
+        assert_eq!(LogIndex(1), leader.leader_state.match_index(&ids[2]));
+        assert_eq!(LogIndex(1), leader.commit_index);
+        assert_eq!(LogIndex(1), leader.last_applied());
+    }
+
+    /// Tests that a leader which is deposed before its no-op entry is acknowledged does not
+    /// commit it.
+    #[test]
+    fn test_election_win_deposed_before_commit() {
+        setup_test!("test_election_win_deposed_before_commit");
+        let mut peers = new_cluster(3);
+        let mut ids: Vec<ServerId> = peers.keys().cloned().collect();
+        ids.sort();
+        let leader = peers.get_mut(&ids[0]).unwrap();
+        win_election(leader, &ids[1..2]);
+
+        let mut actions = Actions::new();
+        let response = into_reader(&*messages::append_entries_response_stale_term(Term(2)));
+        leader.apply_peer_message(ids[2], &response, &mut actions).unwrap();
+        assert!(!leader.is_leader());
+        assert_eq!(Term(2), leader.current_term());
+        assert_eq!(LogIndex(0), leader.commit_index);
+    }
+
+    /// Tests that an adaptive election timeout follows the observed round trip times to peers,
+    /// within its clamps.
+    #[test]
+    fn test_adaptive_election_timeout() {
+        setup_test!("test_adaptive_election_timeout");
+        let (leader, follower) = (ServerId(0), ServerId(1));
+        let clock = ManualClock::new();
+        let mut peers = HashMap::new();
+        peers.insert(follower, SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mut peer = Consensus::with_clock(leader, peers, MemLog::new(), NullStateMachine,
+                                             Box::new(clock.clone()));
+        assert_eq!((ELECTION_MIN, ELECTION_MAX), peer.election_timeout_range());
+        peer.set_adaptive_election_timeout(Some(10), 1500, 5000);
+        // Without samples, the minimum applies.
+        assert_eq!((1500, 1500 + ELECTION_MAX - ELECTION_MIN), peer.election_timeout_range());
+
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(1), false));
+        peer.apply_peer_message(follower, &vote, &mut actions).unwrap();
+        assert!(peer.is_leader());
+
+        // Exchanges a heartbeat with the follower, taking `rtt` milliseconds.
+        let exchange = |peer: &mut TestPeer, rtt: u64| {
+            let mut actions = Actions::new();
+            peer.apply_timeout(ConsensusTimeout::Heartbeat(follower), &mut actions);
+            clock.advance(rtt);
+            let ack = into_reader(&*messages::append_entries_response_success(Term(1), LogIndex(1), 0));
+            peer.apply_peer_message(follower, &ack, &mut actions).unwrap();
+        };
+
+        // Fast round trips are clamped to the minimum.
+        exchange(&mut peer, 10);
+        assert_eq!(1500, peer.election_timeout_range().0);
+
+        for _ in 0..10 {
+            exchange(&mut peer, 300);
+        }
+        assert_eq!(3000, peer.election_timeout_range().0);
+
+        // Slow round trips are clamped to the maximum.
+        exchange(&mut peer, 1000);
+        assert_eq!(5000, peer.election_timeout_range().0);
+
+        // The leader reports its round trip time to followers.
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Heartbeat(follower), &mut actions);
+        let &(_, ref heartbeat) = actions.peer_messages.last().unwrap();
+        let heartbeat = into_reader(&**heartbeat);
+        match heartbeat.get_root::<message::Reader>().unwrap().which().unwrap() {
+            message::Which::AppendEntriesRequest(Ok(request)) => {
+                assert_eq!(1000, request.get_round_trip_ms());
+            },
+            _ => panic!("unexpected message"),
+        }
+
+        // The election timeout scheduled by a follower is drawn from the adapted range.
+        let mut actions = Actions::new();
+        peer.step_down(&mut actions);
+        assert!(!peer.is_leader());
+        let timeout = peer.timeout_ms(ConsensusTimeout::Election);
+        assert!(timeout >= 5000 && timeout < 5000 + ELECTION_MAX - ELECTION_MIN, "timeout {}", timeout);
+    }
+
+    /// Tests that a follower's adaptive election timeout follows the round trip time reported by
+    /// the leader, and not how promptly the leader's heartbeats arrive.
+    #[test]
+    fn test_adaptive_election_timeout_follower() {
+        setup_test!("test_adaptive_election_timeout_follower");
+        let (leader, follower) = (ServerId(0), ServerId(1));
+        let clock = ManualClock::new();
+        let mut peers = HashMap::new();
+        peers.insert(leader, SocketAddr::from_str("127.0.0.1:0").unwrap());
+        let mut peer = Consensus::with_clock(follower, peers, MemLog::new(), NullStateMachine,
+                                             Box::new(clock.clone()));
+        peer.set_adaptive_election_timeout(Some(10), 1500, 5000);
+
+        // Delivers a heartbeat from the leader `interval` milliseconds after the previous one,
+        // reporting a round trip time of `rtt` milliseconds.
+        let heartbeat = |peer: &mut TestPeer, interval: u64, rtt: u64| {
+            clock.advance(interval);
+            let request = messages::append_entries_request(Term(1), LogIndex(0), Term(0), &[],
+                                                           &BTreeMap::new(), LogIndex(0), 0, rtt);
+            peer.apply_peer_message(leader, &into_reader(&*request), &mut Actions::new()).unwrap();
+        };
+
+        // Without a reported round trip time, the minimum applies.
+        heartbeat(&mut peer, 0, 0);
+        assert_eq!(1500, peer.election_timeout_range().0);
+
+        // Fast round trips are clamped to the minimum.
+        heartbeat(&mut peer, HEARTBEAT_DURATION, 10);
+        assert_eq!(1500, peer.election_timeout_range().0);
+
+        // Slow round trips lengthen the election timeout, however promptly they are reported.
+        heartbeat(&mut peer, 10, 300);
+        assert_eq!(3000, peer.election_timeout_range().0);
+
+        // Late heartbeats do not lengthen it further.
+        heartbeat(&mut peer, HEARTBEAT_DURATION + 900, 300);
+        assert_eq!(3000, peer.election_timeout_range().0);
+
+        // Very slow round trips are clamped to the maximum.
+        heartbeat(&mut peer, HEARTBEAT_DURATION, 900);
+        assert_eq!(5000, peer.election_timeout_range().0);
+
+        // A leader which has not measured a round trip reverts to the minimum.
+        heartbeat(&mut peer, HEARTBEAT_DURATION, 0);
+        assert_eq!(1500, peer.election_timeout_range().0);
+    }
+
+    /// Tests that `Actions::is_empty` accounts for every kind of action.
+    #[test]
+    fn test_actions_is_empty() {
+        assert!(Actions::new().is_empty());
+
+        let mut actions = Actions::new();
+        actions.timeouts.push(ConsensusTimeout::Election);
+        assert!(!actions.is_empty());
+
+        let mut actions = Actions::new();
+        actions.clear_timeouts = true;
+        assert!(!actions.is_empty());
+
+        let mut actions = Actions::new();
+        actions.clear_peer_messages = true;
+        assert!(!actions.is_empty());
+
+        let mut actions = Actions::new();
+        actions.client_messages.push((ClientId::new(), messages::command_response_unknown_leader()));
+        assert!(!actions.is_empty());
+    }
+
     /// Tests the majority function.
     #[test]
     fn test_majority () {
@@ -920,6 +2593,43 @@ mod tests {
         assert!(actions.timeouts.is_empty());
     }
 
+    /// Tests that a follower driven by a `ManualClock` campaigns exactly once the clock advances
+    /// past the election timeout.
+    #[test]
+    fn test_manual_clock_election_timeout() {
+        setup_test!("test_manual_clock_election_timeout");
+        let clock = ManualClock::new();
+        let mut peers = HashMap::new();
+        peers.insert(ServerId(1), SocketAddr::from_str("127.0.0.1:1").unwrap());
+        peers.insert(ServerId(2), SocketAddr::from_str("127.0.0.1:2").unwrap());
+        let mut peer = Consensus::with_clock(ServerId(0),
+                                             peers,
+                                             MemLog::new(),
+                                             NullStateMachine,
+                                             Box::new(clock.clone()));
+        peer.init();
+
+        // The election timeout can not have elapsed yet.
+        clock.advance(ELECTION_MIN - 1);
+        let mut actions = Actions::new();
+        peer.tick(&mut actions);
+        assert!(peer.is_follower());
+        assert!(actions.peer_messages.is_empty());
+
+        // The election timeout has certainly elapsed.
+        clock.advance(ELECTION_MAX - ELECTION_MIN + 1);
+        peer.tick(&mut actions);
+        assert!(peer.is_candidate());
+        assert_eq!(2, actions.peer_messages.len());
+        assert_eq!(vec![ConsensusTimeout::Election], actions.timeouts);
+
+        // The new election timeout is scheduled relative to the current time.
+        let mut actions = Actions::new();
+        peer.tick(&mut actions);
+        assert!(peer.is_candidate());
+        assert!(actions.peer_messages.is_empty());
+    }
+
     /// A simple election test over multiple group sizes.
     #[test]
     fn test_election() {
@@ -937,18 +2647,71 @@ mod tests {
         }
     }
 
-    /// Tests the Raft heartbeating mechanism. The leader receives a heartbeat
-    /// timeout, and in response sends an AppendEntries message to the follower.
-    /// The follower in turn resets its election timout, and replies to the
-    /// leader.
+    /// Tests that `is_leader` reflects winning an election, and losing leadership to a peer which
+    /// wins a later election.
     #[test]
-    fn test_heartbeat() {
-        setup_test!("test_heartbeat");
-        let mut peers = new_cluster(2);
+    fn test_is_leader() {
+        setup_test!("test_is_leader");
+        let mut peers = new_cluster(3);
         let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
-        let leader_id = &peer_ids[0];
-        let follower_id = &peer_ids[1];
-        elect_leader(leader_id.clone(), &mut peers);
+        for peer in peers.values() {
+            assert!(!peer.is_leader());
+        }
+
+        elect_leader(peer_ids[0], &mut peers);
+        assert!(peers[&peer_ids[0]].is_leader());
+        assert!(!peers[&peer_ids[1]].is_leader());
+        assert!(!peers[&peer_ids[2]].is_leader());
+
+        elect_leader(peer_ids[1], &mut peers);
+        assert!(!peers[&peer_ids[0]].is_leader());
+        assert!(peers[&peer_ids[1]].is_leader());
+        assert!(!peers[&peer_ids[2]].is_leader());
+    }
+
+    /// Tests that a follower's commit index does not move backward when a leader reports a lower
+    /// commit index than the follower already knows of.
+    #[test]
+    fn test_commit_index_not_regressed() {
+        setup_test!("test_commit_index_not_regressed");
+        let (_, mut follower) = new_cluster(2).into_iter().next().unwrap();
+        let leader = *follower.peers().keys().next().unwrap();
+        let entries: &[(Term, &[u8])] = &[(Term(1), b"foo"), (Term(1), b"bar")];
+        let request = messages::append_entries_request(Term(1), LogIndex(0), Term(0), entries,
+                                                       &BTreeMap::new(), LogIndex(2), 0, 0);
+        follower.apply_peer_message(leader, &into_reader(&*request), &mut Actions::new()).unwrap();
+        assert_eq!(LogIndex(2), follower.commit_index);
+
+        let request = messages::append_entries_request(Term(1), LogIndex(2), Term(1), &[],
+                                                       &BTreeMap::new(), LogIndex(1), 0, 0);
+        follower.apply_peer_message(leader, &into_reader(&*request), &mut Actions::new()).unwrap();
+        assert_eq!(LogIndex(2), follower.commit_index);
+        assert_eq!(LogIndex(2), follower.last_applied);
+    }
+
+    /// Tests that moving the commit index backward is caught.
+    #[test]
+    #[should_panic(expected = "commit index moved backward")]
+    #[cfg(debug_assertions)]
+    fn test_commit_index_regression_guard() {
+        setup_test!("test_commit_index_regression_guard");
+        let (_, mut peer) = new_cluster(1).into_iter().next().unwrap();
+        peer.set_commit_index(LogIndex(2));
+        peer.set_commit_index(LogIndex(1));
+    }
+
+    /// Tests the Raft heartbeating mechanism. The leader receives a heartbeat
+    /// timeout, and in response sends an AppendEntries message to the follower.
+    /// The follower in turn resets its election timout, and replies to the
+    /// leader.
+    #[test]
+    fn test_heartbeat() {
+        setup_test!("test_heartbeat");
+        let mut peers = new_cluster(2);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader_id = &peer_ids[0];
+        let follower_id = &peer_ids[1];
+        elect_leader(leader_id.clone(), &mut peers);
 
         // Leader pings with a heartbeat timeout.
         let leader_append_entries = {
@@ -966,7 +2729,7 @@ mod tests {
         let follower_response = {
             let mut actions = Actions::new();
             let follower = peers.get_mut(&follower_id).unwrap();
-            follower.apply_peer_message(leader_id.clone(), &reader, &mut actions);
+            follower.apply_peer_message(leader_id.clone(), &reader, &mut actions).unwrap();
 
             let election_timeout = actions.timeouts.iter().next().unwrap();
             assert_eq!(election_timeout, &ConsensusTimeout::Election);
@@ -980,11 +2743,436 @@ mod tests {
         // Leader applies and sends back a heartbeat to establish leadership.
         let leader = peers.get_mut(&leader_id).unwrap();
         let mut actions = Actions::new();
-        leader.apply_peer_message(follower_id.clone(), &reader, &mut actions);
+        leader.apply_peer_message(follower_id.clone(), &reader, &mut actions).unwrap();
         let heartbeat_timeout = actions.timeouts.iter().next().unwrap();
         assert_eq!(heartbeat_timeout, &ConsensusTimeout::Heartbeat(follower_id.clone()));
     }
 
+    /// Tests that the leader and follower record the time of their latest AppendEntries exchange,
+    /// and that it grows stale while the follower is silent.
+    #[test]
+    fn test_last_contact() {
+        setup_test!("test_last_contact");
+        let (leader, follower) = (ServerId(0), ServerId(1));
+        let leader_clock = ManualClock::new();
+        let follower_clock = ManualClock::new();
+        let mut peers = HashMap::new();
+        let mut leader_peers = HashMap::new();
+        leader_peers.insert(follower, SocketAddr::from_str("127.0.0.1:1").unwrap());
+        peers.insert(leader,
+                     Consensus::with_clock(leader, leader_peers, MemLog::new(), NullStateMachine,
+                                           Box::new(leader_clock.clone())));
+        let mut follower_peers = HashMap::new();
+        follower_peers.insert(leader, SocketAddr::from_str("127.0.0.1:0").unwrap());
+        peers.insert(follower,
+                     Consensus::with_clock(follower, follower_peers, MemLog::new(), NullStateMachine,
+                                           Box::new(follower_clock.clone())));
+        assert_eq!(None, peers[&leader].last_contact()[&follower]);
+
+        leader_clock.advance(100);
+        follower_clock.advance(100);
+        elect_leader(leader, &mut peers);
+        assert_eq!(Some(0), peers[&leader].last_contact()[&follower]);
+        assert_eq!(Some(0), peers[&follower].last_contact()[&leader]);
+
+        leader_clock.advance(500);
+        follower_clock.advance(500);
+        assert_eq!(Some(500), peers[&leader].last_contact()[&follower]);
+        assert_eq!(Some(500), peers[&follower].last_contact()[&leader]);
+
+        // A successful exchange refreshes the contact time.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().heartbeat_timeout(follower, &mut actions);
+        apply_actions(leader, actions, &mut peers);
+        assert_eq!(Some(0), peers[&leader].last_contact()[&follower]);
+        assert_eq!(Some(0), peers[&follower].last_contact()[&leader]);
+
+        // The follower is silent; the leader's heartbeat goes unanswered.
+        let mut actions = Actions::new();
+        leader_clock.advance(2000);
+        peers.get_mut(&leader).unwrap().heartbeat_timeout(follower, &mut actions);
+        assert_eq!(Some(2000), peers[&leader].last_contact()[&follower]);
+    }
+
+    /// Tests that a follower with an empty log accepts its first entries, and that a heartbeat
+    /// with no previous entry is accepted by an empty log.
+    #[test]
+    fn test_append_entries_to_empty_log() {
+        setup_test!("test_append_entries_to_empty_log");
+        let mut peers = new_cluster(2);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (leader_id, follower_id) = (peer_ids[0], peer_ids[1]);
+        let follower = peers.get_mut(&follower_id).unwrap();
+
+        let entries: &[(Term, &[u8])] = &[];
+        let heartbeat = messages::append_entries_request(Term(1), LogIndex(0), Term(0), entries,
+                                                         &BTreeMap::new(), LogIndex(0), 0, 0);
+        follower.apply_peer_message(leader_id, &into_reader(&*heartbeat), &mut Actions::new()).unwrap();
+        assert_eq!(LogIndex(0), follower.latest_log_index());
+
+        let entries: &[(Term, &[u8])] = &[(Term(1), b"foo")];
+        let request = messages::append_entries_request(Term(1), LogIndex(0), Term(0), entries,
+                                                       &BTreeMap::new(), LogIndex(1), 0, 0);
+        follower.apply_peer_message(leader_id, &into_reader(&*request), &mut Actions::new()).unwrap();
+        assert_eq!(LogIndex(1), follower.latest_log_index());
+        assert_eq!(LogIndex(1), follower.commit_index);
+    }
+
+    /// Tests that the leader tolerates a follower asking for entries from index 0, or from beyond
+    /// the end of the leader's log, and resends its log from a valid index.
+    #[test]
+    fn test_inconsistent_prev_entry_bounds() {
+        setup_test!("test_inconsistent_prev_entry_bounds");
+        let mut peers = new_cluster(2);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (leader_id, follower_id) = (peer_ids[0], peer_ids[1]);
+        elect_leader(leader_id, &mut peers);
+        let leader = peers.get_mut(&leader_id).unwrap();
+
+        // An empty follower log: the leader resends its whole log.
+        let response = messages::append_entries_response_inconsistent_prev_entry(Term(1), LogIndex(0), 0);
+        let mut actions = Actions::new();
+        leader.apply_peer_message(follower_id, &into_reader(&*response), &mut actions).unwrap();
+        let &(to, ref request) = actions.peer_messages.last().unwrap();
+        assert_eq!(follower_id, to);
+        let request = into_reader(&**request);
+        let request = request.get_root::<message::Reader>().unwrap();
+        match request.which().unwrap() {
+            message::Which::AppendEntriesRequest(Ok(request)) => {
+                assert_eq!(0, request.get_prev_log_index());
+                assert_eq!(1, request.get_entries().unwrap().len());
+            },
+            _ => panic!("unexpected message"),
+        }
+
+        // An index beyond the leader's log: the follower is treated as caught up.
+        let response = messages::append_entries_response_inconsistent_prev_entry(Term(1), LogIndex(100), 0);
+        let mut actions = Actions::new();
+        leader.apply_peer_message(follower_id, &into_reader(&*response), &mut actions).unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(vec![ConsensusTimeout::Heartbeat(follower_id)], actions.timeouts);
+    }
+
+    /// Tests that a leader which steps down stops sending heartbeats, and that another server is
+    /// then elected.
+    #[test]
+    fn test_step_down() {
+        setup_test!("test_step_down");
+        let mut peers = new_cluster(3);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (old_leader, new_leader) = (peer_ids[0], peer_ids[1]);
+        elect_leader(old_leader, &mut peers);
+
+        let mut actions = Actions::new();
+        peers.get_mut(&old_leader).unwrap().step_down(&mut actions);
+        assert!(peers[&old_leader].is_follower());
+        assert!(actions.clear_timeouts);
+        assert_eq!(vec![ConsensusTimeout::Election], actions.timeouts);
+        assert!(actions.peer_messages.is_empty());
+
+        elect_leader(new_leader, &mut peers);
+        assert!(!peers[&old_leader].is_leader());
+        assert_eq!(Term(2), peers[&new_leader].current_term());
+    }
+
+    /// Tests that a vote requested by a candidate whose log is behind the voter's is refused
+    /// with the inconsistent log reason.
+    #[test]
+    fn test_vote_refused_for_stale_log() {
+        setup_test!("test_vote_refused_for_stale_log");
+        let mut peers = new_cluster(3);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (leader_id, voter_id, candidate_id) = (peer_ids[0], peer_ids[1], peer_ids[2]);
+        let voter = peers.get_mut(&voter_id).unwrap();
+
+        let entries: &[(Term, &[u8])] = &[(Term(1), b"foo")];
+        let request = messages::append_entries_request(Term(1), LogIndex(0), Term(0), entries,
+                                                       &BTreeMap::new(), LogIndex(0), 0, 0);
+        voter.apply_peer_message(leader_id, &into_reader(&*request), &mut Actions::new()).unwrap();
+
+        let request = messages::request_vote_request(Term(2), LogIndex(0), Term(0), false);
+        let request = into_reader(&*request);
+        let mut actions = Actions::new();
+        voter.apply_peer_message(candidate_id, &request, &mut actions).unwrap();
+        assert_eq!(None, voter.log.voted_for().unwrap());
+
+        let &(to, ref response) = actions.peer_messages.last().unwrap();
+        assert_eq!(candidate_id, to);
+        let response = into_reader(&**response);
+        let response = response.get_root::<message::Reader>().unwrap();
+        match response.which().unwrap() {
+            message::Which::RequestVoteResponse(Ok(response)) => {
+                match response.which().unwrap() {
+                    request_vote_response::InconsistentLog(_) => (),
+                    _ => panic!("vote not refused for inconsistent log"),
+                }
+            },
+            _ => panic!("unexpected response message"),
+        }
+    }
+
+    /// Tests that a vote cast in one term does not prevent voting for a different candidate in a
+    /// later term.
+    #[test]
+    fn test_vote_in_new_term() {
+        setup_test!("test_vote_in_new_term");
+        let mut peers = new_cluster(3);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (voter_id, first_id, second_id) = (peer_ids[0], peer_ids[1], peer_ids[2]);
+
+        for &(candidate, term) in &[(first_id, Term(1)), (second_id, Term(2))] {
+            let request = messages::request_vote_request(term, LogIndex(0), Term(0), false);
+            let request = into_reader(&*request);
+            let mut actions = Actions::new();
+            let voter = peers.get_mut(&voter_id).unwrap();
+            voter.apply_peer_message(candidate, &request, &mut actions).unwrap();
+
+            assert_eq!(term, voter.current_term());
+            assert_eq!(Some(candidate), voter.log.voted_for().unwrap());
+            let &(to, ref response) = actions.peer_messages.last().unwrap();
+            assert_eq!(candidate, to);
+            let response = into_reader(&**response);
+            let response = response.get_root::<message::Reader>().unwrap();
+            match response.which().unwrap() {
+                message::Which::RequestVoteResponse(Ok(response)) => {
+                    match response.which().unwrap() {
+                        request_vote_response::Granted(_) => (),
+                        _ => panic!("vote for {} in term {} not granted", candidate, term),
+                    }
+                },
+                _ => panic!("unexpected response message"),
+            }
+        }
+    }
+
+    /// Tests that the election counters reflect the elections started, won, and lost, and the
+    /// votes granted and received.
+    #[test]
+    fn test_election_metrics() {
+        setup_test!("test_election_metrics");
+        let mut peers = new_cluster(3);
+        let (first, second) = (ServerId(0), ServerId(2));
+        elect_leader(first, &mut peers);
+
+        // The second candidate's first election times out with its requests lost, and its second
+        // one succeeds.
+        let mut actions = Actions::new();
+        peers.get_mut(&second).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let mut actions = Actions::new();
+        peers.get_mut(&second).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        apply_actions(second, actions, &mut peers);
+        assert!(peers[&second].is_leader());
+
+        // The first vote received completes a majority; the other arrives after the election.
+        assert_eq!(ElectionMetrics {
+                       elections_started: 2,
+                       elections_won: 1,
+                       elections_lost: 1,
+                       votes_granted: 3,
+                       votes_received: 1,
+                   },
+                   peers[&second].election_metrics());
+        // Being deposed as leader does not count as a lost election.
+        assert_eq!(ElectionMetrics {
+                       elections_started: 1,
+                       elections_won: 1,
+                       elections_lost: 0,
+                       votes_granted: 2,
+                       votes_received: 1,
+                   },
+                   peers[&first].election_metrics());
+    }
+
+    /// Tests that a proposal is broadcast to every peer as a single shared message, so that the
+    /// memory held by the actions does not grow with the number of peers.
+    #[test]
+    fn test_broadcast_shares_message() {
+        setup_test!("test_broadcast_shares_message");
+        let mut peers = new_cluster(5);
+        let leader_id = *peers.keys().next().unwrap();
+        elect_leader(leader_id, &mut peers);
+
+        let mut actions = Actions::new();
+        let leader = peers.get_mut(&leader_id).unwrap();
+        leader.propose(ClientId::new(), &[0; 1024], &mut actions);
+        assert_eq!(4, actions.peer_messages.len());
+        let first: *const MallocMessageBuilder = &*actions.peer_messages[0].1;
+        for &(_, ref message) in &actions.peer_messages {
+            assert_eq!(first, &**message as *const MallocMessageBuilder);
+        }
+    }
+
+    /// Tests that a read replica replicates and applies committed entries, but never schedules an
+    /// election timeout or grants a vote.
+    #[test]
+    fn test_read_replica() {
+        setup_test!("test_read_replica");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let (leader_id, candidate_id, replica_id) = (peer_ids[0], peer_ids[1], peer_ids[2]);
+        {
+            let replica = peers.get_mut(&replica_id).unwrap();
+            replica.set_read_replica(true);
+            let actions = replica.init();
+            assert!(actions.timeouts.is_empty());
+        }
+
+        elect_leader(leader_id, &mut peers);
+        let mut actions = Actions::new();
+        peers.get_mut(&leader_id).unwrap().propose(ClientId::new(), b"foo", &mut actions);
+        apply_actions(leader_id, actions, &mut peers);
+        {
+            let replica = &peers[&replica_id];
+            assert_eq!((Term(1), &b"foo"[..]), replica.log.entry(LogIndex(2)).unwrap());
+            assert!(!replica.deadlines.contains_key(&ConsensusTimeout::Election));
+        }
+
+        // The replica refuses to vote for a candidate, though it adopts the candidate's term.
+        let mut actions = Actions::new();
+        peers.get_mut(&candidate_id).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let request = actions.peer_messages
+                             .iter()
+                             .find(|&&(peer, _)| peer == replica_id)
+                             .map(|&(_, ref message)| into_reader(&**message))
+                             .unwrap();
+        let replica = peers.get_mut(&replica_id).unwrap();
+        let mut actions = Actions::new();
+        replica.apply_peer_message(candidate_id, &request, &mut actions).unwrap();
+        assert_eq!(Term(2), replica.current_term());
+        assert_eq!(None, replica.log.voted_for().unwrap());
+        assert_eq!(0, replica.election_metrics().votes_granted);
+        assert!(actions.timeouts.is_empty());
+    }
+
+    /// Tests that a read replica answers a query from its own state machine at once, without
+    /// contacting the leader, while a follower redirects the query to the leader.
+    #[test]
+    fn test_read_replica_query() {
+        setup_test!("test_read_replica_query");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let (leader_id, follower_id, replica_id) = (peer_ids[0], peer_ids[1], peer_ids[2]);
+        peers.get_mut(&replica_id).unwrap().set_read_replica(true);
+        elect_leader(leader_id, &mut peers);
+
+        let client = ClientId::new();
+        let query = into_reader(&messages::query_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&replica_id).unwrap().apply_client_message(client, &query, &mut actions).unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(client, actions.client_messages[0].0);
+        let response = into_reader(&*actions.client_messages[0].1);
+        assert!(messages::decode_command_response(&response).is_ok());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&follower_id).unwrap().apply_client_message(client, &query, &mut actions).unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        let response = into_reader(&*actions.client_messages[0].1);
+        assert!(messages::decode_command_response(&response).is_err());
+    }
+
+    /// Tests that a follower reports the leader it has received AppendEntries from, and no leader
+    /// before then or during an election.
+    #[test]
+    fn test_current_leader() {
+        setup_test!("test_current_leader");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader_id = peer_ids[0];
+        let follower_id = peer_ids[1];
+        assert_eq!(None, peers[&follower_id].current_leader());
+
+        elect_leader(leader_id, &mut peers);
+        assert_eq!(Some(leader_id), peers[&leader_id].current_leader());
+        assert_eq!(Some(leader_id), peers[&follower_id].current_leader());
+
+        let follower = peers.get_mut(&follower_id).unwrap();
+        follower.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        assert!(follower.is_candidate());
+        assert_eq!(None, follower.current_leader());
+    }
+
+    /// Tests that a vote policy can force a specific node to win the election.
+    #[test]
+    fn test_vote_policy() {
+        setup_test!("test_vote_policy");
+        let mut peers = new_cluster(3);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (loser_id, winner_id) = (peer_ids[1], peer_ids[2]);
+        for peer in peers.values_mut() {
+            peer.set_vote_policy(move |candidate, _| Some(candidate == winner_id));
+        }
+
+        // The loser's election fails, despite its log being as up-to-date as any.
+        let mut actions = Actions::new();
+        peers.get_mut(&loser_id).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        apply_actions(loser_id, actions, &mut peers);
+        assert!(peers[&loser_id].is_candidate());
+
+        elect_leader(winner_id, &mut peers);
+        assert!(!peers[&loser_id].is_leader());
+    }
+
+    /// Tests that a follower which misses its leader's heartbeats does not depose the leader while
+    /// the rest of the cluster still hears from it.
+    #[test]
+    fn test_leader_stickiness() {
+        setup_test!("test_leader_stickiness");
+        let clock = ManualClock::new();
+        let ids: HashMap<ServerId, SocketAddr> =
+            (0..3).map(Into::into)
+                  .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id)).unwrap()))
+                  .collect();
+        let mut peers: HashMap<ServerId, TestPeer> = ids.keys().map(|&id| {
+            let mut others = ids.clone();
+            others.remove(&id);
+            let mut peer = Consensus::with_clock(id, others, MemLog::new(), NullStateMachine,
+                                                 Box::new(clock.clone()));
+            peer.set_leader_stickiness(Some(ELECTION_MIN));
+            (id, peer)
+        }).collect();
+        let (leader, candidate, follower) = (ServerId(0), ServerId(1), ServerId(2));
+        elect_leader(leader, &mut peers);
+
+        // The candidate missed the heartbeats, and requests pre-votes shortly after the others
+        // heard from the leader. They are refused, and the candidate keeps its term.
+        clock.advance(ELECTION_MIN / 2);
+        let mut actions = Actions::new();
+        peers.get_mut(&candidate).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        apply_actions(candidate, actions, &mut peers);
+        assert!(peers[&leader].is_leader());
+        assert_eq!(Term(1), peers[&leader].current_term());
+        assert_eq!(Term(1), peers[&follower].current_term());
+        assert_eq!(Some(leader), peers[&follower].log.voted_for().unwrap());
+        assert!(peers[&candidate].is_follower());
+        assert_eq!(Term(1), peers[&candidate].current_term());
+
+        // The leader's next heartbeat is accepted, rather than answered with a newer term.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+             .unwrap()
+             .apply_timeout(ConsensusTimeout::Heartbeat(candidate), &mut actions);
+        apply_actions(leader, actions, &mut peers);
+        assert!(peers[&leader].is_leader());
+        assert_eq!(Term(1), peers[&leader].current_term());
+        assert_eq!(Some(leader), peers[&candidate].follower_state.leader);
+
+        // Once the leader has not been heard from for the interval, the election proceeds.
+        clock.advance(ELECTION_MIN);
+        let mut actions = Actions::new();
+        peers.get_mut(&candidate).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        apply_actions(candidate, actions, &mut peers);
+        assert!(peers[&candidate].is_leader());
+        assert_eq!(Term(2), peers[&follower].current_term());
+    }
+
     /// Emulates a slow heartbeat message in a two-node cluster.
     ///
     /// The initial leader (Consensus 0) sends a heartbeat, but before it is received by the follower
@@ -1045,13 +3233,1252 @@ mod tests {
 
             peers.get_mut(&leader)
                  .unwrap()
-                 .apply_client_message(client, &proposal, &mut actions);
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
 
             let client_messages = apply_actions(leader, actions, &mut peers);
             assert_eq!(1, client_messages.len());
+            // The first entry is the leader's no-op.
             for peer in peers.values() {
-                assert_eq!((Term(1), value), peer.log.entry(LogIndex(1)).unwrap());
+                assert_eq!((Term(1), value), peer.log.entry(LogIndex(2)).unwrap());
+            }
+        }
+    }
+
+    /// Tests that when a deposed leader's uncommitted entry is overwritten by the new leader, the
+    /// client which proposed it is told that it was not committed.
+    #[test]
+    fn test_overwritten_proposal_not_committed() {
+        setup_test!("test_overwritten_proposal_not_committed");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let (old_leader, new_leader) = (peer_ids[0], peer_ids[1]);
+        elect_leader(old_leader, &mut peers);
+
+        // The proposal is appended by the old leader, but never replicated.
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&old_leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert_eq!(LogIndex(2), peers[&old_leader].latest_log_index());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&new_leader).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let client_messages = apply_actions(new_leader, actions, &mut peers);
+        assert!(peers[&new_leader].is_leader());
+        assert_eq!(Term(2), peers[&old_leader].log.entry(LogIndex(2)).unwrap().0);
+
+        assert_eq!(1, client_messages.len());
+        assert_eq!(client, client_messages[0].0);
+        let response = into_reader(&*client_messages[0].1);
+        match messages::decode_command_response(&response) {
+            Err(Error::Raft(RaftError::NotCommitted)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(peers[&old_leader].client_origins.is_empty());
+    }
+
+    /// Tests that when a new leader's empty heartbeat truncates a deposed leader's uncommitted
+    /// entry, which conflicts with none of the new leader's entries, the client which proposed it
+    /// is told that it was not committed.
+    #[test]
+    fn test_truncated_proposal_not_committed() {
+        setup_test!("test_truncated_proposal_not_committed");
+        let mut peers = new_cluster(3);
+        let mut ids: Vec<ServerId> = peers.keys().cloned().collect();
+        ids.sort();
+        let old_leader = peers.get_mut(&ids[0]).unwrap();
+        win_election(old_leader, &ids[1..2]);
+
+        // The proposal is appended after the no-op, but never replicated.
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        old_leader.apply_client_message(client, &proposal, &mut Actions::new()).unwrap();
+        assert_eq!(LogIndex(2), old_leader.latest_log_index());
+
+        // The new leader of term 2 holds only the no-op.
+        let heartbeat = messages::append_entries_request(Term(2), LogIndex(1), Term(1), &[],
+                                                         &BTreeMap::new(), LogIndex(0), 0, 0);
+        let mut actions = Actions::new();
+        old_leader.apply_peer_message(ids[1], &into_reader(&*heartbeat), &mut actions).unwrap();
+        assert!(old_leader.is_follower());
+        assert_eq!(LogIndex(1), old_leader.latest_log_index());
+
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(client, actions.client_messages[0].0);
+        let response = into_reader(&*actions.client_messages[0].1);
+        match messages::decode_command_response(&response) {
+            Err(Error::Raft(RaftError::NotCommitted)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(old_leader.client_origins.is_empty());
+    }
+
+    /// Tests that a follower which forwards proposals forwards a client's proposal to the leader,
+    /// and relays the leader's response to the client.
+    #[test]
+    fn test_forwarded_proposal() {
+        setup_test!("test_forwarded_proposal");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        elect_leader(leader, &mut peers);
+        peers.get_mut(&follower).unwrap().set_forward_proposals(true);
+
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(vec![leader], actions.peer_messages.iter().map(|&(to, _)| to).collect::<Vec<_>>());
+
+        // The leader's response reaches the client by way of the follower.
+        let client_messages = apply_actions(follower, actions, &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert_eq!(client, client_messages[0].0);
+        let response = into_reader(&*client_messages[0].1);
+        assert_eq!(b"".to_vec(), messages::decode_command_response(&response).unwrap());
+        for peer in peers.values() {
+            assert_eq!((Term(1), &b"foo"[..]), peer.log.entry(LogIndex(2)).unwrap());
+        }
+        assert!(peers[&follower].forwarded_clients.is_empty());
+        assert!(peers[&leader].forwarded_origins.is_empty());
+    }
+
+    /// Tests that a follower relays the response to each of a client's forwarded proposals when
+    /// the client has several outstanding.
+    #[test]
+    fn test_forwarded_proposals_same_client() {
+        setup_test!("test_forwarded_proposals_same_client");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        elect_leader(leader, &mut peers);
+        peers.get_mut(&follower).unwrap().set_forward_proposals(true);
+
+        let client = ClientId::new();
+        let mut actions = Actions::new();
+        for entry in &[&b"foo"[..], &b"bar"[..]] {
+            let proposal = into_reader(&messages::proposal_request(entry));
+            peers.get_mut(&follower)
+                 .unwrap()
+                 .apply_client_message(client, &proposal, &mut actions)
+                 .unwrap();
+        }
+        assert_eq!(2, peers[&follower].forwarded_clients.len());
+
+        let client_messages = apply_actions(follower, actions, &mut peers);
+        assert_eq!(2, client_messages.len());
+        for &(to, ref message) in &client_messages {
+            assert_eq!(client, to);
+            let response = into_reader(&**message);
+            assert_eq!(b"".to_vec(), messages::decode_command_response(&response).unwrap());
+        }
+        assert!(peers[&follower].forwarded_clients.is_empty());
+        assert!(peers[&leader].forwarded_origins.is_empty());
+    }
+
+    /// Tests that a follower answers the clients of its outstanding forwarded proposals when the
+    /// leader it follows changes, since the new leader will not relay a response.
+    #[test]
+    fn test_forwarded_proposal_leader_change() {
+        setup_test!("test_forwarded_proposal_leader_change");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        let other = peer_ids[2];
+        elect_leader(leader, &mut peers);
+        peers.get_mut(&follower).unwrap().set_forward_proposals(true);
+
+        // The forwarded proposal never reaches the leader.
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+        assert_eq!(1, peers[&follower].forwarded_clients.len());
+
+        let mut actions = Actions::new();
+        peers.get_mut(&other).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let client_messages = apply_actions(other, actions, &mut peers);
+        assert!(peers[&other].is_leader());
+        assert_eq!(1, client_messages.len());
+        assert_eq!(client, client_messages[0].0);
+        let response = into_reader(&*client_messages[0].1);
+        match messages::decode_command_response(&response) {
+            Err(Error::Raft(RaftError::NotLeader)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(peers[&follower].forwarded_clients.is_empty());
+    }
+
+    /// Tests that a follower reports the id and address of every member of the cluster.
+    #[test]
+    fn test_get_configuration() {
+        setup_test!("test_get_configuration");
+        let mut peers = new_cluster(3);
+        let addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let follower = peers.get_mut(&ServerId(0)).unwrap();
+        follower.set_addr(addr);
+
+        let client = ClientId::new();
+        let request = into_reader(&messages::get_configuration_request());
+        let mut actions = Actions::new();
+        follower.apply_client_message(client, &request, &mut actions).unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        let (to, ref response) = actions.client_messages[0];
+        assert_eq!(client, to);
+        let servers = messages::decode_configuration_response(&into_reader(&**response)).unwrap();
+        let expected = vec![(ServerId(0), addr),
+                            (ServerId(1), SocketAddr::from_str("127.0.0.1:1").unwrap()),
+                            (ServerId(2), SocketAddr::from_str("127.0.0.1:2").unwrap())];
+        assert_eq!(expected, servers);
+    }
+
+    /// Tests that a leader answers a query only after a majority of the cluster confirms that it
+    /// is still the leader.
+    #[test]
+    fn test_query_confirms_leadership() {
+        setup_test!("test_query_confirms_leadership");
+        let mut peers = new_cluster(3);
+        let leader = *peers.keys().next().unwrap();
+        elect_leader(leader, &mut peers);
+
+        let client = ClientId::new();
+        let query = into_reader(&messages::query_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().apply_client_message(client, &query, &mut actions).unwrap();
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(2, actions.peer_messages.len());
+
+        let client_messages = apply_actions(leader, actions, &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert_eq!(client, client_messages[0].0);
+        let response = into_reader(&*client_messages[0].1);
+        assert!(messages::decode_command_response(&response).is_ok());
+    }
+
+    /// Tests that a query pending on a leader which is deposed is redirected to the new leader,
+    /// rather than answered from the deposed leader's state.
+    #[test]
+    fn test_query_redirected_after_deposed() {
+        setup_test!("test_query_redirected_after_deposed");
+        let mut peers = new_cluster(3);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (old_leader, new_leader) = (peer_ids[0], peer_ids[1]);
+        elect_leader(old_leader, &mut peers);
+
+        // The heartbeats confirming leadership are lost.
+        let client = ClientId::new();
+        let query = into_reader(&messages::query_request(b"foo"));
+        let leader = peers.get_mut(&old_leader).unwrap();
+        leader.apply_client_message(client, &query, &mut Actions::new()).unwrap();
+
+        // The leader hears from a leader of a newer term.
+        let request = messages::append_entries_request(Term(2), LogIndex(1), Term(1), &[],
+                                                       &BTreeMap::new(), LogIndex(1), 0, 0);
+        let mut actions = Actions::new();
+        leader.apply_peer_message(new_leader, &into_reader(&*request), &mut actions).unwrap();
+        assert!(!leader.is_leader());
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(client, actions.client_messages[0].0);
+
+        let response = into_reader(&*actions.client_messages[0].1);
+        let response = response.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::Proposal(Ok(status)) => match status.which().unwrap() {
+                command_response::Which::NotLeader(addr) => {
+                    assert_eq!(format!("{}", leader.peers()[&new_leader]), addr.unwrap());
+                },
+                _ => panic!("expected NotLeader response"),
+            },
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    /// Tests that when leadership changes after an entry is replicated but before it is
+    /// committed, the new leader responds to the client which proposed it.
+    #[test]
+    fn test_proposal_response_after_leader_change() {
+        setup_test!("test_proposal_response_after_leader_change");
+        let mut peers = new_cluster(3);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        let (old_leader, new_leader) = (peer_ids[0], peer_ids[1]);
+        elect_leader(old_leader, &mut peers);
+
+        let client = ClientId::new();
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        let mut actions = Actions::new();
+        peers.get_mut(&old_leader)
+             .unwrap()
+             .apply_client_message(client, &proposal, &mut actions)
+             .unwrap();
+
+        // Only the new leader receives the entry, and its acknowledgement is lost.
+        let &(_, ref append_entries) = actions.peer_messages
+                                              .iter()
+                                              .find(|&&(to, _)| to == new_leader)
+                                              .unwrap();
+        let append_entries = into_reader(&**append_entries);
+        peers.get_mut(&new_leader)
+             .unwrap()
+             .apply_peer_message(old_leader, &append_entries, &mut Actions::new())
+             .unwrap();
+
+        let mut actions = Actions::new();
+        peers.get_mut(&new_leader).unwrap().apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let client_messages = apply_actions(new_leader, actions, &mut peers);
+        assert!(peers[&new_leader].is_leader());
+        assert_eq!(1, client_messages.len());
+        assert_eq!(client, client_messages[0].0);
+    }
+
+    /// Returns the total size of the entries in an AppendEntries request, or 0 for any other
+    /// message.
+    fn append_entries_bytes(message: &MallocMessageBuilder) -> u64 {
+        let reader = into_reader(message);
+        match reader.get_root::<message::Reader>().unwrap().which().unwrap() {
+            message::Which::AppendEntriesRequest(Ok(request)) => {
+                request.get_entries()
+                       .unwrap()
+                       .iter()
+                       .fold(0, |total, entry| total + entry.get_data().unwrap().len() as u64)
+            },
+            _ => 0,
+        }
+    }
+
+    /// Returns the previous log index of an AppendEntries request.
+    fn append_entries_prev_index(message: &MallocMessageBuilder) -> LogIndex {
+        let reader = into_reader(message);
+        match reader.get_root::<message::Reader>().unwrap().which().unwrap() {
+            message::Which::AppendEntriesRequest(Ok(request)) => {
+                LogIndex(request.get_prev_log_index())
+            },
+            _ => panic!("expected an AppendEntries request"),
+        }
+    }
+
+    /// The fields of a peer message emitted by a consensus module, decoded for assertions.
+    #[derive(Debug, PartialEq, Eq)]
+    enum WireMessage {
+        AppendEntries {
+            term: Term,
+            prev_log_index: LogIndex,
+            prev_log_term: Term,
+            entries: Vec<(Term, Vec<u8>)>,
+            leader_commit: LogIndex,
+        },
+        RequestVote {
+            term: Term,
+            last_log_index: LogIndex,
+            last_log_term: Term,
+        },
+        /// Any other message, whose fields are not decoded.
+        Other,
+    }
+
+    /// Decodes a peer message queued in `Actions`.
+    fn decode_peer_message(message: &MallocMessageBuilder) -> WireMessage {
+        let reader = into_reader(message);
+        match reader.get_root::<message::Reader>().unwrap().which().unwrap() {
+            message::Which::AppendEntriesRequest(Ok(request)) => {
+                WireMessage::AppendEntries {
+                    term: Term(request.get_term()),
+                    prev_log_index: LogIndex(request.get_prev_log_index()),
+                    prev_log_term: Term(request.get_prev_log_term()),
+                    entries: request.get_entries()
+                                    .unwrap()
+                                    .iter()
+                                    .map(|entry| {
+                                        (Term(entry.get_term()), entry.get_data().unwrap().to_vec())
+                                    })
+                                    .collect(),
+                    leader_commit: LogIndex(request.get_leader_commit()),
+                }
+            },
+            message::Which::RequestVoteRequest(Ok(request)) => {
+                WireMessage::RequestVote {
+                    term: Term(request.get_term()),
+                    last_log_index: LogIndex(request.get_last_log_index()),
+                    last_log_term: Term(request.get_last_log_term()),
+                }
+            },
+            _ => WireMessage::Other,
+        }
+    }
+
+    /// Tests the fields of the messages a consensus module with existing entries sends while it
+    /// is elected: the vote requests describe its log, and the first AppendEntries request of
+    /// the new term appends a no-op after the existing entries.
+    #[test]
+    fn test_election_wire_messages() {
+        setup_test!("test_election_wire_messages");
+        let mut log = MemLog::new();
+        log.set_current_term(Term(1)).unwrap();
+        log.append_entries(LogIndex(1), &[(Term(1), &b"foo"[..]), (Term(1), &b"bar"[..])]).unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(ServerId(1), SocketAddr::from_str("127.0.0.1:1").unwrap());
+        peers.insert(ServerId(2), SocketAddr::from_str("127.0.0.1:2").unwrap());
+        let mut peer = Consensus::new(ServerId(0), peers, log, NullStateMachine);
+
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert_eq!(2, actions.peer_messages.len());
+        for &(_, ref message) in &actions.peer_messages {
+            assert_eq!(WireMessage::RequestVote {
+                           term: Term(2),
+                           last_log_index: LogIndex(2),
+                           last_log_term: Term(1),
+                       },
+                       decode_peer_message(message));
+        }
+
+        let mut actions = Actions::new();
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(2), false));
+        peer.apply_peer_message(ServerId(1), &vote, &mut actions).unwrap();
+        assert!(peer.is_leader());
+        let appends: Vec<WireMessage> = actions.peer_messages
+                                               .iter()
+                                               .map(|&(_, ref message)| decode_peer_message(message))
+                                               .filter(|message| *message != WireMessage::Other)
+                                               .collect();
+        assert_eq!(2, appends.len());
+        for append in appends {
+            assert_eq!(WireMessage::AppendEntries {
+                           term: Term(2),
+                           prev_log_index: LogIndex(2),
+                           prev_log_term: Term(1),
+                           entries: vec![(Term(2), vec![])],
+                           leader_commit: LogIndex(0),
+                       },
+                       append);
+        }
+    }
+
+    /// Tests that entries pipelined to a follower and lost with its connection are resent from
+    /// the first lost entry once the connection is reestablished, and that the logs converge.
+    #[test]
+    fn test_connection_reset_resends_lost_entries() {
+        setup_test!("test_connection_reset_resends_lost_entries");
+        let mut peers = new_cluster(2);
+        let (leader, follower) = (ServerId(0), ServerId(1));
+        elect_leader(leader, &mut peers);
+        assert_eq!(LogIndex(1), peers[&follower].latest_log_index());
+
+        // Three proposals are sent to the follower, and lost.
+        for _ in 0..3 {
+            let mut actions = Actions::new();
+            peers.get_mut(&leader).unwrap().propose(ClientId::new(), b"foo", &mut actions);
+            assert_eq!(1, actions.peer_messages.len());
+        }
+        assert_eq!(LogIndex(1), peers[&follower].latest_log_index());
+
+        let mut actions = Actions::new();
+        let follower_addr = peers[&leader].peers[&follower];
+        peers.get_mut(&leader).unwrap().peer_connection_reset(follower, follower_addr, &mut actions);
+        assert_eq!(1, actions.peer_messages.len());
+        assert_eq!(LogIndex(1), append_entries_prev_index(&actions.peer_messages[0].1));
+        assert_eq!(9, append_entries_bytes(&actions.peer_messages[0].1));
+
+        let client_messages = apply_actions(leader, actions, &mut peers);
+        assert_eq!(3, client_messages.len());
+        assert_eq!(LogIndex(4), peers[&follower].latest_log_index());
+        assert_eq!(LogIndex(4), peers[&leader].commit_index);
+    }
+
+    /// Tests that a leader stops sending entries to a follower which is not acknowledging them
+    /// once its replication window is full, and resumes as acknowledgements arrive.
+    #[test]
+    fn test_replication_window() {
+        setup_test!("test_replication_window");
+        let mut peers = new_cluster(2);
+        let mut peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        peer_ids.sort();
+        elect_leader(peer_ids[0], &mut peers);
+        let mut leader = peers.remove(&peer_ids[0]).unwrap();
+        let mut follower = peers.remove(&peer_ids[1]).unwrap();
+        leader.set_max_in_flight_bytes(8);
+
+        // The follower is stalled; none of the proposals are delivered.
+        let client = ClientId::new();
+        let mut actions = Actions::new();
+        for _ in 0..10 {
+            let proposal = into_reader(&messages::proposal_request(b"abcd"));
+            leader.apply_client_message(client, &proposal, &mut actions).unwrap();
+        }
+        let sent = actions.peer_messages
+                          .iter()
+                          .fold(0, |total, &(_, ref message)| total + append_entries_bytes(message));
+        assert_eq!(8, sent);
+        assert_eq!(8, leader.leader_state.in_flight_bytes(&follower.id));
+
+        // Deliver messages as the follower acknowledges them. No request exceeds the window.
+        deliver_until_quiescent(&mut leader, &mut follower, actions.peer_messages, |_, message| {
+            assert!(append_entries_bytes(message) <= 8);
+        });
+        assert_eq!(leader.latest_log_index(), follower.latest_log_index());
+        assert_eq!(0, leader.leader_state.in_flight_bytes(&follower.id));
+    }
+
+    /// Tests that a follower far behind the leader is sent entries no faster than the catch up
+    /// rate, and still catches up.
+    #[test]
+    fn test_catch_up_rate() {
+        setup_test!("test_catch_up_rate");
+        let (leader_id, follower_id) = (ServerId(0), ServerId(1));
+        let leader_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let follower_addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+        let clock = ManualClock::new();
+
+        // The leader has 20 entries of 10 bytes which the follower lacks.
+        let mut log = MemLog::new();
+        log.set_current_term(Term(1)).unwrap();
+        let entries: Vec<(Term, &[u8])> = (0..20).map(|_| (Term(1), &b"0123456789"[..])).collect();
+        log.append_entries(LogIndex(1), &entries).unwrap();
+
+        let mut leader_peers = HashMap::new();
+        leader_peers.insert(follower_id, follower_addr);
+        let mut leader = Consensus::with_clock(leader_id, leader_peers, log, NullStateMachine,
+                                               Box::new(clock.clone()));
+        let mut follower_peers = HashMap::new();
+        follower_peers.insert(leader_id, leader_addr);
+        let mut follower: TestPeer =
+            Consensus::new(follower_id, follower_peers, MemLog::new(), NullStateMachine);
+        // 40 bytes per 100ms interval.
+        leader.set_catch_up_rate(Some(400));
+
+        let mut actions = Actions::new();
+        leader.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let mut pending = actions.peer_messages;
+        for _ in 0..20 {
+            // Deliver messages until the leader waits for the next interval.
+            let mut interval_bytes = 0;
+            deliver_until_quiescent(&mut leader, &mut follower, pending, |to, message| {
+                if to == follower_id {
+                    interval_bytes += append_entries_bytes(message);
+                }
+            });
+            assert!(interval_bytes <= 40, "sent {} bytes in one interval", interval_bytes);
+            if follower.latest_log_index() == leader.latest_log_index() {
+                break;
+            }
+
+            clock.advance(100);
+            let mut actions = Actions::new();
+            leader.tick(&mut actions);
+            pending = actions.peer_messages;
+        }
+        assert!(leader.is_leader());
+        assert_eq!(leader.latest_log_index(), follower.latest_log_index());
+    }
+
+    /// Tests that a leader reads only as many of a lagging follower's missing entries as may fit in
+    /// the replication window or catch up interval, and none while the follower waits for its next
+    /// catch up interval.
+    #[test]
+    fn test_send_entries_bounded_read() {
+        setup_test!("test_send_entries_bounded_read");
+        let (leader_id, follower) = (ServerId(0), ServerId(1));
+
+        // The leader has 100 entries of 10 bytes which the follower lacks.
+        let mut log = MemLog::new();
+        log.set_current_term(Term(1)).unwrap();
+        let entries: Vec<(Term, &[u8])> = (0..100).map(|_| (Term(1), &b"0123456789"[..])).collect();
+        log.append_entries(LogIndex(1), &entries).unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(follower, SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mut leader = Consensus::new(leader_id, peers, log, NullStateMachine);
+        leader.set_max_in_flight_bytes(30);
+        // 40 bytes per 100ms interval.
+        leader.set_catch_up_rate(Some(400));
+
+        let mut actions = Actions::new();
+        leader.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(2), false));
+        leader.apply_peer_message(follower, &vote, &mut actions).unwrap();
+        assert!(leader.is_leader());
+
+        // The follower's log is empty. The three entries which fit in the window are sent. Since
+        // each entry holds at least a byte, no more than 41 entries are read to show that the
+        // follower is missing more than the 40 bytes of an interval.
+        let reads = leader.log_metrics().entry_reads;
+        let response = messages::append_entries_response_inconsistent_prev_entry(Term(2), LogIndex(0), 0);
+        let mut actions = Actions::new();
+        leader.apply_peer_message(follower, &into_reader(&*response), &mut actions).unwrap();
+        let sent = actions.peer_messages
+                          .iter()
+                          .fold(0, |total, &(_, ref message)| total + append_entries_bytes(message));
+        assert_eq!(30, sent);
+        assert_eq!(reads + 41, leader.log_metrics().entry_reads);
+
+        // Until the next interval, resending the entries reads nothing.
+        let reads = leader.log_metrics().entry_reads;
+        let mut actions = Actions::new();
+        let follower_addr = leader.peers[&follower];
+        leader.peer_connection_reset(follower, follower_addr, &mut actions);
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(reads, leader.log_metrics().entry_reads);
+    }
+
+    /// Tests that a leader reads the entries it sends a lagging follower with a single range read,
+    /// rather than reading them one at a time.
+    #[test]
+    fn test_send_entries_range_read() {
+        setup_test!("test_send_entries_range_read");
+        let (leader_id, follower) = (ServerId(0), ServerId(1));
+
+        // The leader has 20 entries which the follower lacks.
+        let mut log = MemLog::new();
+        log.set_current_term(Term(1)).unwrap();
+        let entries: Vec<(Term, &[u8])> = (0..20).map(|_| (Term(1), &b"foo"[..])).collect();
+        log.append_entries(LogIndex(1), &entries).unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(follower, SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mut leader = Consensus::new(leader_id, peers, TestLog::new(log), NullStateMachine);
+        let mut actions = Actions::new();
+        leader.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(2), false));
+        leader.apply_peer_message(follower, &vote, &mut actions).unwrap();
+        assert!(leader.is_leader());
+
+        // The follower's log is empty, so the whole log, including the no-op, is sent.
+        let point_reads = leader.log.point_reads.get();
+        let range_reads = leader.log.range_reads.get();
+        let response = messages::append_entries_response_inconsistent_prev_entry(Term(2), LogIndex(0), 0);
+        let mut actions = Actions::new();
+        leader.apply_peer_message(follower, &into_reader(&*response), &mut actions).unwrap();
+        assert_eq!(1, actions.peer_messages.len());
+        assert_eq!(LogIndex(0), append_entries_prev_index(&actions.peer_messages[0].1));
+        assert_eq!(60, append_entries_bytes(&actions.peer_messages[0].1));
+        assert_eq!(point_reads, leader.log.point_reads.get());
+        assert_eq!(range_reads + 1, leader.log.range_reads.get());
+    }
+
+    /// Tests that a leader splits the entries it sends a follower into requests no larger than
+    /// the maximum message size, and rejects proposals which exceed it.
+    #[test]
+    fn test_max_message_bytes() {
+        setup_test!("test_max_message_bytes");
+        let (leader_id, follower_id) = (ServerId(0), ServerId(1));
+        let leader_addr = SocketAddr::from_str("127.0.0.1:0").unwrap();
+        let follower_addr = SocketAddr::from_str("127.0.0.1:1").unwrap();
+
+        // The leader has 10 entries of 10 bytes which the follower lacks.
+        let mut log = MemLog::new();
+        log.set_current_term(Term(1)).unwrap();
+        let entries: Vec<(Term, &[u8])> = (0..10).map(|_| (Term(1), &b"0123456789"[..])).collect();
+        log.append_entries(LogIndex(1), &entries).unwrap();
+
+        let mut leader_peers = HashMap::new();
+        leader_peers.insert(follower_id, follower_addr);
+        let mut leader: TestPeer = Consensus::new(leader_id, leader_peers, log, NullStateMachine);
+        let mut follower_peers = HashMap::new();
+        follower_peers.insert(leader_id, leader_addr);
+        let mut follower: TestPeer =
+            Consensus::new(follower_id, follower_peers, MemLog::new(), NullStateMachine);
+        leader.set_max_message_bytes(25);
+
+        let mut actions = Actions::new();
+        leader.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let mut requests = 0;
+        deliver_until_quiescent(&mut leader, &mut follower, actions.peer_messages, |to, message| {
+            if to == follower_id && append_entries_bytes(message) > 0 {
+                assert!(append_entries_bytes(message) <= 25);
+                requests += 1;
             }
+        });
+        assert!(requests >= 5, "entries sent in {} requests", requests);
+        assert_eq!(leader.latest_log_index(), follower.latest_log_index());
+
+        // An oversized proposal is rejected without being appended.
+        let latest_log_index = leader.latest_log_index();
+        let mut actions = Actions::new();
+        let proposal = into_reader(&messages::proposal_request(&[0; 26]));
+        leader.apply_client_message(ClientId::new(), &proposal, &mut actions).unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert_eq!(latest_log_index, leader.latest_log_index());
+        match messages::decode_command_response(&into_reader(&*actions.client_messages[0].1)) {
+            Err(Error::Raft(RaftError::CommandTooLarge)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    /// Tests that a leader which can not reach a majority rejects proposals once it holds the
+    /// maximum number of uncommitted entries, and accepts them again once they are committed.
+    #[test]
+    fn test_max_uncommitted_entries() {
+        setup_test!("test_max_uncommitted_entries");
+        let mut peers = new_cluster(3);
+        let mut ids: Vec<ServerId> = peers.keys().cloned().collect();
+        ids.sort();
+        let leader = peers.get_mut(&ids[0]).unwrap();
+        win_election(leader, &ids[1..2]);
+        leader.set_max_uncommitted_entries(Some(3));
+
+        // The followers are unreachable, so the no-op and two proposals fill the limit.
+        for _ in 0..2 {
+            let mut actions = Actions::new();
+            leader.propose(ClientId::new(), b"foo", &mut actions);
+            assert!(actions.client_messages.is_empty());
+        }
+        assert_eq!(3, leader.uncommitted_entries());
+
+        let mut actions = Actions::new();
+        leader.propose(ClientId::new(), b"foo", &mut actions);
+        assert_eq!(LogIndex(3), leader.latest_log_index());
+        match messages::decode_command_response(&into_reader(&*actions.client_messages[0].1)) {
+            Err(Error::Raft(RaftError::Overloaded)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // Once a follower acknowledges the entries they are committed, and proposals are accepted.
+        let mut actions = Actions::new();
+        let ack = into_reader(&*messages::append_entries_response_success(Term(1), LogIndex(3), 0));
+        leader.apply_peer_message(ids[1], &ack, &mut actions).unwrap();
+        assert_eq!(0, leader.uncommitted_entries());
+        let mut actions = Actions::new();
+        leader.propose(ClientId::new(), b"foo", &mut actions);
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(LogIndex(4), leader.latest_log_index());
+    }
+
+    /// A state machine which rejects the command `b"reject"`, fails fatally on the command
+    /// `b"fail"`, and returns every other command.
+    #[derive(Debug)]
+    struct RejectingStateMachine;
+
+    impl StateMachine for RejectingStateMachine {
+        fn apply(&mut self, command: &[u8]) -> result::Result<Vec<u8>, ApplyError> {
+            if command == b"reject" {
+                Err(ApplyError::Rejected(b"rejected".to_vec()))
+            } else if command == b"fail" {
+                Err(ApplyError::Fatal("failed".to_string()))
+            } else {
+                Ok(command.to_vec())
+            }
+        }
+        fn query(&self, _query: &[u8]) -> Vec<u8> {
+            Vec::new()
         }
+        fn snapshot(&self) -> Vec<u8> {
+            Vec::new()
+        }
+        fn restore_snapshot(&mut self, _snapshot: Vec<u8>) {
+        }
+    }
+
+    /// Tests that a proposal rejected by the state machine is committed, that the rejection is
+    /// returned to the client, and that later proposals are applied normally.
+    #[test]
+    fn test_proposal_rejected() {
+        setup_test!("test_proposal_rejected");
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), MemLog::new(), RejectingStateMachine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        assert!(peer.is_leader());
+
+        let client = ClientId::new();
+        for &(proposal, rejected) in &[(&b"reject"[..], true), (&b"accept"[..], false)] {
+            let mut actions = Actions::new();
+            let request = into_reader(&messages::proposal_request(proposal));
+            peer.apply_client_message(client, &request, &mut actions).unwrap();
+            assert_eq!(peer.latest_log_index(), peer.commit_index);
+
+            assert_eq!(1, actions.client_messages.len());
+            let response = into_reader(&*actions.client_messages[0].1);
+            let response = response.get_root::<client_response::Reader>().unwrap();
+            match response.which().unwrap() {
+                client_response::Which::Proposal(Ok(response)) => {
+                    match response.which().unwrap() {
+                        command_response::Which::Rejected(Ok(reason)) => {
+                            assert!(rejected);
+                            assert_eq!(b"rejected", reason);
+                        },
+                        command_response::Which::Success(Ok(result)) => {
+                            assert!(!rejected);
+                            assert_eq!(proposal, result);
+                        },
+                        _ => panic!("unexpected command response"),
+                    }
+                },
+                _ => panic!("unexpected client response"),
+            }
+        }
+    }
+
+    /// Tests that a fatal failure to apply an entry halts the consensus module, without answering
+    /// the proposing client or applying any later entry.
+    #[test]
+    fn test_apply_fatal() {
+        setup_test!("test_apply_fatal");
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), MemLog::new(), RejectingStateMachine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        assert!(peer.is_leader());
+
+        let mut actions = Actions::new();
+        peer.propose(ClientId::new(), b"fail", &mut actions);
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(LogIndex(1), peer.last_applied);
+        match peer.fatal_error() {
+            Some(&RaftError::ApplyFailed(ref error)) => assert_eq!("failed", error),
+            error => panic!("unexpected fatal error: {:?}", error),
+        }
+
+        peer.propose(ClientId::new(), b"foo", &mut actions);
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(LogIndex(1), peer.last_applied);
+    }
+
+    /// Tests that a consensus module whose apply worker has exited halts with a fatal error,
+    /// rather than panicking, when it next hands the worker a task.
+    #[test]
+    fn test_apply_worker_exited() {
+        setup_test!("test_apply_worker_exited");
+        let (tasks, worker) = mpsc::channel();
+        drop(worker);
+        let mut peer: TestPeer =
+            Consensus::with_apply_worker(ServerId(0), HashMap::new(), MemLog::new(), tasks);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        match peer.fatal_error() {
+            Some(&RaftError::ApplyFailed(ref error)) => assert_eq!("apply worker has exited", error),
+            error => panic!("unexpected fatal error: {:?}", error),
+        }
+    }
+
+    /// Tests that an empty proposal is committed and answered with a `committed` response, which
+    /// is distinct from an empty result, without being applied to the state machine.
+    #[test]
+    fn test_empty_proposal() {
+        setup_test!("test_empty_proposal");
+        let (state_machine, applied) = ChannelStateMachine::new();
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), MemLog::new(), state_machine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        assert!(peer.is_leader());
+
+        let mut actions = Actions::new();
+        let request = into_reader(&messages::proposal_request(b""));
+        peer.apply_client_message(ClientId::new(), &request, &mut actions).unwrap();
+        assert_eq!(LogIndex(2), peer.commit_index);
+        assert_eq!(1, actions.client_messages.len());
+        let response = into_reader(&*actions.client_messages[0].1);
+        assert_eq!(Vec::<u8>::new(), messages::decode_command_response(&response).unwrap());
+        let response = response.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::Proposal(Ok(response)) => {
+                match response.which().unwrap() {
+                    command_response::Which::Committed(()) => (),
+                    _ => panic!("unexpected command response"),
+                }
+            },
+            _ => panic!("unexpected client response"),
+        }
+        assert!(applied.try_recv().is_err());
+
+        let mut actions = Actions::new();
+        let request = into_reader(&messages::proposal_request(b"foo"));
+        peer.apply_client_message(ClientId::new(), &request, &mut actions).unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(b"foo".to_vec(), applied.try_recv().unwrap());
+    }
+
+    /// Returns the entries of the subscription messages sent to the client.
+    fn subscription_entries(client: ClientId, actions: &Actions) -> Vec<Vec<(LogIndex, Vec<u8>)>> {
+        actions.client_messages
+               .iter()
+               .filter(|&&(to, _)| to == client)
+               .map(|&(_, ref message)| {
+                   messages::decode_subscription_entries(&into_reader(&**message)).unwrap()
+               })
+               .collect()
+    }
+
+    /// Tests that a subscribed client is streamed the committed entries from the requested index
+    /// in order, with at most one batch outstanding, and nothing once it disconnects.
+    #[test]
+    fn test_subscribe() {
+        setup_test!("test_subscribe");
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), MemLog::new(), NullStateMachine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        for command in &[b"a", b"b"] {
+            peer.propose(ClientId::new(), &command[..], &mut Actions::new());
+        }
+
+        // The committed entries from the requested index are streamed; the no-op is omitted.
+        let subscriber = ClientId::new();
+        let mut actions = Actions::new();
+        let request = into_reader(&messages::subscribe_request(LogIndex(1)));
+        peer.apply_client_message(subscriber, &request, &mut actions).unwrap();
+        assert_eq!(vec![vec![(LogIndex(2), b"a".to_vec()), (LogIndex(3), b"b".to_vec())]],
+                   subscription_entries(subscriber, &actions));
+
+        // Further entries wait until the batch has been written to the subscriber.
+        let mut actions = Actions::new();
+        peer.propose(ClientId::new(), b"c", &mut actions);
+        peer.propose(ClientId::new(), b"d", &mut actions);
+        assert!(subscription_entries(subscriber, &actions).is_empty());
+        let mut actions = Actions::new();
+        peer.client_drained(subscriber, &mut actions);
+        assert_eq!(vec![vec![(LogIndex(4), b"c".to_vec()), (LogIndex(5), b"d".to_vec())]],
+                   subscription_entries(subscriber, &actions));
+
+        // Once drained with nothing pending, new entries are streamed as they commit.
+        peer.client_drained(subscriber, &mut Actions::new());
+        let mut actions = Actions::new();
+        peer.propose(ClientId::new(), b"e", &mut actions);
+        assert_eq!(vec![vec![(LogIndex(6), b"e".to_vec())]],
+                   subscription_entries(subscriber, &actions));
+
+        peer.client_disconnected(subscriber);
+        peer.client_drained(subscriber, &mut Actions::new());
+        let mut actions = Actions::new();
+        peer.propose(ClientId::new(), b"f", &mut actions);
+        assert!(subscription_entries(subscriber, &actions).is_empty());
+    }
+
+    /// Tests that failing to read the entries to stream to a subscriber halts the consensus
+    /// module instead of panicking.
+    #[test]
+    fn test_subscribe_read_failure() {
+        setup_test!("test_subscribe_read_failure");
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), MemLog::new(), NullStateMachine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        peer.propose(ClientId::new(), b"a", &mut Actions::new());
+        peer.log.corrupt_entry(LogIndex(2));
+
+        let subscriber = ClientId::new();
+        let mut actions = Actions::new();
+        let request = into_reader(&messages::subscribe_request(LogIndex(1)));
+        peer.apply_client_message(subscriber, &request, &mut actions).unwrap();
+        assert!(subscription_entries(subscriber, &actions).is_empty());
+        match peer.fatal_error() {
+            Some(&RaftError::LogReadFailed(..)) => (),
+            error => panic!("unexpected fatal error: {:?}", error),
+        }
+    }
+
+    /// Tests that streaming a batch to a subscriber reads only the entries in the batch, rather
+    /// than the whole committed tail of the log.
+    #[test]
+    fn test_subscribe_batch_reads() {
+        setup_test!("test_subscribe_batch_reads");
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), MemLog::new(), NullStateMachine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        let command = vec![0; 1024];
+        for _ in 0..256 {
+            peer.propose(ClientId::new(), &command, &mut Actions::new());
+        }
+
+        let subscriber = ClientId::new();
+        let request = into_reader(&messages::subscribe_request(LogIndex(1)));
+        let mut reads = peer.log.metrics().entry_reads;
+        let mut actions = Actions::new();
+        peer.apply_client_message(subscriber, &request, &mut actions).unwrap();
+        let mut streamed = 0;
+        loop {
+            let batches = subscription_entries(subscriber, &actions);
+            if batches.is_empty() {
+                break;
+            }
+            // Each batch holds 64 KiB of entries, and reads only those, besides the leader's
+            // no-op which precedes the first.
+            assert_eq!(64, batches[0].len());
+            let batch_reads = peer.log.metrics().entry_reads - reads;
+            assert!(batch_reads <= 65, "batch read {} entries", batch_reads);
+            streamed += batches[0].len();
+
+            reads = peer.log.metrics().entry_reads;
+            actions = Actions::new();
+            peer.client_drained(subscriber, &mut actions);
+        }
+        assert_eq!(256, streamed);
+    }
+
+    /// A state machine which records the leadership changes it is notified of.
+    #[derive(Debug)]
+    struct LeadershipStateMachine {
+        changes: Arc<Mutex<Vec<(bool, Term)>>>,
+    }
+
+    impl StateMachine for LeadershipStateMachine {
+        fn apply(&mut self, command: &[u8]) -> result::Result<Vec<u8>, ApplyError> {
+            Ok(command.to_vec())
+        }
+        fn query(&self, _query: &[u8]) -> Vec<u8> {
+            Vec::new()
+        }
+        fn snapshot(&self) -> Vec<u8> {
+            Vec::new()
+        }
+        fn restore_snapshot(&mut self, _snapshot: Vec<u8>) {
+        }
+        fn on_leadership_change(&mut self, is_leader: bool, term: Term) {
+            self.changes.lock().unwrap().push((is_leader, term));
+        }
+    }
+
+    /// Tests that the state machine is notified when the server is elected, steps down, and is
+    /// deposed by a newer term, and only then.
+    #[test]
+    fn test_on_leadership_change() {
+        setup_test!("test_on_leadership_change");
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let other = ServerId(1);
+        let mut peers = HashMap::new();
+        peers.insert(other, SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let state_machine = LeadershipStateMachine { changes: changes.clone() };
+        let mut peer = Consensus::new(ServerId(0), peers, MemLog::new(), state_machine);
+
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert!(changes.lock().unwrap().is_empty());
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(1), false));
+        peer.apply_peer_message(other, &vote, &mut actions).unwrap();
+        assert!(peer.is_leader());
+        peer.step_down(&mut actions);
+        assert_eq!(vec![(true, Term(1)), (false, Term(1))], *changes.lock().unwrap());
+
+        peer.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(2), false));
+        peer.apply_peer_message(other, &vote, &mut actions).unwrap();
+        assert!(peer.is_leader());
+        let request = messages::request_vote_request(Term(3), LogIndex(5), Term(3), false);
+        let request = into_reader(&*request);
+        peer.apply_peer_message(other, &request, &mut actions).unwrap();
+        assert!(!peer.is_leader());
+        assert_eq!(vec![(true, Term(1)), (false, Term(1)), (true, Term(2)), (false, Term(2))],
+                   *changes.lock().unwrap());
+    }
+
+    /// Tests that a solitary leader flushes its log before counting its own entries toward the
+    /// commit majority.
+    #[test]
+    fn test_flush_before_commit() {
+        setup_test!("test_flush_before_commit");
+        let log = TestLog::new(MemLog::new());
+        let mut peer = Consensus::new(ServerId(0), HashMap::new(), log, NullStateMachine);
+
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        assert!(peer.is_leader());
+        // The no-op is committed, and was flushed first.
+        assert_eq!(LogIndex(1), peer.commit_index);
+        assert_eq!(LogIndex(1), peer.log.flushed_index);
+
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        peer.apply_client_message(ClientId::new(), &proposal, &mut actions).unwrap();
+        assert_eq!(LogIndex(2), peer.commit_index);
+        assert_eq!(LogIndex(2), peer.log.flushed_index);
+    }
+
+    /// Tests that a leader which fails to append a proposal halts without recording the proposal
+    /// or replicating it.
+    #[test]
+    fn test_proposal_append_failure() {
+        setup_test!("test_proposal_append_failure");
+        let mut peers = HashMap::new();
+        peers.insert(ServerId(1), SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mut peer = Consensus::new(ServerId(0), peers, TestLog::new(MemLog::new()),
+                                      NullStateMachine);
+        peer.apply_timeout(ConsensusTimeout::Election, &mut Actions::new());
+        let vote = into_reader(&*messages::request_vote_response_granted(Term(1), false));
+        peer.apply_peer_message(ServerId(1), &vote, &mut Actions::new()).unwrap();
+        assert!(peer.is_leader());
+
+        peer.log.fail_appends = true;
+        let mut actions = Actions::new();
+        peer.propose(ClientId::new(), b"foo", &mut actions);
+        match peer.fatal_error() {
+            Some(&RaftError::LogWriteFailed(..)) => (),
+            error => panic!("unexpected fatal error: {:?}", error),
+        }
+        assert!(peer.client_origins.is_empty());
+        assert!(actions.peer_messages.is_empty());
+        assert!(actions.client_messages.is_empty());
+    }
+
+    /// Tests that initialization recovers the persisted state with a single `hard_state` read.
+    #[test]
+    fn test_init_reads_hard_state() {
+        setup_test!("test_init_reads_hard_state");
+        let mut log = MemLog::new();
+        log.set_current_term(Term(3)).unwrap();
+        log.append_entries(LogIndex(1), &[(Term(2), &b"foo"[..]), (Term(3), &b"bar"[..])])
+           .unwrap();
+        let log = TestLog::new(log);
+
+        let mut peers = HashMap::new();
+        peers.insert(ServerId(1), SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mut peer = Consensus::new(ServerId(0), peers, log, NullStateMachine);
+        assert_eq!(1, peer.log.hard_state_reads.get());
+        assert_eq!(0, peer.log.field_reads.get());
+        assert_eq!(LogIndex(4), peer.leader_state.next_index(&ServerId(1)));
+    }
+
+    /// Tests that the log metrics count the cache misses incurred while catching up a follower
+    /// which lacks old entries, and that replication to an up to date follower reads only cached
+    /// entries.
+    #[test]
+    fn test_log_metrics_catch_up() {
+        setup_test!("test_log_metrics_catch_up");
+        let (leader_id, follower_id) = (ServerId(0), ServerId(1));
+
+        // The leader has 20 entries which the follower lacks, and caches the latest 5.
+        let mut log = MemLog::new();
+        log.set_current_term(Term(1)).unwrap();
+        let entries: Vec<(Term, &[u8])> = (0..20).map(|_| (Term(1), &b"foo"[..])).collect();
+        log.append_entries(LogIndex(1), &entries).unwrap();
+        let mut log = TestLog::new(log);
+        log.cache_window = Some(5);
+
+        let mut leader_peers = HashMap::new();
+        leader_peers.insert(follower_id, SocketAddr::from_str("127.0.0.1:1").unwrap());
+        let mut leader = Consensus::new(leader_id, leader_peers, log, NullStateMachine);
+        let mut follower_peers = HashMap::new();
+        follower_peers.insert(leader_id, SocketAddr::from_str("127.0.0.1:0").unwrap());
+        let mut follower: TestPeer =
+            Consensus::new(follower_id, follower_peers, MemLog::new(), NullStateMachine);
+
+        let mut actions = Actions::new();
+        leader.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        deliver_until_quiescent(&mut leader, &mut follower, actions.peer_messages, |_, _| ());
+        assert!(leader.is_leader());
+        assert_eq!(LogIndex(21), leader.commit_index);
+
+        // Entries 1 through 16 fell outside of the cache, and each was read at least once.
+        let metrics = leader.log_metrics();
+        assert_eq!(metrics.entry_reads, metrics.cache_hits + metrics.cache_misses);
+        assert!(metrics.cache_misses >= 16, "{:?}", metrics);
+        assert!(metrics.cache_hits > 0, "{:?}", metrics);
+
+        let mut actions = Actions::new();
+        leader.propose(ClientId::new(), b"bar", &mut actions);
+        // A follower which has caught up needs a single exchange per proposal.
+        assert_eq!(2, deliver_until_quiescent(&mut leader, &mut follower, actions.peer_messages,
+                                              |_, _| ()));
+        assert_eq!(LogIndex(22), leader.commit_index);
+        let steady = leader.log_metrics();
+        assert_eq!(metrics.cache_misses, steady.cache_misses);
+        assert!(steady.cache_hits > metrics.cache_hits);
+    }
+
+    /// Tests that a newly elected leader appends a no-op entry, and that an entry from a previous
+    /// term is only committed once the no-op from the leader's current term is committed.
+    #[test]
+    fn test_prior_term_entry_committed_by_noop() {
+        setup_test!("test_prior_term_entry_committed_by_noop");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader_id = peer_ids[0];
+        let follower_id = peer_ids[1];
+        elect_leader(leader_id, &mut peers);
+
+        let leader = peers.get_mut(&leader_id).unwrap();
+        assert_eq!((Term(1), &b""[..]), leader.log.entry(LogIndex(1)).unwrap());
+        assert_eq!(LogIndex(1), leader.commit_index);
+
+        // The leader appends an entry in term 1, but it does not reach the followers.
+        let proposal = into_reader(&messages::proposal_request(b"foo"));
+        leader.apply_client_message(ClientId::new(), &proposal, &mut Actions::new()).unwrap();
+        assert_eq!(LogIndex(2), leader.latest_log_index());
+
+        // The leader is re-elected in term 2, and appends a no-op entry.
+        let mut actions = Actions::new();
+        leader.transition_to_candidate(&mut actions);
+        leader.transition_to_leader(&mut actions);
+        assert_eq!(Term(2), leader.current_term());
+        assert_eq!((Term(2), &b""[..]), leader.log.entry(LogIndex(3)).unwrap());
+
+        // A majority holding the term 1 entry is not sufficient to commit it.
+        leader.leader_state.set_match_index(follower_id, LogIndex(2));
+        leader.advance_commit_index(&mut actions);
+        assert_eq!(LogIndex(1), leader.commit_index);
+
+        // Once the no-op commits, so does the entry before it.
+        leader.leader_state.set_match_index(follower_id, LogIndex(3));
+        leader.advance_commit_index(&mut actions);
+        assert_eq!(LogIndex(3), leader.commit_index);
+        leader.apply_committed(&mut actions);
+        assert_eq!(LogIndex(3), leader.last_applied);
+    }
+
+    /// Tests that advancing the commit index leaves the entries unapplied until `apply_committed`,
+    /// which applies exactly the committed entries.
+    #[test]
+    fn test_apply_committed() {
+        setup_test!("test_apply_committed");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader_id = peer_ids[0];
+        let follower_id = peer_ids[1];
+        elect_leader(leader_id, &mut peers);
+
+        let leader = peers.get_mut(&leader_id).unwrap();
+        assert_eq!(LogIndex(1), leader.last_applied);
+        let mut actions = Actions::new();
+        for entry in &[b"a", b"b", b"c", b"d"] {
+            leader.append_proposal(ClientId::new(), None, &entry[..], &mut actions);
+        }
+        assert_eq!(LogIndex(5), leader.latest_log_index());
+
+        // Three of the four entries reach a majority.
+        leader.leader_state.set_match_index(follower_id, LogIndex(4));
+        leader.advance_commit_index(&mut actions);
+        assert_eq!(LogIndex(4), leader.commit_index);
+        assert_eq!(LogIndex(1), leader.last_applied);
+
+        leader.apply_committed(&mut actions);
+        assert_eq!(LogIndex(4), leader.last_applied);
+        leader.apply_committed(&mut actions);
+        assert_eq!(LogIndex(4), leader.last_applied);
+    }
+
+    /// Tests that a committed entry which fails its checksum halts the consensus module rather
+    /// than being applied.
+    #[test]
+    fn test_corrupt_entry_halts() {
+        setup_test!("test_corrupt_entry_halts");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader_id = peer_ids[0];
+        let follower_id = peer_ids[1];
+        elect_leader(leader_id, &mut peers);
+
+        let leader = peers.get_mut(&leader_id).unwrap();
+        let mut actions = Actions::new();
+        for entry in &[b"a", b"b", b"c"] {
+            leader.append_proposal(ClientId::new(), None, &entry[..], &mut actions);
+        }
+        leader.leader_state.set_match_index(follower_id, LogIndex(3));
+        leader.advance_commit_index(&mut actions);
+        assert_eq!(LogIndex(3), leader.commit_index);
+
+        leader.log.corrupt_entry(LogIndex(3));
+        leader.apply_committed(&mut actions);
+        assert_eq!(LogIndex(2), leader.last_applied);
+        match leader.fatal_error() {
+            Some(&RaftError::LogReadFailed(..)) => (),
+            error => panic!("unexpected fatal error: {:?}", error),
+        }
+
+        // The entries following the corrupt entry are not applied either.
+        leader.leader_state.set_match_index(follower_id, LogIndex(4));
+        leader.advance_commit_index(&mut actions);
+        leader.apply_committed(&mut actions);
+        assert_eq!(LogIndex(2), leader.last_applied);
     }
 }
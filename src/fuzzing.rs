@@ -0,0 +1,128 @@
+//! Entry points for fuzzing the decoding of untrusted network input.
+//!
+//! The input is the raw bytes received on a connection, exactly as they would arrive from the
+//! network. They are written to a loopback socket and read back through `Connection::readable`,
+//! and the messages read are handled the way the `Server` handles them: the first as a connection
+//! preamble, and the rest as peer or client messages applied to a fresh follower, depending on the
+//! preamble. Malformed input must result in an error, never a panic. Only available with the
+//! `fuzzing` feature; see the `fuzz` directory for the `cargo fuzz` target.
+
+use std::{cmp, io, net};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use mio::tcp::TcpListener;
+
+use {Config, Result, ServerId};
+use connection::Connection;
+use consensus::{Actions, Consensus};
+use messages::{self, Preamble};
+use transport::Stream;
+use persistent_log::MemLog;
+use state_machine::NullStateMachine;
+
+/// The largest number of input bytes written to the connection. Longer inputs are truncated, so
+/// that the whole input fits in the socket's buffers without a concurrent reader.
+const MAX_INPUT_BYTES: usize = 64 * 1024;
+
+/// Opens a loopback connection, returning the accepted end wrapped in a `Connection`, and the
+/// dialing end.
+fn connect() -> Result<(Connection, net::TcpStream)> {
+    let listener = try!(TcpListener::bind(&SocketAddr::from_str("127.0.0.1:0").unwrap()));
+    let stream = try!(net::TcpStream::connect(try!(listener.local_addr())));
+    match try!(listener.accept()) {
+        Some(socket) => Ok((try!(Connection::unknown(Stream::Tcp(socket))), stream)),
+        None => Err(From::from(io::Error::new(io::ErrorKind::WouldBlock, "connection not accepted"))),
+    }
+}
+
+/// Creates a fresh follower with a single peer, `ServerId(1)`.
+fn new_consensus() -> Consensus<MemLog, NullStateMachine> {
+    let mut peers = HashMap::new();
+    peers.insert(ServerId(1), SocketAddr::from_str("127.0.0.1:0").unwrap());
+    Consensus::new(ServerId(0), peers, MemLog::new(), NullStateMachine)
+}
+
+/// Sends the bytes over a connection, and handles every message read from it.
+pub fn connection(data: &[u8]) -> Result<()> {
+    let (mut conn, mut stream) = try!(connect());
+    try!(stream.write_all(&data[..cmp::min(data.len(), MAX_INPUT_BYTES)]));
+    drop(stream);
+
+    let max_bytes = Config::default().max_read_buffer_bytes;
+    let preamble = match try!(conn.readable(max_bytes)) {
+        Some(message) => try!(messages::decode_connection_preamble(&message)),
+        None => return Ok(()),
+    };
+    let mut consensus = new_consensus();
+    while let Some(message) = try!(conn.readable(max_bytes)) {
+        let mut actions = Actions::new();
+        match preamble {
+            Preamble::Server(..) =>
+                try!(consensus.apply_peer_message(ServerId(1), &message, &mut actions)),
+            Preamble::Client(id) =>
+                try!(consensus.apply_client_message(id, &message, &mut actions)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    use capnp::{serialize, MessageBuilder};
+
+    use {ClientId, LogIndex, ServerId, Term};
+    use messages;
+    use super::*;
+
+    /// Serializes messages into their framed wire format.
+    fn to_bytes<M>(messages: &[&M]) -> Vec<u8> where M: MessageBuilder {
+        let mut bytes = Vec::new();
+        for message in messages {
+            serialize::write_message(&mut bytes, *message).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_valid_messages() {
+        setup_test!("test_valid_messages");
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let server_preamble = messages::server_connection_preamble(ServerId(1), &addr);
+        let client_preamble = messages::client_connection_preamble(ClientId::new());
+        connection(&to_bytes(&[&*server_preamble])).unwrap();
+        connection(&to_bytes(&[&*client_preamble])).unwrap();
+
+        let entries: &[(Term, &[u8])] = &[(Term(1), b"foo")];
+        let request = messages::append_entries_request(Term(1), LogIndex(0), Term(0), entries,
+                                                       &BTreeMap::new(), LogIndex(0), 0, 0);
+        connection(&to_bytes(&[&*server_preamble, &*request])).unwrap();
+
+        let mut bytes = to_bytes(&[&*client_preamble]);
+        bytes.extend(to_bytes(&[&messages::proposal_request(b"foo")]));
+        bytes.extend(to_bytes(&[&messages::query_request(b"foo")]));
+        connection(&bytes).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        setup_test!("test_malformed_messages");
+        let addr = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let server_preamble = to_bytes(&[&*messages::server_connection_preamble(ServerId(1), &addr)]);
+        let client_preamble = to_bytes(&[&*messages::client_connection_preamble(ClientId::new())]);
+        let garbage: &[u8] = &[0, 0, 0, 0, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        assert!(connection(garbage).is_err());
+        for preamble in &[&server_preamble, &client_preamble] {
+            let mut bytes = preamble.to_vec();
+            bytes.extend_from_slice(garbage);
+            assert!(connection(&bytes).is_err());
+        }
+    }
+}
@@ -74,6 +74,7 @@
 
 extern crate bufstream;
 extern crate capnp;
+extern crate libc;
 extern crate mio;
 extern crate rand;
 extern crate uuid;
@@ -102,18 +103,27 @@ pub mod messages_capnp {
     include!(concat!(env!("OUT_DIR"), "/messages_capnp.rs"));
 }
 
+mod apply;
 mod backoff;
+mod buffer_pool;
 mod client;
+mod clock;
+mod config;
 mod connection;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 mod messages;
 mod consensus;
 mod server;
 mod state;
+mod transport;
 
-pub use server::Server;
+pub use consensus::ElectionMetrics;
+pub use server::{Server, ServerCommand, ServerHandle, ShutdownReason};
 pub use state_machine::StateMachine;
-pub use persistent_log::Log;
+pub use persistent_log::{HardState, Log, LogMetrics};
 pub use client::Client;
+pub use config::Config;
 
 use std::{io, net, ops, fmt};
 
@@ -145,7 +155,7 @@ wrapped_enum!{
 
 /// A Raft Error represents a Raft specific error that consuming code is expected to handle
 /// gracefully.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum RaftError {
     /// The server ran out of slots in the slab for new connections
     ConnectionLimitReached,
@@ -155,12 +165,64 @@ pub enum RaftError {
     ClusterViolation,
     /// A remote connection attempted to use an unknown connection type in the connection preamble
     UnknownConnectionType,
+    /// A client sent a request type which the server does not support.
+    UnsupportedRequest,
     /// An invalid peer in in the peer set. Returned Server::new().
     InvalidPeerSet,
+    /// The `Config` passed to `Server::new()` can not work with the peer set, for instance
+    /// because `max_connections` leaves no room for a connection to every peer.
+    InvalidConfig,
     /// Registering a connection failed
     ConnectionRegisterFailed,
+    /// Registering a timeout with the event loop failed, because its timer is full. The server
+    /// halts rather than run without the timeout.
+    TimeoutRegisterFailed,
     /// Failed to find a leader in the cluster. Try again later.
     LeaderSearchExhausted,
+    /// A command could not be delivered to a `Server`, or the `Server` stopped before responding.
+    ServerUnavailable,
+    /// The state machine rejected a committed proposal, with the provided application-specific
+    /// reason.
+    ProposalRejected(Vec<u8>),
+    /// A peer opened a connection while an established connection to it already exists, and the
+    /// existing connection takes precedence.
+    DuplicateConnection,
+    /// A proposal was made to a server which is not the leader.
+    NotLeader,
+    /// A proposal was not applied before its deadline. It may still be applied later.
+    Timeout,
+    /// A proposal was overwritten in the log by a new leader before it was committed. It will
+    /// never be applied.
+    NotCommitted,
+    /// A proposal was larger than the leader's maximum message size, and was not appended to the
+    /// log.
+    CommandTooLarge,
+    /// A proposal was made to a leader which holds the maximum number of uncommitted entries, and
+    /// was not appended to the log. It may be retried once the leader commits its entries.
+    Overloaded,
+    /// A remote process opened or answered a connection using the provided, incompatible,
+    /// protocol version.
+    IncompatibleProtocolVersion(u32),
+    /// A remote process answered a connection with a preamble which does not identify the server
+    /// which was dialed.
+    UnexpectedPreamble,
+    /// Persisting Raft state to the `Log` failed with the provided error. The server halts
+    /// rather than continue with state it could not make durable.
+    LogWriteFailed(String),
+    /// Reading a log entry which the server holds failed with the provided error, for instance
+    /// because the entry is corrupt. The server halts rather than replicate or apply anything in
+    /// its place.
+    LogReadFailed(String),
+    /// The `StateMachine` failed fatally to apply a committed entry, with the provided error. The
+    /// server halts rather than continue with a state which may have diverged from the other
+    /// replicas.
+    ApplyFailed(String),
+    /// A remote process sent a message larger than `Config::max_read_buffer_bytes`. Its connection
+    /// is reset.
+    MessageTooLarge,
+    /// A client opened a connection to a server which does not accept client connections. See
+    /// `Config::accept_clients`.
+    ClientConnectionRefused,
 }
 
 impl fmt::Display for Error {
@@ -182,6 +244,16 @@ impl Term {
     pub fn as_u64(self) -> u64 {
         self.0
     }
+
+    /// Returns the term `rhs` terms after this one, or `None` on overflow.
+    pub fn checked_add(self, rhs: u64) -> Option<Term> {
+        self.0.checked_add(rhs).map(Term)
+    }
+
+    /// Returns the term `rhs` terms before this one, or `None` on underflow.
+    pub fn checked_sub(self, rhs: u64) -> Option<Term> {
+        self.0.checked_sub(rhs).map(Term)
+    }
 }
 impl From<u64> for Term {
     fn from(val: u64) -> Term {
@@ -212,12 +284,35 @@ impl fmt::Display for Term {
 }
 
 /// The index of a log entry.
+///
+/// Terms and indices are distinct types, and one may not be used in place of the other:
+///
+/// ```compile_fail
+/// use raft::{LogIndex, Term};
+/// let index: LogIndex = Term::from(1);
+/// ```
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct LogIndex(u64);
 impl LogIndex {
     pub fn as_u64(self) -> u64 {
         self.0
     }
+
+    /// Returns the index `rhs` entries before this one, or index 0 (the empty log) if there is
+    /// no such entry.
+    pub fn saturating_sub(self, rhs: u64) -> LogIndex {
+        LogIndex(self.0.saturating_sub(rhs))
+    }
+
+    /// Returns the index `rhs` entries after this one, or `None` on overflow.
+    pub fn checked_add(self, rhs: u64) -> Option<LogIndex> {
+        self.0.checked_add(rhs).map(LogIndex)
+    }
+
+    /// Returns the index `rhs` entries before this one, or `None` on underflow.
+    pub fn checked_sub(self, rhs: u64) -> Option<LogIndex> {
+        self.0.checked_sub(rhs).map(LogIndex)
+    }
 }
 impl From<u64> for LogIndex {
     fn from(val: u64) -> LogIndex {
@@ -288,13 +383,17 @@ impl fmt::Display for ServerId {
 #[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub struct ClientId(Uuid);
 impl ClientId {
-    fn new() -> ClientId {
+    /// Creates a new, randomly generated client ID.
+    pub fn new() -> ClientId {
         ClientId(Uuid::new_v4())
     }
-    fn as_bytes(&self) -> &[u8] {
+    /// Returns the 16 byte representation of the ID, as sent in the connection preamble.
+    pub fn as_bytes(&self) -> &[u8] {
         self.0.as_bytes()
     }
-    fn from_bytes(bytes: &[u8]) -> Result<ClientId> {
+    /// Parses an ID from its byte representation, returning `InvalidClientId` if `bytes` is not
+    /// exactly 16 bytes long.
+    pub fn from_bytes(bytes: &[u8]) -> Result<ClientId> {
         match Uuid::from_bytes(bytes) {
             Some(uuid) => Ok(ClientId(uuid)),
             None => Err(Error::Raft(RaftError::InvalidClientId)),
@@ -311,3 +410,65 @@ impl fmt::Display for ClientId {
         fmt::Display::fmt(&self.0, f)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_id_round_trip() {
+        setup_test!("test_client_id_round_trip");
+        let id = ClientId::new();
+        assert_eq!(16, id.as_bytes().len());
+        assert_eq!(id, ClientId::from_bytes(id.as_bytes()).unwrap());
+        assert!(ClientId::new() != id);
+    }
+
+    #[test]
+    fn test_term_arithmetic() {
+        setup_test!("test_term_arithmetic");
+        assert_eq!(Term(3), Term(1) + 2);
+        assert_eq!(Term(1), Term(3) - 2);
+        assert_eq!(Some(Term(3)), Term(1).checked_add(2));
+        assert_eq!(None, Term(!0).checked_add(1));
+        assert_eq!(Some(Term(0)), Term(1).checked_sub(1));
+        assert_eq!(None, Term(0).checked_sub(1));
+        assert_eq!(Term(7), Term::from(7));
+        let raw: u64 = Term(7).into();
+        assert_eq!(7, raw);
+        assert!(Term(1) < Term(2));
+    }
+
+    #[test]
+    fn test_log_index_arithmetic() {
+        setup_test!("test_log_index_arithmetic");
+        assert_eq!(LogIndex(3), LogIndex(1) + 2);
+        assert_eq!(LogIndex(1), LogIndex(3) - 2);
+        assert_eq!(2, LogIndex(3) - LogIndex(1));
+        assert_eq!(LogIndex(0), LogIndex(1).saturating_sub(2));
+        assert_eq!(Some(LogIndex(3)), LogIndex(1).checked_add(2));
+        assert_eq!(None, LogIndex(!0).checked_add(1));
+        assert_eq!(None, LogIndex(0).checked_sub(1));
+        assert_eq!(LogIndex(7), LogIndex::from(7));
+        let raw: u64 = LogIndex(7).into();
+        assert_eq!(7, raw);
+        assert!(LogIndex(1) < LogIndex(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_log_index_underflow() {
+        let _ = LogIndex(0) - 1;
+    }
+
+    #[test]
+    fn test_client_id_invalid_length() {
+        setup_test!("test_client_id_invalid_length");
+        for bytes in &[&[][..], &[0u8; 15][..], &[0u8; 17][..]] {
+            match ClientId::from_bytes(bytes) {
+                Err(Error::Raft(RaftError::InvalidClientId)) => (),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
@@ -1,30 +1,61 @@
 //! Utility functions for working with Cap'n Proto Raft messages.
 #![allow(dead_code)]
 
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::str::FromStr;
 
 use capnp::{
     MallocMessageBuilder,
     MessageBuilder,
+    MessageReader,
+    ReaderOptions,
 };
+use capnp::message::SegmentArrayMessageReader;
 
-use {ClientId, Term, LogIndex, ServerId};
+use {ClientId, Error, RaftError, Result, Term, LogIndex, ServerId};
 use messages_capnp::{
     client_request,
     client_response,
+    command_response,
     connection_preamble,
+    forwarded_proposal_response,
     message
 };
 
+/// Returns a reader over the segments of a built message, without copying them.
+pub fn builder_reader<'a, M>(message: &'a M) -> SegmentArrayMessageReader<'a> where M: MessageBuilder {
+    SegmentArrayMessageReader::new(message.get_segments_for_output(), ReaderOptions::new())
+}
+
+/// Returns true if the peer message is a request, which the receiving peer is expected to answer.
+pub fn is_request(message: &MallocMessageBuilder) -> bool {
+    let reader = builder_reader(message);
+    let root = match reader.get_root::<message::Reader>() {
+        Ok(root) => root,
+        Err(_) => return false,
+    };
+    match root.which() {
+        Ok(message::Which::AppendEntriesRequest(_)) |
+        Ok(message::Which::RequestVoteRequest(_)) |
+        Ok(message::Which::ForwardedProposalRequest(_)) => true,
+        _ => false,
+    }
+}
+
 // ConnectionPreamble
 
+/// The major version of the messaging protocol. It must be incremented whenever a change to the
+/// schema would cause a process running the previous version to misinterpret messages.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 pub fn server_connection_preamble(id: ServerId, addr: &SocketAddr) -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
-        let mut server = message.init_root::<connection_preamble::Builder>()
-                                .init_id()
-                                .init_server();
+        let mut preamble = message.init_root::<connection_preamble::Builder>();
+        preamble.set_protocol_version(PROTOCOL_VERSION);
+        let mut server = preamble.init_id().init_server();
         server.set_addr(&format!("{}", addr));
         server.set_id(id.as_u64());
     }
@@ -34,20 +65,54 @@ pub fn server_connection_preamble(id: ServerId, addr: &SocketAddr) -> Rc<MallocM
 pub fn client_connection_preamble(id: ClientId) -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
-        message.init_root::<connection_preamble::Builder>()
-               .init_id()
-               .set_client(id.as_bytes());
+        let mut preamble = message.init_root::<connection_preamble::Builder>();
+        preamble.set_protocol_version(PROTOCOL_VERSION);
+        preamble.init_id().set_client(id.as_bytes());
     }
     Rc::new(message)
 }
 
+/// The identity announced by a remote process in its connection preamble.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Preamble {
+    /// A peer server, along with the address it is listening on.
+    Server(ServerId, SocketAddr),
+    /// A client.
+    Client(ClientId),
+}
+
+/// Decodes a connection preamble, returning an error if the message is malformed or uses an
+/// incompatible protocol version.
+pub fn decode_connection_preamble<R>(message: &R) -> Result<Preamble> where R: MessageReader {
+    let preamble = try!(message.get_root::<connection_preamble::Reader>());
+    if preamble.get_protocol_version() != PROTOCOL_VERSION {
+        return Err(Error::Raft(RaftError::IncompatibleProtocolVersion(preamble.get_protocol_version())));
+    }
+    match try!(preamble.get_id().which()) {
+        connection_preamble::id::Which::Server(peer) => {
+            let peer = try!(peer);
+            let addr = try!(SocketAddr::from_str(try!(peer.get_addr())));
+            Ok(Preamble::Server(ServerId(peer.get_id()), addr))
+        },
+        connection_preamble::id::Which::Client(Ok(id)) => {
+            Ok(Preamble::Client(try!(ClientId::from_bytes(id))))
+        },
+        _ => Err(Error::Raft(RaftError::UnknownConnectionType)),
+    }
+}
+
 // AppendEntries
 
+/// Creates an AppendEntries request. The entries start at `prev_log_index + 1`; `client_origins`
+/// maps log indices to the clients which proposed the entries, and may hold unrelated indices.
 pub fn append_entries_request(term: Term,
                               prev_log_index: LogIndex,
                               prev_log_term: Term,
                               entries: &[(Term, &[u8])],
-                              leader_commit: LogIndex)
+                              client_origins: &BTreeMap<LogIndex, ClientId>,
+                              leader_commit: LogIndex,
+                              read_round: u64,
+                              round_trip_ms: u64)
                               -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
@@ -57,24 +122,33 @@ pub fn append_entries_request(term: Term,
         request.set_prev_log_index(prev_log_index.as_u64());
         request.set_prev_log_term(prev_log_term.as_u64());
         request.set_leader_commit(leader_commit.as_u64());
+        request.set_read_round(read_round);
+        request.set_round_trip_ms(round_trip_ms);
 
         let mut entry_list = request.init_entries(entries.len() as u32);
         for (n, entry) in entries.iter().enumerate() {
             let mut slot = entry_list.borrow().get(n as u32);
             slot.set_term(entry.0.into());
             slot.set_data(entry.1);
+            if let Some(client) = client_origins.get(&(prev_log_index + 1 + n as u64)) {
+                slot.set_client(client.as_bytes());
+            }
         }
     }
     Rc::new(message)
 }
 
-pub fn append_entries_response_success(term: Term, log_index: LogIndex) -> Rc<MallocMessageBuilder> {
+pub fn append_entries_response_success(term: Term,
+                                       log_index: LogIndex,
+                                       read_round: u64)
+                                       -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_append_entries_response();
         response.set_term(term.as_u64());
         response.set_success(log_index.as_u64());
+        response.set_read_round(read_round);
     }
     Rc::new(message)
 }
@@ -90,13 +164,17 @@ pub fn append_entries_response_stale_term(term: Term) -> Rc<MallocMessageBuilder
     Rc::new(message)
 }
 
-pub fn append_entries_response_inconsistent_prev_entry(term: Term, index: LogIndex) -> Rc<MallocMessageBuilder> {
+pub fn append_entries_response_inconsistent_prev_entry(term: Term,
+                                                       index: LogIndex,
+                                                       read_round: u64)
+                                                       -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_append_entries_response();
         response.set_term(term.as_u64());
         response.set_inconsistent_prev_entry(index.into());
+        response.set_read_round(read_round);
     }
     Rc::new(message)
 }
@@ -118,7 +196,8 @@ pub fn append_entries_response_internal_error(term: Term,
 
 pub fn request_vote_request(term: Term,
                             last_log_index: LogIndex,
-                            last_log_term: Term)
+                            last_log_term: Term,
+                            pre_vote: bool)
                             -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
@@ -127,50 +206,57 @@ pub fn request_vote_request(term: Term,
         request.set_term(term.as_u64());
         request.set_last_log_index(last_log_index.as_u64());
         request.set_last_log_term(last_log_term.as_u64());
+        request.set_pre_vote(pre_vote);
     }
     Rc::new(message)
 }
 
-pub fn request_vote_response_granted(term: Term) -> Rc<MallocMessageBuilder> {
+pub fn request_vote_response_granted(term: Term, pre_vote: bool) -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_request_vote_response();
         response.set_term(term.as_u64());
         response.set_granted(());
+        response.set_pre_vote(pre_vote);
     }
     Rc::new(message)
 }
 
-pub fn request_vote_response_stale_term(term: Term) -> Rc<MallocMessageBuilder> {
+pub fn request_vote_response_stale_term(term: Term, pre_vote: bool) -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_request_vote_response();
         response.set_term(term.as_u64());
         response.set_stale_term(());
+        response.set_pre_vote(pre_vote);
     }
     Rc::new(message)
 }
 
-pub fn request_vote_response_already_voted(term: Term) -> Rc<MallocMessageBuilder> {
+pub fn request_vote_response_already_voted(term: Term, pre_vote: bool) -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_request_vote_response();
         response.set_term(term.as_u64());
         response.set_already_voted(());
+        response.set_pre_vote(pre_vote);
     }
     Rc::new(message)
 }
 
-pub fn request_vote_response_inconsistent_log(term: Term) -> Rc<MallocMessageBuilder> {
+pub fn request_vote_response_inconsistent_log(term: Term,
+                                              pre_vote: bool)
+                                              -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
         let mut response = message.init_root::<message::Builder>()
                                   .init_request_vote_response();
         response.set_term(term.as_u64());
         response.set_inconsistent_log(());
+        response.set_pre_vote(pre_vote);
     }
     Rc::new(message)
 }
@@ -186,6 +272,56 @@ pub fn request_vote_response_internal_error(term: Term, error: &str) -> Rc<Mallo
     Rc::new(message)
 }
 
+// Forwarded Proposal
+
+pub fn forwarded_proposal_request(client: ClientId,
+                                  proposal: u64,
+                                  entry: &[u8])
+                                  -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>()
+                                 .init_forwarded_proposal_request();
+        request.set_client(client.as_bytes());
+        request.set_entry(entry);
+        request.set_proposal(proposal);
+    }
+    Rc::new(message)
+}
+
+/// Creates a forwarded proposal response relaying the provided proposal response to the client.
+/// An error is returned if the response is not a proposal response.
+pub fn forwarded_proposal_response(client: ClientId,
+                                   proposal: u64,
+                                   response: &MallocMessageBuilder)
+                                   -> Result<Rc<MallocMessageBuilder>> {
+    let reader = builder_reader(response);
+    let status = match try!(try!(reader.get_root::<client_response::Reader>()).which()) {
+        client_response::Which::Proposal(status) => try!(status),
+        _ => return Err(Error::Raft(RaftError::UnsupportedRequest)),
+    };
+
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        let mut forwarded = message.init_root::<message::Builder>()
+                                   .init_forwarded_proposal_response();
+        forwarded.set_client(client.as_bytes());
+        forwarded.set_proposal(proposal);
+        try!(forwarded.set_response(status));
+    }
+    Ok(Rc::new(message))
+}
+
+/// Decodes a forwarded proposal response, returning the forwarding follower's ID for the proposal
+/// and the response to relay to the client.
+pub fn decode_forwarded_proposal_response(response: forwarded_proposal_response::Reader)
+                                          -> Result<(u64, Rc<MallocMessageBuilder>)> {
+    let mut message = MallocMessageBuilder::new_default();
+    try!(message.init_root::<client_response::Builder>()
+                .set_proposal(try!(response.get_response())));
+    Ok((response.get_proposal(), Rc::new(message)))
+}
+
 // Ping
 
 pub fn ping_request() -> MallocMessageBuilder {
@@ -210,6 +346,92 @@ pub fn query_request(entry: &[u8]) -> MallocMessageBuilder {
 }
 
 
+// Subscription
+
+pub fn subscribe_request(from_index: LogIndex) -> MallocMessageBuilder {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_request::Builder>()
+               .init_subscribe()
+               .set_from_index(from_index.as_u64());
+    }
+    message
+}
+
+pub fn subscription_entries(entries: &[(LogIndex, &[u8])]) -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        let mut entry_list = message.init_root::<client_response::Builder>()
+                                    .init_subscription()
+                                    .init_entries(entries.len() as u32);
+        for (n, &(index, data)) in entries.iter().enumerate() {
+            let mut slot = entry_list.borrow().get(n as u32);
+            slot.set_index(index.as_u64());
+            slot.set_data(data);
+        }
+    }
+    Rc::new(message)
+}
+
+/// Decodes the committed entries streamed to a subscribed client, as their indexes and commands.
+/// An error is returned if the message is not a subscription message, or is malformed.
+pub fn decode_subscription_entries<R>(message: &R) -> Result<Vec<(LogIndex, Vec<u8>)>>
+where R: MessageReader {
+    match try!(try!(message.get_root::<client_response::Reader>()).which()) {
+        client_response::Which::Subscription(subscription) => {
+            try!(try!(subscription).get_entries())
+                .iter()
+                .map(|entry| Ok((LogIndex(entry.get_index()), Vec::from(try!(entry.get_data())))))
+                .collect()
+        },
+        _ => Err(Error::Raft(RaftError::UnsupportedRequest)),
+    }
+}
+
+// Configuration
+
+pub fn get_configuration_request() -> MallocMessageBuilder {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_request::Builder>()
+               .init_get_configuration();
+    }
+    message
+}
+
+pub fn configuration_response(servers: &[(ServerId, SocketAddr)]) -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        let mut server_list = message.init_root::<client_response::Builder>()
+                                     .init_configuration()
+                                     .init_servers(servers.len() as u32);
+        for (n, &(id, addr)) in servers.iter().enumerate() {
+            let mut slot = server_list.borrow().get(n as u32);
+            slot.set_id(id.as_u64());
+            slot.set_addr(&format!("{}", addr));
+        }
+    }
+    Rc::new(message)
+}
+
+/// Decodes the members of the cluster reported to a client, as their ids and addresses. An error
+/// is returned if the message is not a configuration message, or is malformed.
+pub fn decode_configuration_response<R>(message: &R) -> Result<Vec<(ServerId, SocketAddr)>>
+where R: MessageReader {
+    match try!(try!(message.get_root::<client_response::Reader>()).which()) {
+        client_response::Which::Configuration(configuration) => {
+            try!(try!(configuration).get_servers())
+                .iter()
+                .map(|server| {
+                    let addr = try!(SocketAddr::from_str(try!(server.get_addr())));
+                    Ok((ServerId(server.get_id()), addr))
+                })
+                .collect()
+        },
+        _ => Err(Error::Raft(RaftError::UnsupportedRequest)),
+    }
+}
+
 // Proposal
 
 pub fn proposal_request(entry: &[u8]) -> MallocMessageBuilder {
@@ -234,6 +456,26 @@ pub fn command_response_success(data: &[u8]) -> Rc<MallocMessageBuilder> {
     Rc::new(message)
 }
 
+pub fn command_response_committed() -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_response::Builder>()
+               .init_proposal()
+               .set_committed(());
+    }
+    Rc::new(message)
+}
+
+pub fn command_response_rejected(reason: &[u8]) -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_response::Builder>()
+               .init_proposal()
+               .set_rejected(reason);
+    }
+    Rc::new(message)
+}
+
 pub fn command_response_unknown_leader() -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
@@ -244,6 +486,36 @@ pub fn command_response_unknown_leader() -> Rc<MallocMessageBuilder> {
     Rc::new(message)
 }
 
+pub fn command_response_not_committed() -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_response::Builder>()
+               .init_proposal()
+               .set_not_committed(());
+    }
+    Rc::new(message)
+}
+
+pub fn command_response_command_too_large() -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_response::Builder>()
+               .init_proposal()
+               .set_command_too_large(());
+    }
+    Rc::new(message)
+}
+
+pub fn command_response_overloaded() -> Rc<MallocMessageBuilder> {
+    let mut message = MallocMessageBuilder::new_default();
+    {
+        message.init_root::<client_response::Builder>()
+               .init_proposal()
+               .set_overloaded(());
+    }
+    Rc::new(message)
+}
+
 pub fn command_response_not_leader(leader_hint: &SocketAddr) -> Rc<MallocMessageBuilder> {
     let mut message = MallocMessageBuilder::new_default();
     {
@@ -253,3 +525,83 @@ pub fn command_response_not_leader(leader_hint: &SocketAddr) -> Rc<MallocMessage
     }
     Rc::new(message)
 }
+
+/// Decodes a proposal or query response, returning the state machine's result, or an empty result
+/// for a committed empty command. An error is returned if the command was rejected or not
+/// committed, if it was sent to a server which is not the leader, or if the message is malformed.
+pub fn decode_command_response<R>(message: &R) -> Result<Vec<u8>> where R: MessageReader {
+    let status = match try!(try!(message.get_root::<client_response::Reader>()).which()) {
+        client_response::Which::Proposal(status) | client_response::Which::Query(status) => {
+            try!(status)
+        },
+        client_response::Which::Ping(..) |
+        client_response::Which::Subscription(..) |
+        client_response::Which::Configuration(..) => {
+            return Err(Error::Raft(RaftError::UnsupportedRequest))
+        },
+    };
+    match try!(status.which()) {
+        command_response::Which::Success(data) => Ok(Vec::from(try!(data))),
+        command_response::Which::Committed(..) => Ok(Vec::new()),
+        command_response::Which::Rejected(reason) => {
+            Err(Error::Raft(RaftError::ProposalRejected(Vec::from(try!(reason)))))
+        },
+        command_response::Which::UnknownLeader(..) | command_response::Which::NotLeader(..) => {
+            Err(Error::Raft(RaftError::NotLeader))
+        },
+        command_response::Which::NotCommitted(..) => Err(Error::Raft(RaftError::NotCommitted)),
+        command_response::Which::CommandTooLarge(..) => Err(Error::Raft(RaftError::CommandTooLarge)),
+        command_response::Which::Overloaded(..) => Err(Error::Raft(RaftError::Overloaded)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    use capnp::{MessageReader, ReaderOptions};
+    use capnp::serialize;
+
+    use {ClientId, LogIndex, Term};
+    use messages_capnp::message;
+    use super::*;
+
+    /// Tests that every field of an AppendEntries request survives a round trip through
+    /// serialization.
+    #[test]
+    fn test_append_entries_request_round_trip() {
+        setup_test!("test_append_entries_request_round_trip");
+        let client = ClientId::new();
+        let mut origins = BTreeMap::new();
+        origins.insert(LogIndex(4), client);
+        let entries: &[(Term, &[u8])] = &[(Term(2), b"foo"), (Term(3), b"bar")];
+        let request = append_entries_request(Term(3), LogIndex(2), Term(1), entries, &origins,
+                                             LogIndex(3), 7, 40);
+
+        let mut buf = Cursor::new(Vec::new());
+        serialize::write_message(&mut buf, &*request).unwrap();
+        buf.set_position(0);
+        let reader = serialize::read_message(&mut buf, ReaderOptions::new()).unwrap();
+        let request = match reader.get_root::<message::Reader>().unwrap().which().unwrap() {
+            message::Which::AppendEntriesRequest(request) => request.unwrap(),
+            _ => panic!("unexpected message"),
+        };
+        assert_eq!(3, request.get_term());
+        assert_eq!(2, request.get_prev_log_index());
+        assert_eq!(1, request.get_prev_log_term());
+        assert_eq!(3, request.get_leader_commit());
+        assert_eq!(7, request.get_read_round());
+        assert_eq!(40, request.get_round_trip_ms());
+
+        let entries = request.get_entries().unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!(2, entries.get(0).get_term());
+        assert_eq!(b"foo", entries.get(0).get_data().unwrap());
+        assert!(!entries.get(0).has_client());
+        assert_eq!(3, entries.get(1).get_term());
+        assert_eq!(b"bar", entries.get(1).get_data().unwrap());
+        assert_eq!(client, ClientId::from_bytes(entries.get(1).get_client().unwrap()).unwrap());
+    }
+}
@@ -1,42 +1,53 @@
-use std::{error, fmt, result};
+use std::{cmp, error, fmt, result};
+use std::cell::Cell;
 
-use persistent_log::Log;
+use persistent_log::{entry_checksum, HardState, Log, LogMetrics};
 use LogIndex;
 use ServerId;
 use Term;
 
 /// This is a `Log` implementation that stores entries in a simple in-memory vector. Other data
-/// is stored in a struct. It is chiefly intended for testing.
+/// is stored in a struct. It is chiefly intended for testing. Each entry is stored with its
+/// checksum, which is verified when the entry is read.
+///
+/// Reading an entry which does not exist fails with `Error::Missing`.
 ///
 /// # Panic
 ///
-/// No bounds checking is performed and attempted access to non-existing log
-/// indexes will panic.
+/// Appending entries which would leave a gap in the log panics.
 #[derive(Clone, Debug)]
 pub struct MemLog {
     current_term: Term,
     voted_for: Option<ServerId>,
-    entries: Vec<(Term, Vec<u8>)>,
+    entries: Vec<(Term, Vec<u8>, u32)>,
+    /// The number of entries read, all of which are served from memory.
+    entry_reads: Cell<u64>,
 }
 
-/// Non-instantiable error type for MemLog
-pub enum Error { }
-
-impl fmt::Display for Error {
-    fn fmt(&self, _fmt: &mut fmt::Formatter) -> fmt::Result {
-        unreachable!()
-    }
+/// Error type for MemLog
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The entry at the index does not match its checksum.
+    Corruption(LogIndex),
+    /// The log holds no entry at the index.
+    Missing(LogIndex),
 }
 
-impl fmt::Debug for Error {
-    fn fmt(&self, _fmt: &mut fmt::Formatter) -> fmt::Result {
-        unreachable!()
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Corruption(index) => write!(fmt, "log entry {} is corrupt", index),
+            Error::Missing(index) => write!(fmt, "log entry {} does not exist", index),
+        }
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        unreachable!()
+        match *self {
+            Error::Corruption(..) => "corrupt log entry",
+            Error::Missing(..) => "missing log entry",
+        }
     }
 }
 
@@ -47,8 +58,17 @@ impl MemLog {
             current_term: Term(0),
             voted_for: None,
             entries: Vec::new(),
+            entry_reads: Cell::new(0),
         }
     }
+
+    /// Flips a bit of the stored entry at the provided index, without updating its checksum. The
+    /// entry must not be empty.
+    #[cfg(test)]
+    pub fn corrupt_entry(&mut self, index: LogIndex) {
+        let bytes = &mut self.entries[(index - 1).as_u64() as usize].1;
+        bytes[0] ^= 1;
+    }
 }
 
 impl Log for MemLog {
@@ -92,17 +112,73 @@ impl Log for MemLog {
     }
 
     fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), Error> {
-        let (term, ref bytes) = self.entries[(index - 1).as_u64() as usize];
+        if index == LogIndex(0) || index.as_u64() > self.entries.len() as u64 {
+            return Err(Error::Missing(index));
+        }
+        self.entry_reads.set(self.entry_reads.get() + 1);
+        let (term, ref bytes, checksum) = self.entries[(index - 1).as_u64() as usize];
+        if entry_checksum(term, bytes) != checksum {
+            return Err(Error::Corruption(index));
+        }
         Ok((term, &bytes))
     }
 
+    fn entries(&self, lo: LogIndex, hi: LogIndex) -> result::Result<Vec<(Term, &[u8])>, Error> {
+        if lo >= hi {
+            return Ok(Vec::new());
+        }
+        let len = self.entries.len() as u64;
+        if lo == LogIndex(0) {
+            return Err(Error::Missing(lo));
+        }
+        if hi.as_u64() > len + 1 {
+            return Err(Error::Missing(LogIndex(cmp::max(lo.as_u64(), len + 1))));
+        }
+        let (lo, hi) = ((lo - 1).as_u64() as usize, (hi - 1).as_u64() as usize);
+        self.entry_reads.set(self.entry_reads.get() + (hi - lo) as u64);
+        self.entries[lo..hi]
+            .iter()
+            .enumerate()
+            .map(|(offset, &(term, ref bytes, checksum))| {
+                if entry_checksum(term, bytes) != checksum {
+                    return Err(Error::Corruption(LogIndex((lo + offset + 1) as u64)));
+                }
+                Ok((term, &bytes[..]))
+            })
+            .collect()
+    }
+
     fn append_entries(&mut self,
                       from: LogIndex,
                       entries: &[(Term, &[u8])])
                       -> result::Result<(), Error> {
         assert!(self.latest_log_index().unwrap() + 1 >= from);
         self.entries.truncate((from - 1).as_u64() as usize);
-        Ok(self.entries.extend(entries.iter().map(|&(term, command)| (term, command.to_vec()))))
+        Ok(self.entries.extend(entries.iter().map(|&(term, command)| {
+            (term, command.to_vec(), entry_checksum(term, command))
+        })))
+    }
+
+    fn flush(&mut self) -> result::Result<(), Error> {
+        // Entries are held in memory; there is nothing to make durable.
+        Ok(())
+    }
+
+    fn hard_state(&self) -> result::Result<HardState, Error> {
+        Ok(HardState {
+            current_term: self.current_term,
+            voted_for: self.voted_for,
+            latest_log_index: LogIndex(self.entries.len() as u64),
+            latest_log_term: self.entries.last().map_or(Term(0), |&(term, _, _)| term),
+        })
+    }
+
+    fn metrics(&self) -> LogMetrics {
+        LogMetrics {
+            entry_reads: self.entry_reads.get(),
+            cache_hits: self.entry_reads.get(),
+            cache_misses: 0,
+        }
     }
 }
 
@@ -113,7 +189,7 @@ mod test {
     use LogIndex;
     use ServerId;
     use Term;
-    use persistent_log::Log;
+    use persistent_log::{HardState, Log};
 
     #[test]
     fn test_current_term() {
@@ -168,4 +244,59 @@ mod test {
         assert_eq!((Term::from(2), &*vec![3u8]), store.entry(LogIndex::from(3)).unwrap());
         assert_eq!((Term::from(3), &*vec![4u8]), store.entry(LogIndex::from(4)).unwrap());
     }
+
+    #[test]
+    fn test_entries() {
+        let mut store = MemLog::new();
+        store.append_entries(LogIndex(1), &[(Term(1), &[1]), (Term(1), &[2]), (Term(2), &[3]),
+                                            (Term(2), &[4])]).unwrap();
+        assert_eq!(vec![(Term(1), &[2u8][..]), (Term(2), &[3u8][..])],
+                   store.entries(LogIndex(2), LogIndex(4)).unwrap());
+        assert_eq!(4, store.entries(LogIndex(1), LogIndex(5)).unwrap().len());
+        assert!(store.entries(LogIndex(5), LogIndex(5)).unwrap().is_empty());
+
+        // A reversed range is empty.
+        assert!(store.entries(LogIndex(3), LogIndex(2)).unwrap().is_empty());
+        assert!(store.entries(LogIndex(9), LogIndex(1)).unwrap().is_empty());
+
+        // Ranges reaching outside of the log fail with the first missing index.
+        assert_eq!(Err(Error::Missing(LogIndex(0))), store.entries(LogIndex(0), LogIndex(2)));
+        assert_eq!(Err(Error::Missing(LogIndex(5))), store.entries(LogIndex(3), LogIndex(6)));
+        assert_eq!(Err(Error::Missing(LogIndex(7))), store.entries(LogIndex(7), LogIndex(9)));
+        assert_eq!(Err(Error::Missing(LogIndex(0))), store.entry(LogIndex(0)));
+        assert_eq!(Err(Error::Missing(LogIndex(5))), store.entry(LogIndex(5)));
+    }
+
+    #[test]
+    fn test_hard_state() {
+        let mut store = MemLog::new();
+        assert_eq!(HardState {
+                       current_term: Term(0),
+                       voted_for: None,
+                       latest_log_index: LogIndex(0),
+                       latest_log_term: Term(0),
+                   },
+                   store.hard_state().unwrap());
+
+        store.set_current_term(Term(2)).unwrap();
+        store.set_voted_for(ServerId::from(1)).unwrap();
+        store.append_entries(LogIndex(1), &[(Term(1), &[1]), (Term(2), &[2])]).unwrap();
+        assert_eq!(HardState {
+                       current_term: Term(2),
+                       voted_for: Some(ServerId::from(1)),
+                       latest_log_index: LogIndex(2),
+                       latest_log_term: Term(2),
+                   },
+                   store.hard_state().unwrap());
+    }
+
+    #[test]
+    fn test_corrupt_entry() {
+        let mut store = MemLog::new();
+        store.append_entries(LogIndex(1), &[(Term(1), &[1]), (Term(1), &[2])]).unwrap();
+        store.corrupt_entry(LogIndex(2));
+        assert_eq!((Term(1), &*vec![1u8]), store.entry(LogIndex(1)).unwrap());
+        assert_eq!(Err(Error::Corruption(LogIndex(2))), store.entry(LogIndex(2)));
+        assert!(store.entries(LogIndex(1), LogIndex(3)).is_err());
+    }
 }
@@ -6,12 +6,16 @@
 //! *Note:* Your consuming application should not necessarily interface with this data. It is meant
 //! for internal use by the library, we simply chose not to be opinionated about how data is stored.
 mod mem;
+#[cfg(test)]
+mod test;
 
 use std::error;
 use std::fmt::Debug;
 use std::result;
 
 pub use persistent_log::mem::{MemLog, Error};
+#[cfg(test)]
+pub use persistent_log::test::TestLog;
 
 use LogIndex;
 use Term;
@@ -58,4 +62,72 @@ pub trait Log: Clone + Debug + Send + 'static {
 
     /// Appends the provided entries to the log beginning at the given index.
     fn append_entries(&mut self, from: LogIndex, entries: &[(Term, &[u8])]) -> result::Result<(), Self::Error>;
+
+    /// Makes all previously appended entries durable. Entries are only acknowledged to the leader,
+    /// or counted toward the commit majority by the leader itself, after a call to `flush`
+    /// returns, so implementations may buffer appended entries until then. Called frequently;
+    /// should be cheap when there is nothing to flush. Logs which make entries durable as they are
+    /// appended implement it as a no-op.
+    fn flush(&mut self) -> result::Result<(), Self::Error>;
+
+    /// Returns counters describing how entries have been read from the log, for sizing any
+    /// in-memory cache the implementation keeps. Implementations which do not track reads return
+    /// zeroed counters.
+    fn metrics(&self) -> LogMetrics {
+        LogMetrics::default()
+    }
+
+    /// Returns the persisted term, vote, and log bounds in a single read. Called when a
+    /// `Consensus` is initialized; implementations backed by slow storage should override it to
+    /// recover the state with one access instead of one per field.
+    fn hard_state(&self) -> result::Result<HardState, Self::Error> {
+        Ok(HardState {
+            current_term: try!(self.current_term()),
+            voted_for: try!(self.voted_for()),
+            latest_log_index: try!(self.latest_log_index()),
+            latest_log_term: try!(self.latest_log_term()),
+        })
+    }
+}
+
+/// The persisted Raft state which must be recovered before a server can take part in consensus.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HardState {
+    /// The latest known term.
+    pub current_term: Term,
+    /// The candidate voted for in the current term (or none).
+    pub voted_for: Option<ServerId>,
+    /// The index of the latest persisted log entry (0 if the log is empty).
+    pub latest_log_index: LogIndex,
+    /// The term of the latest persisted log entry (0 if the log is empty).
+    pub latest_log_term: Term,
+}
+
+/// Counters of entry reads made against a `Log`. A read is either served from an in-memory cache
+/// (a hit) or from the backing store (a miss); logs which hold every entry in memory count all
+/// reads as hits.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogMetrics {
+    /// The number of entries read.
+    pub entry_reads: u64,
+    /// The number of entry reads served from an in-memory cache.
+    pub cache_hits: u64,
+    /// The number of entry reads served from the backing store.
+    pub cache_misses: u64,
+}
+
+/// Computes a CRC-32 (IEEE) checksum of a log entry. `Log` implementations should store the
+/// checksum alongside each entry, and return an error from `entry` if it no longer matches, so
+/// that corrupted data is never applied to the state machine.
+pub fn entry_checksum(term: Term, data: &[u8]) -> u32 {
+    let term = term.as_u64();
+    let term_bytes = (0..8).map(|i| (term >> (8 * i)) as u8);
+    let mut crc = !0u32;
+    for byte in term_bytes.chain(data.iter().cloned()) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
 }
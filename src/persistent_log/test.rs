@@ -0,0 +1,157 @@
+use std::{io, result};
+use std::cell::Cell;
+
+use persistent_log::{HardState, Log, LogMetrics, MemLog};
+use LogIndex;
+use ServerId;
+use Term;
+
+/// A `Log` for tests which wraps a `MemLog` and records how it is used: the latest index made
+/// durable by `flush`, reads of single entries and of entry ranges, and reads of its persisted
+/// state through the per-field accessors and through `hard_state`. It can be configured to cache only the latest entries, counting reads
+/// of older entries as cache misses, and to fail appends, as if its disk were full.
+#[derive(Clone, Debug)]
+pub struct TestLog {
+    log: MemLog,
+    /// The number of latest entries held in the cache, or `None` to cache every entry.
+    pub cache_window: Option<u64>,
+    /// Whether appends fail.
+    pub fail_appends: bool,
+    /// The latest log index made durable by `flush`.
+    pub flushed_index: LogIndex,
+    /// The number of entry reads served from the cache.
+    pub cache_hits: Cell<u64>,
+    /// The number of entry reads which fell outside of the cache.
+    pub cache_misses: Cell<u64>,
+    /// The number of calls to `entry`.
+    pub point_reads: Cell<u64>,
+    /// The number of calls to `entries`.
+    pub range_reads: Cell<u64>,
+    /// The number of reads through `current_term`, `voted_for`, `latest_log_index`, and
+    /// `latest_log_term`.
+    pub field_reads: Cell<u64>,
+    /// The number of reads through `hard_state`.
+    pub hard_state_reads: Cell<u64>,
+}
+
+/// Converts an error of the wrapped `MemLog`.
+fn mem_error(error: <MemLog as Log>::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+impl TestLog {
+
+    /// Returns a `TestLog` wrapping the provided log, which caches every entry and does not
+    /// fail.
+    pub fn new(log: MemLog) -> TestLog {
+        TestLog {
+            log: log,
+            cache_window: None,
+            fail_appends: false,
+            flushed_index: LogIndex(0),
+            cache_hits: Cell::new(0),
+            cache_misses: Cell::new(0),
+            point_reads: Cell::new(0),
+            range_reads: Cell::new(0),
+            field_reads: Cell::new(0),
+            hard_state_reads: Cell::new(0),
+        }
+    }
+
+    /// Counts a read of a persisted field.
+    fn field_read(&self) {
+        self.field_reads.set(self.field_reads.get() + 1);
+    }
+
+    /// Counts a read of the entry at the index as a cache hit or miss.
+    fn entry_read(&self, index: LogIndex) -> result::Result<(), io::Error> {
+        let latest_log_index = try!(self.log.latest_log_index().map_err(mem_error));
+        match self.cache_window {
+            Some(window) if index + window <= latest_log_index => {
+                self.cache_misses.set(self.cache_misses.get() + 1);
+            },
+            _ => self.cache_hits.set(self.cache_hits.get() + 1),
+        }
+        Ok(())
+    }
+}
+
+impl Log for TestLog {
+
+    type Error = io::Error;
+
+    fn current_term(&self) -> result::Result<Term, io::Error> {
+        self.field_read();
+        self.log.current_term().map_err(mem_error)
+    }
+
+    fn set_current_term(&mut self, term: Term) -> result::Result<(), io::Error> {
+        self.log.set_current_term(term).map_err(mem_error)
+    }
+
+    fn inc_current_term(&mut self) -> result::Result<Term, io::Error> {
+        self.log.inc_current_term().map_err(mem_error)
+    }
+
+    fn voted_for(&self) -> result::Result<Option<ServerId>, io::Error> {
+        self.field_read();
+        self.log.voted_for().map_err(mem_error)
+    }
+
+    fn set_voted_for(&mut self, server: ServerId) -> result::Result<(), io::Error> {
+        self.log.set_voted_for(server).map_err(mem_error)
+    }
+
+    fn latest_log_index(&self) -> result::Result<LogIndex, io::Error> {
+        self.field_read();
+        self.log.latest_log_index().map_err(mem_error)
+    }
+
+    fn latest_log_term(&self) -> result::Result<Term, io::Error> {
+        self.field_read();
+        self.log.latest_log_term().map_err(mem_error)
+    }
+
+    fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), io::Error> {
+        self.point_reads.set(self.point_reads.get() + 1);
+        try!(self.entry_read(index));
+        self.log.entry(index).map_err(mem_error)
+    }
+
+    fn entries(&self, lo: LogIndex, hi: LogIndex) -> result::Result<Vec<(Term, &[u8])>, io::Error> {
+        self.range_reads.set(self.range_reads.get() + 1);
+        let entries = try!(self.log.entries(lo, hi).map_err(mem_error));
+        for offset in 0..entries.len() as u64 {
+            try!(self.entry_read(lo + offset));
+        }
+        Ok(entries)
+    }
+
+    fn append_entries(&mut self,
+                      from: LogIndex,
+                      entries: &[(Term, &[u8])])
+                      -> result::Result<(), io::Error> {
+        if self.fail_appends {
+            return Err(io::Error::new(io::ErrorKind::Other, "no space left on device"));
+        }
+        self.log.append_entries(from, entries).map_err(mem_error)
+    }
+
+    fn flush(&mut self) -> result::Result<(), io::Error> {
+        self.flushed_index = try!(self.log.latest_log_index().map_err(mem_error));
+        Ok(())
+    }
+
+    fn metrics(&self) -> LogMetrics {
+        LogMetrics {
+            entry_reads: self.cache_hits.get() + self.cache_misses.get(),
+            cache_hits: self.cache_hits.get(),
+            cache_misses: self.cache_misses.get(),
+        }
+    }
+
+    fn hard_state(&self) -> result::Result<HardState, io::Error> {
+        self.hard_state_reads.set(self.hard_state_reads.get() + 1);
+        self.log.hard_state().map_err(mem_error)
+    }
+}
@@ -3,44 +3,253 @@
 //! `StateMachine` consensus. A `Server` may be a `Leader`, `Follower`, or `Candidate` at any given
 //! time as described by the Raft Consensus Algorithm.
 
-use std::{fmt, io};
-use std::str::FromStr;
-use std::collections::HashMap;
+use std::{cmp, fmt};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net::SocketAddr;
+use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
 
-use mio::tcp::TcpListener;
 use mio::util::Slab;
 use mio::{
     EventLoop,
     EventSet,
     Handler,
+    Sender,
     Token,
 };
 use mio::Timeout as TimeoutHandle;
 use capnp::{
+    MallocMessageBuilder,
     MessageReader,
 };
 
 use ClientId;
+use Config;
+use LogIndex;
 use Result;
 use Error;
 use RaftError;
 use ServerId;
-use messages;
-use messages_capnp::connection_preamble;
-use consensus::{Consensus, Actions, ConsensusTimeout};
+use apply::{self, ApplyOutput};
+use backoff::Backoff;
+use buffer_pool::BufferPool;
+use messages::{self, Preamble};
+use consensus::{Consensus, Actions, ConsensusTimeout, ElectionMetrics};
 use state_machine::StateMachine;
-use persistent_log::Log;
-use connection::{Connection, ConnectionKind};
+use persistent_log::{Log, LogMetrics};
+use connection::{self, Connection, ConnectionKind, Fault};
+use transport::{Listener, TcpTransport, Transport};
 
-const LISTENER: Token = Token(0);
+/// The maximum number of buffers retained for serializing outgoing messages.
+const BUFFER_POOL_SIZE: usize = 256;
+/// The maximum size of a buffer retained for serializing outgoing messages.
+const BUFFER_POOL_MAX_BUFFER_BYTES: usize = 1024 * 1024;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 
 pub enum ServerTimeout {
     Consensus(ConsensusTimeout),
     Reconnect(Token),
+    Keepalive,
+    Proposal(ClientId),
+    WaitApplied(u64),
+}
+
+/// Commands which may be sent to a running `Server` through its event loop channel. Each command
+/// carries a channel on which the `Server` sends its response.
+pub enum ServerCommand {
+    /// Requests whether the server currently believes it is the leader of its term.
+    IsLeader {
+        response: mpsc::Sender<bool>,
+    },
+    /// Changes the address at which a peer is reached. The peer's current connection is left
+    /// untouched; the new address is used the next time the connection is reestablished.
+    UpdatePeerAddress {
+        id: ServerId,
+        addr: SocketAddr,
+    },
+    /// Relinquishes leadership, if held, without designating a successor.
+    StepDown,
+    /// Proposes a command to the cluster through this server, which must be the leader. The
+    /// response carries the state machine's result once the command is applied, or a `Timeout`
+    /// error if it is not applied within `timeout_ms` milliseconds.
+    Propose {
+        command: Vec<u8>,
+        timeout_ms: u64,
+        response: mpsc::Sender<Result<Vec<u8>>>,
+    },
+    /// Waits until the entry at `index` has been applied to the local state machine. The response
+    /// is sent once it is applied, or carries a `Timeout` error if it is not applied within
+    /// `timeout_ms` milliseconds.
+    WaitApplied {
+        index: LogIndex,
+        timeout_ms: u64,
+        response: mpsc::Sender<Result<()>>,
+    },
+    /// Requests the number of milliseconds since the latest AppendEntries exchange with each peer.
+    LastContact {
+        response: mpsc::Sender<HashMap<ServerId, Option<u64>>>,
+    },
+    /// Requests the peers which are presumed dead after repeated failed connection attempts.
+    DeadPeers {
+        response: mpsc::Sender<HashSet<ServerId>>,
+    },
+    /// Requests the number of entries in the server's log beyond the commit index.
+    UncommittedEntries {
+        response: mpsc::Sender<u64>,
+    },
+    /// Requests the log's entry read counters.
+    LogMetrics {
+        response: mpsc::Sender<LogMetrics>,
+    },
+    /// Requests the counters of the elections the server has taken part in.
+    ElectionMetrics {
+        response: mpsc::Sender<ElectionMetrics>,
+    },
+    /// Requests the leader of the current term, as known to the server.
+    CurrentLeader {
+        response: mpsc::Sender<Option<ServerId>>,
+    },
+    /// The output of the server's apply worker thread, when `Config::apply_on_worker` is set.
+    /// Sent only by the server itself.
+    Applied(ApplyOutput),
+    /// Stops the server. Its event loop exits with `ShutdownReason::Requested`.
+    Shutdown,
+}
+
+/// The reason a `Server`'s event loop stopped running.
+#[derive(Debug)]
+pub enum ShutdownReason {
+    /// The server was asked to stop through `ServerCommand::Shutdown`.
+    Requested,
+    /// The listening socket failed and could not be reregistered, so the server could no longer
+    /// accept connections.
+    ListenerError,
+    /// The event loop failed.
+    Fatal(Error),
+}
+
+/// A handle to a `Server` running in a background thread, used to issue commands to it.
+#[derive(Clone)]
+pub struct ServerHandle {
+    sender: Sender<ServerCommand>,
+}
+
+impl ServerHandle {
+
+    /// Returns whether the server currently believes it is the leader of its term.
+    ///
+    /// The answer reflects only the server's local view of the cluster, and may be briefly stale.
+    /// For instance, a leader which has been deposed will continue to report `true` until it
+    /// hears from a peer with a newer term.
+    pub fn is_leader(&self) -> Result<bool> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::IsLeader { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Changes the address at which the peer is reached. The new address takes effect the next
+    /// time the server reconnects to the peer.
+    pub fn update_peer_address(&self, id: ServerId, addr: SocketAddr) -> Result<()> {
+        self.send(ServerCommand::UpdatePeerAddress { id: id, addr: addr })
+    }
+
+    /// Asks the server to relinquish leadership, if it holds it. Another server will be elected
+    /// once its election timeout fires.
+    pub fn step_down(&self) -> Result<()> {
+        self.send(ServerCommand::StepDown)
+    }
+
+    /// Asks the server to stop. Its thread exits with `ShutdownReason::Requested`.
+    pub fn shutdown(&self) -> Result<()> {
+        self.send(ServerCommand::Shutdown)
+    }
+
+    /// Proposes a command to the cluster, and blocks until it is applied to the state machine,
+    /// returning the result. The server must be the leader. If the command is not applied within
+    /// `timeout_ms` milliseconds a `Timeout` error is returned, though the command may still be
+    /// applied later.
+    pub fn propose_and_wait(&self, command: &[u8], timeout_ms: u64) -> Result<Vec<u8>> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::Propose {
+            command: command.to_vec(),
+            timeout_ms: timeout_ms,
+            response: tx,
+        }));
+        rx.recv().unwrap_or(Err(Error::Raft(RaftError::ServerUnavailable)))
+    }
+
+    /// Blocks until the entry at `index` has been applied to the server's state machine, whether
+    /// the server is the leader or a follower. Useful for reading the effect of a command through
+    /// the local state machine. A `Timeout` error is returned if the entry is not applied within
+    /// `timeout_ms` milliseconds.
+    pub fn wait_applied(&self, index: LogIndex, timeout_ms: u64) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::WaitApplied {
+            index: index,
+            timeout_ms: timeout_ms,
+            response: tx,
+        }));
+        rx.recv().unwrap_or(Err(Error::Raft(RaftError::ServerUnavailable)))
+    }
+
+    /// Returns the number of milliseconds since the server last exchanged AppendEntries messages
+    /// with each peer, or `None` for peers it has not heard from. A leader tracks the responses
+    /// of every follower; a follower tracks only the leader. A peer whose time keeps growing is
+    /// unreachable, or, for a follower, is no longer the leader.
+    pub fn last_contact(&self) -> Result<HashMap<ServerId, Option<u64>>> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::LastContact { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Returns the peers which the server has failed to connect to `Config::peer_dead_after_reconnects`
+    /// times in a row, and has not heard from since. The server keeps trying to reconnect to them.
+    pub fn dead_peers(&self) -> Result<HashSet<ServerId>> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::DeadPeers { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Returns the number of entries in the server's log which are not yet known to be committed.
+    /// On a leader, a count which keeps growing indicates that it can not reach a majority.
+    pub fn uncommitted_entries(&self) -> Result<u64> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::UncommittedEntries { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Returns counters of the entries read from the server's log, and how many of those reads
+    /// were served from the log's in-memory cache.
+    pub fn log_metrics(&self) -> Result<LogMetrics> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::LogMetrics { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Returns counters of the elections the server has started, won, and lost, and of the votes
+    /// it has granted and received. A count of elections which keeps rising indicates unstable
+    /// leadership.
+    pub fn election_metrics(&self) -> Result<ElectionMetrics> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::ElectionMetrics { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Returns the leader of the current term: the server itself while it leads, or the peer it
+    /// last received AppendEntries from in the current term. `None` during an election, or before
+    /// the leader has been heard from. Clients may be redirected to the leader's address.
+    pub fn current_leader(&self) -> Result<Option<ServerId>> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(ServerCommand::CurrentLeader { response: tx }));
+        rx.recv().map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
+
+    /// Sends a command to the server's event loop.
+    fn send(&self, command: ServerCommand) -> Result<()> {
+        self.sender.send(command).map_err(|_| Error::Raft(RaftError::ServerUnavailable))
+    }
 }
 
 /// The `Server` is responsible for receiving events from peer `Server` instance or clients,
@@ -60,11 +269,21 @@ pub struct Server<L, M> where L: Log, M: StateMachine {
     /// Id of this server.
     id: ServerId,
 
+    /// Tunable parameters.
+    config: Config,
+
     /// Raft state machine consensus.
     consensus: Consensus<L, M>,
 
-    /// Connection listener.
-    listener: TcpListener,
+    /// The transport through which connections are made and accepted.
+    transport: Box<Transport>,
+
+    /// Connection listeners, registered under the tokens preceding those of the connections. The
+    /// first listens on the address the server was created with.
+    listeners: Vec<Listener>,
+
+    /// The addresses the listeners are bound to, in the same order.
+    local_addrs: Vec<SocketAddr>,
 
     /// Collection of connections indexed by token.
     connections: Slab<Connection>,
@@ -80,50 +299,220 @@ pub struct Server<L, M> where L: Log, M: StateMachine {
 
     /// Currently registered reconnection timeouts.
     reconnection_timeouts: HashMap<Token, TimeoutHandle>,
+
+    /// Reconnection backoff of each peer. Kept here rather than in the peer's `Connection`, which
+    /// is replaced when the peer reconnects to this server, so that the backoff of a peer whose
+    /// connections keep failing grows until the peer sends a message.
+    peer_backoffs: HashMap<ServerId, Backoff>,
+
+    /// The peers presumed dead after repeated failed connection attempts.
+    dead_peers: HashSet<ServerId>,
+
+    /// Proposals made through `ServerCommand::Propose` awaiting a response, indexed by the client
+    /// id they were made under, along with their deadline timeouts.
+    proposals: HashMap<ClientId, (mpsc::Sender<Result<Vec<u8>>>, TimeoutHandle)>,
+
+    /// Buffers into which outgoing messages are serialized.
+    buffer_pool: BufferPool,
+
+    /// Requests made through `ServerCommand::WaitApplied` awaiting the application of an entry,
+    /// indexed by request id, along with the awaited index and deadline timeouts.
+    applied_waits: HashMap<u64, (LogIndex, mpsc::Sender<Result<()>>, TimeoutHandle)>,
+
+    /// The id of the next `ServerCommand::WaitApplied` request.
+    next_wait_id: u64,
+
+    /// Whether the consensus module has been initialized and the keepalive scheduled.
+    started: bool,
+
+    /// The reason the event loop was shut down, once it has been.
+    shutdown_reason: Option<ShutdownReason>,
 }
 
 /// The implementation of the Server.
 impl<L, M> Server<L, M> where L: Log, M: StateMachine {
 
-    /// Creates a new instance of the server.
-    /// *Gotcha:* `peers` must not contain the local `id`.
-    fn new(id: ServerId,
-           addr: SocketAddr,
-           peers: HashMap<ServerId, SocketAddr>,
-           store: L,
-           state_machine: M) -> Result<(Server<L, M>, EventLoop<Server<L, M>>)> {
+    /// Creates a new instance of the server, along with the event loop which drives it.
+    /// *Gotcha:* `peers` must not contain the local `id`, no two servers may share an address, and
+    /// `config.max_connections` must be greater than the number of peers.
+    pub fn new(id: ServerId,
+               addr: SocketAddr,
+               peers: HashMap<ServerId, SocketAddr>,
+               store: L,
+               state_machine: M,
+               config: Config) -> Result<(Server<L, M>, EventLoop<Server<L, M>>)> {
+        let mut event_loop = try!(EventLoop::<Server<L, M>>::new());
+        let server = try!(Server::with_event_loop(id, addr, peers, store, state_machine, config,
+                                                  &mut event_loop));
+        Ok((server, event_loop))
+    }
+
+    /// Creates a new instance of the server, registering its sockets with the provided event
+    /// loop. The server does not take part in consensus until it is driven through
+    /// `run_with_event_loop` or `poll_once`.
+    ///
+    /// See `Server::new` for the restrictions on `peers`.
+    pub fn with_event_loop(id: ServerId,
+                           addr: SocketAddr,
+                           peers: HashMap<ServerId, SocketAddr>,
+                           store: L,
+                           state_machine: M,
+                           config: Config,
+                           event_loop: &mut EventLoop<Server<L, M>>) -> Result<Server<L, M>> {
+        Server::with_transport(id, addr, peers, store, state_machine, config,
+                               Box::new(TcpTransport), event_loop)
+    }
+
+    /// Creates a new instance of the server which listens and connects through the provided
+    /// transport rather than over TCP, registering its listeners and connections with the provided
+    /// event loop. Used by tests to connect servers through a `LocalTransport`.
+    ///
+    /// See `Server::new` for the restrictions on `peers`.
+    fn with_transport(id: ServerId,
+                      addr: SocketAddr,
+                      peers: HashMap<ServerId, SocketAddr>,
+                      store: L,
+                      state_machine: M,
+                      config: Config,
+                      transport: Box<Transport>,
+                      event_loop: &mut EventLoop<Server<L, M>>) -> Result<Server<L, M>> {
         if peers.contains_key(&id) {
             return Err(Error::Raft(RaftError::InvalidPeerSet))
         }
+        // Messages are routed by id, but connections are made by address; two ids sharing an
+        // address would receive each other's messages.
+        let mut peer_addrs = HashSet::new();
+        for &peer_addr in peers.values() {
+            if peer_addr == addr || !peer_addrs.insert(peer_addr) {
+                scoped_warn!("address {} is used by more than one server", peer_addr);
+                return Err(Error::Raft(RaftError::InvalidPeerSet))
+            }
+        }
+        // Every peer holds a connection, and at least one must be left for clients; otherwise
+        // evicting a client could never make room for a reconnecting peer.
+        if config.max_connections <= peers.len() {
+            scoped_warn!("max_connections ({}) must be greater than the number of peers ({})",
+                         config.max_connections, peers.len());
+            return Err(Error::Raft(RaftError::InvalidConfig))
+        }
+        // A follower which trails by a full replication window would otherwise have its healthy
+        // connection reset.
+        if config.max_write_buffer_bytes as u64 <= config.max_in_flight_bytes {
+            scoped_warn!("max_write_buffer_bytes ({}) must be greater than max_in_flight_bytes ({})",
+                         config.max_write_buffer_bytes, config.max_in_flight_bytes);
+            return Err(Error::Raft(RaftError::InvalidConfig))
+        }
+        // A message at the size limit would otherwise reset the connection it arrives on.
+        if config.max_read_buffer_bytes as u64 <= config.max_message_bytes {
+            scoped_warn!("max_read_buffer_bytes ({}) must be greater than max_message_bytes ({})",
+                         config.max_read_buffer_bytes, config.max_message_bytes);
+            return Err(Error::Raft(RaftError::InvalidConfig))
+        }
 
-        let consensus = Consensus::new(id, peers.clone(), store, state_machine);
-        let mut event_loop = try!(EventLoop::<Server<L, M>>::new());
-        let listener = try!(TcpListener::bind(&addr));
-        try!(event_loop.register(&listener, LISTENER));
+        let mut consensus = if config.apply_on_worker {
+            let tasks = try!(apply::spawn_worker(id, state_machine, event_loop.channel()));
+            Consensus::with_apply_worker(id, peers.clone(), store, tasks)
+        } else {
+            Consensus::new(id, peers.clone(), store, state_machine)
+        };
+        consensus.set_max_in_flight_bytes(config.max_in_flight_bytes);
+        consensus.set_max_message_bytes(config.max_message_bytes);
+        consensus.set_max_uncommitted_entries(config.max_uncommitted_entries);
+        consensus.set_catch_up_rate(config.catch_up_bytes_per_sec);
+        consensus.set_leader_stickiness(config.leader_stickiness_ms);
+        consensus.set_read_replica(config.read_replica);
+        consensus.set_adaptive_election_timeout(config.election_rtt_multiple,
+                                                config.election_timeout_min_ms,
+                                                config.election_timeout_max_ms);
+        consensus.set_forward_proposals(config.forward_proposals);
+        let mut listeners = Vec::with_capacity(1 + config.additional_addrs.len());
+        let mut local_addrs = Vec::with_capacity(listeners.capacity());
+        for listen_addr in Some(&addr).into_iter().chain(&config.additional_addrs) {
+            let listener = try!(transport.listen(listen_addr));
+            try!(event_loop.register(&listener, Token(listeners.len())));
+            local_addrs.push(try!(listener.local_addr()));
+            listeners.push(listener);
+        }
+        // Advertise the bound address to peers and clients, in case an ephemeral port was
+        // requested.
+        let addr = local_addrs[0];
+        consensus.set_addr(addr);
 
+        let connections = Slab::new_starting_at(Token(listeners.len()), config.max_connections);
         let mut server = Server {
             id: id,
+            config: config,
             consensus: consensus,
-            listener: listener,
-            connections: Slab::new_starting_at(Token(1), 129),
+            transport: transport,
+            listeners: listeners,
+            local_addrs: local_addrs,
+            connections: connections,
             peer_tokens: HashMap::new(),
             client_tokens: HashMap::new(),
             consensus_timeouts: HashMap::new(),
             reconnection_timeouts: HashMap::new(),
+            peer_backoffs: HashMap::new(),
+            dead_peers: HashSet::new(),
+            proposals: HashMap::new(),
+            buffer_pool: BufferPool::new(BUFFER_POOL_SIZE, BUFFER_POOL_MAX_BUFFER_BYTES),
+            applied_waits: HashMap::new(),
+            next_wait_id: 0,
+            started: false,
+            shutdown_reason: None,
         };
 
         for (peer_id, peer_addr) in peers {
+            server.peer_backoffs.insert(peer_id, Backoff::with_duration_range(50, 10000));
+            // A peer which can not be reached yet must not prevent the server from starting;
+            // its connection is retried with backoff like any other reset connection.
+            let connection = match Connection::peer(peer_id, peer_addr, &*server.transport) {
+                Ok(connection) => connection,
+                Err(error) => {
+                    scoped_warn!("unable to connect to peer {} at {}: {}",
+                                 peer_id, peer_addr, error);
+                    Connection::unconnected_peer(peer_id, peer_addr)
+                },
+            };
             let token: Token = try!(server.connections
-                                          .insert(try!(Connection::peer(peer_id, peer_addr)))
+                                          .insert(connection)
                                           .map_err(|_| Error::Raft(RaftError::ConnectionLimitReached)));
             scoped_assert!(server.peer_tokens.insert(peer_id, token).is_none());
 
-            let mut connection = &mut server.connections[token];
-            connection.send_message(messages::server_connection_preamble(id, &addr));
-            try!(connection.register(&mut event_loop, token));
+            let registered = if server.connections[token].is_connected() {
+                let send_bytes = server.config.tcp_send_buffer_bytes;
+                let recv_bytes = server.config.tcp_recv_buffer_bytes;
+                let mut connection = &mut server.connections[token];
+                connection.send_preamble(messages::server_connection_preamble(id, &addr),
+                                         &mut server.buffer_pool);
+                connection.set_nodelay(server.config.tcp_nodelay)
+                          .and_then(|_| connection.set_buffer_sizes(send_bytes, recv_bytes))
+                          .and_then(|_| connection.register(event_loop, token))
+                          .is_ok()
+            } else {
+                false
+            };
+            if !registered {
+                server.reset_connection(event_loop, token);
+            }
         }
 
-        Ok((server, event_loop))
+        Ok(server)
+    }
+
+    /// Returns the address the server is listening on. Useful when the server was bound to port 0
+    /// and the operating system assigned the port.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addrs[0]
+    }
+
+    /// Returns all of the addresses the server is listening on, beginning with `local_addr`.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Returns whether the token belongs to one of the listeners.
+    fn is_listener(&self, token: Token) -> bool {
+        token.as_usize() < self.listeners.len()
     }
 
     /// Runs a new Raft server in the current thread.
@@ -135,18 +524,32 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
     /// * `peers` - The ID and address of all peers in the Raft cluster.
     /// * `store` - The persistent log store.
     /// * `state_machine` - The client state machine to which client commands will be applied.
+    ///
+    /// Returns an error if the server fails to start, or the reason it stopped running.
     pub fn run(id: ServerId,
                addr: SocketAddr,
                peers: HashMap<ServerId, SocketAddr>,
                store: L,
-               state_machine: M) -> Result<()> {
-        let (mut server, mut event_loop) = try!(Server::new(id, addr, peers, store, state_machine));
-        let actions = server.consensus.init();
-        server.execute_actions(&mut event_loop, actions);
-        event_loop.run(&mut server).map_err(From::from)
+               state_machine: M) -> Result<ShutdownReason> {
+        Server::run_with_config(id, addr, peers, store, state_machine, Config::default())
+    }
+
+    /// Runs a new Raft server with the provided configuration in the current thread.
+    ///
+    /// See `Server::run` for a description of the arguments.
+    pub fn run_with_config(id: ServerId,
+                           addr: SocketAddr,
+                           peers: HashMap<ServerId, SocketAddr>,
+                           store: L,
+                           state_machine: M,
+                           config: Config) -> Result<ShutdownReason> {
+        let (mut server, mut event_loop) = try!(Server::new(id, addr, peers, store, state_machine, config));
+        Ok(server.run_with_event_loop(&mut event_loop))
     }
 
-    /// Spawns a new Raft server in a background thread.
+    /// Spawns a new Raft server in a background thread. Returns a handle for issuing commands to
+    /// the server, and the handle of the background thread, which yields the reason the server
+    /// stopped running.
     ///
     /// # Arguments
     ///
@@ -159,18 +562,158 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
                  addr: SocketAddr,
                  peers: HashMap<ServerId, SocketAddr>,
                  store: L,
-                 state_machine: M) -> Result<JoinHandle<Result<()>>> {
-        thread::Builder::new().name(format!("raft::Server({})", id)).spawn(move || {
-            Server::run(id, addr, peers, store, state_machine)
-        }).map_err(From::from)
+                 state_machine: M) -> Result<(ServerHandle, JoinHandle<Result<ShutdownReason>>)> {
+        Server::spawn_with_config(id, addr, peers, store, state_machine, Config::default())
+    }
+
+    /// Spawns a new Raft server with the provided configuration in a background thread.
+    ///
+    /// See `Server::spawn` for a description of the arguments and return value.
+    pub fn spawn_with_config(id: ServerId,
+                             addr: SocketAddr,
+                             peers: HashMap<ServerId, SocketAddr>,
+                             store: L,
+                             state_machine: M,
+                             config: Config)
+                             -> Result<(ServerHandle, JoinHandle<Result<ShutdownReason>>)> {
+        let (tx, rx) = mpsc::channel();
+        let join_handle = try!(thread::Builder::new().name(format!("raft::Server({})", id)).spawn(move || {
+            let (mut server, mut event_loop) = try!(Server::new(id, addr, peers, store, state_machine, config));
+            // The receiver only hangs up if the spawning thread has gone away.
+            let _ = tx.send(event_loop.channel());
+            Ok(server.run_with_event_loop(&mut event_loop))
+        }));
+
+        match rx.recv() {
+            Ok(sender) => Ok((ServerHandle { sender: sender }, join_handle)),
+            // The server failed to start; return the reason.
+            Err(_) => match join_handle.join() {
+                Ok(Err(error)) => Err(error),
+                _ => Err(Error::Raft(RaftError::ServerUnavailable)),
+            },
+        }
+    }
+
+    /// Runs the event loop, which must be the one the server was created with, until the server
+    /// is shut down, returning the reason it was.
+    pub fn run_with_event_loop(&mut self, event_loop: &mut EventLoop<Server<L, M>>) -> ShutdownReason {
+        self.start(event_loop);
+        if let Some(reason) = self.shutdown_reason.take() {
+            return reason;
+        }
+        match event_loop.run(self) {
+            Ok(()) => self.shutdown_reason.take().unwrap_or(ShutdownReason::Requested),
+            Err(error) => ShutdownReason::Fatal(From::from(error)),
+        }
+    }
+
+    /// Runs a single iteration of the event loop, which must be the one the server was created
+    /// with, blocking until an event arrives or a timeout is due. Returns the reason the server
+    /// stopped, once it has; it must not be polled again after that.
+    ///
+    /// The server's timeouts, including its heartbeats and election timeout, fire only while the
+    /// event loop is polled. An embedder must poll the loop continually, and promptly once a
+    /// timeout is due; a server which is not polled for longer than the election timeout
+    /// disrupts the cluster.
+    pub fn poll_once(&mut self, event_loop: &mut EventLoop<Server<L, M>>) -> Option<ShutdownReason> {
+        self.start(event_loop);
+        if self.shutdown_reason.is_some() {
+            return self.shutdown_reason.take();
+        }
+        match event_loop.run_once(self) {
+            Ok(()) => self.shutdown_reason.take(),
+            Err(error) => Some(ShutdownReason::Fatal(From::from(error))),
+        }
+    }
+
+    /// Initializes the consensus module, unless it already has been.
+    fn start(&mut self, event_loop: &mut EventLoop<Server<L, M>>) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        let actions = self.consensus.init();
+        self.execute_actions(event_loop, actions);
+        self.schedule_keepalive(event_loop);
+    }
+
+    /// Handles an error event from the listener by reregistering it, so that the server keeps
+    /// accepting connections. Shuts the server down if the listener can not be reregistered.
+    fn listener_error(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token) {
+        let reregistered = {
+            let listener = &self.listeners[token.as_usize()];
+            scoped_error!("error event from listener {:?}; reregistering", listener.local_addr());
+            let _ = event_loop.deregister(listener);
+            event_loop.register(listener, token)
+                      .map_err(|error| {
+                          scoped_error!("unable to reregister listener {:?}: {}",
+                                        listener.local_addr(), error);
+                      })
+                      .is_ok()
+        };
+        if !reregistered {
+            self.shutdown(event_loop, ShutdownReason::ListenerError);
+        }
+    }
+
+    /// Stops the event loop after the current iteration.
+    fn shutdown(&mut self, event_loop: &mut EventLoop<Server<L, M>>, reason: ShutdownReason) {
+        scoped_info!("shutting down: {:?}", reason);
+        self.shutdown_reason = Some(reason);
+        event_loop.shutdown();
+    }
+
+    /// Stops the event loop after the current iteration because of `error`, unless it is already
+    /// stopping.
+    fn halt(&mut self, event_loop: &mut EventLoop<Server<L, M>>, error: Error) {
+        if self.shutdown_reason.is_none() {
+            self.shutdown(event_loop, ShutdownReason::Fatal(error));
+        }
+    }
+
+    /// Registers `timeout` to fire in `duration` milliseconds. Registering fails only if the event
+    /// loop's timer is full, in which case the server halts and `None` is returned.
+    fn schedule_timeout(&mut self,
+                        event_loop: &mut EventLoop<Server<L, M>>,
+                        timeout: ServerTimeout,
+                        duration: u64)
+                        -> Option<TimeoutHandle> {
+        match event_loop.timeout_ms(timeout, duration) {
+            Ok(handle) => Some(handle),
+            Err(error) => {
+                scoped_error!("unable to register timeout {:?}: {:?}", timeout, error);
+                self.halt(event_loop, Error::Raft(RaftError::TimeoutRegisterFailed));
+                None
+            },
+        }
+    }
+
+    /// Schedules the next check for idle peer connections and stalled reads.
+    fn schedule_keepalive(&mut self, event_loop: &mut EventLoop<Server<L, M>>) {
+        // Checking at half the timeout bounds detection to within 1.5 times the timeout.
+        let timeout_ms = cmp::min(self.config.peer_idle_timeout_ms, self.config.partial_read_timeout_ms);
+        let interval = cmp::max(1, timeout_ms / 2);
+        self.schedule_timeout(event_loop, ServerTimeout::Keepalive, interval);
     }
 
     fn execute_actions(&mut self,
                        event_loop: &mut EventLoop<Server<L, M>>,
                        actions: Actions) {
+        // A consensus module which failed to read or persist its state is halted, and none of its
+        // actions may take effect: they could rest on state which is not durable, or not intact.
+        if let Some(error) = self.consensus.fatal_error().cloned() {
+            self.halt(event_loop, Error::Raft(error));
+            return;
+        }
+        if actions.is_empty() {
+            // Entries may still have been applied, for instance by the apply worker.
+            self.complete_applied_waits(event_loop);
+            return;
+        }
         scoped_trace!("executing actions: {:?}", actions);
         let Actions {
             peer_messages,
+            replaceable_peer_messages,
             client_messages,
             timeouts,
             clear_timeouts,
@@ -182,9 +725,20 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
                 self.connections[token].clear_messages();
             }
         }
-        for (peer, message) in peer_messages {
+        for (index, (peer, message)) in peer_messages.into_iter().enumerate() {
             let token = self.peer_tokens[&peer];
-            if self.connections[token].send_message(message) {
+            if messages::is_request(&message) {
+                self.connections[token].await_response();
+            }
+            let reregister = if replaceable_peer_messages.contains(&index) {
+                self.connections[token].send_replaceable_message(message, &mut self.buffer_pool)
+            } else {
+                self.connections[token].send_message(message, &mut self.buffer_pool)
+            };
+            if self.reset_if_backlogged(event_loop, token) {
+                continue;
+            }
+            if reregister {
                 self.connections[token]
                     .reregister(event_loop, token)
                     .unwrap_or_else(|_| self.reset_connection(event_loop, token));
@@ -192,13 +746,31 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
         }
         for (client, message) in client_messages {
             if let Some(&token) = self.client_tokens.get(&client) {
-                if self.connections[token].send_message(message) {
+                let reregister = self.connections[token]
+                                     .send_message_coalesced(message,
+                                                             &mut self.buffer_pool,
+                                                             self.config.client_write_coalesce_bytes);
+                if self.reset_if_backlogged(event_loop, token) {
+                    continue;
+                }
+                if reregister {
                     self.connections[token]
                         .reregister(event_loop, token)
                         .unwrap_or_else(|_| self.reset_connection(event_loop, token));
                 }
+            } else if let Some((response, handle)) = self.proposals.remove(&client) {
+                event_loop.clear_timeout(handle);
+                let _ = response.send(proposal_result(&message));
+            } else {
+                // The client disconnected before its response was ready. It learns the outcome by
+                // retrying once reconnected.
+                scoped_debug!("dropping response to disconnected client {}", client);
             }
         }
+        // Every message has been queued, so the last one serialized will not be queued again.
+        // Releasing it keeps an idle server from pinning a large broadcast, and lets its buffer
+        // return to the pool once written.
+        self.buffer_pool.forget_serialized();
         if clear_timeouts {
             for (timeout, &handle) in &self.consensus_timeouts {
                 scoped_assert!(event_loop.clear_timeout(handle),
@@ -207,18 +779,46 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
             self.consensus_timeouts.clear();
         }
         for timeout in timeouts {
-            let duration = timeout.duration_ms();
-
-            // Registering a timeout may only fail if the maximum number of timeouts
-            // is already registered, which is by default 65,536. We use a
-            // maximum of one timeout per peer, so this unwrap should be safe.
-            let handle = event_loop.timeout_ms(ServerTimeout::Consensus(timeout), duration)
-                                   .unwrap();
+            let duration = self.consensus.timeout_ms(timeout);
+            let server_timeout = ServerTimeout::Consensus(timeout);
+            let handle = match self.schedule_timeout(event_loop, server_timeout, duration) {
+                Some(handle) => handle,
+                None => return,
+            };
             self.consensus_timeouts
                 .insert(timeout, handle)
                 .map(|handle| scoped_assert!(event_loop.clear_timeout(handle),
                                              "unable to clear timeout: {:?}", timeout));
         }
+        self.complete_applied_waits(event_loop);
+    }
+
+    /// Resets the connection if more than `Config::max_write_buffer_bytes` are queued for writing
+    /// to it, since the remote has stopped reading. Returns whether the connection was reset.
+    fn reset_if_backlogged(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token) -> bool {
+        let queued_bytes = self.connections[token].queued_bytes();
+        if queued_bytes <= self.config.max_write_buffer_bytes {
+            return false;
+        }
+        scoped_warn!("{:?}: {} bytes queued for writing exceeds the limit of {}; resetting",
+                     self.connections[token], queued_bytes, self.config.max_write_buffer_bytes);
+        self.reset_connection(event_loop, token);
+        true
+    }
+
+    /// Responds to the `ServerCommand::WaitApplied` requests whose entries have been applied.
+    fn complete_applied_waits(&mut self, event_loop: &mut EventLoop<Server<L, M>>) {
+        let last_applied = self.consensus.last_applied();
+        let applied: Vec<u64> = self.applied_waits
+                                    .iter()
+                                    .filter(|&(_, &(index, _, _))| index <= last_applied)
+                                    .map(|(&id, _)| id)
+                                    .collect();
+        for id in applied {
+            let (_, response, handle) = self.applied_waits.remove(&id).unwrap();
+            event_loop.clear_timeout(handle);
+            let _ = response.send(Ok(()));
+        }
     }
 
     /// Resets the connection corresponding to the provided token.
@@ -230,19 +830,37 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
     fn reset_connection(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token) {
         let kind = *self.connections[token].kind();
         match kind {
-            ConnectionKind::Peer(..) => {
-                // Crash if reseting the connection fails.
-                let (timeout, handle) = self.connections[token]
-                                            .reset_peer(event_loop, token)
-                                            .unwrap();
-
-                scoped_assert!(self.reconnection_timeouts.insert(token, handle).is_none(),
-                               "timeout already registered: {:?}", timeout);
+            ConnectionKind::Peer(id) => {
+                let (duration, presumed_dead) = {
+                    let backoff = self.peer_backoffs.get_mut(&id).unwrap();
+                    let duration = backoff.next_backoff_ms();
+                    let presumed_dead = self.config
+                                            .peer_dead_after_reconnects
+                                            .map_or(false, |max| backoff.retries() >= max);
+                    (duration, presumed_dead)
+                };
+                if presumed_dead && self.dead_peers.insert(id) {
+                    scoped_warn!("peer {} presumed dead after {} failed connection attempts; \
+                                  continuing to reconnect", id, self.peer_backoffs[&id].retries());
+                }
+                // The server cannot reconnect to the peer without the timeout, so it halts.
+                let reset = self.connections[token].reset_peer(event_loop, token, duration);
+                match reset {
+                    Ok((timeout, handle)) => {
+                        scoped_assert!(self.reconnection_timeouts.insert(token, handle).is_none(),
+                                       "timeout already registered: {:?}", timeout);
+                    },
+                    Err(error) => {
+                        scoped_error!("{:?}: unable to reset: {}", self.connections[token], error);
+                        self.halt(event_loop, error);
+                    },
+                }
             },
             ConnectionKind::Client(ref id) => {
                 self.connections.remove(token).expect("unable to find client connection");
                 scoped_assert!(self.client_tokens.remove(id).is_some(),
                              "client {:?} not connected", id);
+                self.consensus.client_disconnected(*id);
             },
             ConnectionKind::Unknown => {
                 self.connections.remove(token).expect("unable to find unknown connection");
@@ -250,41 +868,97 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
         }
     }
 
+    /// Closes the least recently active client connection to make room for a new connection.
+    /// Peer connections are never evicted.
+    fn evict_client(&mut self, event_loop: &mut EventLoop<Server<L, M>>) {
+        let lru = {
+            let connections = &self.connections;
+            self.client_tokens
+                .values()
+                .cloned()
+                .min_by_key(|&token| connections[token].last_active())
+        };
+        match lru {
+            Some(token) => {
+                scoped_info!("connection limit of {} reached; evicting {:?}",
+                             self.config.max_connections, self.connections[token]);
+                self.reset_connection(event_loop, token);
+            },
+            None => scoped_warn!("connection limit of {} reached with no client connections to evict",
+                                 self.config.max_connections),
+        }
+    }
+
     /// Reads messages from the connection until no more are available.
     ///
     /// If the connection returns an error on any operation, or any message fails to be
     /// deserialized, an error result is returned.
     fn readable(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token) -> Result<()> {
         scoped_trace!("{:?}: readable event", self.connections[token]);
-        // Read messages from the connection until there are no more.
-        while let Some(message) = try!(self.connections[token].readable()) {
+        // Read messages from the connection until there are no more. Handling a message may reset
+        // the connection, for instance if reregistering it to send a response fails, in which case
+        // nothing more is read from it.
+        while self.is_connection_open(token) {
+            let max_bytes = self.config.max_read_buffer_bytes;
+            let message = match try!(self.connections[token].readable(max_bytes)) {
+                Some(message) => message,
+                None => break,
+            };
             match *self.connections[token].kind() {
                 ConnectionKind::Peer(id) => {
+                    if self.connections[token].awaiting_preamble() {
+                        // A dialed peer answers with its own preamble, so that its protocol
+                        // version is checked in both directions.
+                        match try!(decode_preamble(&message)) {
+                            Preamble::Server(peer_id, _) if peer_id == id => {
+                                scoped_debug!("received preamble from peer {}", id);
+                                self.connections[token].preamble_received();
+                            },
+                            _ => return Err(Error::Raft(RaftError::UnexpectedPreamble)),
+                        }
+                        continue;
+                    }
+                    // The peer is responsive; the next reset starts from the initial backoff.
+                    self.peer_backoffs.get_mut(&id).unwrap().reset();
+                    if self.dead_peers.remove(&id) {
+                        scoped_info!("peer {} presumed dead is reachable again", id);
+                    }
                     let mut actions = Actions::new();
-                    self.consensus.apply_peer_message(id, &message, &mut actions);
+                    let result = self.consensus.apply_peer_message(id, &message, &mut actions);
                     self.execute_actions(event_loop, actions);
+                    try!(result);
                 },
                 ConnectionKind::Client(id) => {
                     let mut actions = Actions::new();
-                    self.consensus.apply_client_message(id, &message, &mut actions);
+                    let result = self.consensus.apply_client_message(id, &message, &mut actions);
                     self.execute_actions(event_loop, actions);
+                    try!(result);
                 },
                 ConnectionKind::Unknown => {
-                    let preamble = try!(message.get_root::<connection_preamble::Reader>());
-                    match try!(preamble.get_id().which()) {
-                        connection_preamble::id::Which::Server(peer) => {
-                            let peer = try!(peer);
-                            let peer_id = ServerId(peer.get_id());
-
-                            // Not the source address of this connection, but the
-                            // address the peer tells us it's listening on.
-                            let peer_addr = SocketAddr::from_str(try!(peer.get_addr())).unwrap();
+                    match try!(decode_preamble(&message)) {
+                        // Not the source address of this connection, but the
+                        // address the peer tells us it's listening on.
+                        Preamble::Server(peer_id, peer_addr) => {
                             scoped_debug!("received new connection from {:?} ({})", peer_id, peer_addr);
 
+                            // When two peers dial each other simultaneously, both sides must
+                            // agree on which connection survives: keep the one initiated by the
+                            // server with the higher id. An incoming connection from a lower id
+                            // peer is refused while our own connection to it is established.
+                            let keep_existing = peer_id.as_u64() < self.id.as_u64() &&
+                                                self.peer_tokens
+                                                    .get(&peer_id)
+                                                    .map_or(false, |&tok| self.connections[tok].is_connected());
+                            if keep_existing {
+                                scoped_debug!("refusing duplicate connection from {:?}", peer_id);
+                                return Err(Error::Raft(RaftError::DuplicateConnection));
+                            }
+
                             self.connections[token].set_kind(ConnectionKind::Peer(peer_id));
                             // Use the advertised address, not the remote's source
                             // address, for future retries in this connection.
                             self.connections[token].set_addr(peer_addr);
+                            self.send_preamble(token);
 
                             let prev_token = Some(self.peer_tokens
                                                       .insert(peer_id, token)
@@ -311,20 +985,21 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
                             self.consensus.peer_connection_reset(peer_id, peer_addr, &mut actions);
                             self.execute_actions(event_loop, actions);
                         },
-                        connection_preamble::id::Which::Client(Ok(id)) => {
-                            let client_id = try!(ClientId::from_bytes(id));
+                        Preamble::Client(client_id) => {
+                            if !self.config.accept_clients {
+                                scoped_debug!("refusing client connection from {}", client_id);
+                                return Err(Error::Raft(RaftError::ClientConnectionRefused));
+                            }
                             scoped_debug!("received new client connection from {}", client_id);
                             self.connections[token]
                                 .set_kind(ConnectionKind::Client(client_id));
+                            self.send_preamble(token);
                             let prev_token = self.client_tokens
                                                  .insert(client_id, token);
                             scoped_assert!(prev_token.is_none(),
                                     "{:?}: two clients connected with the same id: {:?}",
                                     self, client_id);
                         },
-                        _ => {
-                            return Err(Error::Raft(RaftError::UnknownConnectionType));
-                        }
                     }
                 }
             }
@@ -332,16 +1007,61 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
         Ok(())
     }
 
-    /// Accepts a new TCP connection, adds it to the connection slab, and registers it with the
-    /// event loop.
-    fn accept_connection(&mut self, event_loop: &mut EventLoop<Server<L, M>>) -> Result<()> {
+    /// Answers an accepted connection with this server's preamble, which lets the remote check
+    /// the protocol version. The connection is reregistered once its preamble is handled.
+    fn send_preamble(&mut self, token: Token) {
+        let preamble = messages::server_connection_preamble(self.id, &self.local_addr());
+        self.connections[token].send_preamble(preamble, &mut self.buffer_pool);
+    }
+
+    /// Returns true if the connection is in the slab and has not been reset.
+    fn is_connection_open(&self, token: Token) -> bool {
+        self.connections.get(token).map_or(false, Connection::is_connected)
+    }
+
+    /// Accepts pending connections from the listener, up to `Config::accept_batch_size`,
+    /// stopping early once no more are pending. Returns the error with which accepting failed,
+    /// if it did for a reason other than an empty accept queue.
+    fn accept_connections(&mut self,
+                          event_loop: &mut EventLoop<Server<L, M>>,
+                          listener: Token)
+                          -> Result<()> {
+        for _ in 0..cmp::max(1, self.config.accept_batch_size) {
+            if !try!(self.accept_connection(event_loop, listener)) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Accepts a new connection from the listener, adds it to the connection slab, and
+    /// registers it with the event loop. Returns `false` if no connection is pending, and an error
+    /// only if accepting failed for another reason.
+    fn accept_connection(&mut self,
+                         event_loop: &mut EventLoop<Server<L, M>>,
+                         listener: Token)
+                         -> Result<bool> {
         scoped_trace!("accept_connection");
-        self.listener.accept().map_err(From::from)
-            .and_then(|stream_opt| stream_opt.ok_or(Error::Io(
-                    io::Error::new(io::ErrorKind::WouldBlock, "listener.accept() returned None"))))
-            .and_then(|stream| Connection::unknown(stream))
-            .and_then(|conn| self.connections.insert(conn)
-                                 .map_err(|_| Error::Raft(RaftError::ConnectionLimitReached)))
+        let accepted = connection::injected_fault(Fault::Accept)
+                                  .and_then(|_| self.listeners[listener.as_usize()].accept());
+        let stream = match accepted {
+            Ok(Some(stream)) => stream,
+            // mio reports an empty accept queue as `None`, but a `WouldBlock` error means the same.
+            Ok(None) => return Ok(false),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(error) => return Err(Error::Io(error)),
+        };
+        Connection::unknown(stream)
+            .and_then(|mut conn| {
+                try!(conn.set_nodelay(self.config.tcp_nodelay));
+                try!(conn.set_buffer_sizes(self.config.tcp_send_buffer_bytes,
+                                           self.config.tcp_recv_buffer_bytes));
+                if !self.connections.has_remaining() {
+                    self.evict_client(event_loop);
+                }
+                self.connections.insert(conn)
+                    .map_err(|_| Error::Raft(RaftError::ConnectionLimitReached))
+            })
             .and_then(|token|
                 // Until this point if any failures occur the connection is simply dropped. From
                 // this point down, the connection is stored in the slab, so dropping it would
@@ -356,40 +1076,52 @@ impl<L, M> Server<L, M> where L: Log, M: StateMachine {
                     .map(|_| scoped_debug!("new connection accepted from {}",
                                            self.connections[token].addr()))
             )
+            .map(|_| true)
     }
 }
 
 impl<L, M> Handler for Server<L, M> where L: Log, M: StateMachine {
 
-    type Message = ();
+    type Message = ServerCommand;
     type Timeout = ServerTimeout;
 
     fn ready(&mut self, event_loop: &mut EventLoop<Server<L, M>>, token: Token, events: EventSet) {
         push_log_scope!("{:?}", self);
         scoped_trace!("ready; token: {:?}; events: {:?}", token, events);
 
+        if self.is_listener(token) && (events.is_error() || events.is_hup()) {
+            self.listener_error(event_loop, token);
+            return;
+        }
+
         if events.is_error() {
-            scoped_assert!(token != LISTENER, "unexpected error event from LISTENER");
             scoped_warn!("{:?}: error event", self.connections[token]);
             self.reset_connection(event_loop, token);
             return;
         }
 
         if events.is_hup() {
-            scoped_assert!(token != LISTENER, "unexpected hup event from LISTENER");
             scoped_trace!("{:?}: hup event", self.connections[token]);
             self.reset_connection(event_loop, token);
             return;
         }
 
+        // A client whose queued messages have all been written may be streamed more committed
+        // entries, once the event is otherwise handled.
+        let mut drained_client = None;
         if events.is_writable() {
-            scoped_assert!(token != LISTENER, "unexpected writeable event for LISTENER");
-            if let Err(error) = self.connections[token].writable() {
+            scoped_assert!(!self.is_listener(token), "unexpected writeable event for listener");
+            if let Err(error) = self.connections[token].writable(&mut self.buffer_pool) {
                 scoped_warn!("{:?}: failed write: {}",
                              self.connections[token], error);
                 self.reset_connection(event_loop, token);
                 return;
             }
+            if let ConnectionKind::Client(client) = *self.connections[token].kind() {
+                if self.connections[token].queued_buffers() == 0 {
+                    drained_client = Some(client);
+                }
+            }
             if !events.is_readable() {
                 self.connections[token]
                     .reregister(event_loop, token)
@@ -398,21 +1130,33 @@ impl<L, M> Handler for Server<L, M> where L: Log, M: StateMachine {
         }
 
         if events.is_readable() {
-            if token == LISTENER {
-                self.accept_connection(event_loop)
-                    .unwrap_or_else(|error| scoped_warn!("unable to accept connection: {}", error));
+            if self.is_listener(token) {
+                if let Err(error) = self.accept_connections(event_loop, token) {
+                    scoped_warn!("unable to accept connection: {}", error);
+                }
             } else {
-                self.readable(event_loop, token)
+                let result = self.readable(event_loop, token);
+                if self.is_connection_open(token) {
                     // Only reregister the connection with the event loop if no error occurs and
                     // the connection is *not* reset.
-                    .and_then(|_| self.connections[token].reregister(event_loop, token))
-                    .unwrap_or_else(|error| {
-                        scoped_warn!("{:?}: failed read: {}",
-                                     self.connections[token], error);
-                        self.reset_connection(event_loop, token);
-                    });
+                    result.and_then(|_| self.connections[token].reregister(event_loop, token))
+                          .unwrap_or_else(|error| {
+                              scoped_warn!("{:?}: failed read: {}",
+                                           self.connections[token], error);
+                              self.reset_connection(event_loop, token);
+                          });
+                } else if let Err(error) = result {
+                    // The connection was reset while handling its messages.
+                    scoped_warn!("failed read on reset connection {:?}: {}", token, error);
+                }
             }
         }
+
+        if let Some(client) = drained_client {
+            let mut actions = Actions::new();
+            self.consensus.client_drained(client, &mut actions);
+            self.execute_actions(event_loop, actions);
+        }
     }
 
     fn timeout(&mut self, event_loop: &mut EventLoop<Server<L, M>>, timeout: ServerTimeout) {
@@ -430,15 +1174,18 @@ impl<L, M> Handler for Server<L, M> where L: Log, M: StateMachine {
             ServerTimeout::Reconnect(token) => {
                 scoped_assert!(self.reconnection_timeouts.remove(&token).is_some(),
                                "{:?} missing timeout: {:?}", self.connections[token], timeout);
-                let local_addr = self.listener.local_addr();
-                scoped_assert!(local_addr.is_ok(), "could not obtain listener address");
+                let local_addr = self.local_addr();
                 let id = match *self.connections[token].kind() {
                     ConnectionKind::Peer(id) => id,
                     _ => unreachable!(),
                 };
                 let addr = self.connections[token].addr().clone();
+                let send_bytes = self.config.tcp_send_buffer_bytes;
+                let recv_bytes = self.config.tcp_recv_buffer_bytes;
                 self.connections[token]
-                    .reconnect_peer(self.id, &local_addr.unwrap())
+                    .reconnect_peer(self.id, &local_addr, &*self.transport, &mut self.buffer_pool)
+                    .and_then(|_| self.connections[token].set_nodelay(self.config.tcp_nodelay))
+                    .and_then(|_| self.connections[token].set_buffer_sizes(send_bytes, recv_bytes))
                     .and_then(|_| self.connections[token].register(event_loop, token))
                     .map(|_| {
                         let mut actions = Actions::new();
@@ -451,10 +1198,163 @@ impl<L, M> Handler for Server<L, M> where L: Log, M: StateMachine {
                         self.reset_connection(event_loop, token);
                     });
             },
+
+            ServerTimeout::Proposal(client) => {
+                if let Some((response, _)) = self.proposals.remove(&client) {
+                    scoped_debug!("proposal {} timed out", client);
+                    let _ = response.send(Err(Error::Raft(RaftError::Timeout)));
+                }
+            },
+
+            ServerTimeout::WaitApplied(id) => {
+                if let Some((index, response, _)) = self.applied_waits.remove(&id) {
+                    scoped_debug!("wait for entry {} to be applied timed out", index);
+                    let _ = response.send(Err(Error::Raft(RaftError::Timeout)));
+                }
+            },
+
+            ServerTimeout::Keepalive => {
+                let timeout_ms = self.config.peer_idle_timeout_ms;
+                let idle_tokens: Vec<Token> = self.peer_tokens
+                                                  .values()
+                                                  .cloned()
+                                                  .filter(|&token| self.connections[token].is_idle(timeout_ms))
+                                                  .collect();
+                for token in idle_tokens {
+                    scoped_info!("{:?}: nothing received for {}ms; presuming peer is dead",
+                                 self.connections[token], timeout_ms);
+                    self.reset_connection(event_loop, token);
+                }
+                let read_timeout_ms = self.config.partial_read_timeout_ms;
+                let first = self.listeners.len();
+                let stalled_tokens: Vec<Token> = (first..first + self.config.max_connections)
+                    .map(Token)
+                    .filter(|&token| {
+                        self.connections
+                            .get(token)
+                            .map_or(false, |connection| connection.is_stalled_reading(read_timeout_ms))
+                    })
+                    .collect();
+                for token in stalled_tokens {
+                    scoped_warn!("{:?}: message incomplete after {}ms; resetting",
+                                 self.connections[token], read_timeout_ms);
+                    self.reset_connection(event_loop, token);
+                }
+                self.schedule_keepalive(event_loop);
+            },
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Server<L, M>>, command: ServerCommand) {
+        push_log_scope!("{:?}", self);
+        // Failures to send a response are ignored, since the requester may have stopped waiting.
+        match command {
+            ServerCommand::IsLeader { response } => {
+                scoped_trace!("notify: IsLeader");
+                let _ = response.send(self.consensus.is_leader());
+            },
+            ServerCommand::UpdatePeerAddress { id, addr } => {
+                match self.peer_tokens.get(&id) {
+                    Some(&token) => {
+                        scoped_info!("{:?}: updating address to {}", self.connections[token], addr);
+                        self.connections[token].set_addr(addr);
+                        self.consensus.set_peer_addr(id, addr);
+                    },
+                    None => scoped_warn!("unable to update address of unknown peer {}", id),
+                }
+            },
+            ServerCommand::StepDown => {
+                scoped_trace!("notify: StepDown");
+                let mut actions = Actions::new();
+                self.consensus.step_down(&mut actions);
+                self.execute_actions(event_loop, actions);
+            },
+            ServerCommand::Propose { command, timeout_ms, response } => {
+                scoped_trace!("notify: Propose");
+                // The proposal is made under a fresh client id, to which consensus addresses the
+                // response.
+                let client = ClientId::new();
+                // The caller learns that the server is unavailable once `response` is dropped.
+                let timeout = ServerTimeout::Proposal(client);
+                let handle = match self.schedule_timeout(event_loop, timeout, timeout_ms) {
+                    Some(handle) => handle,
+                    None => return,
+                };
+                self.proposals.insert(client, (response, handle));
+                let mut actions = Actions::new();
+                self.consensus.propose(client, &command, &mut actions);
+                self.execute_actions(event_loop, actions);
+            },
+            ServerCommand::WaitApplied { index, timeout_ms, response } => {
+                scoped_trace!("notify: WaitApplied");
+                if index <= self.consensus.last_applied() {
+                    let _ = response.send(Ok(()));
+                } else {
+                    let id = self.next_wait_id;
+                    self.next_wait_id += 1;
+                    let timeout = ServerTimeout::WaitApplied(id);
+                    let handle = match self.schedule_timeout(event_loop, timeout, timeout_ms) {
+                        Some(handle) => handle,
+                        None => return,
+                    };
+                    self.applied_waits.insert(id, (index, response, handle));
+                }
+            },
+            ServerCommand::LastContact { response } => {
+                scoped_trace!("notify: LastContact");
+                let _ = response.send(self.consensus.last_contact());
+            },
+            ServerCommand::DeadPeers { response } => {
+                scoped_trace!("notify: DeadPeers");
+                let _ = response.send(self.dead_peers.clone());
+            },
+            ServerCommand::UncommittedEntries { response } => {
+                scoped_trace!("notify: UncommittedEntries");
+                let _ = response.send(self.consensus.uncommitted_entries());
+            },
+            ServerCommand::LogMetrics { response } => {
+                scoped_trace!("notify: LogMetrics");
+                let _ = response.send(self.consensus.log_metrics());
+            },
+            ServerCommand::ElectionMetrics { response } => {
+                scoped_trace!("notify: ElectionMetrics");
+                let _ = response.send(self.consensus.election_metrics());
+            },
+            ServerCommand::CurrentLeader { response } => {
+                scoped_trace!("notify: CurrentLeader");
+                let _ = response.send(self.consensus.current_leader());
+            },
+            ServerCommand::Applied(output) => {
+                scoped_trace!("notify: Applied");
+                let mut actions = Actions::new();
+                self.consensus.apply_output(output, &mut actions);
+                self.execute_actions(event_loop, actions);
+            },
+            ServerCommand::Shutdown => {
+                scoped_trace!("notify: Shutdown");
+                self.shutdown(event_loop, ShutdownReason::Requested);
+            },
         }
     }
 }
 
+/// Decodes a connection preamble, logging an error if the remote uses an incompatible protocol
+/// version.
+fn decode_preamble<R>(message: &R) -> Result<Preamble> where R: MessageReader {
+    messages::decode_connection_preamble(message).map_err(|error| {
+        if let Error::Raft(RaftError::IncompatibleProtocolVersion(version)) = error {
+            scoped_error!("refusing connection using protocol version {}; this server uses \
+                           version {}", version, messages::PROTOCOL_VERSION);
+        }
+        error
+    })
+}
+
+/// Decodes the response to a proposal made through `ServerCommand::Propose`.
+fn proposal_result(message: &MallocMessageBuilder) -> Result<Vec<u8>> {
+    messages::decode_command_response(&messages::builder_reader(message))
+}
+
 impl <L, M> fmt::Debug for Server<L, M> where L: Log, M: StateMachine {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "Server({})", self.id)
@@ -466,33 +1366,51 @@ mod tests {
 
     extern crate env_logger;
 
-    use std::collections::HashMap;
-    use std::io::{self, Read, Write};
+    use std::{fmt, result};
+    use std::collections::{HashMap, HashSet};
+    use std::io::{self, Cursor, Read, Write};
     use std::net::{SocketAddr, TcpListener, TcpStream};
     use std::str::FromStr;
+    use std::sync::mpsc;
+    use std::time::Duration;
 
-    use capnp::{serialize, MessageReader, ReaderOptions};
-    use mio::EventLoop;
+    use capnp::{serialize, MallocMessageBuilder, MessageBuilder, MessageReader, ReaderOptions};
+    use mio::{EventLoop, EventSet, Handler, Token};
 
     use ClientId;
+    use Config;
+    use Error;
+    use RaftError;
     use Result;
     use ServerId;
+    use LogIndex;
+    use Term;
     use messages;
     use messages_capnp::connection_preamble;
-    use consensus::Actions;
-    use state_machine::NullStateMachine;
-    use persistent_log::MemLog;
+    use connection::{self, Fault};
+    use consensus::{Actions, ConsensusTimeout};
+    use state_machine::{ApplyError, NullStateMachine, StateMachine};
+    use persistent_log::{MemLog, TestLog};
+    #[cfg(unix)]
+    use transport::{BufferOption, LocalTransport};
     use super::*;
 
     type TestServer = Server<MemLog, NullStateMachine>;
 
     fn new_test_server(peers: HashMap<ServerId, SocketAddr>)
                        -> Result<(TestServer, EventLoop<TestServer>)> {
+        new_test_server_with_config(peers, Config::default())
+    }
+
+    fn new_test_server_with_config(peers: HashMap<ServerId, SocketAddr>,
+                                   config: Config)
+                                   -> Result<(TestServer, EventLoop<TestServer>)> {
         Server::new(ServerId::from(0),
                     SocketAddr::from_str("127.0.0.1:0").unwrap(),
                     peers,
                     MemLog::new(),
-                    NullStateMachine)
+                    NullStateMachine,
+                    config)
     }
 
     /// Attempts to grab a local, unbound socket address for testing.
@@ -516,6 +1434,19 @@ mod tests {
         }
     }
 
+    /// Builds a server preamble with the provided protocol version.
+    fn versioned_preamble(id: ServerId, addr: &SocketAddr, version: u32) -> MallocMessageBuilder {
+        let mut preamble = MallocMessageBuilder::new_default();
+        {
+            let mut builder = preamble.init_root::<connection_preamble::Builder>();
+            builder.set_protocol_version(version);
+            let mut server = builder.init_id().init_server();
+            server.set_addr(&format!("{}", addr));
+            server.set_id(id.as_u64());
+        }
+        preamble
+    }
+
     /// Returns true if the server has an open connection with the peer.
     fn peer_connected(server: &TestServer, peer: ServerId) -> bool {
         let token = server.peer_tokens[&peer];
@@ -527,6 +1458,26 @@ mod tests {
         server.client_tokens.contains_key(&client)
     }
 
+    /// Connects a new client to the server, reads the server's preamble in reply, and returns the
+    /// client's ID and stream.
+    fn connect_client(server: &mut TestServer,
+                      event_loop: &mut EventLoop<TestServer>)
+                      -> (ClientId, TcpStream) {
+        let server_addr = server.local_addr();
+        let mut stream = TcpStream::connect(server_addr).unwrap();
+        event_loop.run_once(server).unwrap();
+
+        let client_id = ClientId::new();
+        serialize::write_message(&mut stream, &*messages::client_connection_preamble(client_id))
+                 .unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(server).unwrap();
+        assert!(client_connected(server, client_id));
+        event_loop.run_once(server).unwrap();
+        assert_eq!(server.id, read_server_preamble(&mut stream));
+        (client_id, stream)
+    }
+
     /// Returns true if the provided TCP connection has been shutdown.
     ///
     /// TODO: figure out a more robust way to implement this, the current check
@@ -543,6 +1494,16 @@ mod tests {
         }
     }
 
+    /// Tests that a server bound to port 0 reports the port assigned to it.
+    #[test]
+    fn test_local_addr() {
+        setup_test!("test_local_addr");
+        let (server, _) = new_test_server(HashMap::new()).unwrap();
+        let addr = server.local_addr();
+        assert!(addr.port() != 0);
+        assert!(TcpStream::connect(addr).is_ok());
+    }
+
     /// Tests that a Server will reject an invalid peer configuration set.
     #[test]
     fn test_illegal_peer_set() {
@@ -591,6 +1552,36 @@ mod tests {
         assert!(peer_connected(&server, peer_id));
     }
 
+    /// Tests that a Server starts up when registering a peer connection fails, and retries the
+    /// connection after a backoff.
+    #[test]
+    fn test_peer_register_failure() {
+        setup_test!("test_peer_register_failure");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        connection::inject_faults(Fault::Registration, io::ErrorKind::Other, 1);
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+        assert!(!peer_connected(&server, peer_id));
+        assert_eq!(1, server.reconnection_timeouts.len());
+
+        // The failed connection is closed without sending anything.
+        let (mut stream, _)  = peer_listener.accept().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        assert!(buf.is_empty());
+
+        // Check that the server reconnects after a timeout.
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+        let (mut stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut stream));
+    }
+
     /// Tests that a Server will replace a peer's TCP connection if the peer
     /// connects through another TCP connection.
     #[test]
@@ -612,7 +1603,7 @@ mod tests {
         assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
         assert!(peer_connected(&server, peer_id));
 
-        let server_addr = server.listener.local_addr().unwrap();
+        let server_addr = server.local_addr();
 
         // Open a replacement connection to the server.
         let mut out_stream = TcpStream::connect(server_addr).unwrap();
@@ -636,16 +1627,419 @@ mod tests {
         assert!(server.connections.iter().any(|conn| conn.addr().port() == 12345))
     }
 
-    /// Tests that the server will accept a client connection, then disposes of
-    /// it when the client disconnects.
+    /// Tests that when two peers dial each other simultaneously, both keep the connection
+    /// initiated by the peer with the higher id.
     #[test]
-    fn test_client_accept() {
-        setup_test!("test_client_accept");
-
-        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+    fn test_simultaneous_peer_dial() {
+        setup_test!("test_simultaneous_peer_dial");
+
+        // The server has the higher id: it refuses the incoming connection from the lower id
+        // peer, and keeps its own outgoing connection.
+        let low_id = ServerId::from(0);
+        let low_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let low_addr = low_listener.local_addr().unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(low_id, low_addr);
+        let (mut server, mut event_loop) = Server::new(ServerId::from(1),
+                                                       SocketAddr::from_str("127.0.0.1:0").unwrap(),
+                                                       peers,
+                                                       MemLog::new(),
+                                                       NullStateMachine,
+                                                       Config::default())
+                                                  .unwrap();
+
+        let (mut in_stream, _) = low_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(1), read_server_preamble(&mut in_stream));
+        let outgoing = server.peer_tokens[&low_id];
+
+        let mut out_stream = TcpStream::connect(server.local_addr()).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        serialize::write_message(&mut out_stream, &*messages::server_connection_preamble(low_id, &low_addr))
+                 .unwrap();
+        out_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+
+        assert!(stream_shutdown(&mut out_stream));
+        assert_eq!(outgoing, server.peer_tokens[&low_id]);
+        assert!(peer_connected(&server, low_id));
+        assert_eq!(1, server.connections.iter().count());
+
+        // The server has the lower id: it replaces its outgoing connection with the incoming
+        // connection from the higher id peer.
+        let high_id = ServerId::from(1);
+        let high_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let high_addr = high_listener.local_addr().unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(high_id, high_addr);
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        let (mut in_stream, _) = high_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+        let outgoing = server.peer_tokens[&high_id];
+
+        let mut out_stream = TcpStream::connect(server.local_addr()).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        serialize::write_message(&mut out_stream, &*messages::server_connection_preamble(high_id, &high_addr))
+                 .unwrap();
+        out_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+
+        assert!(stream_shutdown(&mut in_stream));
+        assert!(outgoing != server.peer_tokens[&high_id]);
+        assert!(peer_connected(&server, high_id));
+        assert_eq!(1, server.connections.iter().count());
+    }
+
+    /// Tests that when the connection limit is reached, the least recently active client is
+    /// evicted to make room for a reconnecting peer.
+    #[test]
+    fn test_evict_idle_client() {
+        setup_test!("test_evict_idle_client");
+        let peer_id = ServerId::from(1);
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        let mut config = Config::default();
+        config.max_connections = 3;
+        let (mut server, mut event_loop) = new_test_server_with_config(peers, config).unwrap();
+
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+
+        // Fill the remaining connection slots with clients.
+        let (idle_client, mut idle_stream) = connect_client(&mut server, &mut event_loop);
+        let (active_client, _active_stream) = connect_client(&mut server, &mut event_loop);
+        assert!(!server.connections.has_remaining());
+
+        // The peer reconnects.
+        let mut out_stream = TcpStream::connect(server.local_addr()).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        let peer_addr = peer_listener.local_addr().unwrap();
+        serialize::write_message(&mut out_stream, &*messages::server_connection_preamble(peer_id, &peer_addr))
+                 .unwrap();
+        out_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+
+        assert!(peer_connected(&server, peer_id));
+        assert!(!client_connected(&server, idle_client));
+        assert!(stream_shutdown(&mut idle_stream));
+        assert!(client_connected(&server, active_client));
+    }
+
+    /// Tests that a server is not created with a connection limit which leaves no room for
+    /// clients.
+    #[test]
+    fn test_max_connections_below_peers() {
+        setup_test!("test_max_connections_below_peers");
+        let mut peers = HashMap::new();
+        peers.insert(ServerId::from(1), get_unbound_address());
+        peers.insert(ServerId::from(2), get_unbound_address());
+        let mut config = Config::default();
+        config.max_connections = 2;
+        match new_test_server_with_config(peers, config) {
+            Err(Error::Raft(RaftError::InvalidConfig)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(..) => panic!("server created with too few connections for its peers"),
+        }
+    }
+
+    /// Tests that a server is not created with a write buffer limit which does not exceed the
+    /// bytes in flight to a peer.
+    #[test]
+    fn test_write_buffer_below_in_flight() {
+        setup_test!("test_write_buffer_below_in_flight");
+        let mut config = Config::default();
+        config.max_write_buffer_bytes = config.max_in_flight_bytes as usize;
+        match new_test_server_with_config(HashMap::new(), config) {
+            Err(Error::Raft(RaftError::InvalidConfig)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(..) => panic!("server created with a write buffer limit within the in-flight bytes"),
+        }
+    }
+
+    /// Tests that a server is not created with a read buffer limit which does not exceed the
+    /// maximum message size.
+    #[test]
+    fn test_read_buffer_below_max_message() {
+        setup_test!("test_read_buffer_below_max_message");
+        let mut config = Config::default();
+        config.max_read_buffer_bytes = config.max_message_bytes as usize;
+        match new_test_server_with_config(HashMap::new(), config) {
+            Err(Error::Raft(RaftError::InvalidConfig)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(..) => panic!("server created with a read buffer limit within the maximum message size"),
+        }
+    }
+
+    /// Tests that the server keeps accepting connections after an error event from the listener.
+    #[test]
+    fn test_listener_error() {
+        setup_test!("test_listener_error");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+
+        server.ready(&mut event_loop, Token(0), EventSet::error());
+        assert!(server.shutdown_reason.is_none());
+
+        // The listener still accepts connections.
+        connect_client(&mut server, &mut event_loop);
+    }
+
+    /// Tests that a server listening on several addresses accepts connections on each of them.
+    #[test]
+    fn test_additional_addrs() {
+        setup_test!("test_additional_addrs");
+        let mut config = Config::default();
+        config.additional_addrs.push(SocketAddr::from_str("127.0.0.1:0").unwrap());
+        let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config).unwrap();
+        let addrs = server.local_addrs().to_vec();
+        assert_eq!(2, addrs.len());
+        assert_eq!(server.local_addr(), addrs[0]);
+
+        for addr in addrs {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            event_loop.run_once(&mut server).unwrap();
+            let client_id = ClientId::new();
+            serialize::write_message(&mut stream, &*messages::client_connection_preamble(client_id))
+                     .unwrap();
+            stream.flush().unwrap();
+            event_loop.run_once(&mut server).unwrap();
+            assert!(client_connected(&server, client_id));
+        }
+    }
+
+    /// Tests that the server refuses a connection from a peer speaking an incompatible protocol
+    /// version.
+    #[test]
+    fn test_incompatible_protocol_version() {
+        setup_test!("test_incompatible_protocol_version");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer_listener.local_addr().unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_addr);
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+        let peer_token = server.peer_tokens[&peer_id];
+
+        // The peer connects with a newer protocol version.
+        let mut out_stream = TcpStream::connect(server.local_addr()).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        let preamble = versioned_preamble(peer_id, &peer_addr, messages::PROTOCOL_VERSION + 1);
+        serialize::write_message(&mut out_stream, &preamble).unwrap();
+        out_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+
+        // The connection is refused, and the server's own connection to the peer remains.
+        assert!(stream_shutdown(&mut out_stream));
+        assert_eq!(peer_token, server.peer_tokens[&peer_id]);
+    }
+
+    /// Tests that the server resets its connection to a peer which replies to the server's
+    /// preamble with an incompatible protocol version, and keeps one which replies with its own.
+    #[test]
+    fn test_incompatible_protocol_version_reply() {
+        setup_test!("test_incompatible_protocol_version_reply");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer_listener.local_addr().unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_addr);
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        // The peer replies with a newer protocol version.
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+        let preamble = versioned_preamble(peer_id, &peer_addr, messages::PROTOCOL_VERSION + 1);
+        serialize::write_message(&mut in_stream, &preamble).unwrap();
+        in_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!peer_connected(&server, peer_id));
+
+        // The server reconnects after a timeout, and the peer replies with the same version.
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+        serialize::write_message(&mut in_stream, &*messages::server_connection_preamble(peer_id, &peer_addr))
+                 .unwrap();
+        in_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+        let token = server.peer_tokens[&peer_id];
+        assert!(!server.connections[token].awaiting_preamble());
+    }
+
+    /// Tests that responses queued for a client at the same time are written together.
+    #[test]
+    fn test_client_write_coalescing() {
+        setup_test!("test_client_write_coalescing");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+        let token = server.client_tokens[&client_id];
+
+        let mut actions = Actions::new();
+        actions.client_messages.push((client_id, messages::command_response_success(b"foo")));
+        actions.client_messages.push((client_id, messages::command_response_success(b"bar")));
+        server.execute_actions(&mut event_loop, actions);
+        assert_eq!(1, server.connections[token].queued_buffers());
+
+        // The client reads both responses.
+        event_loop.run_once(&mut server).unwrap();
+        for expected in &[b"foo", b"bar"] {
+            let response = serialize::read_message(&mut stream, ReaderOptions::new()).unwrap();
+            assert_eq!(&expected[..], &messages::decode_command_response(&response).unwrap()[..]);
+        }
+    }
+
+    /// Tests that a client connection which does not read its responses is closed once the bytes
+    /// queued for it exceed the limit.
+    #[test]
+    fn test_write_buffer_limit() {
+        setup_test!("test_write_buffer_limit");
+        let mut config = Config::default();
+        config.max_write_buffer_bytes = 1024;
+        config.max_in_flight_bytes = 512;
+        let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config)
+                                               .unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+        let token = server.client_tokens[&client_id];
+
+        let response = messages::command_response_success(&[0; 400]);
+        let mut actions = Actions::new();
+        actions.client_messages.push((client_id, response.clone()));
+        actions.client_messages.push((client_id, response.clone()));
+        server.execute_actions(&mut event_loop, actions);
+        assert!(client_connected(&server, client_id));
+        assert!(server.connections[token].queued_bytes() > 800);
+
+        let mut actions = Actions::new();
+        actions.client_messages.push((client_id, response));
+        server.execute_actions(&mut event_loop, actions);
+        assert!(!client_connected(&server, client_id));
+        assert!(stream_shutdown(&mut stream));
+    }
+
+    /// Tests that the response to an applied proposal is routed to the connection of the client
+    /// which proposed it, and is dropped if that client disconnected before the proposal was
+    /// committed.
+    #[test]
+    fn test_proposal_response_routing() {
+        setup_test!("test_proposal_response_routing");
+        let peer_id = ServerId::from(1);
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, get_unbound_address());
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        // Delivers a message from the unreachable peer, whose acknowledgements commit entries.
+        let deliver = |server: &mut TestServer,
+                       event_loop: &mut EventLoop<TestServer>,
+                       message: &MallocMessageBuilder| {
+            let mut buf = Cursor::new(Vec::new());
+            serialize::write_message(&mut buf, message).unwrap();
+            buf.set_position(0);
+            let message = serialize::read_message(&mut buf, ReaderOptions::new()).unwrap();
+            let mut actions = Actions::new();
+            server.consensus.apply_peer_message(peer_id, &message, &mut actions).unwrap();
+            server.execute_actions(event_loop, actions);
+        };
+
+        let mut actions = Actions::new();
+        server.consensus.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        server.execute_actions(&mut event_loop, actions);
+        let vote = messages::request_vote_response_granted(Term(1), false);
+        deliver(&mut server, &mut event_loop, &*vote);
+        assert!(server.consensus.is_leader());
+
+        let (proposer_id, mut proposer) = connect_client(&mut server, &mut event_loop);
+        let (_, mut other) = connect_client(&mut server, &mut event_loop);
+        other.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        serialize::write_message(&mut proposer, &messages::proposal_request(b"foo")).unwrap();
+        proposer.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        let ack = messages::append_entries_response_success(Term(1), LogIndex(2), 0);
+        deliver(&mut server, &mut event_loop, &*ack);
+        event_loop.run_once(&mut server).unwrap();
+        let response = serialize::read_message(&mut proposer, ReaderOptions::new()).unwrap();
+        assert!(messages::decode_command_response(&response).is_ok());
+        assert!(serialize::read_message(&mut other, ReaderOptions::new()).is_err());
+
+        // The proposer disconnects before its next proposal is committed. The response is dropped,
+        // rather than sent to another client.
+        serialize::write_message(&mut proposer, &messages::proposal_request(b"bar")).unwrap();
+        proposer.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(LogIndex(2), server.consensus.last_applied());
+        drop(proposer);
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!client_connected(&server, proposer_id));
+
+        let ack = messages::append_entries_response_success(Term(1), LogIndex(3), 0);
+        deliver(&mut server, &mut event_loop, &*ack);
+        assert_eq!(LogIndex(3), server.consensus.last_applied());
+        event_loop.run_once(&mut server).unwrap();
+        assert!(serialize::read_message(&mut other, ReaderOptions::new()).is_err());
+        assert!(!client_connected(&server, proposer_id));
+    }
+
+    /// Tests that a server can not be created with a peer set in which servers share an address.
+    #[test]
+    fn test_duplicate_peer_address() {
+        setup_test!("test_duplicate_peer_address");
+        let peer_addr = get_unbound_address();
+        let mut peers = HashMap::new();
+        peers.insert(ServerId::from(1), peer_addr);
+        peers.insert(ServerId::from(2), peer_addr);
+        match new_test_server(peers) {
+            Err(Error::Raft(RaftError::InvalidPeerSet)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(..) => panic!("server created with a duplicate peer address"),
+        }
+
+        // The local address may not be used by a peer either.
+        let addr = get_unbound_address();
+        let mut peers = HashMap::new();
+        peers.insert(ServerId::from(1), addr);
+        let result = Server::new(ServerId::from(0), addr, peers, MemLog::new(), NullStateMachine,
+                                 Config::default());
+        match result {
+            Err(Error::Raft(RaftError::InvalidPeerSet)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(..) => panic!("server created with a peer at its own address"),
+        }
+    }
+
+    /// Tests that a server can not be created with itself in its peer set.
+    #[test]
+    fn test_local_id_in_peer_set() {
+        setup_test!("test_local_id_in_peer_set");
+        let mut peers = HashMap::new();
+        peers.insert(ServerId::from(0), get_unbound_address());
+        match new_test_server(peers) {
+            Err(Error::Raft(RaftError::InvalidPeerSet)) => (),
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(..) => panic!("server created with itself as a peer"),
+        }
+    }
+
+    /// Tests that the server will accept a client connection, then disposes of
+    /// it when the client disconnects.
+    #[test]
+    fn test_client_accept() {
+        setup_test!("test_client_accept");
+
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
 
         // Connect to the server.
-        let server_addr = server.listener.local_addr().unwrap();
+        let server_addr = server.local_addr();
         let mut stream = TcpStream::connect(server_addr).unwrap();
         event_loop.run_once(&mut server).unwrap();
 
@@ -667,6 +2061,106 @@ mod tests {
         assert!(!client_connected(&server, client_id));
     }
 
+    /// Tests that a server which does not accept clients closes client connections, and still
+    /// accepts peer connections.
+    #[test]
+    fn test_peer_only() {
+        setup_test!("test_peer_only");
+        let peer_id = ServerId::from(1);
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        let mut config = Config::default();
+        config.accept_clients = false;
+        let (mut server, mut event_loop) = new_test_server_with_config(peers, config).unwrap();
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+        let server_addr = server.local_addr();
+
+        let mut client_stream = TcpStream::connect(server_addr).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        let client_id = ClientId::new();
+        serialize::write_message(&mut client_stream, &*messages::client_connection_preamble(client_id))
+                 .unwrap();
+        client_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!client_connected(&server, client_id));
+        assert!(stream_shutdown(&mut client_stream));
+
+        let mut peer_stream = TcpStream::connect(server_addr).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        let peer_addr = SocketAddr::from_str("192.168.0.1:12345").unwrap();
+        serialize::write_message(&mut peer_stream, &*messages::server_connection_preamble(peer_id, &peer_addr))
+                 .unwrap();
+        peer_stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(peer_addr, server.consensus.peers()[&peer_id]);
+        assert!(peer_connected(&server, peer_id));
+    }
+
+    /// Tests that the server accepts several pending connections each time the listener becomes
+    /// readable, up to the configured batch size.
+    #[test]
+    fn test_accept_batch() {
+        setup_test!("test_accept_batch");
+        let mut config = Config::default();
+        config.accept_batch_size = 2;
+        let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config)
+                                               .unwrap();
+        let server_addr = server.local_addr();
+
+        let _streams: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(server_addr).unwrap())
+                                             .collect();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(2, server.connections.iter().count());
+    }
+
+    /// Tests that a `WouldBlock` accept error is treated as an empty accept queue, and that other
+    /// accept errors are reported without disturbing the listener.
+    #[test]
+    fn test_accept_error_classification() {
+        setup_test!("test_accept_error_classification");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let server_addr = server.local_addr();
+        let _stream = TcpStream::connect(server_addr).unwrap();
+
+        // A `WouldBlock` error ends the batch without an error to warn of.
+        connection::inject_faults(Fault::Accept, io::ErrorKind::WouldBlock, 1);
+        assert!(server.accept_connections(&mut event_loop, Token(0)).is_ok());
+        assert_eq!(0, server.connections.iter().count());
+
+        connection::inject_faults(Fault::Accept, io::ErrorKind::Other, 1);
+        assert!(server.accept_connections(&mut event_loop, Token(0)).is_err());
+        assert_eq!(0, server.connections.iter().count());
+
+        // The pending connection is still accepted afterwards.
+        assert!(server.accept_connections(&mut event_loop, Token(0)).is_ok());
+        assert_eq!(1, server.connections.iter().count());
+    }
+
+    /// Tests that the server stops reading from a connection which is reset while handling one
+    /// of its messages.
+    #[test]
+    fn test_connection_reset_while_reading() {
+        setup_test!("test_connection_reset_while_reading");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+
+        // Two proposals arrive together, and are answered that the leader is unknown.
+        // Reregistering the connection to send the response to the first fails, which resets the
+        // connection before the second is read.
+        let mut buffer = Vec::new();
+        serialize::write_message(&mut buffer, &messages::proposal_request(b"foo")).unwrap();
+        serialize::write_message(&mut buffer, &messages::proposal_request(b"bar")).unwrap();
+        stream.write_all(&buffer).unwrap();
+        stream.flush().unwrap();
+        connection::inject_faults(Fault::Registration, io::ErrorKind::Other, 1);
+        event_loop.run_once(&mut server).unwrap();
+
+        assert!(!client_connected(&server, client_id));
+    }
+
     /// Tests that the server will throw away connections that do not properly
     /// send a preamble.
     #[test]
@@ -676,7 +2170,7 @@ mod tests {
         let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
 
         // Connect to the server.
-        let server_addr = server.listener.local_addr().unwrap();
+        let server_addr = server.local_addr();
         let mut stream = TcpStream::connect(server_addr).unwrap();
         event_loop.run_once(&mut server).unwrap();
 
@@ -710,7 +2204,10 @@ mod tests {
         event_loop.run_once(&mut server).unwrap();
         assert_eq!(ServerId::from(0), read_server_preamble(&mut stream_a));
 
-        // Send an invalid message.
+        // Answer with a preamble, then send an invalid message.
+        let peer_addr = peer_listener.local_addr().unwrap();
+        serialize::write_message(&mut stream_a, &*messages::server_connection_preamble(peer_id, &peer_addr))
+                 .unwrap();
         stream_a.write(b"foo bar baz").unwrap();
         stream_a.flush().unwrap();
         event_loop.run_once(&mut server).unwrap();
@@ -732,7 +2229,7 @@ mod tests {
         let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
 
         // Connect to the server.
-        let server_addr = server.listener.local_addr().unwrap();
+        let server_addr = server.local_addr();
         let mut stream = TcpStream::connect(server_addr).unwrap();
         event_loop.run_once(&mut server).unwrap();
 
@@ -756,6 +2253,53 @@ mod tests {
         assert!(!client_connected(&server, client_id));
     }
 
+    /// Tests that a well-framed but malformed client message resets only the
+    /// offending client connection, and does not take down the server.
+    #[test]
+    fn test_malformed_client_message() {
+        setup_test!("test_malformed_client_message");
+
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let (client_a, mut stream_a) = connect_client(&mut server, &mut event_loop);
+        let (client_b, _stream_b) = connect_client(&mut server, &mut event_loop);
+
+        // A single segment message whose root pointer is garbage.
+        let message: [u8; 16] = [0, 0, 0, 0, 1, 0, 0, 0,
+                                 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+        stream_a.write(&message).unwrap();
+        stream_a.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+
+        // Check that only the offending client connection is disposed of.
+        assert!(!client_connected(&server, client_a));
+        assert!(client_connected(&server, client_b));
+    }
+
+    /// Tests that a client connection which closes partway through a message is disposed of,
+    /// and other connections are unaffected.
+    #[test]
+    fn test_truncated_client_message() {
+        setup_test!("test_truncated_client_message");
+
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let (client_a, mut stream_a) = connect_client(&mut server, &mut event_loop);
+        let (client_b, _stream_b) = connect_client(&mut server, &mut event_loop);
+
+        // Only the first half of a proposal's frame is sent before the stream is closed.
+        let mut buffer = Vec::new();
+        serialize::write_message(&mut buffer, &messages::proposal_request(b"foo")).unwrap();
+        stream_a.write_all(&buffer[..buffer.len() / 2]).unwrap();
+        stream_a.flush().unwrap();
+        drop(stream_a);
+        for _ in 0..10 {
+            if !client_connected(&server, client_a) { break; }
+            event_loop.run_once(&mut server).unwrap();
+        }
+
+        assert!(!client_connected(&server, client_a));
+        assert!(client_connected(&server, client_b));
+    }
+
     /// Tests that a Server will attempt to reconnect to an unreachable peer
     /// after failing to connect at startup.
     #[test]
@@ -781,32 +2325,870 @@ mod tests {
         assert!(!peer_connected(&mut server, peer_id));
     }
 
-    /// Tests that the server will send a message to a peer connection.
+    /// Tests that a server starts and serves clients when a peer can not be connected to at all.
     #[test]
-    fn test_connection_send() {
-        setup_test!("test_connection_send");
+    fn test_unconnectable_peer_startup() {
+        setup_test!("test_unconnectable_peer_startup");
         let peer_id = ServerId::from(1);
+        let mut peers = HashMap::new();
+        // TCP connections to a broadcast address fail immediately.
+        peers.insert(peer_id, SocketAddr::from_str("255.255.255.255:12345").unwrap());
 
-        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+        assert!(!peer_connected(&server, peer_id));
+
+        let (client_id, _stream) = connect_client(&mut server, &mut event_loop);
+        assert!(client_connected(&server, client_id));
+    }
 
+    /// Tests that the reconnection backoff of a peer grows across resets, including when the
+    /// peer's connection is replaced by one the peer initiates.
+    #[test]
+    fn test_peer_backoff_accumulates() {
+        setup_test!("test_peer_backoff_accumulates");
+        let peer_id = ServerId::from(1);
+        let peer_addr = get_unbound_address();
         let mut peers = HashMap::new();
-        let peer_addr = peer_listener.local_addr().unwrap();
         peers.insert(peer_id, peer_addr);
         let (mut server, mut event_loop) = new_test_server(peers).unwrap();
 
-        // Accept the server's connection.
-        let (mut in_stream, _)  = peer_listener.accept().unwrap();
-
-        // Accept the preamble.
+        // Error event for the peer connection; connection is reset.
         event_loop.run_once(&mut server).unwrap();
-        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+        assert_eq!(1, server.peer_backoffs[&peer_id].retries());
 
-        // Send a test message (the type is not important).
-        let mut actions = Actions::new();
-        actions.peer_messages.push((peer_id, messages::server_connection_preamble(peer_id, &peer_addr)));
-        server.execute_actions(&mut event_loop, actions);
+        // Reconnection timeout fires, and the new connection fails as well.
         event_loop.run_once(&mut server).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!peer_connected(&server, peer_id));
+        assert_eq!(2, server.peer_backoffs[&peer_id].retries());
 
-        assert_eq!(peer_id, read_server_preamble(&mut in_stream));
+        // The peer connects to the server, replacing the connection, then hangs up.
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        serialize::write_message(&mut stream, &*messages::server_connection_preamble(peer_id, &peer_addr))
+                 .unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+        drop(stream);
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!peer_connected(&server, peer_id));
+        assert_eq!(3, server.peer_backoffs[&peer_id].retries());
+    }
+
+    /// Tests that a peer is presumed dead once the configured number of connection attempts has
+    /// failed, only once, that reconnection continues, and that it is no longer presumed dead once
+    /// it connects.
+    #[test]
+    fn test_peer_presumed_dead() {
+        setup_test!("test_peer_presumed_dead");
+        let peer_id = ServerId::from(1);
+        let peer_addr = get_unbound_address();
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_addr);
+        let mut config = Config::default();
+        config.peer_dead_after_reconnects = Some(2);
+        let (mut server, mut event_loop) = new_test_server_with_config(peers, config).unwrap();
+
+        // Error event for the peer connection; connection is reset.
+        event_loop.run_once(&mut server).unwrap();
+        assert!(server.dead_peers.is_empty());
+
+        // Reconnection timeout fires, and the new connection fails as well.
+        event_loop.run_once(&mut server).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(2, server.peer_backoffs[&peer_id].retries());
+        assert!(server.dead_peers.contains(&peer_id));
+
+        // Further failures do not presume the peer dead again, and reconnection continues.
+        event_loop.run_once(&mut server).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(3, server.peer_backoffs[&peer_id].retries());
+        assert_eq!(1, server.dead_peers.len());
+        assert_eq!(1, server.reconnection_timeouts.len());
+
+        // The peer connects to the server, and is heard from.
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        serialize::write_message(&mut stream, &*messages::server_connection_preamble(peer_id, &peer_addr))
+                 .unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+        let heartbeat = messages::append_entries_request(Term(1), LogIndex(0), Term(0), &[],
+                                                         &Default::default(), LogIndex(0), 0, 0);
+        serialize::write_message(&mut stream, &*heartbeat).unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(server.dead_peers.is_empty());
+    }
+
+    /// Tests that the server sends its preamble again when it reconnects to a peer after the
+    /// connection is reset.
+    #[test]
+    fn test_peer_reconnect_preamble() {
+        setup_test!("test_peer_reconnect_preamble");
+        let peer_id = ServerId::from(1);
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+
+        let token = server.peer_tokens[&peer_id];
+        server.reset_connection(&mut event_loop, token);
+        assert!(!peer_connected(&server, peer_id));
+
+        // The reconnection timeout fires and the connection is recreated.
+        while !peer_connected(&server, peer_id) {
+            event_loop.run_once(&mut server).unwrap();
+        }
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+    }
+
+    /// Tests that the server will send a message to a peer connection.
+    #[test]
+    fn test_connection_send() {
+        setup_test!("test_connection_send");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut peers = HashMap::new();
+        let peer_addr = peer_listener.local_addr().unwrap();
+        peers.insert(peer_id, peer_addr);
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        // Accept the server's connection.
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+
+        // Accept the preamble.
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+
+        // Send a test message (the type is not important).
+        let mut actions = Actions::new();
+        actions.peer_messages.push((peer_id, messages::server_connection_preamble(peer_id, &peer_addr)));
+        server.execute_actions(&mut event_loop, actions);
+        event_loop.run_once(&mut server).unwrap();
+
+        assert_eq!(peer_id, read_server_preamble(&mut in_stream));
+    }
+
+    /// Tests that only the latest unsent control message is kept queued for a peer.
+    #[test]
+    fn test_replaceable_peer_messages() {
+        setup_test!("test_replaceable_peer_messages");
+        let peer_id = ServerId::from(1);
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer_listener.local_addr().unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_addr);
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+
+        // Queue several control messages (the type is not important) before the peer is writable.
+        let mut actions = Actions::new();
+        for id in 1..4 {
+            actions.push_replaceable_peer_message(peer_id,
+                                                  messages::server_connection_preamble(ServerId::from(id),
+                                                                                       &peer_addr));
+        }
+        server.execute_actions(&mut event_loop, actions);
+        let token = server.peer_tokens[&peer_id];
+        assert_eq!(1, server.connections[token].queued_buffers());
+
+        // A message which is not replaceable is queued behind the control message.
+        let mut actions = Actions::new();
+        actions.peer_messages.push((peer_id, messages::server_connection_preamble(peer_id, &peer_addr)));
+        server.execute_actions(&mut event_loop, actions);
+        assert_eq!(2, server.connections[token].queued_buffers());
+
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(3), read_server_preamble(&mut in_stream));
+        assert_eq!(peer_id, read_server_preamble(&mut in_stream));
+    }
+
+    /// Tests that the server answers `IsLeader` commands sent through the event loop channel.
+    #[test]
+    fn test_is_leader_command() {
+        setup_test!("test_is_leader_command");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let sender = event_loop.channel();
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::IsLeader { response: tx }).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(false, rx.recv().unwrap());
+
+        // A solitary server becomes leader upon its first election timeout.
+        let mut actions = Actions::new();
+        server.consensus.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        server.execute_actions(&mut event_loop, actions);
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::IsLeader { response: tx }).unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(true, rx.recv().unwrap());
+    }
+
+    /// Tests that a peer connection which receives nothing in response to a
+    /// message is reset once the idle timeout elapses.
+    #[test]
+    fn test_idle_peer_reset() {
+        setup_test!("test_idle_peer_reset");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut peers = HashMap::new();
+        let peer_addr = peer_listener.local_addr().unwrap();
+        peers.insert(peer_id, peer_addr);
+        let mut config = Config::default();
+        config.peer_idle_timeout_ms = 100;
+        let (mut server, mut event_loop) = new_test_server_with_config(peers, config).unwrap();
+
+        // Accept the server's connection, but never reply to it.
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+
+        // Send a request which the silent peer will never answer.
+        let mut actions = Actions::new();
+        let request = messages::request_vote_request(Term(1), LogIndex(0), Term(0), false);
+        actions.peer_messages.push((peer_id, request));
+        server.execute_actions(&mut event_loop, actions);
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+
+        // The connection is reset after the idle timeout.
+        server.schedule_keepalive(&mut event_loop);
+        for _ in 0..10 {
+            if !peer_connected(&server, peer_id) { break; }
+            event_loop.run_once(&mut server).unwrap();
+        }
+        assert!(!peer_connected(&server, peer_id));
+    }
+
+    /// Tests that a peer connection is not reset for failing to answer a response.
+    #[test]
+    fn test_idle_peer_response() {
+        setup_test!("test_idle_peer_response");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        // Any unanswered request makes the connection idle, so idleness does not depend on how
+        // much time passes during the test.
+        let mut config = Config::default();
+        config.peer_idle_timeout_ms = 0;
+        let (mut server, mut event_loop) = new_test_server_with_config(peers, config).unwrap();
+
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+
+        // Responses are not answered, so the peer's silence is expected.
+        let mut actions = Actions::new();
+        actions.peer_messages.push((peer_id, messages::append_entries_response_stale_term(Term(1))));
+        server.execute_actions(&mut event_loop, actions);
+        event_loop.run_once(&mut server).unwrap();
+        server.timeout(&mut event_loop, ServerTimeout::Keepalive);
+        assert!(peer_connected(&server, peer_id));
+
+        // A request, by contrast, is expected to be answered.
+        let mut actions = Actions::new();
+        let request = messages::request_vote_request(Term(1), LogIndex(0), Term(0), false);
+        actions.peer_messages.push((peer_id, request));
+        server.execute_actions(&mut event_loop, actions);
+        server.timeout(&mut event_loop, ServerTimeout::Keepalive);
+        assert!(!peer_connected(&server, peer_id));
+    }
+
+    /// Tests that executing an empty set of actions leaves the server's timeouts and connections
+    /// untouched.
+    #[test]
+    fn test_execute_empty_actions() {
+        setup_test!("test_execute_empty_actions");
+        let peer_id = ServerId::from(1);
+
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        let (mut in_stream, _)  = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut in_stream));
+        let (client_id, _stream) = connect_client(&mut server, &mut event_loop);
+
+        let timeouts: HashSet<ConsensusTimeout> = server.consensus_timeouts.keys().cloned().collect();
+        assert!(!timeouts.is_empty());
+        server.execute_actions(&mut event_loop, Actions::new());
+
+        assert_eq!(timeouts, server.consensus_timeouts.keys().cloned().collect::<HashSet<_>>());
+        assert!(server.connections.iter().all(|connection| connection.queued_bytes() == 0));
+        assert!(peer_connected(&server, peer_id));
+        assert!(client_connected(&server, client_id));
+        assert!(server.shutdown_reason.is_none());
+    }
+
+    /// Tests that a client connection which sends only part of a message is reset once the
+    /// partial read timeout elapses.
+    #[test]
+    fn test_partial_read_timeout() {
+        setup_test!("test_partial_read_timeout");
+        let mut config = Config::default();
+        config.partial_read_timeout_ms = 100;
+        let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config)
+                                               .unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+
+        let mut buffer = Vec::new();
+        serialize::write_message(&mut buffer, &messages::proposal_request(b"foo")).unwrap();
+        stream.write_all(&buffer[..buffer.len() / 2]).unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(client_connected(&server, client_id));
+
+        server.schedule_keepalive(&mut event_loop);
+        for _ in 0..10 {
+            if !client_connected(&server, client_id) { break; }
+            event_loop.run_once(&mut server).unwrap();
+        }
+        assert!(!client_connected(&server, client_id));
+    }
+
+    /// Tests that a client connection which sends a message larger than the read buffer limit is
+    /// reset.
+    #[test]
+    fn test_read_buffer_limit() {
+        setup_test!("test_read_buffer_limit");
+        let mut config = Config::default();
+        config.max_read_buffer_bytes = 1024;
+        config.max_message_bytes = 512;
+        let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config)
+                                               .unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+
+        let mut buffer = Vec::new();
+        serialize::write_message(&mut buffer, &messages::proposal_request(&[0; 4096])).unwrap();
+        stream.write_all(&buffer[..2048]).unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!client_connected(&server, client_id));
+    }
+
+    /// Tests that a connection is reset as soon as a message declares a size over the read buffer
+    /// limit, before the rest of the message is sent.
+    #[test]
+    fn test_read_buffer_limit_declared_size() {
+        setup_test!("test_read_buffer_limit_declared_size");
+        let mut config = Config::default();
+        config.max_read_buffer_bytes = 1024;
+        config.max_message_bytes = 512;
+        let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config)
+                                               .unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+
+        // A segment table declaring a single segment of 2^28 words.
+        stream.write_all(&[0, 0, 0, 0, 0, 0, 0, 16]).unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!client_connected(&server, client_id));
+    }
+
+    /// Tests that the server reconnects to a peer at the address given by an
+    /// `UpdatePeerAddress` command.
+    #[test]
+    fn test_update_peer_address() {
+        setup_test!("test_update_peer_address");
+        let peer_id = ServerId::from(1);
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, get_unbound_address());
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        // Error event for the peer connection; connection is reset.
+        event_loop.run_once(&mut server).unwrap();
+        assert!(!peer_connected(&server, peer_id));
+
+        // The peer moves to a new address.
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = peer_listener.local_addr().unwrap();
+        event_loop.channel()
+                  .send(ServerCommand::UpdatePeerAddress { id: peer_id, addr: peer_addr })
+                  .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(peer_addr, *server.connections[server.peer_tokens[&peer_id]].addr());
+        assert_eq!(peer_addr, server.consensus.peers()[&peer_id]);
+
+        // Reconnection timeout fires, and the server connects to the new address.
+        event_loop.run_once(&mut server).unwrap();
+        assert!(peer_connected(&server, peer_id));
+        let (mut stream, _) = peer_listener.accept().unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut stream));
+    }
+
+    /// Tests that a `Propose` command is answered with the result of applying the command, or an
+    /// error if the server is not the leader.
+    #[test]
+    fn test_propose_command() {
+        setup_test!("test_propose_command");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let sender = event_loop.channel();
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::Propose { command: b"foo".to_vec(), timeout_ms: 1000, response: tx })
+              .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        match rx.recv().unwrap() {
+            Err(Error::Raft(RaftError::NotLeader)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let mut actions = Actions::new();
+        server.consensus.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        server.execute_actions(&mut event_loop, actions);
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::Propose { command: b"foo".to_vec(), timeout_ms: 1000, response: tx })
+              .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(Vec::<u8>::new(), rx.recv().unwrap().unwrap());
+        assert!(server.proposals.is_empty());
+    }
+
+    /// Tests that a server without peers is leader as soon as it is initialized, and commits a
+    /// proposal right away.
+    #[test]
+    fn test_solitary_server_init() {
+        setup_test!("test_solitary_server_init");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let actions = server.consensus.init();
+        server.execute_actions(&mut event_loop, actions);
+        assert!(server.consensus.is_leader());
+        assert!(server.consensus_timeouts.is_empty());
+
+        let (tx, rx) = mpsc::channel();
+        event_loop.channel()
+                  .send(ServerCommand::Propose { command: b"foo".to_vec(), timeout_ms: 1000, response: tx })
+                  .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert_eq!(Vec::<u8>::new(), rx.recv().unwrap().unwrap());
+        assert_eq!(LogIndex(2), server.consensus.last_applied());
+    }
+
+    /// Tests that a server halts with a fatal shutdown reason when it fails to persist a log
+    /// entry, rather than continue without it.
+    #[test]
+    fn test_log_write_failure_halts() {
+        setup_test!("test_log_write_failure_halts");
+        let mut event_loop = EventLoop::new().unwrap();
+        // A solitary server appends a no-op entry as soon as it starts, and the append fails.
+        let mut log = TestLog::new(MemLog::new());
+        log.fail_appends = true;
+        let mut server = Server::with_event_loop(ServerId::from(0),
+                                                 SocketAddr::from_str("127.0.0.1:0").unwrap(),
+                                                 HashMap::new(),
+                                                 log,
+                                                 NullStateMachine,
+                                                 Config::default(),
+                                                 &mut event_loop)
+                             .unwrap();
+        match server.run_with_event_loop(&mut event_loop) {
+            ShutdownReason::Fatal(Error::Raft(RaftError::LogWriteFailed(_))) => (),
+            reason => panic!("unexpected shutdown reason: {:?}", reason),
+        }
+        assert_eq!(LogIndex(0), server.consensus.last_applied());
+    }
+
+    /// Tests that a `Propose` command times out when the leader can not reach a quorum.
+    #[test]
+    fn test_propose_command_timeout() {
+        setup_test!("test_propose_command_timeout");
+        let peer_id = ServerId::from(1);
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, get_unbound_address());
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        // Win the election with the unreachable peer's vote.
+        let mut actions = Actions::new();
+        server.consensus.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        let mut buf = Cursor::new(Vec::new());
+        let response = messages::request_vote_response_granted(Term::from(1), false);
+        serialize::write_message(&mut buf, &*response).unwrap();
+        buf.set_position(0);
+        let vote = serialize::read_message(&mut buf, ReaderOptions::new()).unwrap();
+        server.consensus.apply_peer_message(peer_id, &vote, &mut actions).unwrap();
+        server.execute_actions(&mut event_loop, actions);
+        assert!(server.consensus.is_leader());
+
+        let (tx, rx) = mpsc::channel();
+        event_loop.channel()
+                  .send(ServerCommand::Propose { command: b"foo".to_vec(), timeout_ms: 50, response: tx })
+                  .unwrap();
+        let mut result = rx.try_recv();
+        for _ in 0..10 {
+            if result.is_ok() { break; }
+            event_loop.run_once(&mut server).unwrap();
+            result = rx.try_recv();
+        }
+        match result {
+            Ok(Err(Error::Raft(RaftError::Timeout))) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(server.proposals.is_empty());
+    }
+
+    /// Tests that `WaitApplied` commands are answered once the awaited entry is applied, and time
+    /// out otherwise.
+    #[test]
+    fn test_wait_applied() {
+        setup_test!("test_wait_applied");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let sender = event_loop.channel();
+        let mut actions = Actions::new();
+        server.consensus.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        server.execute_actions(&mut event_loop, actions);
+        assert_eq!(LogIndex(1), server.consensus.last_applied());
+
+        // The next proposal is applied at index 2.
+        let (wait_tx, wait_rx) = mpsc::channel();
+        sender.send(ServerCommand::WaitApplied { index: LogIndex(2), timeout_ms: 10000, response: wait_tx })
+              .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        assert!(wait_rx.try_recv().is_err());
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::Propose { command: b"foo".to_vec(), timeout_ms: 10000, response: tx })
+              .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        rx.recv().unwrap().unwrap();
+        wait_rx.recv().unwrap().unwrap();
+        assert!(server.applied_waits.is_empty());
+
+        // An already applied entry is answered immediately.
+        let (wait_tx, wait_rx) = mpsc::channel();
+        sender.send(ServerCommand::WaitApplied { index: LogIndex(2), timeout_ms: 10000, response: wait_tx })
+              .unwrap();
+        event_loop.run_once(&mut server).unwrap();
+        wait_rx.recv().unwrap().unwrap();
+
+        let (wait_tx, wait_rx) = mpsc::channel();
+        sender.send(ServerCommand::WaitApplied { index: LogIndex(3), timeout_ms: 50, response: wait_tx })
+              .unwrap();
+        let mut result = wait_rx.try_recv();
+        while result.is_err() {
+            event_loop.run_once(&mut server).unwrap();
+            result = wait_rx.try_recv();
+        }
+        match result.unwrap() {
+            Err(Error::Raft(RaftError::Timeout)) => (),
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert!(server.applied_waits.is_empty());
+    }
+
+    /// Tests that `TCP_NODELAY` and the socket buffer sizes are set on accepted connections
+    /// according to the configuration.
+    #[test]
+    #[cfg(unix)]
+    fn test_tcp_nodelay() {
+        setup_test!("test_tcp_nodelay");
+        for &nodelay in &[true, false] {
+            let mut config = Config::default();
+            config.tcp_nodelay = nodelay;
+            config.tcp_send_buffer_bytes = Some(10 * 1024);
+            config.tcp_recv_buffer_bytes = Some(12 * 1024);
+            let (mut server, mut event_loop) = new_test_server_with_config(HashMap::new(), config)
+                                                   .unwrap();
+            let (client_id, _stream) = connect_client(&mut server, &mut event_loop);
+            let token = server.client_tokens[&client_id];
+            assert_eq!(nodelay, server.connections[token].nodelay());
+            // Linux doubles the size which is set, to leave room for its bookkeeping.
+            let send_bytes = server.connections[token].buffer_size(BufferOption::Send);
+            assert!(send_bytes == 10 * 1024 || send_bytes == 20 * 1024, "{}", send_bytes);
+            let recv_bytes = server.connections[token].buffer_size(BufferOption::Recv);
+            assert!(recv_bytes == 12 * 1024 || recv_bytes == 24 * 1024, "{}", recv_bytes);
+        }
+    }
+
+    /// Tests that the buffers of sent messages are reused for subsequent messages.
+    #[test]
+    fn test_send_buffer_reuse() {
+        setup_test!("test_send_buffer_reuse");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let (client_id, mut stream) = connect_client(&mut server, &mut event_loop);
+
+        for _ in 0..100 {
+            let mut actions = Actions::new();
+            actions.client_messages.push((client_id, messages::command_response_success(b"foo")));
+            server.execute_actions(&mut event_loop, actions);
+            event_loop.run_once(&mut server).unwrap();
+            let response = serialize::read_message(&mut stream, ReaderOptions::new()).unwrap();
+            assert_eq!(b"foo".to_vec(), messages::decode_command_response(&response).unwrap());
+        }
+        // Only the first message required a buffer to be allocated.
+        assert_eq!(99, server.buffer_pool.reused());
+    }
+
+    /// A state machine which signals when it begins applying a command, and then waits to be
+    /// released before returning the command.
+    struct GatedStateMachine {
+        started: mpsc::Sender<()>,
+        release: mpsc::Receiver<()>,
+    }
+
+    impl fmt::Debug for GatedStateMachine {
+        fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+            write!(fmt, "GatedStateMachine")
+        }
+    }
+
+    impl StateMachine for GatedStateMachine {
+        fn apply(&mut self, command: &[u8]) -> result::Result<Vec<u8>, ApplyError> {
+            self.started.send(()).unwrap();
+            self.release.recv().unwrap();
+            Ok(command.to_vec())
+        }
+        fn query(&self, _query: &[u8]) -> Vec<u8> {
+            Vec::new()
+        }
+        fn snapshot(&self) -> Vec<u8> {
+            Vec::new()
+        }
+        fn restore_snapshot(&mut self, _snapshot: Vec<u8>) {}
+    }
+
+    /// Tests that with `apply_on_worker` set, the server continues to process network events while
+    /// a command is being applied, and responds once it is applied.
+    #[test]
+    fn test_apply_on_worker() {
+        setup_test!("test_apply_on_worker");
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let state_machine = GatedStateMachine { started: started_tx, release: release_rx };
+        let mut config = Config::default();
+        config.apply_on_worker = true;
+        let (mut server, mut event_loop) = Server::new(ServerId::from(0),
+                                                       SocketAddr::from_str("127.0.0.1:0").unwrap(),
+                                                       HashMap::new(),
+                                                       MemLog::new(),
+                                                       state_machine,
+                                                       config).unwrap();
+        let sender = event_loop.channel();
+
+        let mut actions = Actions::new();
+        server.consensus.apply_timeout(ConsensusTimeout::Election, &mut actions);
+        server.execute_actions(&mut event_loop, actions);
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::Propose { command: b"foo".to_vec(), timeout_ms: 10000, response: tx })
+              .unwrap();
+        while started_rx.try_recv().is_err() {
+            event_loop.run_once(&mut server).unwrap();
+        }
+
+        // The command is being applied; a client connection is accepted meanwhile.
+        let client_id = ClientId::new();
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        serialize::write_message(&mut stream, &*messages::client_connection_preamble(client_id))
+                 .unwrap();
+        stream.flush().unwrap();
+        while !server.client_tokens.contains_key(&client_id) {
+            event_loop.run_once(&mut server).unwrap();
+        }
+        assert!(rx.try_recv().is_err());
+
+        release_tx.send(()).unwrap();
+        let mut result = rx.try_recv();
+        while result.is_err() {
+            event_loop.run_once(&mut server).unwrap();
+            result = rx.try_recv();
+        }
+        assert_eq!(b"foo".to_vec(), result.unwrap().unwrap());
+        assert!(server.proposals.is_empty());
+    }
+
+    /// A cluster of servers driven by a single thread, each with its own event loop.
+    #[cfg(unix)]
+    type TestCluster = Vec<(TestServer, EventLoop<TestServer>)>;
+
+    /// Creates a cluster of servers which connect to each other through a `LocalTransport`.
+    #[cfg(unix)]
+    fn new_local_cluster(size: u64) -> TestCluster {
+        let transport = LocalTransport::new();
+        let addrs: HashMap<ServerId, SocketAddr> =
+            (0..size).map(|id| {
+                         let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", id + 1)).unwrap();
+                         (ServerId(id), addr)
+                     })
+                     .collect();
+        (0..size).map(|id| {
+                     let id = ServerId(id);
+                     let mut peers = addrs.clone();
+                     peers.remove(&id);
+                     let mut event_loop = EventLoop::new().unwrap();
+                     let server = Server::with_transport(id,
+                                                         addrs[&id],
+                                                         peers,
+                                                         MemLog::new(),
+                                                         NullStateMachine,
+                                                         Config::default(),
+                                                         Box::new(transport.clone()),
+                                                         &mut event_loop)
+                                      .unwrap();
+                     (server, event_loop)
+                 })
+                 .collect()
+    }
+
+    /// Polls each server of the cluster once, until the condition holds. Each event loop is woken
+    /// with a command before it is polled, so that the poll handles the events which are ready
+    /// rather than waiting on a timer.
+    #[cfg(unix)]
+    fn poll_cluster_until<F>(cluster: &mut TestCluster, mut condition: F)
+    where F: FnMut(&TestCluster) -> bool {
+        for _ in 0..100 {
+            if condition(cluster) {
+                return;
+            }
+            for &mut (ref mut server, ref mut event_loop) in cluster.iter_mut() {
+                let (response, _) = mpsc::channel();
+                event_loop.channel().send(ServerCommand::IsLeader { response: response }).unwrap();
+                assert!(server.poll_once(event_loop).is_none());
+            }
+        }
+        panic!("cluster did not reach the expected state");
+    }
+
+    /// Fires a consensus timeout of the server at once, rather than waiting for its timer.
+    #[cfg(unix)]
+    fn fire_timeout(server: &mut TestServer,
+                    event_loop: &mut EventLoop<TestServer>,
+                    timeout: ConsensusTimeout) {
+        let handle = server.consensus_timeouts[&timeout];
+        assert!(event_loop.clear_timeout(handle));
+        server.timeout(event_loop, ServerTimeout::Consensus(timeout));
+    }
+
+    /// Tests that three servers connected through a `LocalTransport` elect a leader and replicate
+    /// a proposed entry, through the full server, connection, and consensus stack. The test fires
+    /// the election and heartbeat timeouts itself, so the cluster progresses deterministically and
+    /// without waiting on timers.
+    #[test]
+    #[cfg(unix)]
+    fn test_local_cluster() {
+        setup_test!("test_local_cluster");
+        let mut cluster = new_local_cluster(3);
+
+        // The servers connect to each other and exchange preambles.
+        poll_cluster_until(&mut cluster, |cluster| {
+            cluster.iter().all(|&(ref server, _)| {
+                server.peer_tokens.values().all(|&token| {
+                    let connection = &server.connections[token];
+                    connection.is_connected() && !connection.awaiting_preamble()
+                })
+            })
+        });
+
+        {
+            let (ref mut server, ref mut event_loop) = cluster[0];
+            fire_timeout(server, event_loop, ConsensusTimeout::Election);
+        }
+        poll_cluster_until(&mut cluster, |cluster| {
+            cluster.iter()
+                   .all(|&(ref server, _)| server.consensus.current_leader() == Some(ServerId(0)))
+        });
+        assert!(cluster[0].0.consensus.is_leader());
+
+        let (response, result) = mpsc::channel();
+        let command = ServerCommand::Propose {
+            command: b"foo".to_vec(),
+            timeout_ms: 60000,
+            response: response,
+        };
+        cluster[0].1.channel().send(command).unwrap();
+        let mut outcome = None;
+        poll_cluster_until(&mut cluster, |_| {
+            outcome = result.try_recv().ok();
+            outcome.is_some()
+        });
+        assert!(outcome.unwrap().is_ok());
+
+        // The followers learn that the entry is committed from the leader's next heartbeats.
+        let applied = cluster[0].0.consensus.last_applied();
+        {
+            let (ref mut server, ref mut event_loop) = cluster[0];
+            for peer in (1..3).map(ServerId) {
+                fire_timeout(server, event_loop, ConsensusTimeout::Heartbeat(peer));
+            }
+        }
+        poll_cluster_until(&mut cluster, |cluster| {
+            cluster.iter().all(|&(ref server, _)| server.consensus.last_applied() == applied)
+        });
+    }
+
+    /// Tests that a spawned server which is asked to stop reports the request as the reason.
+    #[test]
+    fn test_shutdown_requested() {
+        setup_test!("test_shutdown_requested");
+        let (handle, join_handle) = Server::spawn(ServerId::from(0),
+                                                  SocketAddr::from_str("127.0.0.1:0").unwrap(),
+                                                  HashMap::new(),
+                                                  MemLog::new(),
+                                                  NullStateMachine).unwrap();
+        handle.shutdown().unwrap();
+        match join_handle.join().unwrap() {
+            Ok(ShutdownReason::Requested) => (),
+            other => panic!("unexpected shutdown: {:?}", other),
+        }
+        assert!(handle.is_leader().is_err());
+    }
+
+    /// Tests that a server created in a caller-owned event loop is driven by polling it: it is
+    /// started by the first poll, commands sent through the loop's channel are answered, and a
+    /// requested shutdown is reported.
+    #[test]
+    fn test_poll_once() {
+        setup_test!("test_poll_once");
+        let mut event_loop = EventLoop::new().unwrap();
+        let mut server = Server::with_event_loop(ServerId::from(0),
+                                                 SocketAddr::from_str("127.0.0.1:0").unwrap(),
+                                                 HashMap::new(),
+                                                 MemLog::new(),
+                                                 NullStateMachine,
+                                                 Config::default(),
+                                                 &mut event_loop).unwrap();
+        let sender = event_loop.channel();
+
+        // A solitary server becomes leader as soon as the first poll starts it, without waiting
+        // for an election timeout.
+        assert!(!server.consensus.is_leader());
+        assert!(server.poll_once(&mut event_loop).is_none());
+        assert!(server.consensus.is_leader());
+
+        let (tx, rx) = mpsc::channel();
+        sender.send(ServerCommand::IsLeader { response: tx }).map_err(|_| ()).unwrap();
+        assert!(server.poll_once(&mut event_loop).is_none());
+        assert!(rx.try_recv().unwrap());
+
+        sender.send(ServerCommand::Shutdown).map_err(|_| ()).unwrap();
+        match server.poll_once(&mut event_loop) {
+            Some(ShutdownReason::Requested) => (),
+            other => panic!("unexpected shutdown: {:?}", other),
+        }
     }
 }
@@ -24,8 +24,27 @@ pub enum ConsensusState {
 pub struct LeaderState {
     next_index: HashMap<ServerId, LogIndex>,
     match_index: HashMap<ServerId, LogIndex>,
-    /// Stores in-flight client proposals.
-    pub proposals: VecDeque<(ClientId, LogIndex)>,
+    /// The AppendEntries requests sent to each follower which have not yet been acknowledged, as
+    /// the indexes of the first and last entries in the request and the size of the request's
+    /// entries in bytes.
+    in_flight: HashMap<ServerId, VecDeque<(LogIndex, LogIndex, u64)>>,
+    /// The latest read round. Each query starts a new round, which heartbeats carry to followers.
+    read_round: u64,
+    /// Queries awaiting confirmation of leadership, in the order they were received.
+    pending_reads: VecDeque<PendingRead>,
+}
+
+/// A client query which may only be served once a majority of the cluster has confirmed that the
+/// leader is still the leader, and the leader has applied every entry which may have been committed
+/// when the query arrived.
+#[derive(Clone, Debug)]
+struct PendingRead {
+    client: ClientId,
+    query: Vec<u8>,
+    round: u64,
+    read_index: LogIndex,
+    /// The followers which have acknowledged a heartbeat of this round or a later one.
+    confirmations: HashSet<ServerId>,
 }
 
 impl LeaderState {
@@ -40,11 +59,14 @@ impl LeaderState {
     pub fn new(latest_log_index: LogIndex, peers: &HashSet<ServerId>) -> LeaderState {
         let next_index = peers.iter().cloned().map(|peer| (peer, latest_log_index + 1)).collect();
         let match_index = peers.iter().cloned().map(|peer| (peer, LogIndex::from(0))).collect();
+        let in_flight = peers.iter().cloned().map(|peer| (peer, VecDeque::new())).collect();
 
         LeaderState {
             next_index: next_index,
             match_index: match_index,
-            proposals: VecDeque::new(),
+            in_flight: in_flight,
+            read_round: 0,
+            pending_reads: VecDeque::new(),
         }
     }
 
@@ -58,12 +80,98 @@ impl LeaderState {
         self.next_index.insert(follower, index);
     }
 
+    /// Returns the index of the highest log entry known to be replicated on the follower.
+    #[cfg(test)]
+    pub fn match_index(&self, follower: &ServerId) -> LogIndex {
+        self.match_index[follower]
+    }
+
     /// Sets the index of the highest log entry known to be replicated on the
     /// follower.
     pub fn set_match_index(&mut self, follower: ServerId, index: LogIndex) {
         self.match_index.insert(follower, index);
     }
 
+    /// Records that the entries from `first_index` to `last_index`, totalling `bytes` bytes, were
+    /// sent to the follower.
+    pub fn record_in_flight(&mut self,
+                            follower: ServerId,
+                            first_index: LogIndex,
+                            last_index: LogIndex,
+                            bytes: u64) {
+        self.in_flight.get_mut(&follower).unwrap().push_back((first_index, last_index, bytes));
+    }
+
+    /// Records that the follower has acknowledged all entries up to and including `index`.
+    pub fn ack_in_flight(&mut self, follower: ServerId, index: LogIndex) {
+        let in_flight = self.in_flight.get_mut(&follower).unwrap();
+        while in_flight.front().map_or(false, |&(_, last_index, _)| last_index <= index) {
+            in_flight.pop_front();
+        }
+    }
+
+    /// Forgets the entries sent to the follower, for instance because they were lost or rejected.
+    /// Returns the index of the first entry which was not acknowledged, if any were in flight.
+    pub fn clear_in_flight(&mut self, follower: ServerId) -> Option<LogIndex> {
+        let in_flight = self.in_flight.get_mut(&follower).unwrap();
+        let first_index = in_flight.front().map(|&(first_index, _, _)| first_index);
+        in_flight.clear();
+        first_index
+    }
+
+    /// Returns the size in bytes of the entries sent to the follower which have not yet been
+    /// acknowledged.
+    pub fn in_flight_bytes(&self, follower: &ServerId) -> u64 {
+        self.in_flight[follower].iter().fold(0, |total, &(_, _, bytes)| total + bytes)
+    }
+
+    /// Returns the latest read round.
+    pub fn read_round(&self) -> u64 {
+        self.read_round
+    }
+
+    /// Registers a query, which may be served once leadership is confirmed for a new read round
+    /// and entries up to `read_index` are applied. Returns the new read round.
+    pub fn register_read(&mut self, client: ClientId, query: Vec<u8>, read_index: LogIndex) -> u64 {
+        self.read_round += 1;
+        self.pending_reads.push_back(PendingRead {
+            client: client,
+            query: query,
+            round: self.read_round,
+            read_index: read_index,
+            confirmations: HashSet::new(),
+        });
+        self.read_round
+    }
+
+    /// Records that the follower acknowledged a heartbeat of the given read round.
+    pub fn confirm_reads(&mut self, follower: ServerId, round: u64) {
+        for read in self.pending_reads.iter_mut().take_while(|read| read.round <= round) {
+            read.confirmations.insert(follower);
+        }
+    }
+
+    /// Removes and returns the pending reads which have been confirmed by `majority` servers
+    /// (including the leader) and whose read index is at most `last_applied`, in order.
+    pub fn take_ready_reads(&mut self,
+                            majority: usize,
+                            last_applied: LogIndex)
+                            -> Vec<(ClientId, Vec<u8>)> {
+        let mut ready = Vec::new();
+        while self.pending_reads.front().map_or(false, |read| {
+            read.confirmations.len() + 1 >= majority && read.read_index <= last_applied
+        }) {
+            let read = self.pending_reads.pop_front().unwrap();
+            ready.push((read.client, read.query));
+        }
+        ready
+    }
+
+    /// Removes all pending reads, returning their clients.
+    pub fn take_pending_reads(&mut self) -> Vec<ClientId> {
+        self.pending_reads.drain(..).map(|read| read.client).collect()
+    }
+
     /// Counts the number of followers containing the given log index.
     pub fn count_match_indexes(&self, index: LogIndex) -> usize {
         // +1 for self.
@@ -78,7 +186,9 @@ impl LeaderState {
         for (_, match_index) in self.match_index.iter_mut() {
             *match_index = LogIndex::from(0);
         }
-        self.proposals.clear();
+        for (_, in_flight) in self.in_flight.iter_mut() {
+            in_flight.clear();
+        }
     }
 }
 
@@ -96,8 +206,8 @@ impl CandidateState {
     }
 
     /// Records a vote from `voter`.
-    pub fn record_vote(&mut self, voter: ServerId) {
-        self.granted_votes.insert(voter);
+    pub fn record_vote(&mut self, voter: ServerId) -> bool {
+        self.granted_votes.insert(voter)
     }
 
     /// Returns the number of votes.
@@ -137,12 +247,70 @@ impl FollowerState {
     }
 }
 
+/// The number of round trip time samples retained for each peer.
+const ROUND_TRIP_SAMPLES: usize = 64;
+
+/// Round trip times of the requests a consensus module sends to each peer, measured from sending
+/// a request to receiving the peer's response. While a request is unanswered, further requests
+/// to the peer are not timed.
+#[derive(Clone, Debug)]
+pub struct RoundTrips {
+    /// The time at which the oldest unanswered request to each peer was sent.
+    pending: HashMap<ServerId, u64>,
+    /// The most recent round trip times to each peer, in milliseconds.
+    samples: HashMap<ServerId, VecDeque<u64>>,
+}
+
+impl RoundTrips {
+
+    /// Returns a new `RoundTrips` with no samples.
+    pub fn new() -> RoundTrips {
+        RoundTrips { pending: HashMap::new(), samples: HashMap::new() }
+    }
+
+    /// Records that a request was sent to the peer at time `now`.
+    pub fn sent(&mut self, peer: ServerId, now: u64) {
+        self.pending.entry(peer).or_insert(now);
+    }
+
+    /// Records that a response was received from the peer at time `now`.
+    pub fn received(&mut self, peer: ServerId, now: u64) {
+        if let Some(sent) = self.pending.remove(&peer) {
+            let samples = self.samples.entry(peer).or_insert_with(VecDeque::new);
+            if samples.len() == ROUND_TRIP_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back(now.saturating_sub(sent));
+        }
+    }
+
+    /// Forgets the unanswered requests, whose responses may never arrive.
+    pub fn clear_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Returns the 99th percentile of the round trip times to all peers, or `None` if there are
+    /// no samples.
+    pub fn p99(&self) -> Option<u64> {
+        let mut samples: Vec<u64> = self.samples
+                                        .values()
+                                        .flat_map(|samples| samples.iter().cloned())
+                                        .collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+        let rank = (samples.len() * 99 + 99) / 100;
+        Some(samples[rank - 1])
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashSet;
 
     use {LogIndex, ServerId};
-    use state::LeaderState;
+    use state::{LeaderState, RoundTrips};
 
     /// Tests the `LeaderState`'s  `.count_match_indexes()` function and makes sure it adequately
     /// produces the correct values.
@@ -172,4 +340,33 @@ mod tests {
         leader_state.set_match_index(ServerId(2), LogIndex(1));
         assert_eq!(3, leader_state.count_match_indexes(LogIndex(1)));
     }
+
+    /// Tests that `RoundTrips` measures one request at a time per peer, and computes the 99th
+    /// percentile over all peers.
+    #[test]
+    fn test_round_trips() {
+        let mut round_trips = RoundTrips::new();
+        assert_eq!(None, round_trips.p99());
+
+        // Only the first of two unanswered requests is timed.
+        round_trips.sent(ServerId(1), 0);
+        round_trips.sent(ServerId(1), 5);
+        round_trips.received(ServerId(1), 10);
+        assert_eq!(Some(10), round_trips.p99());
+
+        // A response without a timed request is ignored.
+        round_trips.received(ServerId(1), 20);
+        assert_eq!(Some(10), round_trips.p99());
+
+        for rtt in 1..100 {
+            round_trips.sent(ServerId(2), 100);
+            round_trips.received(ServerId(2), 100 + rtt);
+        }
+        assert_eq!(Some(99), round_trips.p99());
+
+        round_trips.sent(ServerId(1), 200);
+        round_trips.clear_pending();
+        round_trips.received(ServerId(1), 1000);
+        assert_eq!(Some(99), round_trips.p99());
+    }
 }
@@ -1,7 +1,8 @@
 use std::fmt::{self, Debug};
+use std::result;
 use std::sync::mpsc;
 
-use state_machine::StateMachine;
+use state_machine::{ApplyError, StateMachine};
 
 
 /// A state machine that simply redirects all commands to a channel.
@@ -20,8 +21,10 @@ impl ChannelStateMachine {
 
 impl StateMachine for ChannelStateMachine {
 
-    fn apply(&mut self, command: &[u8]) -> Vec<u8> {
-        self.tx.send(command.to_vec()).map(|_| Vec::new()).unwrap_or(b"An error occured."[..].into())
+    fn apply(&mut self, command: &[u8]) -> result::Result<Vec<u8>, ApplyError> {
+        self.tx.send(command.to_vec())
+               .map(|_| Vec::new())
+               .map_err(|_| ApplyError::Fatal("channel receiver disconnected".to_string()))
     }
 
     fn query(&self, _query: &[u8]) -> Vec<u8> {
@@ -8,6 +8,9 @@
 //! `remove`. The `raft` library would guarantee that the same order of `insert` and `remove`
 //! commands would be seen by all consensus modules.
 use std::fmt::Debug;
+use std::result;
+
+use Term;
 
 mod channel;
 mod null;
@@ -15,6 +18,18 @@ mod null;
 pub use state_machine::channel::ChannelStateMachine;
 pub use state_machine::null::NullStateMachine;
 
+/// An error applying a committed command to a `StateMachine`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The state machine refused the command, for instance because it is invalid for the current
+    /// state. The entry remains committed, and the application-specific reason is returned to the
+    /// client. The server continues normally.
+    Rejected(Vec<u8>),
+    /// The state machine can no longer apply commands. The server halts, rather than continue with
+    /// a state which may have diverged from the other replicas.
+    Fatal(String),
+}
+
 /// This trait is meant to be implemented such that the commands issued to it via `apply()` will
 /// be reflected in your consuming application. Commands sent via `apply()` have been committed
 /// in the cluser. Unlike `store`, your application should consume data produced by this and
@@ -26,7 +41,10 @@ pub trait StateMachine: Debug + Send + 'static {
 
     /// Applies a command to the state machine.
     /// Returns an application-specific result value.
-    fn apply(&mut self, command: &[u8]) -> Vec<u8>;
+    ///
+    /// `apply` must be deterministic: every replica applies the same commands in the same order,
+    /// and must arrive at the same state and the same result, including the same rejections.
+    fn apply(&mut self, command: &[u8]) -> result::Result<Vec<u8>, ApplyError>;
 
     /// Queries a value of the state machine. Does not go through the durable log, or mutate the
     /// state machine.
@@ -38,4 +56,10 @@ pub trait StateMachine: Debug + Send + 'static {
 
     /// Restore a snapshot of the state machine.
     fn restore_snapshot(&mut self, snapshot: Vec<u8>) -> ();
+
+    /// Called when the local server gains leadership of `term` (`is_leader` is `true`), or loses
+    /// the leadership it held of `term` (`is_leader` is `false`). Useful for starting and
+    /// stopping work which should only run on the leader. Calls are ordered with respect to
+    /// `apply`. Does nothing by default.
+    fn on_leadership_change(&mut self, _is_leader: bool, _term: Term) {}
 }
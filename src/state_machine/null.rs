@@ -1,4 +1,6 @@
-use state_machine::StateMachine;
+use std::result;
+
+use state_machine::{ApplyError, StateMachine};
 
 /// A state machine with no states.
 #[derive(Debug)]
@@ -6,8 +8,8 @@ pub struct NullStateMachine;
 
 impl StateMachine for NullStateMachine {
 
-    fn apply(&mut self, _command: &[u8]) -> Vec<u8> {
-        Vec::new()
+    fn apply(&mut self, _command: &[u8]) -> result::Result<Vec<u8>, ApplyError> {
+        Ok(Vec::new())
     }
 
     fn query(&self, _query: &[u8]) -> Vec<u8> {
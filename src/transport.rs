@@ -0,0 +1,393 @@
+use std::{cmp, mem};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(all(test, unix))]
+use std::cell::RefCell;
+#[cfg(all(test, unix))]
+use std::collections::{HashMap, VecDeque};
+#[cfg(all(test, unix))]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+#[cfg(all(test, unix))]
+use std::os::unix::net;
+#[cfg(all(test, unix))]
+use std::rc::Rc;
+
+use libc;
+use mio::{Evented, EventSet, PollOpt, Selector, Token};
+use mio::tcp::{TcpListener, TcpStream};
+#[cfg(all(test, unix))]
+use mio::unix::UnixStream;
+
+/// Establishes the connections of a `Server`: listens for connections from peers and clients, and
+/// connects to peers. Deployments use `TcpTransport`; tests may connect servers in-process
+/// through a `LocalTransport`, which exercises the same connection and event loop code.
+///
+/// The trait is private to the crate. The mio event loop polls only OS handles, so every
+/// `Listener` and `Stream` wraps one: the local transport runs over Unix socket pairs rather than
+/// channels, and another crate could not implement a transport which the event loop can poll.
+pub trait Transport {
+
+    /// Returns a listener bound to the address.
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Listener>;
+
+    /// Begins connecting to the address. As with a non-blocking TCP connect, a failure to connect
+    /// may only be reported once the stream is polled.
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Stream>;
+}
+
+/// The transport over TCP sockets.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport {
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Listener> {
+        TcpListener::bind(addr).map(Listener::Tcp)
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Stream> {
+        TcpStream::connect(addr).map(Stream::Tcp)
+    }
+}
+
+/// A stream connecting a server to a peer or client, established through a `Transport`.
+pub enum Stream {
+    Tcp(TcpStream),
+    /// One end of an in-process socket pair, and the address it was connected through.
+    #[cfg(all(test, unix))]
+    Local(UnixStream, SocketAddr),
+}
+
+impl Stream {
+
+    /// Returns the address of the remote end of the stream. The remote end of a local stream is
+    /// known only by the address it was connected through.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.peer_addr(),
+            #[cfg(all(test, unix))]
+            Stream::Local(_, addr) => Ok(addr),
+        }
+    }
+
+    /// Sets the `TCP_NODELAY` option of a TCP stream. Other streams do not delay writes.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.set_nodelay(nodelay),
+            #[cfg(all(test, unix))]
+            Stream::Local(..) => Ok(()),
+        }
+    }
+
+    /// Sets the size, in bytes, of the kernel send (`SO_SNDBUF`) or receive (`SO_RCVBUF`) buffer
+    /// of a TCP stream. The buffers of other streams are left as they are.
+    pub fn set_buffer_size(&self, option: BufferOption, bytes: usize) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => set_socket_option(stream.as_raw_fd(), option.name(), bytes),
+            #[cfg(all(test, unix))]
+            Stream::Local(..) => Ok(()),
+        }
+    }
+}
+
+/// A socket buffer whose size may be set with `Stream::set_buffer_size`.
+#[derive(Clone, Copy, Debug)]
+pub enum BufferOption {
+    Send,
+    Recv,
+}
+
+impl BufferOption {
+    fn name(self) -> libc::c_int {
+        match self {
+            BufferOption::Send => libc::SO_SNDBUF,
+            BufferOption::Recv => libc::SO_RCVBUF,
+        }
+    }
+}
+
+/// Sets a `SOL_SOCKET` level option of a socket to an integer value, which saturates at the
+/// largest value the option can hold.
+fn set_socket_option(fd: RawFd, name: libc::c_int, value: usize) -> io::Result<()> {
+    let value = cmp::min(value, libc::c_int::max_value() as usize) as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(fd,
+                         libc::SOL_SOCKET,
+                         name,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+/// Returns the size, in bytes, of the kernel send or receive buffer of a socket.
+#[cfg(test)]
+pub fn buffer_size(fd: RawFd, option: BufferOption) -> io::Result<usize> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(fd,
+                         libc::SOL_SOCKET,
+                         option.name(),
+                         &mut value as *mut libc::c_int as *mut libc::c_void,
+                         &mut len)
+    };
+    if ret == 0 { Ok(value as usize) } else { Err(io::Error::last_os_error()) }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref mut stream, _) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref mut stream, _) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref mut stream, _) => stream.flush(),
+        }
+    }
+}
+
+impl Evented for Stream {
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.register(selector, token, interest, opts),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref stream, _) => stream.register(selector, token, interest, opts),
+        }
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.reregister(selector, token, interest, opts),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref stream, _) => stream.reregister(selector, token, interest, opts),
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref stream) => stream.deregister(selector),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref stream, _) => stream.deregister(selector),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref stream) => stream.as_raw_fd(),
+            #[cfg(all(test, unix))]
+            Stream::Local(ref stream, _) => stream.as_raw_fd(),
+        }
+    }
+}
+
+/// A listener for the connections of peers and clients, bound through a `Transport`.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(all(test, unix))]
+    Local(LocalListener),
+}
+
+impl Listener {
+
+    /// Accepts a pending connection, or returns `None` if none is pending.
+    pub fn accept(&self) -> io::Result<Option<Stream>> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.accept().map(|stream| stream.map(Stream::Tcp)),
+            #[cfg(all(test, unix))]
+            Listener::Local(ref listener) => listener.accept(),
+        }
+    }
+
+    /// Returns the address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr(),
+            #[cfg(all(test, unix))]
+            Listener::Local(ref listener) => Ok(listener.addr),
+        }
+    }
+}
+
+impl Evented for Listener {
+    fn register(&self,
+                selector: &mut Selector,
+                token: Token,
+                interest: EventSet,
+                opts: PollOpt)
+                -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.register(selector, token, interest, opts),
+            #[cfg(all(test, unix))]
+            Listener::Local(ref listener) => {
+                listener.doorbell.borrow().register(selector, token, interest, opts)
+            },
+        }
+    }
+
+    fn reregister(&self,
+                  selector: &mut Selector,
+                  token: Token,
+                  interest: EventSet,
+                  opts: PollOpt)
+                  -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.reregister(selector, token, interest, opts),
+            #[cfg(all(test, unix))]
+            Listener::Local(ref listener) => {
+                listener.doorbell.borrow().reregister(selector, token, interest, opts)
+            },
+        }
+    }
+
+    fn deregister(&self, selector: &mut Selector) -> io::Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.deregister(selector),
+            #[cfg(all(test, unix))]
+            Listener::Local(ref listener) => listener.doorbell.borrow().deregister(selector),
+        }
+    }
+}
+
+/// Returns both ends of a new non-blocking Unix socket pair.
+#[cfg(all(test, unix))]
+fn socket_pair() -> io::Result<(UnixStream, UnixStream)> {
+    let (a, b) = try!(net::UnixStream::pair());
+    try!(a.set_nonblocking(true));
+    try!(b.set_nonblocking(true));
+    // The descriptors are released by the standard library streams, and owned by the mio streams.
+    unsafe {
+        Ok((UnixStream::from_raw_fd(a.into_raw_fd()), UnixStream::from_raw_fd(b.into_raw_fd())))
+    }
+}
+
+/// An in-process transport for tests, which connects servers through Unix socket pairs rather
+/// than network sockets. Servers sharing a `LocalTransport`, through clones of it, reach each
+/// other by the addresses they listen on, though no address is bound and port 0 is not assigned
+/// a port. A connection made to an address before it is listened on is accepted once it is.
+#[cfg(all(test, unix))]
+#[derive(Clone, Default)]
+pub struct LocalTransport {
+    /// The connections awaiting acceptance at each address.
+    backlogs: Rc<RefCell<HashMap<SocketAddr, Backlog>>>,
+}
+
+/// The connections awaiting acceptance at an address. Each pending connection is signalled by a
+/// byte written to the doorbell, whose other end the listener polls for readability, so that the
+/// listener is readable exactly while connections are pending, like a TCP listener.
+#[cfg(all(test, unix))]
+struct Backlog {
+    /// The accepting ends of the pending connections.
+    pending: VecDeque<UnixStream>,
+    /// The end of the doorbell rung by connecting streams.
+    doorbell: UnixStream,
+    /// The end of the doorbell polled by the listener, until the address is listened on.
+    listener_doorbell: Option<UnixStream>,
+}
+
+#[cfg(all(test, unix))]
+impl LocalTransport {
+
+    pub fn new() -> LocalTransport {
+        LocalTransport::default()
+    }
+
+    /// Applies the function to the backlog of the address, creating it if necessary.
+    fn with_backlog<F, T>(&self, addr: &SocketAddr, f: F) -> io::Result<T>
+    where F: FnOnce(&mut Backlog) -> io::Result<T> {
+        let mut backlogs = self.backlogs.borrow_mut();
+        if !backlogs.contains_key(addr) {
+            let (doorbell, listener_doorbell) = try!(socket_pair());
+            backlogs.insert(*addr, Backlog {
+                pending: VecDeque::new(),
+                doorbell: doorbell,
+                listener_doorbell: Some(listener_doorbell),
+            });
+        }
+        f(backlogs.get_mut(addr).unwrap())
+    }
+}
+
+#[cfg(all(test, unix))]
+impl Transport for LocalTransport {
+
+    fn listen(&self, addr: &SocketAddr) -> io::Result<Listener> {
+        let doorbell = try!(self.with_backlog(addr, |backlog| {
+            backlog.listener_doorbell.take().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::AddrInUse, "address is already listened on")
+            })
+        }));
+        Ok(Listener::Local(LocalListener {
+            addr: *addr,
+            doorbell: RefCell::new(doorbell),
+            backlogs: self.backlogs.clone(),
+        }))
+    }
+
+    fn connect(&self, addr: &SocketAddr) -> io::Result<Stream> {
+        let (stream, accepting_stream) = try!(socket_pair());
+        try!(self.with_backlog(addr, |backlog| {
+            backlog.pending.push_back(accepting_stream);
+            backlog.doorbell.write_all(&[0])
+        }));
+        Ok(Stream::Local(stream, *addr))
+    }
+}
+
+/// A listener of a `LocalTransport`.
+#[cfg(all(test, unix))]
+pub struct LocalListener {
+    addr: SocketAddr,
+    /// The end of the address's doorbell polled by the listener.
+    doorbell: RefCell<UnixStream>,
+    backlogs: Rc<RefCell<HashMap<SocketAddr, Backlog>>>,
+}
+
+#[cfg(all(test, unix))]
+impl LocalListener {
+
+    /// Accepts a pending connection, or returns `None` if none is pending.
+    fn accept(&self) -> io::Result<Option<Stream>> {
+        match self.doorbell.borrow_mut().read(&mut [0]) {
+            Ok(_) => {},
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(error) => return Err(error),
+        }
+        let stream = self.backlogs
+                         .borrow_mut()
+                         .get_mut(&self.addr)
+                         .and_then(|backlog| backlog.pending.pop_front());
+        Ok(stream.map(|stream| Stream::Local(stream, self.addr)))
+    }
+}